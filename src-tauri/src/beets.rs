@@ -1,5 +1,5 @@
 use log::info;
-use rusqlite::{Connection, OpenFlags, Result};
+use rusqlite::{params_from_iter, Connection, OpenFlags, Result};
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
@@ -10,6 +10,7 @@ use tauri::Emitter;
 use crate::{
     artwork::get_image_format,
     metadata::{convert_file_src, country_name, Album, AlbumArtwork, FileInfo, Song},
+    smart_query::SqlFilter,
     store::load_settings,
 };
 
@@ -141,7 +142,8 @@ fn map_sort_column_album(field: &str) -> &'static str {
 
 const SONG_FIELDS: &str = "id, path, title, artist, album, albumartist, comp, 
             year, genre, composer, track, tracktotal, disc, disctotal, 
-            length, bitrate, samplerate, bitdepth, channels, format, added, country, album_id";
+            length, bitrate, samplerate, bitdepth, channels, format, added, country, album_id, 
+            bpm, initial_key";
 
 pub fn query_beets_to_songs(
     db_path: &PathBuf,
@@ -416,6 +418,33 @@ pub fn query_beets_albums_by_ids(
     albums_iter.collect()
 }
 
+/// Songs matching a compiled smart query, or all of them without one
+pub fn query_beets_filtered(
+    db_path: &PathBuf,
+    filter: Option<&SqlFilter>,
+    sort_by: &str,
+    descending: bool,
+) -> Result<Vec<Song>> {
+    let conn = Connection::open_with_flags(
+        db_path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+
+    let order_dir = if descending { "DESC" } else { "ASC" };
+    let query_sql = format!(
+        "SELECT {} FROM items WHERE {} ORDER BY {} {}",
+        SONG_FIELDS,
+        filter.map(|f| f.sql.as_str()).unwrap_or("1"),
+        map_sort_column(sort_by),
+        order_dir
+    );
+
+    let mut stmt = conn.prepare(&query_sql)?;
+    let values = filter.map(|f| f.values.clone()).unwrap_or_default();
+    let song_iter = stmt.query_map(params_from_iter(values.iter()), row_to_song)?;
+    song_iter.collect()
+}
+
 pub fn query_beets_album_tracks(db_path: &PathBuf, album_id: String) -> Result<Vec<Song>> {
     let conn = Connection::open_with_flags(
         db_path,
@@ -490,6 +519,7 @@ fn row_to_song(row: &rusqlite::Row) -> rusqlite::Result<Song> {
             .map(|s| s.to_string_lossy().into_owned())
             .unwrap_or_default(),
         file_info,
+        dynamics: None,
         metadata: HashMap::new(),
         title: final_title,
         artist: row
@@ -521,6 +551,22 @@ fn row_to_song(row: &rusqlite::Row) -> rusqlite::Result<Song> {
             .and_then(|c| country_name(c.as_str()))
             .map(|c| c.to_string()),
         date_added: Some((row.get::<_, f64>(20).unwrap_or(0.0) * 1000.0) as u128),
+        cue_path: None,
+        cue_track: None,
+        start_offset: None,
+        end_offset: None,
+        chapters: vec![],
+        bpm: row
+            .get::<_, Option<i64>>(23)
+            .ok()
+            .flatten()
+            .filter(|b| *b > 0)
+            .map(|b| b as f64),
+        key: row
+            .get::<_, Option<String>>(24)
+            .ok()
+            .flatten()
+            .filter(|k| !k.is_empty()),
     })
 }
 
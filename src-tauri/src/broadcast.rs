@@ -0,0 +1,268 @@
+use axum::body::Body;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use log::{error, info, warn};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use symphonia::core::conv::IntoSample;
+use tauri::{AppHandle, Listener};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::encoder::{quantize, Encoder, EncoderFormat};
+use crate::store::load_settings;
+
+const DEFAULT_PORT: u16 = 8000;
+const BITS_PER_SAMPLE: u32 = 16;
+/// Bytes of audio between ICY metadata blocks
+const ICY_METAINT: usize = 16000;
+/// Send silence when nothing is playing so clients don't time out
+const SILENCE_AFTER: Duration = Duration::from_secs(1);
+
+/// Post-DSP samples, before the local volume is applied
+pub struct AudioChunk {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub samples: Vec<f32>,
+}
+
+static SENDER: OnceLock<broadcast::Sender<Arc<AudioChunk>>> = OnceLock::new();
+static SAMPLE_RATE: AtomicU32 = AtomicU32::new(0);
+static CHANNELS: AtomicUsize = AtomicUsize::new(0);
+/// "Artist - Title" of the current song
+static TITLE: Mutex<String> = Mutex::new(String::new());
+
+/**
+ * Called by the output with every processed packet. Only copies the samples
+ * when a client is listening, and never blocks the audio path.
+ */
+pub fn tap<T: IntoSample<f32> + Copy>(samples: &[T], channels: usize, sample_rate: u32) {
+    SAMPLE_RATE.store(sample_rate, Ordering::Relaxed);
+    CHANNELS.store(channels, Ordering::Relaxed);
+
+    let Some(sender) = SENDER.get() else {
+        return;
+    };
+    if sender.receiver_count() == 0 {
+        return;
+    }
+    let chunk = AudioChunk {
+        sample_rate,
+        channels,
+        samples: samples.iter().map(|s| (*s).into_sample()).collect(),
+    };
+    // Slow clients skip ahead on their own
+    let _ = sender.send(Arc::new(chunk));
+}
+
+/**
+ * Starts the HTTP broadcast if enabled in the settings. Serves the output on
+ * all interfaces so other devices on the network can listen along.
+ */
+pub fn init(app: AppHandle) {
+    let Ok(settings) = load_settings(&app) else {
+        return;
+    };
+    if !settings.broadcast_enabled.unwrap_or(false) {
+        return;
+    }
+    let port = settings.broadcast_port.unwrap_or(DEFAULT_PORT);
+    let format = EncoderFormat::from_setting(settings.broadcast_format.as_deref());
+
+    let (sender, _) = broadcast::channel(256);
+    let _ = SENDER.set(sender);
+
+    app.listen_any("song_change", |event| {
+        let Ok(song) = serde_json::from_str::<serde_json::Value>(event.payload()) else {
+            return;
+        };
+        let artist = song["artist"].as_str().unwrap_or_default();
+        let title = song["title"].as_str().unwrap_or_default();
+        *TITLE.lock().unwrap() = if artist.is_empty() {
+            title.to_string()
+        } else {
+            format!("{} - {}", artist, title)
+        };
+    });
+
+    let router = Router::new()
+        .route("/", get(move |headers: HeaderMap| stream(headers, format)))
+        .route(
+            "/stream",
+            get(move |headers: HeaderMap| stream(headers, format)),
+        );
+
+    tauri::async_runtime::spawn(async move {
+        let address = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let listener = match TcpListener::bind((address, port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("[Broadcast] Error listening on port {}: {}", port, err);
+                return;
+            }
+        };
+        info!(
+            "[Broadcast] Streaming {} on {}:{}",
+            format.extension(),
+            address,
+            port
+        );
+        if let Err(err) = axum::serve(listener, router).await {
+            error!("[Broadcast] Server error: {}", err);
+        }
+    });
+}
+
+async fn stream(headers: HeaderMap, format: EncoderFormat) -> Response {
+    let Some(sender) = SENDER.get() else {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    };
+    let wants_metadata = headers
+        .get("icy-metadata")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.trim() == "1");
+
+    info!("[Broadcast] Client connected, metadata: {}", wants_metadata);
+    let client = Client {
+        receiver: sender.subscribe(),
+        format,
+        encoder: None,
+        icy: wants_metadata.then(IcyWriter::new),
+    };
+    let body = futures_util::stream::unfold(client, |mut client| async move {
+        let bytes = client.next_bytes().await?;
+        Some((Ok::<_, std::io::Error>(bytes), client))
+    });
+
+    let mut response = Response::builder()
+        .header(header::CONTENT_TYPE, format.content_type())
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("icy-name", "Musicat");
+    if wants_metadata {
+        response = response.header("icy-metaint", ICY_METAINT.to_string());
+    }
+    response
+        .body(Body::from_stream(body))
+        .unwrap_or_else(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())
+}
+
+struct Client {
+    receiver: broadcast::Receiver<Arc<AudioChunk>>,
+    format: EncoderFormat,
+    encoder: Option<(Encoder, u32, usize)>,
+    icy: Option<IcyWriter>,
+}
+
+impl Client {
+    /**
+     * Encodes the next chunk, or silence while paused. Ends the stream when the
+     * output format changes, clients reconnect and get a new header.
+     */
+    async fn next_bytes(&mut self) -> Option<Vec<u8>> {
+        loop {
+            let (samples, sample_rate, channels) =
+                match tokio::time::timeout(SILENCE_AFTER, self.receiver.recv()).await {
+                    Ok(Ok(chunk)) => (
+                        chunk
+                            .samples
+                            .iter()
+                            .map(|s| quantize(*s, BITS_PER_SAMPLE))
+                            .collect::<Vec<i32>>(),
+                        chunk.sample_rate,
+                        chunk.channels,
+                    ),
+                    Ok(Err(RecvError::Lagged(skipped))) => {
+                        warn!("[Broadcast] Client lagging, skipped {} chunks", skipped);
+                        continue;
+                    }
+                    Ok(Err(RecvError::Closed)) => return None,
+                    Err(_) => {
+                        let sample_rate = SAMPLE_RATE.load(Ordering::Relaxed);
+                        let channels = CHANNELS.load(Ordering::Relaxed);
+                        if sample_rate == 0 || channels == 0 {
+                            continue;
+                        }
+                        let frames = (sample_rate as f64 * SILENCE_AFTER.as_secs_f64()) as usize;
+                        (vec![0; frames * channels], sample_rate, channels)
+                    }
+                };
+
+            let mut output = Vec::new();
+            match &self.encoder {
+                Some((_, rate, count)) if *rate != sample_rate || *count != channels => {
+                    info!("[Broadcast] Output format changed, ending stream");
+                    return None;
+                }
+                Some(_) => {}
+                None => {
+                    let encoder = Encoder::new(self.format, sample_rate, channels, BITS_PER_SAMPLE);
+                    output.extend(encoder.header(None));
+                    self.encoder = Some((encoder, sample_rate, channels));
+                }
+            }
+            let (encoder, _, _) = self.encoder.as_mut()?;
+            output.extend(encoder.encode(&samples));
+            if output.is_empty() {
+                continue;
+            }
+            return Some(match &mut self.icy {
+                Some(icy) => icy.interleave(&output),
+                None => output,
+            });
+        }
+    }
+}
+
+/**
+ * Inserts a metadata block every ICY_METAINT bytes of audio,
+ * with the title only when it changed.
+ */
+struct IcyWriter {
+    until_metadata: usize,
+    sent_title: Option<String>,
+}
+
+impl IcyWriter {
+    fn new() -> Self {
+        IcyWriter {
+            until_metadata: ICY_METAINT,
+            sent_title: None,
+        }
+    }
+
+    fn interleave(&mut self, mut audio: &[u8]) -> Vec<u8> {
+        let mut output = Vec::with_capacity(audio.len() + 64);
+        while audio.len() >= self.until_metadata {
+            let (before, after) = audio.split_at(self.until_metadata);
+            output.extend(before);
+            output.extend(self.metadata_block());
+            audio = after;
+            self.until_metadata = ICY_METAINT;
+        }
+        output.extend(audio);
+        self.until_metadata -= audio.len();
+        output
+    }
+
+    fn metadata_block(&mut self) -> Vec<u8> {
+        let title = TITLE.lock().unwrap().clone();
+        if self.sent_title.as_ref() == Some(&title) {
+            return vec![0];
+        }
+        // Length is stored in 16 byte units in a single byte
+        let shortened: String = title.replace('\'', "\u{2019}").chars().take(1000).collect();
+        let mut text = format!("StreamTitle='{}';", shortened).into_bytes();
+        let blocks = text.len().div_ceil(16);
+        text.resize(blocks * 16, 0);
+        let mut block = Vec::with_capacity(text.len() + 1);
+        block.push(blocks as u8);
+        block.extend(text);
+        self.sent_title = Some(title);
+        block
+    }
+}
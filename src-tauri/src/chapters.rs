@@ -0,0 +1,523 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Refuse to load a `moov` atom bigger than this (it's normally well under 1MB)
+const MAX_MOOV_SIZE: u64 = 64 * 1024 * 1024;
+/// Chapter tracks have a sample per chapter, anything past this is a broken or hostile file
+const MAX_CHAPTER_SAMPLES: usize = 10_000;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Chapter {
+    pub title: String,
+    /// Start in seconds
+    pub start: f64,
+    /// End in seconds, the start of the next chapter or the end of the file
+    pub end: f64,
+}
+
+/// Going to the previous chapter past this point restarts the current one instead
+const PREVIOUS_CHAPTER_THRESHOLD: f64 = 3.0;
+
+#[derive(Clone, Debug)]
+pub enum ChapterRequest {
+    Next,
+    Previous,
+    Jump(usize),
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentChapter {
+    pub index: usize,
+    pub chapter: Chapter,
+}
+
+/// Index of the chapter playing at the given position
+pub fn chapter_at(chapters: &[Chapter], position: f64) -> Option<usize> {
+    chapters.iter().rposition(|c| position >= c.start)
+}
+
+/// Position (in seconds) to seek to for a chapter request, None if there's nowhere to go
+pub fn chapter_target(
+    chapters: &[Chapter],
+    position: f64,
+    request: &ChapterRequest,
+) -> Option<f64> {
+    let current = chapter_at(chapters, position);
+    let index = match request {
+        ChapterRequest::Next => current.map_or(0, |idx| idx + 1),
+        ChapterRequest::Previous => match current {
+            Some(idx) if position - chapters[idx].start > PREVIOUS_CHAPTER_THRESHOLD => idx,
+            Some(idx) => idx.saturating_sub(1),
+            None => 0,
+        },
+        ChapterRequest::Jump(idx) => *idx,
+    };
+    chapters.get(index).map(|c| c.start)
+}
+
+/**
+ * Reads chapter markers: ID3v2 CHAP frames for MP3,
+ * chapter tracks or Nero chapters (chpl) for MP4/M4A/M4B.
+ */
+pub fn read_chapters(path: &Path, duration: Option<f64>) -> Vec<Chapter> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_lowercase())
+        .unwrap_or_default();
+    let result = match extension.as_str() {
+        "mp3" => read_id3_chapters(path),
+        "m4a" | "m4b" | "mp4" => read_mp4_chapters(path),
+        _ => return vec![],
+    };
+    match result {
+        Ok(starts) => finish_chapters(starts, duration),
+        Err(err) => {
+            warn!("Error reading chapters from {}: {}", path.display(), err);
+            vec![]
+        }
+    }
+}
+
+/// Sorts the chapters and fills in the end times
+fn finish_chapters(
+    mut starts: Vec<(f64, Option<f64>, String)>,
+    duration: Option<f64>,
+) -> Vec<Chapter> {
+    starts.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let total = duration.unwrap_or(0.0);
+    let next_starts: Vec<f64> = starts.iter().skip(1).map(|c| c.0).collect();
+    starts
+        .into_iter()
+        .enumerate()
+        .map(|(idx, (start, end, title))| Chapter {
+            title: if title.trim().is_empty() {
+                format!("Chapter {}", idx + 1)
+            } else {
+                title
+            },
+            start,
+            end: next_starts
+                .get(idx)
+                .copied()
+                .or(end)
+                .unwrap_or(total)
+                .max(start),
+        })
+        .collect()
+}
+
+fn be_u32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn be_u64(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+fn synchsafe(data: &[u8], pos: usize) -> Option<u32> {
+    let b = data.get(pos..pos + 4)?;
+    Some((b[0] as u32) << 21 | (b[1] as u32) << 14 | (b[2] as u32) << 7 | b[3] as u32)
+}
+
+/// Undo ID3v2 unsynchronisation (0xFF 0x00 -> 0xFF)
+fn unsynchronise(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut prev = 0u8;
+    for &b in data {
+        if !(prev == 0xFF && b == 0x00) {
+            out.push(b);
+        }
+        prev = b;
+    }
+    out
+}
+
+fn decode_utf16(data: &[u8], big_endian: bool) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| {
+            if big_endian {
+                u16::from_be_bytes([c[0], c[1]])
+            } else {
+                u16::from_le_bytes([c[0], c[1]])
+            }
+        })
+        .collect();
+    String::from_utf16_lossy(&units)
+}
+
+/// Decodes an ID3v2 text frame (encoding byte followed by the text)
+fn decode_id3_text(data: &[u8]) -> String {
+    let Some((&encoding, text)) = data.split_first() else {
+        return String::new();
+    };
+    let decoded = match encoding {
+        0 => text.iter().map(|b| *b as char).collect(),
+        1 => match text {
+            [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, false),
+            [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, true),
+            _ => decode_utf16(text, false),
+        },
+        2 => decode_utf16(text, true),
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+    decoded.trim_end_matches('\0').to_string()
+}
+
+/// Splits ID3v2 frames into (id, content)
+fn id3_frames(data: &[u8], version: u8) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos + 10 <= data.len() {
+        let id: [u8; 4] = data[pos..pos + 4].try_into().unwrap();
+        if id[0] == 0 {
+            break; // Padding
+        }
+        let size = if version >= 4 {
+            synchsafe(data, pos + 4)
+        } else {
+            be_u32(data, pos + 4)
+        };
+        let Some(size) = size.map(|s| s as usize) else {
+            break;
+        };
+        let format_flags = data[pos + 9];
+        let start = pos + 10;
+        let Some(content) = data.get(start..start + size) else {
+            break;
+        };
+        let content = if version >= 4 && format_flags & 0x02 != 0 {
+            unsynchronise(content)
+        } else {
+            content.to_vec()
+        };
+        frames.push((id, content));
+        pos = start + size;
+    }
+    frames
+}
+
+fn read_id3_chapters(path: &Path) -> std::io::Result<Vec<(f64, Option<f64>, String)>> {
+    let mut file = File::open(path)?;
+    let mut header = [0u8; 10];
+    if file.read_exact(&mut header).is_err() || &header[0..3] != b"ID3" {
+        return Ok(vec![]);
+    }
+    let version = header[3];
+    let flags = header[5];
+    let size = synchsafe(&header, 6).unwrap_or(0) as usize;
+    let mut tag = vec![0u8; size];
+    file.read_exact(&mut tag)?;
+
+    // Whole tag unsynchronisation (v2.3)
+    if version < 4 && flags & 0x80 != 0 {
+        tag = unsynchronise(&tag);
+    }
+    // Skip the extended header
+    let mut start = 0;
+    if flags & 0x40 != 0 {
+        start = if version >= 4 {
+            synchsafe(&tag, 0).unwrap_or(0) as usize
+        } else {
+            be_u32(&tag, 0).unwrap_or(0) as usize + 4
+        };
+    }
+
+    let mut chapters = Vec::new();
+    for (id, content) in id3_frames(tag.get(start..).unwrap_or_default(), version) {
+        if &id != b"CHAP" {
+            continue;
+        }
+        // Element ID (null terminated), start time, end time (ms), start and end byte offsets
+        let Some(id_end) = content.iter().position(|b| *b == 0) else {
+            continue;
+        };
+        let (Some(start_ms), Some(end_ms)) =
+            (be_u32(&content, id_end + 1), be_u32(&content, id_end + 5))
+        else {
+            continue;
+        };
+        let sub_frames = content.get(id_end + 17..).unwrap_or_default();
+        let title = id3_frames(sub_frames, version)
+            .into_iter()
+            .find(|(id, _)| id == b"TIT2")
+            .map(|(_, text)| decode_id3_text(&text))
+            .unwrap_or_default();
+        chapters.push((
+            start_ms as f64 / 1000.0,
+            Some(end_ms as f64 / 1000.0),
+            title,
+        ));
+    }
+    Ok(chapters)
+}
+
+/// Children of an MP4 atom as (type, content)
+fn mp4_atoms(data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut atoms = Vec::new();
+    let mut pos = 0;
+    while pos + 8 <= data.len() {
+        let size = be_u32(data, pos).unwrap() as u64;
+        let kind: [u8; 4] = data[pos + 4..pos + 8].try_into().unwrap();
+        let (header, size) = match size {
+            0 => (8, (data.len() - pos) as u64),
+            1 => match be_u64(data, pos + 8) {
+                Some(large) => (16, large),
+                None => break,
+            },
+            _ => (8, size),
+        };
+        if size < header as u64 || pos as u64 + size > data.len() as u64 {
+            break;
+        }
+        atoms.push((kind, &data[pos + header..pos + size as usize]));
+        pos += size as usize;
+    }
+    atoms
+}
+
+fn mp4_child<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    let mut current = data;
+    for kind in path {
+        current = mp4_atoms(current).into_iter().find(|(k, _)| k == *kind)?.1;
+    }
+    Some(current)
+}
+
+/// Reads the `moov` atom, which can be at either end of the file
+fn read_moov(file: &mut File) -> std::io::Result<Option<Vec<u8>>> {
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while pos + 8 <= len {
+        file.seek(SeekFrom::Start(pos))?;
+        let mut header = [0u8; 16];
+        file.read_exact(&mut header[..8])?;
+        let mut size = be_u32(&header, 0).unwrap() as u64;
+        let mut header_size = 8;
+        if size == 1 {
+            file.read_exact(&mut header[8..])?;
+            size = be_u64(&header, 8).unwrap();
+            header_size = 16;
+        } else if size == 0 {
+            size = len - pos;
+        }
+        if size < header_size {
+            break;
+        }
+        if &header[4..8] == b"moov" {
+            if size > MAX_MOOV_SIZE {
+                return Ok(None);
+            }
+            let mut moov = vec![0u8; (size - header_size) as usize];
+            file.read_exact(&mut moov)?;
+            return Ok(Some(moov));
+        }
+        pos += size;
+    }
+    Ok(None)
+}
+
+struct Mp4Track<'a> {
+    id: u32,
+    chapter_refs: Vec<u32>,
+    timescale: u32,
+    stbl: Option<&'a [u8]>,
+}
+
+fn parse_trak(trak: &[u8]) -> Option<Mp4Track<'_>> {
+    let tkhd = mp4_child(trak, &[b"tkhd"])?;
+    let id = if tkhd.first() == Some(&1) {
+        be_u32(tkhd, 20)?
+    } else {
+        be_u32(tkhd, 12)?
+    };
+    let chapter_refs = mp4_child(trak, &[b"tref", b"chap"])
+        .map(|chap| {
+            chap.chunks_exact(4)
+                .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mdhd = mp4_child(trak, &[b"mdia", b"mdhd"])?;
+    let timescale = if mdhd.first() == Some(&1) {
+        be_u32(mdhd, 20)?
+    } else {
+        be_u32(mdhd, 12)?
+    };
+    Some(Mp4Track {
+        id,
+        chapter_refs,
+        timescale,
+        stbl: mp4_child(trak, &[b"mdia", b"minf", b"stbl"]),
+    })
+}
+
+/// Reads the chapter titles from the samples of a QuickTime chapter (text) track
+fn read_chapter_track(
+    file: &mut File,
+    track: &Mp4Track,
+) -> Option<Vec<(f64, Option<f64>, String)>> {
+    let stbl = track.stbl?;
+    if track.timescale == 0 {
+        return None;
+    }
+
+    // Sample durations
+    let stts = mp4_child(stbl, &[b"stts"])?;
+    let entries = be_u32(stts, 4)? as usize;
+    if entries > stts.len().saturating_sub(8) / 8 {
+        return None;
+    }
+    let mut durations = Vec::new();
+    for i in 0..entries {
+        let count = be_u32(stts, 8 + i * 8)? as usize;
+        let delta = be_u32(stts, 12 + i * 8)?;
+        if durations.len() + count > MAX_CHAPTER_SAMPLES {
+            return None;
+        }
+        durations.extend(std::iter::repeat(delta).take(count));
+    }
+
+    // Sample sizes
+    let stsz = mp4_child(stbl, &[b"stsz"])?;
+    let fixed_size = be_u32(stsz, 4)?;
+    let sample_count = be_u32(stsz, 8)? as usize;
+    let max_samples = if fixed_size != 0 {
+        MAX_CHAPTER_SAMPLES
+    } else {
+        stsz.len().saturating_sub(12) / 4
+    };
+    if sample_count > max_samples {
+        return None;
+    }
+    let sizes: Vec<u32> = (0..sample_count)
+        .map(|i| {
+            if fixed_size != 0 {
+                Some(fixed_size)
+            } else {
+                be_u32(stsz, 12 + i * 4)
+            }
+        })
+        .collect::<Option<_>>()?;
+
+    // Chunk offsets
+    let offsets: Vec<u64> = if let Some(stco) = mp4_child(stbl, &[b"stco"]) {
+        (0..be_u32(stco, 4)? as usize)
+            .map(|i| be_u32(stco, 8 + i * 4).map(|o| o as u64))
+            .collect::<Option<_>>()?
+    } else {
+        let co64 = mp4_child(stbl, &[b"co64"])?;
+        (0..be_u32(co64, 4)? as usize)
+            .map(|i| be_u64(co64, 8 + i * 8))
+            .collect::<Option<_>>()?
+    };
+
+    // Samples per chunk (first chunk, samples per chunk)
+    let stsc = mp4_child(stbl, &[b"stsc"])?;
+    let stsc_entries: Vec<(u32, u32)> = (0..be_u32(stsc, 4)? as usize)
+        .map(|i| Some((be_u32(stsc, 8 + i * 12)?, be_u32(stsc, 12 + i * 12)?)))
+        .collect::<Option<_>>()?;
+
+    let mut sample_offsets = Vec::with_capacity(sample_count);
+    for (chunk_idx, chunk_offset) in offsets.iter().enumerate() {
+        let chunk_number = chunk_idx as u32 + 1;
+        let samples_in_chunk = stsc_entries
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= chunk_number)
+            .map(|(_, n)| *n)
+            .unwrap_or(1);
+        let mut offset = *chunk_offset;
+        for _ in 0..samples_in_chunk {
+            let Some(size) = sizes.get(sample_offsets.len()) else {
+                break;
+            };
+            sample_offsets.push((offset, *size));
+            offset += *size as u64;
+        }
+    }
+
+    let mut chapters = Vec::new();
+    let mut time = 0u64;
+    for (idx, (offset, size)) in sample_offsets.into_iter().enumerate() {
+        let start = time as f64 / track.timescale as f64;
+        time += *durations.get(idx).unwrap_or(&0) as u64;
+
+        // Text samples: 16 bit length, then the text (UTF-8, or UTF-16 with a BOM)
+        // Only read the text, whatever the sample size claims
+        let mut header = [0u8; 2];
+        file.seek(SeekFrom::Start(offset)).ok()?;
+        file.read_exact(&mut header).ok()?;
+        let len = (u16::from_be_bytes(header) as usize).min((size as usize).saturating_sub(2));
+        let mut sample = vec![0u8; len];
+        file.read_exact(&mut sample).ok()?;
+        let text = sample.as_slice();
+        let title = match text {
+            [0xFE, 0xFF, rest @ ..] => decode_utf16(rest, true),
+            [0xFF, 0xFE, rest @ ..] => decode_utf16(rest, false),
+            _ => String::from_utf8_lossy(text).into_owned(),
+        };
+        chapters.push((start, None, title));
+    }
+    Some(chapters)
+}
+
+/// Nero chapters: version, flags, (reserved), count, then start (100ns units) and a pascal string
+fn read_nero_chapters(chpl: &[u8]) -> Vec<(f64, Option<f64>, String)> {
+    let mut pos = if chpl.first().is_some_and(|v| *v > 0) {
+        8
+    } else {
+        4
+    };
+    let count = chpl.get(pos).copied().unwrap_or(0);
+    pos += 1;
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        let (Some(start), Some(len)) = (be_u64(chpl, pos), chpl.get(pos + 8)) else {
+            break;
+        };
+        let len = *len as usize;
+        let title = chpl
+            .get(pos + 9..pos + 9 + len)
+            .map(|t| String::from_utf8_lossy(t).into_owned())
+            .unwrap_or_default();
+        chapters.push((start as f64 / 10_000_000.0, None, title));
+        pos += 9 + len;
+    }
+    chapters
+}
+
+fn read_mp4_chapters(path: &Path) -> std::io::Result<Vec<(f64, Option<f64>, String)>> {
+    let mut file = File::open(path)?;
+    let Some(moov) = read_moov(&mut file)? else {
+        return Ok(vec![]);
+    };
+
+    let tracks: Vec<Mp4Track> = mp4_atoms(&moov)
+        .into_iter()
+        .filter(|(kind, _)| kind == b"trak")
+        .filter_map(|(_, trak)| parse_trak(trak))
+        .collect();
+
+    // QuickTime chapter track, referenced from the audio track
+    if let Some(chapter_id) = tracks.iter().find_map(|t| t.chapter_refs.first().copied()) {
+        if let Some(track) = tracks.iter().find(|t| t.id == chapter_id) {
+            if let Some(chapters) = read_chapter_track(&mut file, track) {
+                if !chapters.is_empty() {
+                    info!("Found {} chapters in chapter track", chapters.len());
+                    return Ok(chapters);
+                }
+            }
+        }
+    }
+
+    Ok(mp4_child(&moov, &[b"udta", b"chpl"])
+        .map(read_nero_chapters)
+        .unwrap_or_default())
+}
@@ -0,0 +1,238 @@
+use log::{info, warn};
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::sync::mpsc;
+use std::time::Duration;
+use tauri::{AppHandle, Manager};
+
+use crate::remote::{self, RemoteState};
+
+/// Added when re-launching ourselves to forward --now-playing, the running
+/// instance connects back to this port with the output
+const REPLY_PORT_ARG: &str = "--reply-port";
+const REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug)]
+pub enum CliCommand {
+    PlayPause,
+    Next,
+    Previous,
+    /// Seconds
+    Seek {
+        position: f64,
+        relative: bool,
+    },
+    /// Percent
+    Volume {
+        volume: f64,
+        relative: bool,
+    },
+    Enqueue(Vec<String>),
+    NowPlaying {
+        json: bool,
+    },
+}
+
+/**
+ * Parses control verbs from argv (including the program name).
+ * Anything else (file paths, URLs) is left for the frontend.
+ */
+pub fn parse(args: &[String], cwd: &str) -> Result<Vec<CliCommand>, String> {
+    let mut commands = Vec::new();
+    let mut json = false;
+    let mut now_playing = false;
+    let mut args = args.iter().skip(1).peekable();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--play-pause" => commands.push(CliCommand::PlayPause),
+            "--next" => commands.push(CliCommand::Next),
+            "--prev" | "--previous" => commands.push(CliCommand::Previous),
+            "--seek" => {
+                let value = args.next().ok_or("--seek needs a position")?;
+                let (position, relative) = parse_seek(value)?;
+                commands.push(CliCommand::Seek { position, relative });
+            }
+            "--volume" => {
+                let value = args.next().ok_or("--volume needs a value")?;
+                let (volume, relative) = parse_relative(value)?;
+                commands.push(CliCommand::Volume { volume, relative });
+            }
+            "--enqueue" => {
+                let mut paths = Vec::new();
+                while let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+                    paths.push(Path::new(cwd).join(path).to_string_lossy().to_string());
+                }
+                if paths.is_empty() {
+                    return Err("--enqueue needs at least one path".into());
+                }
+                commands.push(CliCommand::Enqueue(paths));
+            }
+            "--now-playing" => now_playing = true,
+            "--json" => json = true,
+            REPLY_PORT_ARG => {
+                args.next();
+            }
+            _ => {}
+        }
+    }
+    if now_playing {
+        commands.push(CliCommand::NowPlaying { json });
+    }
+    Ok(commands)
+}
+
+/// "+10", "-10", "90" or "1:30"
+fn parse_seek(value: &str) -> Result<(f64, bool), String> {
+    if value.contains(':') && !value.starts_with(['+', '-']) {
+        let mut seconds = 0.0;
+        for part in value.split(':') {
+            let part: f64 = part
+                .parse()
+                .map_err(|_| format!("Invalid position: {}", value))?;
+            seconds = seconds * 60.0 + part;
+        }
+        return Ok((seconds, false));
+    }
+    parse_relative(value)
+}
+
+/// "+5" and "-5" are relative, "40" is absolute
+fn parse_relative(value: &str) -> Result<(f64, bool), String> {
+    let number: f64 = value
+        .parse()
+        .map_err(|_| format!("Invalid number: {}", value))?;
+    if !number.is_finite() {
+        return Err(format!("Invalid number: {}", value));
+    }
+    Ok((number, value.starts_with(['+', '-'])))
+}
+
+fn reply_port(args: &[String]) -> Option<u16> {
+    args.iter()
+        .position(|arg| arg == REPLY_PORT_ARG)
+        .and_then(|index| args.get(index + 1))
+        .and_then(|port| port.parse().ok())
+}
+
+/**
+ * Runs before the app starts. --now-playing has to print the running
+ * instance's answer, but the single instance plugin only forwards argv one
+ * way, so we listen on a local port and re-launch ourselves with it.
+ *
+ * Returns the exit code if this process was only a client.
+ */
+pub fn run_client() -> Option<i32> {
+    let args: Vec<String> = std::env::args().collect();
+    if !args.iter().any(|arg| arg == "--now-playing") || reply_port(&args).is_some() {
+        return None;
+    }
+
+    let listener = match TcpListener::bind((Ipv4Addr::LOCALHOST, 0)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("Error listening for a reply: {}", err);
+            return Some(1);
+        }
+    };
+    let port = listener.local_addr().map(|address| address.port()).ok()?;
+    let exe = std::env::current_exe().ok()?;
+    if let Err(err) = Command::new(exe)
+        .args(&args[1..])
+        .args([REPLY_PORT_ARG, &port.to_string()])
+        .spawn()
+    {
+        eprintln!("Error forwarding to the running instance: {}", err);
+        return Some(1);
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut output = String::new();
+        if let Ok((mut stream, _)) = listener.accept() {
+            let _ = stream.read_to_string(&mut output);
+        }
+        let _ = sender.send(output);
+    });
+    match receiver.recv_timeout(REPLY_TIMEOUT) {
+        Ok(output) => {
+            print!("{}", output);
+            Some(0)
+        }
+        Err(_) => {
+            eprintln!("Musicat is not running");
+            Some(1)
+        }
+    }
+}
+
+/**
+ * Control verbs only make sense when forwarded to a running instance.
+ */
+pub fn is_control_invocation() -> bool {
+    let args: Vec<String> = std::env::args().collect();
+    matches!(parse(&args, ""), Ok(commands) if !commands.is_empty())
+}
+
+/**
+ * Called with argv forwarded from a second instance.
+ * Returns false if there were no control verbs, so the frontend can handle it.
+ */
+pub fn handle(app: &AppHandle, args: &[String], cwd: &str) -> bool {
+    let commands = match parse(args, cwd) {
+        Ok(commands) => commands,
+        Err(err) => {
+            warn!("[CLI] {}", err);
+            return true;
+        }
+    };
+    if commands.is_empty() {
+        return false;
+    }
+
+    let state = app.state::<RemoteState>();
+    for command in commands {
+        info!("[CLI] {:?}", command);
+        match command {
+            CliCommand::PlayPause => remote::toggle(app),
+            CliCommand::Next => remote::next(app),
+            CliCommand::Previous => remote::previous(app),
+            CliCommand::Seek { position, relative } => {
+                let base = if relative {
+                    state.status().position
+                } else {
+                    0.0
+                };
+                remote::seek(app, base + position);
+            }
+            CliCommand::Volume { volume, relative } => {
+                let base = if relative { state.status().volume } else { 0.0 };
+                remote::set_volume(app, base + volume / 100.0);
+            }
+            CliCommand::Enqueue(paths) => remote::enqueue(app, paths, false),
+            CliCommand::NowPlaying { json } => {
+                let Some(port) = reply_port(args) else {
+                    continue;
+                };
+                let status = state.status();
+                let output = if json {
+                    serde_json::to_string(&status).unwrap_or_default() + "\n"
+                } else {
+                    status
+                        .song
+                        .map(|song| format!("{} - {}\n", song.artist, song.title))
+                        .unwrap_or_default()
+                };
+                let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+                match TcpStream::connect_timeout(&address, REPLY_TIMEOUT) {
+                    Ok(mut stream) => {
+                        let _ = stream.write_all(output.as_bytes());
+                    }
+                    Err(err) => warn!("[CLI] Error replying to {}: {}", address, err),
+                }
+            }
+        }
+    }
+    true
+}
@@ -2,5 +2,6 @@ use crate::dsp::PeakMethod;
 
 /// Buffer size in seconds
 pub const BUFFER_SIZE: f64 = 1.0;
+/// Frames per peak of the waveform overview, see [`crate::waveform`]
 pub const WAVEFORM_WINDOW_SIZE: usize = 8192;
 pub const WAVEFORM_PEAK_METHOD: PeakMethod = PeakMethod::Rms;
@@ -0,0 +1,503 @@
+use chksum_md5::MD5;
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, MetadataRevision};
+use symphonia::core::probe::{Hint, ProbeResult};
+use symphonia::default::get_probe;
+use tauri::AppHandle;
+
+use crate::metadata::{extract_metadata, seconds_to_hms, Song};
+
+/// CD frames per second, used by the MSF (mm:ss:ff) timestamps in CUE sheets
+const CUE_FRAMES_PER_SECOND: f64 = 75.0;
+
+#[derive(Clone, Debug, Default)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub genre: Option<String>,
+    pub date: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    /// Start of the track (INDEX 01) in seconds
+    pub start: f64,
+}
+
+/// Splits a CUE line into its command and arguments, keeping quoted strings together
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_quotes = false;
+    for c in line.chars() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+    tokens
+}
+
+/// Parses an MSF timestamp (mm:ss:ff) into seconds
+fn parse_msf(value: &str) -> Option<f64> {
+    let mut parts = value.split(':').map(|p| p.trim().parse::<u64>().ok());
+    let minutes = parts.next()??;
+    let seconds = parts.next()??;
+    let frames = parts.next().unwrap_or(Some(0))?;
+    Some(minutes as f64 * 60.0 + seconds as f64 + frames as f64 / CUE_FRAMES_PER_SECOND)
+}
+
+pub fn parse_cue(content: &str) -> CueSheet {
+    let mut sheet = CueSheet::default();
+    let mut current_track: Option<CueTrack> = None;
+
+    // Push the track being parsed into the last FILE
+    fn finish_track(sheet: &mut CueSheet, track: Option<CueTrack>) {
+        if let Some(track) = track {
+            if let Some(file) = sheet.files.last_mut() {
+                file.tracks.push(track);
+            }
+        }
+    }
+
+    for line in content.trim_start_matches('\u{feff}').lines() {
+        let tokens = tokenize(line);
+        let Some(command) = tokens.first() else {
+            continue;
+        };
+        let arg = tokens.get(1).cloned();
+        match command.to_uppercase().as_str() {
+            "FILE" => {
+                finish_track(&mut sheet, current_track.take());
+                if let Some(name) = arg {
+                    sheet.files.push(CueFile {
+                        name,
+                        tracks: vec![],
+                    });
+                }
+            }
+            "TRACK" => {
+                finish_track(&mut sheet, current_track.take());
+                current_track = Some(CueTrack {
+                    number: arg.and_then(|n| n.parse().ok()).unwrap_or(0),
+                    ..Default::default()
+                });
+            }
+            "TITLE" => match current_track.as_mut() {
+                Some(track) => track.title = arg,
+                None => sheet.title = arg,
+            },
+            "PERFORMER" => match current_track.as_mut() {
+                Some(track) => track.performer = arg,
+                None => sheet.performer = arg,
+            },
+            "SONGWRITER" => match current_track.as_mut() {
+                Some(track) => track.songwriter = arg,
+                None => sheet.songwriter = arg,
+            },
+            "INDEX" => {
+                // INDEX 00 is the pregap, the track itself starts at INDEX 01
+                if let (Some(track), Some("01")) = (current_track.as_mut(), arg.as_deref()) {
+                    if let Some(start) = tokens.get(2).and_then(|t| parse_msf(t)) {
+                        track.start = start;
+                    }
+                }
+            }
+            "REM" => {
+                let value = tokens.get(2).cloned();
+                match arg.map(|a| a.to_uppercase()).as_deref() {
+                    Some("GENRE") => sheet.genre = value,
+                    Some("DATE") => sheet.date = value,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    finish_track(&mut sheet, current_track.take());
+    sheet
+}
+
+/// Formats seconds as an MSF timestamp (mm:ss:ff)
+fn format_msf(seconds: f64) -> String {
+    let fps = CUE_FRAMES_PER_SECOND as u64;
+    let frames = (seconds.max(0.0) * CUE_FRAMES_PER_SECOND).round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        frames / fps / 60,
+        frames / fps % 60,
+        frames % fps
+    )
+}
+
+/// Writes a CUE sheet that parse_cue can read back
+pub fn write_cue(sheet: &CueSheet) -> String {
+    // Quotes can't be escaped in CUE sheets
+    let quote = |value: &str| format!("\"{}\"", value.replace('"', "'"));
+    let mut lines = Vec::new();
+    if let Some(genre) = &sheet.genre {
+        lines.push(format!("REM GENRE {}", quote(genre)));
+    }
+    if let Some(date) = &sheet.date {
+        lines.push(format!("REM DATE {}", date));
+    }
+    if let Some(performer) = &sheet.performer {
+        lines.push(format!("PERFORMER {}", quote(performer)));
+    }
+    if let Some(title) = &sheet.title {
+        lines.push(format!("TITLE {}", quote(title)));
+    }
+    for file in &sheet.files {
+        lines.push(format!("FILE {} WAVE", quote(&file.name)));
+        for track in &file.tracks {
+            lines.push(format!("  TRACK {:02} AUDIO", track.number));
+            if let Some(title) = &track.title {
+                lines.push(format!("    TITLE {}", quote(title)));
+            }
+            if let Some(performer) = &track.performer {
+                lines.push(format!("    PERFORMER {}", quote(performer)));
+            }
+            if let Some(songwriter) = &track.songwriter {
+                lines.push(format!("    SONGWRITER {}", quote(songwriter)));
+            }
+            lines.push(format!("    INDEX 01 {}", format_msf(track.start)));
+        }
+    }
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// CUE sheets are often not UTF-8 (EAC writes them in the system codepage),
+/// so fall back to Latin-1 rather than failing
+fn read_cue_file(path: &Path) -> Option<CueSheet> {
+    let bytes = fs::read(path).ok()?;
+    let content = match String::from_utf8(bytes) {
+        Ok(content) => content,
+        Err(err) => err.into_bytes().iter().map(|b| *b as char).collect(),
+    };
+    Some(parse_cue(&content))
+}
+
+/**
+ * Finds the audio file a FILE entry points to. Rips are often re-encoded after the CUE was written,
+ * so if the exact file doesn't exist, look for one with the same name and a supported extension.
+ */
+pub(crate) fn resolve_cue_file(cue_path: &Path, name: &str) -> Option<PathBuf> {
+    let dir = cue_path.parent()?;
+    let path = dir.join(name);
+    if path.is_file() {
+        return Some(path);
+    }
+    // Not with_extension, names like "01. Intro.wav" have dots in the stem
+    let stem = Path::new(name).file_stem()?.to_string_lossy();
+    ["flac", "wav", "ape", "aiff", "mp3", "ogg", "m4a"]
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", stem, ext)))
+        .find(|p| p.is_file())
+}
+
+/// Audio files referenced by the CUE sheets in this directory, these shouldn't be imported as songs
+pub fn cue_audio_files(directory_path: &Path) -> HashSet<PathBuf> {
+    let mut files = HashSet::new();
+    if let Ok(entries) = fs::read_dir(directory_path) {
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if is_cue_file(&path) {
+                files.extend(referenced_files(&path));
+            }
+        }
+    }
+    files
+}
+
+pub fn referenced_files(cue_path: &Path) -> Vec<PathBuf> {
+    read_cue_file(cue_path)
+        .map(|sheet| {
+            sheet
+                .files
+                .iter()
+                .filter_map(|f| resolve_cue_file(cue_path, &f.name))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn is_cue_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("cue"))
+}
+
+fn probe_file(path: &Path) -> Option<ProbeResult> {
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .ok()
+}
+
+/// Exact length of the audio stream in seconds, when the container knows its frame count
+fn stream_length(path: &Path) -> Option<f64> {
+    let probed = probe_file(path)?;
+    let params = &probed.format.default_track()?.codec_params;
+    Some(params.n_frames? as f64 / params.sample_rate? as f64)
+}
+
+/**
+ * Creates a virtual song for each track, sharing the audio file of the original song.
+ * Tracks end where the next one starts, the last one at the end of the stream.
+ */
+fn virtual_songs(
+    song: &Song,
+    sheet: &CueSheet,
+    tracks: &[CueTrack],
+    track_total: usize,
+    cue_path: &Path,
+) -> Vec<Song> {
+    // The tag reader's duration can be an estimate, the frame count isn't
+    let stream_end = stream_length(Path::new(&song.path));
+    let file_duration = stream_end.or(song.file_info.duration).unwrap_or(0.0);
+    let year = sheet
+        .date
+        .as_ref()
+        .and_then(|d| d.get(..4))
+        .and_then(|y| y.parse::<i32>().ok());
+
+    tracks
+        .iter()
+        .enumerate()
+        .map(|(idx, track)| {
+            let end = tracks.get(idx + 1).map(|next| next.start).or(stream_end);
+            let duration = end.unwrap_or(file_duration) - track.start;
+
+            let mut virtual_song = song.clone();
+            virtual_song.id =
+                MD5::hash(format!("{}#{}", song.path, track.number).as_bytes()).to_hex_lowercase();
+            virtual_song.title = track
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Track {:02}", track.number));
+            if let Some(performer) = track.performer.as_ref().or(sheet.performer.as_ref()) {
+                virtual_song.artist = performer.clone();
+            }
+            if let Some(album) = &sheet.title {
+                virtual_song.album = album.clone();
+            }
+            if sheet.performer.is_some() {
+                virtual_song.album_artist = sheet.performer.clone();
+            }
+            if let Some(songwriter) = track.songwriter.as_ref().or(sheet.songwriter.as_ref()) {
+                virtual_song.composer = vec![songwriter.clone()];
+            }
+            if let Some(genre) = &sheet.genre {
+                virtual_song.genre = vec![genre.clone()];
+            }
+            if let Some(year) = year {
+                virtual_song.year = year;
+            }
+            virtual_song.track_number = track.number as i32;
+            virtual_song.track_total = track_total as i32;
+            virtual_song.duration = seconds_to_hms(duration.max(0.0) as u64);
+            virtual_song.file_info.duration = Some(duration.max(0.0));
+            virtual_song.file_info.duration_display = Some(virtual_song.duration.clone());
+            virtual_song.cue_path = Some(cue_path.to_string_lossy().into_owned());
+            virtual_song.cue_track = Some(track.number);
+            virtual_song.start_offset = Some(track.start);
+            virtual_song.end_offset = end;
+            virtual_song.chapters = vec![];
+            virtual_song
+        })
+        .collect()
+}
+
+/// Virtual songs for every track in a `.cue` file
+pub fn songs_from_cue_file(
+    cue_path: &Path,
+    is_import: bool,
+    is_cover_fullcheck: bool,
+    app: &AppHandle,
+) -> Vec<Song> {
+    let Some(sheet) = read_cue_file(cue_path) else {
+        return vec![];
+    };
+    let track_total: usize = sheet.files.iter().map(|f| f.tracks.len()).sum();
+    let mut songs = Vec::new();
+    for file in &sheet.files {
+        let Some(audio_path) = resolve_cue_file(cue_path, &file.name) else {
+            warn!(
+                "CUE: file not found: {} ({})",
+                file.name,
+                cue_path.display()
+            );
+            continue;
+        };
+        if let Some(song) = extract_metadata(
+            &audio_path,
+            is_import,
+            is_cover_fullcheck,
+            false,
+            false,
+            app,
+        ) {
+            songs.extend(virtual_songs(
+                &song,
+                &sheet,
+                &file.tracks,
+                track_total,
+                cue_path,
+            ));
+        }
+    }
+    info!("CUE: {} tracks from {}", songs.len(), cue_path.display());
+    songs
+}
+
+/**
+ * Reads the cuesheet embedded in a FLAC file, either as a CUESHEET vorbis comment (full CUE text)
+ * or as the native CUESHEET metadata block (only offsets, no titles).
+ */
+fn read_embedded_cue(path: &Path) -> Option<CueSheet> {
+    let mut probed = probe_file(path)?;
+
+    let find_cuesheet = |rev: &MetadataRevision| {
+        rev.tags()
+            .iter()
+            .find(|t| t.key.eq_ignore_ascii_case("CUESHEET"))
+            .map(|t| t.value.to_string())
+    };
+    let mut reader = probed.format;
+    let mut comment = reader.metadata().current().and_then(find_cuesheet);
+    if comment.is_none() {
+        if let Some(metadata) = probed.metadata.get() {
+            comment = metadata.current().and_then(find_cuesheet);
+        }
+    }
+    if let Some(content) = comment {
+        let sheet = parse_cue(&content);
+        if sheet.files.iter().any(|f| !f.tracks.is_empty()) {
+            return Some(sheet);
+        }
+    }
+
+    let sample_rate = reader.default_track()?.codec_params.sample_rate? as f64;
+    let cues = reader.cues();
+    // The last entry is always the lead-out
+    if cues.len() < 2 {
+        return None;
+    }
+    let tracks = cues[..cues.len() - 1]
+        .iter()
+        .map(|cue| {
+            // The points are INDEX 00 (pregap, optional) and INDEX 01, relative to the track offset
+            let index_01 = cue
+                .points
+                .get(1)
+                .or(cue.points.first())
+                .map(|p| p.start_offset_ts)
+                .unwrap_or(0);
+            CueTrack {
+                number: cue.index,
+                start: (cue.start_ts + index_01) as f64 / sample_rate,
+                ..Default::default()
+            }
+        })
+        .collect();
+    Some(CueSheet {
+        files: vec![CueFile {
+            name: path.to_string_lossy().into_owned(),
+            tracks,
+        }],
+        ..Default::default()
+    })
+}
+
+/// Virtual songs from a cuesheet embedded in the song's file, if it has one
+pub fn songs_from_embedded_cue(song: &Song) -> Option<Vec<Song>> {
+    let path = Path::new(&song.path);
+    let is_flac = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("flac"));
+    if !is_flac {
+        return None;
+    }
+    let sheet = read_embedded_cue(path)?;
+    // An embedded cuesheet only describes its own file
+    let tracks = &sheet.files.first()?.tracks;
+    if tracks.len() < 2 {
+        return None;
+    }
+    Some(virtual_songs(song, &sheet, tracks, tracks.len(), path))
+}
+
+/**
+ * Extracts the songs for a path during a scan: the tracks of a `.cue` file,
+ * the tracks of a FLAC with an embedded cuesheet, or just the song itself.
+ */
+pub fn extract_songs(
+    path: &Path,
+    is_import: bool,
+    is_cover_fullcheck: bool,
+    app: &AppHandle,
+) -> Vec<Song> {
+    if is_cue_file(path) {
+        return songs_from_cue_file(path, is_import, is_cover_fullcheck, app);
+    }
+    match extract_metadata(path, is_import, is_cover_fullcheck, false, false, app) {
+        Some(song) => songs_from_embedded_cue(&song).unwrap_or_else(|| vec![song]),
+        None => vec![],
+    }
+}
+
+/// Rebuilds a virtual song for playback, from either a `.cue` file or an embedded cuesheet
+pub fn get_cue_song(cue_path: &Path, track: u32, app: &AppHandle) -> Option<Song> {
+    let songs = if is_cue_file(cue_path) {
+        let sheet = read_cue_file(cue_path)?;
+        let track_total: usize = sheet.files.iter().map(|f| f.tracks.len()).sum();
+        let file = sheet
+            .files
+            .iter()
+            .find(|f| f.tracks.iter().any(|t| t.number == track))?;
+        let audio_path = resolve_cue_file(cue_path, &file.name)?;
+        let song = extract_metadata(&audio_path, false, false, true, false, app)?;
+        virtual_songs(&song, &sheet, &file.tracks, track_total, cue_path)
+    } else {
+        let song = extract_metadata(cue_path, false, false, true, false, app)?;
+        songs_from_embedded_cue(&song)?
+    };
+    songs.into_iter().find(|s| s.cue_track == Some(track))
+}
@@ -0,0 +1,149 @@
+//! Decoding whole files outside of playback, for waveforms and analysis
+
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::Cursor;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{Limit, MetadataOptions};
+use symphonia::core::probe::Hint;
+use symphonia::default::get_probe;
+use tokio_util::sync::CancellationToken;
+
+/**
+ * Reads a file packet by packet as interleaved f32 samples.
+ */
+pub struct FileDecoder {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    buffer: Option<SampleBuffer<f32>>,
+    pub sample_rate: u32,
+    pub channels: usize,
+    /// Length in frames, when the container knows it
+    pub n_frames: Option<u64>,
+}
+
+impl FileDecoder {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut hint = Hint::new();
+        if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(extension);
+        }
+
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let mss = MediaSourceStream::new(Box::new(Cursor::new(mmap)), Default::default());
+
+        let format_opts = FormatOptions {
+            enable_gapless: false,
+            ..Default::default()
+        };
+        // We only need the audio
+        let metadata_opts = MetadataOptions {
+            limit_metadata_bytes: Limit::Maximum(50),
+            limit_visual_bytes: Limit::Maximum(0),
+        };
+        let reader = get_probe()
+            .format(&hint, mss, &format_opts, &metadata_opts)?
+            .format;
+
+        let track = reader
+            .tracks()
+            .iter()
+            .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+            .ok_or(Error::Unsupported("no supported audio track"))?;
+        let decoder = symphonia::default::get_codecs()
+            .make(&track.codec_params, &DecoderOptions { verify: false })?;
+
+        Ok(FileDecoder {
+            track_id: track.id,
+            sample_rate: track.codec_params.sample_rate.unwrap_or(44100),
+            channels: track.codec_params.channels.map_or(2, |ch| ch.count()),
+            n_frames: track.codec_params.n_frames,
+            reader,
+            decoder,
+            buffer: None,
+        })
+    }
+
+    /**
+     * Decodes the next packet. Returns None at the end of the file,
+     * corrupt packets are skipped.
+     */
+    pub fn next_samples(&mut self) -> Result<Option<&[f32]>, Error> {
+        if !self.decode_next()? {
+            return Ok(None);
+        }
+        Ok(self.buffer.as_ref().map(|buffer| buffer.samples()))
+    }
+
+    /**
+     * Feeds every packet to `on_samples` with the channel count until the end
+     * of the file, or until cancelled.
+     */
+    pub fn for_each(
+        &mut self,
+        cancel_token: &CancellationToken,
+        mut on_samples: impl FnMut(&[f32], usize),
+    ) -> Result<(), Error> {
+        loop {
+            if cancel_token.is_cancelled() {
+                return Err(Error::LimitError("cancelled"));
+            }
+            if !self.decode_next()? {
+                return Ok(());
+            }
+            if let Some(buffer) = &self.buffer {
+                on_samples(buffer.samples(), self.channels);
+            }
+        }
+    }
+
+    /// Decodes the next packet into the buffer, false at the end of the file
+    fn decode_next(&mut self) -> Result<bool, Error> {
+        loop {
+            let packet = match self.reader.next_packet() {
+                Ok(packet) => packet,
+                // The only way a format reader can tell the media is complete
+                Err(Error::IoError(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(false)
+                }
+                Err(err) => return Err(err),
+            };
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+            let decoded = match self.decoder.decode(&packet) {
+                Ok(decoded) => decoded,
+                Err(Error::DecodeError(err)) => {
+                    log::info!("decode error: {}", err);
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            // The channel count from the codec params is only a guess for some formats
+            self.channels = decoded.spec().channels.count();
+
+            let needed = decoded.capacity() * self.channels;
+            if self
+                .buffer
+                .as_ref()
+                .map_or(true, |buffer| buffer.capacity() < needed)
+            {
+                self.buffer = Some(SampleBuffer::<f32>::new(
+                    decoded.capacity() as u64,
+                    *decoded.spec(),
+                ));
+            }
+            if let Some(buffer) = &mut self.buffer {
+                buffer.copy_interleaved_ref(decoded);
+            }
+            return Ok(true);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! Dynamic range (DR) scores like foobar2000's DR Meter, and clipping, to pick
+//! the best of several masterings of an album
+
+use lofty::file::TaggedFileExt;
+use lofty::read_from_path;
+use lofty::tag::{Accessor, ItemKey};
+use log::{info, warn};
+use rayon::prelude::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tauri::{AppHandle, Emitter, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::decode::FileDecoder;
+use crate::files::expand_audio_paths;
+use crate::library::{save_analysis, SongAnalysis};
+use crate::metadata::{album_id, DynamicsInfo};
+use crate::meters::{interpolation_phases, OVERSAMPLING, TAPS_PER_PHASE};
+use crate::player::AudioPlayer;
+
+/// The report's entry in the player's cancel tokens
+pub const CANCEL_TOKEN_KEY: &str = "dynamics_report";
+const BLOCK_SECONDS: f64 = 3.0;
+/// Share of the loudest blocks the RMS is taken from
+const LOUDEST_BLOCKS: f64 = 0.2;
+/// The largest 16-bit sample, anything at or above counts as clipped
+const CLIP_LEVEL: f32 = 32767.0 / 32768.0;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SongDynamics {
+    pub path: String,
+    pub album_id: String,
+    /// None when the file couldn't be decoded
+    pub dynamics: Option<DynamicsInfo>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumDynamics {
+    pub album_id: String,
+    pub album: String,
+    pub artist: String,
+    pub path: String,
+    /// Average of the track scores, like the DR Meter's "Official DR value"
+    pub dr: u8,
+    pub tracks: usize,
+    pub clipped_samples: u64,
+    pub inter_sample_overs: u64,
+    /// Highest of the tracks (dBTP)
+    pub true_peak: f32,
+    /// Other albums with the same artist and title, eg. remasters
+    pub other_masterings: Vec<String>,
+    /// The highest DR, then the least clipping, among the masterings
+    pub best_mastering: bool,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DynamicsReport {
+    pub songs: Vec<SongDynamics>,
+    pub albums: Vec<AlbumDynamics>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DynamicsProgress {
+    total: usize,
+    done: usize,
+    song: SongDynamics,
+}
+
+/// Album title and artist from the tags
+struct AlbumTags {
+    album: String,
+    artist: String,
+}
+
+/**
+ * Measures the DR score, peaks and clipping of the given songs, and of every
+ * song in the given folders, with scores for each album.
+ * Emits "dynamics_progress" after each file. A new report cancels the previous one.
+ */
+#[tauri::command]
+pub async fn get_dynamics_report(
+    paths: Vec<String>,
+    state: State<'_, AudioPlayer>,
+    app_handle: AppHandle,
+) -> Result<DynamicsReport, String> {
+    let token = CancellationToken::new();
+    if let Some(previous) = state
+        .cancel_tokens
+        .lock()
+        .await
+        .insert(CANCEL_TOKEN_KEY.to_string(), token.clone())
+    {
+        previous.cancel();
+    }
+
+    let thread_token = token.clone();
+    let songs = tauri::async_runtime::spawn_blocking(move || {
+        let files = expand_audio_paths(&paths);
+        info!("[Dynamics] Measuring {} files", files.len());
+        let done = AtomicUsize::new(0);
+        files
+            .par_iter()
+            .filter_map(|path| {
+                if thread_token.is_cancelled() {
+                    return None;
+                }
+                let (song, tags) = measure_file(path, &thread_token, &app_handle);
+                if thread_token.is_cancelled() {
+                    return None;
+                }
+                let _ = app_handle.emit(
+                    "dynamics_progress",
+                    DynamicsProgress {
+                        total: files.len(),
+                        done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                        song: song.clone(),
+                    },
+                );
+                Some((song, tags))
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if token.is_cancelled() {
+        return Err("Cancelled".into());
+    }
+    state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY);
+
+    let albums = album_dynamics(&songs);
+    Ok(DynamicsReport {
+        songs: songs.into_iter().map(|(song, _)| song).collect(),
+        albums,
+    })
+}
+
+#[tauri::command]
+pub async fn cancel_dynamics_report(state: State<'_, AudioPlayer>) -> Result<(), String> {
+    if let Some(token) = state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY) {
+        token.cancel();
+    }
+    Ok(())
+}
+
+fn album_dynamics(songs: &[(SongDynamics, AlbumTags)]) -> Vec<AlbumDynamics> {
+    let mut by_album: BTreeMap<&str, Vec<&(SongDynamics, AlbumTags)>> = BTreeMap::new();
+    for song in songs {
+        by_album
+            .entry(song.0.album_id.as_str())
+            .or_default()
+            .push(song);
+    }
+
+    let mut albums: Vec<AlbumDynamics> = by_album
+        .into_iter()
+        .filter_map(|(album_id, tracks)| {
+            let measured: Vec<&DynamicsInfo> = tracks
+                .iter()
+                .filter_map(|(song, _)| song.dynamics.as_ref())
+                .collect();
+            if measured.is_empty() {
+                return None;
+            }
+            let dr = measured.iter().map(|d| d.dr as f32).sum::<f32>() / measured.len() as f32;
+            let (first, tags) = tracks[0];
+            Some(AlbumDynamics {
+                album_id: album_id.to_string(),
+                album: tags.album.clone(),
+                artist: tags.artist.clone(),
+                path: Path::new(&first.path)
+                    .parent()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                dr: dr.round() as u8,
+                tracks: tracks.len(),
+                clipped_samples: measured.iter().map(|d| d.clipped_samples).sum(),
+                inter_sample_overs: measured.iter().map(|d| d.inter_sample_overs).sum(),
+                true_peak: measured
+                    .iter()
+                    .map(|d| d.true_peak)
+                    .fold(f32::MIN, f32::max),
+                other_masterings: vec![],
+                best_mastering: false,
+            })
+        })
+        .collect();
+
+    // Masterings of the same album live in different folders, so they have different ids
+    let mut masterings: BTreeMap<(String, String), Vec<usize>> = BTreeMap::new();
+    for (index, album) in albums.iter().enumerate() {
+        if !album.album.is_empty() {
+            masterings
+                .entry((album.artist.to_lowercase(), album.album.to_lowercase()))
+                .or_default()
+                .push(index);
+        }
+    }
+    for indexes in masterings.values().filter(|indexes| indexes.len() > 1) {
+        let best = indexes.iter().copied().max_by(|a, b| {
+            let (a, b) = (&albums[*a], &albums[*b]);
+            a.dr.cmp(&b.dr).then(
+                (b.clipped_samples + b.inter_sample_overs)
+                    .cmp(&(a.clipped_samples + a.inter_sample_overs)),
+            )
+        });
+        let ids: Vec<String> = indexes
+            .iter()
+            .map(|index| albums[*index].album_id.clone())
+            .collect();
+        for index in indexes {
+            let album = &mut albums[*index];
+            album.other_masterings = ids
+                .iter()
+                .filter(|id| **id != album.album_id)
+                .cloned()
+                .collect();
+            album.best_mastering = best == Some(*index);
+        }
+    }
+    albums
+}
+
+/// Also saves the measurement on the file's songs in the library
+fn measure_file(
+    path: &Path,
+    cancel_token: &CancellationToken,
+    app_handle: &AppHandle,
+) -> (SongDynamics, AlbumTags) {
+    let folder = path
+        .parent()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let tags = read_from_path(path)
+        .ok()
+        .and_then(|tagged_file| {
+            let tag = tagged_file.primary_tag().or(tagged_file.first_tag())?;
+            Some(AlbumTags {
+                album: tag.album().unwrap_or_default().to_string(),
+                artist: tag
+                    .get_string(&ItemKey::AlbumArtist)
+                    .map(|a| a.to_string())
+                    .or(tag.artist().map(|a| a.to_string()))
+                    .unwrap_or_default(),
+            })
+        })
+        .unwrap_or(AlbumTags {
+            album: String::new(),
+            artist: String::new(),
+        });
+    let mut song = SongDynamics {
+        path: path.to_string_lossy().to_string(),
+        album_id: album_id(&folder, &tags.album),
+        dynamics: None,
+        error: None,
+    };
+
+    let measurement = FileDecoder::open(path).and_then(|mut decoder| {
+        let mut meter = DrMeter::new(decoder.sample_rate, decoder.channels);
+        decoder.for_each(cancel_token, |samples, channels| {
+            meter.push(samples, channels)
+        })?;
+        Ok(meter)
+    });
+    match measurement {
+        Ok(meter) => {
+            song.dynamics = meter.finish();
+            info!("[Dynamics] {:?}: {:?}", path, song.dynamics);
+            if song.dynamics.is_some() {
+                save_analysis(
+                    app_handle,
+                    &SongAnalysis {
+                        path: song.path.clone(),
+                        dynamics: song.dynamics.clone(),
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+        Err(err) => {
+            if !cancel_token.is_cancelled() {
+                warn!("[Dynamics] Error decoding {:?}: {}", path, err);
+            }
+            song.error = Some(err.to_string());
+        }
+    }
+    (song, tags)
+}
+
+fn to_db(amplitude: f64) -> f32 {
+    if amplitude <= 0.0 {
+        return -120.0;
+    }
+    (20.0 * amplitude.log10()).max(-120.0) as f32
+}
+
+#[derive(Clone, Default)]
+struct Channel {
+    /// Sum of squares and peak of the current block
+    block: (f64, f32),
+    /// RMS and peak of each block
+    blocks: Vec<(f64, f32)>,
+    /// Last input samples for the interpolation, newest first
+    history: [f32; TAPS_PER_PHASE],
+}
+
+/// DR Meter measurement: per channel, the second highest peak of the 3 s blocks
+/// over the RMS of the loudest 20% of them
+struct DrMeter {
+    block_len: usize,
+    block_pos: usize,
+    channels: Vec<Channel>,
+    phases: [[f32; TAPS_PER_PHASE]; OVERSAMPLING],
+    peak: f32,
+    true_peak: f32,
+    clipped_samples: u64,
+    inter_sample_overs: u64,
+}
+
+impl DrMeter {
+    fn new(sample_rate: u32, channels: usize) -> Self {
+        DrMeter {
+            block_len: ((sample_rate.max(1) as f64 * BLOCK_SECONDS) as usize).max(1),
+            block_pos: 0,
+            channels: vec![Channel::default(); channels.max(1)],
+            phases: interpolation_phases(),
+            peak: 0.0,
+            true_peak: 0.0,
+            clipped_samples: 0,
+            inter_sample_overs: 0,
+        }
+    }
+
+    fn push(&mut self, interleaved: &[f32], channels: usize) {
+        // The channel count can change between packets for some formats
+        if channels != self.channels.len() {
+            self.channels.resize(channels.max(1), Channel::default());
+        }
+        for frame in interleaved.chunks_exact(self.channels.len()) {
+            for (sample, channel) in frame.iter().zip(self.channels.iter_mut()) {
+                let level = sample.abs();
+                channel.block.0 += (*sample as f64).powi(2);
+                channel.block.1 = channel.block.1.max(level);
+                self.peak = self.peak.max(level);
+                if level >= CLIP_LEVEL {
+                    self.clipped_samples += 1;
+                }
+
+                channel.history.copy_within(0..TAPS_PER_PHASE - 1, 1);
+                channel.history[0] = *sample;
+                let mut over = false;
+                for coefficients in &self.phases {
+                    let interpolated: f32 = coefficients
+                        .iter()
+                        .zip(channel.history.iter())
+                        .map(|(c, s)| c * s)
+                        .sum::<f32>()
+                        .abs();
+                    self.true_peak = self.true_peak.max(interpolated);
+                    over |= interpolated > 1.0;
+                }
+                // Overs on a clipped sample are just the clipping
+                if over && level < CLIP_LEVEL {
+                    self.inter_sample_overs += 1;
+                }
+            }
+
+            self.block_pos += 1;
+            if self.block_pos == self.block_len {
+                self.end_block();
+            }
+        }
+    }
+
+    fn end_block(&mut self) {
+        for channel in self.channels.iter_mut() {
+            // Twice the mean square, so a sine's RMS is its peak
+            let rms = (2.0 * channel.block.0 / self.block_pos as f64).sqrt();
+            channel.blocks.push((rms, channel.block.1));
+            channel.block = (0.0, 0.0);
+        }
+        self.block_pos = 0;
+    }
+
+    fn finish(mut self) -> Option<DynamicsInfo> {
+        // A partial last block only counts when it's most of one, or all there is
+        let has_blocks = self.channels.first().is_some_and(|c| !c.blocks.is_empty());
+        if self.block_pos > 0 && (!has_blocks || self.block_pos * 2 >= self.block_len) {
+            self.end_block();
+        }
+
+        let scores: Vec<f64> = self
+            .channels
+            .iter()
+            .filter_map(|channel| {
+                let mut rms: Vec<f64> = channel.blocks.iter().map(|(rms, _)| *rms).collect();
+                let mut peaks: Vec<f32> = channel.blocks.iter().map(|(_, peak)| *peak).collect();
+                if rms.is_empty() {
+                    return None;
+                }
+                rms.sort_by(|a, b| b.total_cmp(a));
+                peaks.sort_by(|a, b| b.total_cmp(a));
+
+                let loudest = ((rms.len() as f64 * LOUDEST_BLOCKS) as usize).max(1);
+                let rms =
+                    (rms[..loudest].iter().map(|r| r * r).sum::<f64>() / loudest as f64).sqrt();
+                let peak = *peaks.get(1).unwrap_or(&peaks[0]) as f64;
+                (rms > 0.0 && peak > 0.0).then(|| 20.0 * (peak / rms).log10())
+            })
+            .collect();
+        if scores.is_empty() {
+            return None;
+        }
+
+        let dr = (scores.iter().sum::<f64>() / scores.len() as f64).max(0.0);
+        Some(DynamicsInfo {
+            dr: dr.round() as u8,
+            dr_precise: dr as f32,
+            peak: to_db(self.peak as f64),
+            true_peak: to_db(self.true_peak as f64),
+            clipped_samples: self.clipped_samples,
+            inter_sample_overs: self.inter_sample_overs,
+        })
+    }
+}
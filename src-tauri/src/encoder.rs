@@ -0,0 +1,364 @@
+//! Minimal WAV and FLAC encoders for streaming and recording the output
+
+use crc::{Crc, CRC_16_UMTS, CRC_8_SMBUS};
+
+/// FLAC frame header CRC (x^8 + x^2 + x + 1)
+const CRC_8: Crc<u8> = Crc::<u8>::new(&CRC_8_SMBUS);
+/// FLAC frame footer CRC (x^16 + x^15 + x^2 + 1)
+const CRC_16: Crc<u16> = Crc::<u16>::new(&CRC_16_UMTS);
+
+const FLAC_BLOCK_SIZE: usize = 4096;
+const MAX_FIXED_ORDER: usize = 4;
+/// With 5-bit Rice parameters, 31 is the escape code
+const MAX_RICE_PARAMETER: u32 = 30;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncoderFormat {
+    Wav,
+    Flac,
+}
+
+impl EncoderFormat {
+    pub fn from_setting(value: Option<&str>) -> Self {
+        match value {
+            Some("flac") => EncoderFormat::Flac,
+            _ => EncoderFormat::Wav,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EncoderFormat::Wav => "wav",
+            EncoderFormat::Flac => "flac",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            EncoderFormat::Wav => "audio/wav",
+            EncoderFormat::Flac => "audio/flac",
+        }
+    }
+}
+
+/// Converts a float sample to a signed integer with the given bit depth
+pub fn quantize(sample: f32, bits_per_sample: u32) -> i32 {
+    let max = ((1i64 << (bits_per_sample - 1)) - 1) as f32;
+    (sample * max).round().clamp(-max - 1.0, max) as i32
+}
+
+/**
+ * Integer PCM encoder, either WAV or FLAC.
+ * Takes interleaved samples and returns the encoded bytes ready to be written.
+ */
+pub struct Encoder {
+    format: EncoderFormat,
+    sample_rate: u32,
+    channels: usize,
+    bits_per_sample: u32,
+    /// Interleaved samples waiting for a full FLAC block
+    pending: Vec<i32>,
+    frame_number: u64,
+}
+
+impl Encoder {
+    pub fn new(
+        format: EncoderFormat,
+        sample_rate: u32,
+        channels: usize,
+        bits_per_sample: u32,
+    ) -> Self {
+        Encoder {
+            format,
+            sample_rate,
+            channels,
+            bits_per_sample,
+            pending: Vec::with_capacity(FLAC_BLOCK_SIZE * channels),
+            frame_number: 0,
+        }
+    }
+
+    /**
+     * Header to start the stream with. When the length isn't known yet
+     * (live streams) it is left open, files can be fixed up with a final header
+     * once finished.
+     */
+    pub fn header(&self, total_frames: Option<u64>) -> Vec<u8> {
+        match self.format {
+            EncoderFormat::Wav => self.wav_header(total_frames),
+            EncoderFormat::Flac => self.flac_header(total_frames.unwrap_or(0)),
+        }
+    }
+
+    pub fn encode(&mut self, samples: &[i32]) -> Vec<u8> {
+        match self.format {
+            EncoderFormat::Wav => self.encode_wav(samples),
+            EncoderFormat::Flac => {
+                self.pending.extend_from_slice(samples);
+                let block_len = FLAC_BLOCK_SIZE * self.channels;
+                let mut output = Vec::new();
+                let mut offset = 0;
+                while self.pending.len() - offset >= block_len {
+                    let block = self.pending[offset..offset + block_len].to_vec();
+                    output.extend(self.flac_frame(&block));
+                    offset += block_len;
+                }
+                self.pending.drain(..offset);
+                output
+            }
+        }
+    }
+
+    /// Encodes whatever is left over, call once at the end
+    pub fn finish(&mut self) -> Vec<u8> {
+        if self.format == EncoderFormat::Flac && !self.pending.is_empty() {
+            let block = std::mem::take(&mut self.pending);
+            return self.flac_frame(&block);
+        }
+        vec![]
+    }
+
+    fn wav_header(&self, total_frames: Option<u64>) -> Vec<u8> {
+        let block_align = self.channels as u32 * self.bits_per_sample / 8;
+        // Open-ended streams use the maximum size, which players treat as unknown
+        let data_len = total_frames
+            .map(|frames| (frames * block_align as u64).min(u32::MAX as u64 - 36) as u32)
+            .unwrap_or(u32::MAX - 36);
+
+        let mut header = Vec::with_capacity(44);
+        header.extend(b"RIFF");
+        header.extend((data_len + 36).to_le_bytes());
+        header.extend(b"WAVEfmt ");
+        header.extend(16u32.to_le_bytes());
+        // PCM
+        header.extend(1u16.to_le_bytes());
+        header.extend((self.channels as u16).to_le_bytes());
+        header.extend(self.sample_rate.to_le_bytes());
+        header.extend((self.sample_rate * block_align).to_le_bytes());
+        header.extend((block_align as u16).to_le_bytes());
+        header.extend((self.bits_per_sample as u16).to_le_bytes());
+        header.extend(b"data");
+        header.extend(data_len.to_le_bytes());
+        header
+    }
+
+    fn encode_wav(&self, samples: &[i32]) -> Vec<u8> {
+        let bytes_per_sample = self.bits_per_sample as usize / 8;
+        let mut output = Vec::with_capacity(samples.len() * bytes_per_sample);
+        for sample in samples {
+            output.extend(&sample.to_le_bytes()[..bytes_per_sample]);
+        }
+        output
+    }
+
+    fn flac_header(&self, total_frames: u64) -> Vec<u8> {
+        let mut writer = BitWriter::default();
+        writer.write_bytes(b"fLaC");
+        // Last metadata block, STREAMINFO, 34 bytes
+        writer.write(1, 1);
+        writer.write(0, 7);
+        writer.write(34, 24);
+        writer.write(FLAC_BLOCK_SIZE as u64, 16);
+        writer.write(FLAC_BLOCK_SIZE as u64, 16);
+        // Min and max frame sizes are unknown
+        writer.write(0, 24);
+        writer.write(0, 24);
+        writer.write(self.sample_rate as u64, 20);
+        writer.write(self.channels as u64 - 1, 3);
+        writer.write(self.bits_per_sample as u64 - 1, 5);
+        writer.write(total_frames & 0xF_FFFF_FFFF, 36);
+        // No MD5
+        writer.write_bytes(&[0; 16]);
+        writer.into_bytes()
+    }
+
+    fn flac_frame(&mut self, block: &[i32]) -> Vec<u8> {
+        let block_size = block.len() / self.channels;
+        let mut writer = BitWriter::default();
+
+        // Sync code, fixed block size
+        writer.write(0b1111_1111_1111_1000, 16);
+        // Block size is stored at the end of the header, sample rate comes from STREAMINFO
+        writer.write(0b0111, 4);
+        writer.write(0b0000, 4);
+        // Independent channels
+        writer.write(self.channels as u64 - 1, 4);
+        writer.write(
+            match self.bits_per_sample {
+                8 => 0b001,
+                16 => 0b100,
+                24 => 0b110,
+                _ => 0b000,
+            },
+            3,
+        );
+        writer.write(0, 1);
+        writer.write_bytes(&utf8_number(self.frame_number));
+        writer.write(block_size as u64 - 1, 16);
+        let crc = CRC_8.checksum(writer.bytes());
+        writer.write(crc as u64, 8);
+
+        for channel in 0..self.channels {
+            let samples: Vec<i64> = block
+                .iter()
+                .skip(channel)
+                .step_by(self.channels)
+                .map(|sample| *sample as i64)
+                .collect();
+            self.write_subframe(&mut writer, &samples);
+        }
+
+        writer.align();
+        let crc = CRC_16.checksum(writer.bytes());
+        writer.write(crc as u64, 16);
+
+        self.frame_number += 1;
+        writer.into_bytes()
+    }
+
+    /**
+     * Picks the fixed predictor with the smallest residual and Rice codes it.
+     */
+    fn write_subframe(&self, writer: &mut BitWriter, samples: &[i64]) {
+        let max_order = MAX_FIXED_ORDER.min(samples.len().saturating_sub(1));
+        let (order, residual) = (0..=max_order)
+            .map(|order| (order, fixed_residual(samples, order)))
+            .min_by_key(|(_, residual)| residual.iter().map(|r| r.unsigned_abs()).sum::<u64>())
+            .unwrap();
+
+        // Zero padding, FIXED subframe type with order, no wasted bits
+        writer.write(0, 1);
+        writer.write(0b001000 | order as u64, 6);
+        writer.write(0, 1);
+
+        for sample in &samples[..order] {
+            writer.write_signed(*sample, self.bits_per_sample);
+        }
+
+        // Rice coding with 5-bit parameters, a single partition
+        let parameter = rice_parameter(&residual);
+        writer.write(0b01, 2);
+        writer.write(0, 4);
+        writer.write(parameter as u64, 5);
+        for r in residual {
+            let folded = ((r << 1) ^ (r >> 63)) as u64;
+            writer.write_unary(folded >> parameter);
+            if parameter > 0 {
+                writer.write(folded & ((1 << parameter) - 1), parameter);
+            }
+        }
+    }
+}
+
+/// Residual of the fixed polynomial predictor of the given order
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    samples
+        .iter()
+        .enumerate()
+        .skip(order)
+        .map(|(i, sample)| {
+            let prediction = match order {
+                0 => 0,
+                1 => samples[i - 1],
+                2 => 2 * samples[i - 1] - samples[i - 2],
+                3 => 3 * samples[i - 1] - 3 * samples[i - 2] + samples[i - 3],
+                _ => 4 * samples[i - 1] - 6 * samples[i - 2] + 4 * samples[i - 3] - samples[i - 4],
+            };
+            sample - prediction
+        })
+        .collect()
+}
+
+/// Rice parameter estimated from the mean folded residual
+fn rice_parameter(residual: &[i64]) -> u32 {
+    if residual.is_empty() {
+        return 0;
+    }
+    let sum: u64 = residual.iter().map(|r| ((r << 1) ^ (r >> 63)) as u64).sum();
+    let mean = sum / residual.len() as u64;
+    let mut parameter = 0;
+    while parameter < MAX_RICE_PARAMETER && (1u64 << (parameter + 1)) <= mean {
+        parameter += 1;
+    }
+    parameter
+}
+
+/// FLAC frame numbers use the UTF-8 variable length scheme
+pub(crate) fn utf8_number(value: u64) -> Vec<u8> {
+    if value < 0x80 {
+        return vec![value as u8];
+    }
+    let mut continuation = Vec::new();
+    let mut value = value;
+    let mut first_bits = 6;
+    while value >= (1 << first_bits) {
+        continuation.push(0x80 | (value & 0x3F) as u8);
+        value >>= 6;
+        first_bits -= 1;
+    }
+    let count = continuation.len() + 1;
+    let prefix = !(0xFFu8 >> count);
+    let mut bytes = vec![prefix | value as u8];
+    bytes.extend(continuation.into_iter().rev());
+    bytes
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    accumulator: u64,
+    bits: u32,
+}
+
+impl BitWriter {
+    /// Writes the lowest `bits` bits of value, at most 32 at a time
+    fn write(&mut self, value: u64, bits: u32) {
+        if bits > 32 {
+            self.write(value >> 32, bits - 32);
+            self.write(value & 0xFFFF_FFFF, 32);
+            return;
+        }
+        self.accumulator = (self.accumulator << bits) | (value & ((1 << bits) - 1));
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            self.bytes.push((self.accumulator >> self.bits) as u8);
+        }
+        self.accumulator &= (1 << self.bits) - 1;
+    }
+
+    fn write_signed(&mut self, value: i64, bits: u32) {
+        self.write(value as u64 & ((1 << bits) - 1), bits);
+    }
+
+    /// `value` zeros followed by a one
+    fn write_unary(&mut self, mut value: u64) {
+        while value >= 32 {
+            self.write(0, 32);
+            value -= 32;
+        }
+        self.write(1, value as u32 + 1);
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write(*byte as u64, 8);
+        }
+    }
+
+    fn align(&mut self) {
+        if self.bits > 0 {
+            self.write(0, 8 - self.bits);
+        }
+    }
+
+    /// Only the complete bytes written so far
+    fn bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn into_bytes(mut self) -> Vec<u8> {
+        self.align();
+        self.bytes
+    }
+}
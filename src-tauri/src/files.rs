@@ -1,12 +1,86 @@
 use futures_util::StreamExt;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
 use std::fs;
 use std::io::Write;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tauri::{AppHandle, Emitter};
 use tempfile::Builder;
 
+use crate::store::load_settings;
+
+/// Files we scan into the library
+pub const AUDIO_EXTENSIONS: [&str; 8] = ["mp3", "flac", "wav", "aiff", "ape", "ogg", "m4a", "m4b"];
+
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| AUDIO_EXTENSIONS.iter().any(|a| a.eq_ignore_ascii_case(e)))
+}
+
+/// Every audio file in the folder and its subfolders, skipping hidden ones
+pub fn audio_files(folder: &Path) -> Vec<PathBuf> {
+    files_in(folder, is_audio_file)
+}
+
+/// Every file in the folder and its subfolders that passes the filter, skipping hidden ones
+pub fn files_in(folder: &Path, filter: impl Fn(&Path) -> bool) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut folders = vec![folder.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        let Ok(entries) = fs::read_dir(&folder) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => folders.push(path),
+                Ok(_) if filter(&path) => files.push(path),
+                _ => {}
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+/// Files as they are, folders replaced with the audio files in them
+pub fn expand_audio_paths(paths: &[String]) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    paths
+        .iter()
+        .map(PathBuf::from)
+        .flat_map(|path| {
+            if path.is_dir() {
+                audio_files(&path)
+            } else if is_audio_file(&path) {
+                vec![path]
+            } else {
+                vec![]
+            }
+        })
+        .filter(|path| seen.insert(path.clone()))
+        .collect()
+}
+
+/// Audio files in the watched folders
+pub fn library_audio_files(app_handle: &AppHandle) -> Vec<PathBuf> {
+    let Ok(settings) = load_settings(app_handle) else {
+        return vec![];
+    };
+    let mut seen = HashSet::new();
+    settings
+        .folders_to_watch
+        .iter()
+        .flat_map(|folder| audio_files(Path::new(folder)))
+        .filter(|path| seen.insert(path.clone()))
+        .collect()
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct DeleteFilesEvent {
@@ -91,6 +165,15 @@ pub async fn download_file(
     Ok(())
 }
 
+/// Identifies the file on its volume, surviving renames and moves within it
+pub fn file_inode(path: &Path) -> Option<u64> {
+    match file_id::get_file_id(path).ok()? {
+        file_id::FileId::Inode { inode_number, .. } => Some(inode_number),
+        file_id::FileId::HighRes { file_id, .. } => Some(file_id as u64),
+        file_id::FileId::LowRes { file_index, .. } => Some(file_index),
+    }
+}
+
 fn get_device_id(path: &Path) -> u64 {
     let parent_path = path.parent().expect("Failed to get parent directory");
 
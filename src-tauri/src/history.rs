@@ -0,0 +1,601 @@
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{error, info};
+use rusqlite::{params, Connection, OpenFlags};
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Manager};
+
+use crate::library::{get_library_db_path, LibraryStore};
+use crate::metadata::Song;
+use crate::store::load_settings;
+
+/// Share of the track that has to be heard before it counts as a play
+pub const DEFAULT_PLAY_THRESHOLD: f64 = 0.5;
+
+/// Raw playback events sent from the decoding thread.
+/// These describe what is actually heard, i.e `Started` is sent when the song
+/// change happens in the output, not when we start decoding the next track.
+#[derive(Clone, Debug)]
+pub enum PlaybackEvent {
+    Started {
+        song: Song,
+        output_device: String,
+        playback_speed: f64,
+        /// Where playback starts in seconds, more than 0 when seeking
+        position: f64,
+    },
+    /// Current position in seconds, received from the audio callback
+    Position(f64),
+    SpeedChanged(f64),
+    Paused,
+    Resumed,
+    /// Track played until the end (gapless transition or end of queue)
+    Ended,
+}
+
+/// Fans out playback events to any number of subscribers (history, scrobbler...)
+#[derive(Clone, Default)]
+pub struct PlaybackEvents {
+    subscribers: Arc<Mutex<Vec<Sender<PlaybackEvent>>>>,
+}
+
+impl PlaybackEvents {
+    pub fn subscribe(&self) -> Receiver<PlaybackEvent> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    pub fn send(&self, event: PlaybackEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        // Drop subscribers that have gone away
+        subscribers.retain(|s| s.send(event.clone()).is_ok());
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenOutcome {
+    /// Played past the threshold
+    Play,
+    /// Left early, before reaching the threshold
+    Skip,
+    /// Reached the end without hearing enough of it (eg. seeked to the end)
+    Partial,
+}
+
+impl ListenOutcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ListenOutcome::Play => "play",
+            ListenOutcome::Skip => "skip",
+            ListenOutcome::Partial => "partial",
+        }
+    }
+
+    fn from_str(s: &str) -> Self {
+        match s {
+            "play" => ListenOutcome::Play,
+            "skip" => ListenOutcome::Skip,
+            _ => ListenOutcome::Partial,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ListenThreshold {
+    /// Share of the track (0 to 1)
+    pub fraction: f64,
+    /// Absolute listening time after which the threshold is reached regardless of duration
+    pub max_seconds: Option<f64>,
+}
+
+impl ListenThreshold {
+    pub fn is_reached(&self, played_seconds: f64, duration: Option<f64>) -> bool {
+        if let Some(max) = self.max_seconds {
+            if played_seconds >= max {
+                return true;
+            }
+        }
+        match duration {
+            Some(d) if d > 0.0 => played_seconds >= d * self.fraction,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ListenSession {
+    pub song: Song,
+    pub started_at: i64,
+    pub output_device: String,
+    pub playback_speed: f64,
+    pub played_seconds: f64,
+    pub threshold_reached: bool,
+    /// Since when we've been listening, None while paused
+    playing_since: Option<Instant>,
+}
+
+impl ListenSession {
+    /// Adds the time listened since the last call
+    fn count_until(&mut self, now: Instant) {
+        if let Some(since) = self.playing_since.as_mut() {
+            self.played_seconds += now.saturating_duration_since(*since).as_secs_f64();
+            *since = now;
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum ListenUpdate {
+    Started(ListenSession),
+    ThresholdReached(ListenSession),
+    Finished(ListenSession, ListenOutcome),
+}
+
+/**
+ * Turns raw playback events into listening sessions.
+ * Listened time is the time spent playing, so seeking forward doesn't count
+ * as listening.
+ */
+pub struct ListenTracker {
+    threshold: ListenThreshold,
+    current: Option<ListenSession>,
+}
+
+impl ListenTracker {
+    pub fn new(threshold: ListenThreshold) -> Self {
+        Self {
+            threshold,
+            current: None,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: ListenThreshold) {
+        self.threshold = threshold;
+    }
+
+    pub fn handle(&mut self, event: &PlaybackEvent) -> Vec<ListenUpdate> {
+        self.handle_at(event, Instant::now())
+    }
+
+    pub fn handle_at(&mut self, event: &PlaybackEvent, now: Instant) -> Vec<ListenUpdate> {
+        let mut updates = Vec::new();
+        if let Some(current) = self.current.as_mut() {
+            current.count_until(now);
+        }
+        match event {
+            PlaybackEvent::Started {
+                song,
+                output_device,
+                playback_speed,
+                position,
+            } => {
+                // Seeking or changing the audio device restarts the same song
+                // part way through, playing it again starts from the top
+                if let Some(current) = self.current.as_mut() {
+                    if current.song.id == song.id && *position > 0.0 {
+                        current.output_device = output_device.clone();
+                        current.playback_speed = *playback_speed;
+                        current.playing_since = Some(now);
+                        return updates;
+                    }
+                }
+                if let Some(previous) = self.current.take() {
+                    let outcome = if previous.threshold_reached {
+                        ListenOutcome::Play
+                    } else {
+                        ListenOutcome::Skip
+                    };
+                    updates.push(ListenUpdate::Finished(previous, outcome));
+                }
+                let session = ListenSession {
+                    song: song.clone(),
+                    started_at: now_millis(),
+                    output_device: output_device.clone(),
+                    playback_speed: *playback_speed,
+                    played_seconds: 0.0,
+                    threshold_reached: false,
+                    playing_since: Some(now),
+                };
+                updates.push(ListenUpdate::Started(session.clone()));
+                self.current = Some(session);
+            }
+            PlaybackEvent::Position(_) => {
+                if let Some(current) = self.current.as_mut() {
+                    if !current.threshold_reached
+                        && self
+                            .threshold
+                            .is_reached(current.played_seconds, current.song.file_info.duration)
+                    {
+                        current.threshold_reached = true;
+                        updates.push(ListenUpdate::ThresholdReached(current.clone()));
+                    }
+                }
+            }
+            PlaybackEvent::SpeedChanged(speed) => {
+                if let Some(current) = self.current.as_mut() {
+                    current.playback_speed = *speed;
+                }
+            }
+            PlaybackEvent::Paused => {
+                if let Some(current) = self.current.as_mut() {
+                    current.playing_since = None;
+                }
+            }
+            PlaybackEvent::Resumed => {
+                if let Some(current) = self.current.as_mut() {
+                    current.playing_since.get_or_insert(now);
+                }
+            }
+            PlaybackEvent::Ended => {
+                if let Some(previous) = self.current.take() {
+                    let outcome = if previous.threshold_reached {
+                        ListenOutcome::Play
+                    } else {
+                        ListenOutcome::Partial
+                    };
+                    updates.push(ListenUpdate::Finished(previous, outcome));
+                }
+            }
+        }
+        updates
+    }
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as i64
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayRecord {
+    pub id: i64,
+    pub song_id: String,
+    pub path: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub duration: Option<f64>,
+    pub started_at: i64,
+    pub ended_at: Option<i64>,
+    pub played_seconds: f64,
+    pub outcome: ListenOutcome,
+    pub output_device: Option<String>,
+    pub playback_speed: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SongPlayStats {
+    pub song_id: String,
+    pub path: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub plays: i64,
+    pub skips: i64,
+    pub last_played: Option<i64>,
+}
+
+pub struct HistoryStore {
+    conn: Connection,
+}
+
+impl HistoryStore {
+    pub fn open(path: &PathBuf) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS plays (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                song_id TEXT NOT NULL,
+                path TEXT NOT NULL,
+                title TEXT NOT NULL DEFAULT '',
+                artist TEXT NOT NULL DEFAULT '',
+                album TEXT NOT NULL DEFAULT '',
+                duration REAL,
+                started_at INTEGER NOT NULL,
+                ended_at INTEGER,
+                played_seconds REAL NOT NULL DEFAULT 0,
+                outcome TEXT NOT NULL,
+                output_device TEXT,
+                playback_speed REAL NOT NULL DEFAULT 1.0
+            );
+            CREATE INDEX IF NOT EXISTS idx_plays_started_at ON plays(started_at);
+            CREATE INDEX IF NOT EXISTS idx_plays_song_id ON plays(song_id);
+            ",
+        )?;
+        Ok(Self { conn })
+    }
+
+    pub fn open_read_only(path: &PathBuf) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        Ok(Self { conn })
+    }
+
+    pub fn insert(
+        &self,
+        session: &ListenSession,
+        outcome: ListenOutcome,
+        ended_at: Option<i64>,
+    ) -> rusqlite::Result<i64> {
+        self.conn.execute(
+            "INSERT INTO plays (song_id, path, title, artist, album, duration, started_at,
+                ended_at, played_seconds, outcome, output_device, playback_speed)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                session.song.id,
+                session.song.path,
+                session.song.title,
+                session.song.artist,
+                session.song.album,
+                session.song.file_info.duration,
+                session.started_at,
+                ended_at,
+                session.played_seconds,
+                outcome.as_str(),
+                session.output_device,
+                session.playback_speed,
+            ],
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn finish(&self, id: i64, session: &ListenSession, ended_at: i64) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "UPDATE plays SET ended_at = ?1, played_seconds = ?2, playback_speed = ?3 WHERE id = ?4",
+            params![ended_at, session.played_seconds, session.playback_speed, id],
+        )?;
+        Ok(())
+    }
+
+    /// Keeps the plays of a song that was moved or renamed
+    pub fn remap_song(&self, from: &str, to: &str, path: &str) -> rusqlite::Result<usize> {
+        self.conn.execute(
+            "UPDATE plays SET song_id = ?2, path = ?3 WHERE song_id = ?1",
+            params![from, to, path],
+        )
+    }
+
+    pub fn query_range(
+        &self,
+        from: Option<i64>,
+        to: Option<i64>,
+        limit: Option<i64>,
+    ) -> rusqlite::Result<Vec<PlayRecord>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, song_id, path, title, artist, album, duration, started_at, ended_at,
+                played_seconds, outcome, output_device, playback_speed
+             FROM plays
+             WHERE started_at >= ?1 AND started_at <= ?2
+             ORDER BY started_at DESC
+             LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                from.unwrap_or(0),
+                to.unwrap_or(i64::MAX),
+                limit.unwrap_or(-1)
+            ],
+            |row| {
+                Ok(PlayRecord {
+                    id: row.get(0)?,
+                    song_id: row.get(1)?,
+                    path: row.get(2)?,
+                    title: row.get(3)?,
+                    artist: row.get(4)?,
+                    album: row.get(5)?,
+                    duration: row.get(6)?,
+                    started_at: row.get(7)?,
+                    ended_at: row.get(8)?,
+                    played_seconds: row.get(9)?,
+                    outcome: ListenOutcome::from_str(&row.get::<_, String>(10)?),
+                    output_device: row.get(11)?,
+                    playback_speed: row.get(12)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+
+    /// Per-song play and skip counts, sorted by the given outcome
+    pub fn top_songs(
+        &self,
+        order_by: ListenOutcome,
+        from: Option<i64>,
+        to: Option<i64>,
+        limit: Option<i64>,
+    ) -> rusqlite::Result<Vec<SongPlayStats>> {
+        let order_col = match order_by {
+            ListenOutcome::Skip => "skips",
+            _ => "plays",
+        };
+        let sql = format!(
+            "SELECT song_id, path, title, artist, album,
+                SUM(CASE WHEN outcome = 'play' THEN 1 ELSE 0 END) AS plays,
+                SUM(CASE WHEN outcome = 'skip' THEN 1 ELSE 0 END) AS skips,
+                MAX(CASE WHEN outcome = 'play' THEN started_at END) AS last_played
+             FROM plays
+             WHERE started_at >= ?1 AND started_at <= ?2
+             GROUP BY song_id
+             HAVING {0} > 0
+             ORDER BY {0} DESC, last_played DESC
+             LIMIT ?3",
+            order_col
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![
+                from.unwrap_or(0),
+                to.unwrap_or(i64::MAX),
+                limit.unwrap_or(-1)
+            ],
+            |row| {
+                Ok(SongPlayStats {
+                    song_id: row.get(0)?,
+                    path: row.get(1)?,
+                    title: row.get(2)?,
+                    artist: row.get(3)?,
+                    album: row.get(4)?,
+                    plays: row.get(5)?,
+                    skips: row.get(6)?,
+                    last_played: row.get(7)?,
+                })
+            },
+        )?;
+        rows.collect()
+    }
+}
+
+pub fn get_history_db_path(app: &AppHandle) -> Option<PathBuf> {
+    let data_dir = app.path().app_data_dir().ok()?;
+    if !data_dir.exists() {
+        std::fs::create_dir_all(&data_dir).ok()?;
+    }
+    Some(data_dir.join("history.db"))
+}
+
+fn load_threshold(app: &AppHandle) -> ListenThreshold {
+    let fraction = load_settings(app)
+        .ok()
+        .and_then(|s| s.play_count_threshold)
+        .filter(|t| *t > 0.0 && *t <= 1.0)
+        .unwrap_or(DEFAULT_PLAY_THRESHOLD);
+    ListenThreshold {
+        fraction,
+        max_seconds: None,
+    }
+}
+
+/// Start the history writer thread, consuming events from the decoding thread.
+pub fn init(app: AppHandle, receiver: Receiver<PlaybackEvent>) {
+    std::thread::spawn(move || {
+        let store = match get_history_db_path(&app).map(|p| HistoryStore::open(&p)) {
+            Some(Ok(store)) => store,
+            Some(Err(err)) => {
+                error!("[History] Error opening history database: {}", err);
+                return;
+            }
+            None => {
+                error!("[History] App data directory not available");
+                return;
+            }
+        };
+
+        // Play counts for the library, the history above stays the record
+        let library = get_library_db_path(&app).and_then(|p| LibraryStore::open(&p).ok());
+
+        let mut tracker = ListenTracker::new(load_threshold(&app));
+        // Row for the current session, once it has been counted as a play
+        let mut current_row: Option<i64> = None;
+
+        while let Ok(event) = receiver.recv() {
+            if let PlaybackEvent::Started { .. } = event {
+                tracker.set_threshold(load_threshold(&app));
+            }
+            for update in tracker.handle(&event) {
+                let result = match update {
+                    ListenUpdate::Started(session) => {
+                        info!("[History] Started listening to {}", session.song.path);
+                        Ok(())
+                    }
+                    ListenUpdate::ThresholdReached(session) => {
+                        record_listen(library.as_ref(), &session, ListenOutcome::Play);
+                        // Write the play straight away, so it's not lost if the app is closed
+                        store.insert(&session, ListenOutcome::Play, None).map(|id| {
+                            current_row.replace(id);
+                        })
+                    }
+                    ListenUpdate::Finished(session, outcome) => {
+                        info!(
+                            "[History] {:?} {} ({:.0}s)",
+                            outcome, session.song.path, session.played_seconds
+                        );
+                        if let Some(id) = current_row.take() {
+                            store.finish(id, &session, now_millis())
+                        } else {
+                            record_listen(library.as_ref(), &session, outcome);
+                            store
+                                .insert(&session, outcome, Some(now_millis()))
+                                .map(|_| ())
+                        }
+                    }
+                };
+                if let Err(err) = result {
+                    error!("[History] Error writing play history: {}", err);
+                }
+            }
+        }
+    });
+}
+
+fn record_listen(library: Option<&LibraryStore>, session: &ListenSession, outcome: ListenOutcome) {
+    if let Some(Err(err)) =
+        library.map(|library| library.record_listen(&session.song.id, outcome, now_millis()))
+    {
+        error!("[History] Error updating library play counts: {}", err);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayHistoryRequest {
+    /// Unix timestamp in ms (inclusive)
+    from: Option<i64>,
+    /// Unix timestamp in ms (inclusive)
+    to: Option<i64>,
+    limit: Option<i64>,
+}
+
+fn open_history(app: &AppHandle) -> Result<HistoryStore, String> {
+    let path = get_history_db_path(app).ok_or_else(|| "History database not found".to_string())?;
+    if !path.exists() {
+        // Nothing has been played yet
+        return HistoryStore::open(&path).map_err(|e| e.to_string());
+    }
+    HistoryStore::open_read_only(&path).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_play_history(
+    event: PlayHistoryRequest,
+    app_handle: AppHandle,
+) -> Result<Vec<PlayRecord>, String> {
+    info!("[History] Querying play history {:?}", event);
+    let store = open_history(&app_handle)?;
+    store
+        .query_range(event.from, event.to, event.limit)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_most_played(
+    event: PlayHistoryRequest,
+    app_handle: AppHandle,
+) -> Result<Vec<SongPlayStats>, String> {
+    let store = open_history(&app_handle)?;
+    store
+        .top_songs(ListenOutcome::Play, event.from, event.to, event.limit)
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_most_skipped(
+    event: PlayHistoryRequest,
+    app_handle: AppHandle,
+) -> Result<Vec<SongPlayStats>, String> {
+    let store = open_history(&app_handle)?;
+    store
+        .top_songs(ListenOutcome::Skip, event.from, event.to, event.limit)
+        .map_err(|e| e.to_string())
+}
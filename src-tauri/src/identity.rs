@@ -0,0 +1,163 @@
+//! Recognises songs after they're moved or renamed, and carries over what was
+//! stored under their old id, since song ids are a hash of the path
+
+use chksum_md5::MD5;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{Limit, MetadataOptions};
+use symphonia::core::probe::Hint;
+use symphonia::default::get_probe;
+use tauri::AppHandle;
+
+use crate::files::{files_in, is_audio_file};
+use crate::history::{get_history_db_path, HistoryStore};
+use crate::library_index::MovedSong;
+use crate::similarity::{get_features_db_path, FeatureStore};
+use crate::stem_separator::move_stems;
+use crate::store::load_settings;
+use crate::waveform;
+
+/// Audio read for the hash, plenty to tell songs apart
+const AUDIO_HASH_BYTES: usize = 256 * 1024;
+
+/**
+ * Hash of the format and the start of the audio stream, which stays the same
+ * when the tags are edited. None for files we can't read.
+ */
+pub fn audio_hash(path: &Path) -> Option<String> {
+    if !is_audio_file(path) {
+        return None;
+    }
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(extension);
+    }
+    let mss = MediaSourceStream::new(Box::new(File::open(path).ok()?), Default::default());
+    // Only the audio matters
+    let metadata_opts = MetadataOptions {
+        limit_metadata_bytes: Limit::Maximum(0),
+        limit_visual_bytes: Limit::Maximum(0),
+    };
+    let mut reader = get_probe()
+        .format(&hint, mss, &FormatOptions::default(), &metadata_opts)
+        .ok()?
+        .format;
+    let track = reader
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)?;
+    let track_id = track.id;
+    let mut data = format!(
+        "{:?}|{:?}|{:?}|",
+        track.codec_params.sample_rate,
+        track.codec_params.channels.map(|channels| channels.count()),
+        track.codec_params.n_frames
+    )
+    .into_bytes();
+
+    while data.len() < AUDIO_HASH_BYTES {
+        match reader.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => data.extend_from_slice(&packet.data),
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    Some(MD5::hash(data.as_slice()).to_hex_lowercase())
+}
+
+/**
+ * Carries over the play history, audio features, stems, waveforms and playlist
+ * entries of moved songs to their new ids and paths.
+ */
+pub fn remap_references(moved: &[MovedSong], app: &AppHandle) {
+    if moved.is_empty() {
+        return;
+    }
+    let history = get_history_db_path(app)
+        .filter(|path| path.exists())
+        .and_then(|path| HistoryStore::open(&path).ok());
+    let features = get_features_db_path(app)
+        .filter(|path| path.exists())
+        .and_then(|path| FeatureStore::open(&path).ok());
+
+    for song in moved {
+        let (from, to, path) = (&song.from, &song.song.id, &song.song.path);
+        if let Some(Err(err)) = history.as_ref().map(|h| h.remap_song(from, to, path)) {
+            warn!(
+                "[Identity] Error remapping the history of {}: {}",
+                from, err
+            );
+        }
+        if let Some(Err(err)) = features.as_ref().map(|f| f.remap_song(from, to, path)) {
+            warn!(
+                "[Identity] Error remapping the features of {}: {}",
+                from, err
+            );
+        }
+        if let Err(err) = move_stems(from, to, app) {
+            warn!("[Identity] Error moving the stems of {}: {}", from, err);
+        }
+    }
+
+    // CUE tracks share a file
+    let renamed: HashMap<&str, &str> = moved
+        .iter()
+        .filter(|song| song.from_path != song.song.path)
+        .map(|song| (song.from_path.as_str(), song.song.path.as_str()))
+        .collect();
+    for (from, to) in renamed.iter() {
+        if let Err(err) = waveform::move_cached(Path::new(from), Path::new(to), app) {
+            warn!("[Identity] Error moving the waveform of {}: {}", from, err);
+        }
+    }
+    remap_playlists(&renamed, app);
+    info!("[Identity] Remapped {} moved songs", moved.len());
+}
+
+/// M3U playlists list songs by path
+fn remap_playlists(renamed: &HashMap<&str, &str>, app: &AppHandle) {
+    let Some(folder) = load_settings(app)
+        .ok()
+        .and_then(|settings| settings.playlists_location)
+    else {
+        return;
+    };
+    for playlist in files_in(Path::new(&folder), is_playlist_file) {
+        let Ok(contents) = std::fs::read_to_string(&playlist) else {
+            continue;
+        };
+        let mut changed = false;
+        let lines: Vec<&str> = contents
+            .lines()
+            .map(|line| match renamed.get(line.trim()) {
+                Some(to) => {
+                    changed = true;
+                    *to
+                }
+                None => line,
+            })
+            .collect();
+        if !changed {
+            continue;
+        }
+        let mut remapped = lines.join("\n");
+        if contents.ends_with('\n') {
+            remapped.push('\n');
+        }
+        match std::fs::write(&playlist, remapped) {
+            Ok(()) => info!("[Identity] Updated playlist {:?}", playlist),
+            Err(err) => warn!("[Identity] Error updating playlist {:?}: {}", playlist, err),
+        }
+    }
+}
+
+fn is_playlist_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case("m3u") || e.eq_ignore_ascii_case("m3u8"))
+}
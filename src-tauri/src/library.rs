@@ -0,0 +1,971 @@
+//! The library in SQLite, written to by scans, so it can be queried a page at
+//! a time instead of going through the frontend
+
+use log::{error, info};
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::history::{now_millis, ListenOutcome};
+use crate::library_index::ScanDelta;
+use crate::metadata::{album_id, Album, DynamicsInfo, Song};
+use crate::smart_query::SqlFilter;
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+const MAX_PAGE_SIZE: i64 = 5000;
+/// Scans, the watcher and the history can all write at once
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Each runs once, in order, tracked with `PRAGMA user_version`. Only ever append.
+/// history.db is attached as `history_db` while they run.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE songs (
+        id TEXT PRIMARY KEY,
+        path TEXT NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        album TEXT NOT NULL,
+        album_id TEXT,
+        album_artist TEXT,
+        compilation INTEGER NOT NULL DEFAULT 0,
+        year INTEGER NOT NULL DEFAULT 0,
+        genre TEXT NOT NULL DEFAULT '',
+        track_number INTEGER NOT NULL DEFAULT 0,
+        disc_number INTEGER NOT NULL DEFAULT 0,
+        duration REAL,
+        bpm REAL,
+        key TEXT,
+        date_added INTEGER,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX songs_path ON songs (path);
+    CREATE INDEX songs_album_id ON songs (album_id);
+    CREATE INDEX songs_artist ON songs (artist COLLATE NOCASE);
+
+    CREATE TABLE albums (
+        id TEXT PRIMARY KEY,
+        title TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        year INTEGER NOT NULL DEFAULT 0,
+        compilation INTEGER NOT NULL DEFAULT 0,
+        path TEXT NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX albums_artist ON albums (artist COLLATE NOCASE);
+
+    CREATE TABLE artists (
+        name TEXT PRIMARY KEY,
+        song_count INTEGER NOT NULL,
+        album_count INTEGER NOT NULL
+    );
+
+    CREATE TABLE playlists (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        path TEXT NOT NULL UNIQUE,
+        title TEXT NOT NULL
+    );
+    CREATE TABLE playlist_tracks (
+        playlist_id INTEGER NOT NULL REFERENCES playlists (id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        song_id TEXT NOT NULL,
+        PRIMARY KEY (playlist_id, position)
+    );
+    CREATE INDEX playlist_tracks_song_id ON playlist_tracks (song_id);
+
+    CREATE TABLE history (
+        song_id TEXT PRIMARY KEY,
+        play_count INTEGER NOT NULL DEFAULT 0,
+        skip_count INTEGER NOT NULL DEFAULT 0,
+        last_played_at INTEGER
+    );
+    ",
+    // Play counts from before the library kept them. The history has every
+    // listen, including the ones already counted here.
+    "
+    INSERT OR REPLACE INTO history (song_id, play_count, skip_count, last_played_at)
+        SELECT song_id, SUM(outcome = 'play'), SUM(outcome = 'skip'),
+            MAX(COALESCE(ended_at, started_at))
+        FROM history_db.plays GROUP BY song_id;
+    ",
+];
+
+pub struct LibraryStore {
+    conn: Connection,
+}
+
+/**
+ * What an analysis found out about a file, for each of its songs (the tracks
+ * of a CUE sheet share it). Only what is set gets changed.
+ */
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SongAnalysis {
+    pub path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bpm: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dynamics: Option<DynamicsInfo>,
+}
+
+impl SongAnalysis {
+    fn apply(&self, song: &mut Song) {
+        if self.bpm.is_some() {
+            song.bpm = self.bpm;
+        }
+        if self.key.is_some() {
+            song.key = self.key.clone();
+        }
+        if self.dynamics.is_some() {
+            song.dynamics = self.dynamics.clone();
+        }
+    }
+}
+
+impl LibraryStore {
+    pub fn open(path: &PathBuf) -> rusqlite::Result<Self> {
+        let mut conn = Connection::open(path)?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        conn.execute_batch("PRAGMA journal_mode = WAL; PRAGMA foreign_keys = ON;")?;
+        migrate(&mut conn, path)?;
+        Ok(Self { conn })
+    }
+
+    pub fn open_read_only(path: &PathBuf) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        conn.busy_timeout(BUSY_TIMEOUT)?;
+        Ok(Self { conn })
+    }
+
+    /// Adds or replaces the songs and albums from a scan
+    pub fn save(&mut self, songs: &[Song], albums: &[Album]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        let ids: Vec<&str> = songs.iter().map(|song| song.id.as_str()).collect();
+        let mut artists = artist_names(&tx, &ids)?;
+        upsert_songs(&tx, songs)?;
+        upsert_albums(&tx, albums)?;
+        artists.extend(artist_names(&tx, &ids)?);
+        refresh_artists(&tx, &artists)?;
+        tx.commit()
+    }
+
+    /// Brings the library in line with a rescan
+    pub fn apply_delta(&mut self, delta: &ScanDelta) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        let ids: Vec<&str> = delta
+            .added
+            .iter()
+            .chain(delta.updated.iter())
+            .map(|song| song.id.as_str())
+            .chain(
+                delta
+                    .moved
+                    .iter()
+                    .flat_map(|moved| [moved.from.as_str(), moved.song.id.as_str()]),
+            )
+            .chain(delta.removed.iter().map(|id| id.as_str()))
+            .collect();
+        let mut artists = artist_names(&tx, &ids)?;
+        upsert_songs(&tx, &delta.added)?;
+        upsert_songs(&tx, &delta.updated)?;
+        for moved in delta.moved.iter() {
+            let date_added: Option<i64> = tx
+                .query_row(
+                    "SELECT date_added FROM songs WHERE id = ?1",
+                    params![moved.from],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .flatten();
+            upsert_songs(&tx, std::slice::from_ref(&moved.song))?;
+            tx.execute(
+                "UPDATE songs SET date_added = COALESCE(?2, date_added) WHERE id = ?1",
+                params![moved.song.id, date_added],
+            )?;
+            tx.execute(
+                "UPDATE OR REPLACE history SET song_id = ?2 WHERE song_id = ?1",
+                params![moved.from, moved.song.id],
+            )?;
+            tx.execute(
+                "UPDATE playlist_tracks SET song_id = ?2 WHERE song_id = ?1",
+                params![moved.from, moved.song.id],
+            )?;
+            tx.execute("DELETE FROM songs WHERE id = ?1", params![moved.from])?;
+        }
+        for id in delta.removed.iter() {
+            tx.execute("DELETE FROM songs WHERE id = ?1", params![id])?;
+        }
+        upsert_albums(&tx, &delta.albums)?;
+        delete_empty_albums(&tx)?;
+        artists.extend(artist_names(&tx, &ids)?);
+        refresh_artists(&tx, &artists)?;
+        tx.commit()
+    }
+
+    /// Removes songs from the library, and their albums once empty. Their play
+    /// counts and playlist tracks stay, for when they're scanned again.
+    pub fn delete_songs(&mut self, ids: &[String]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        let ids: Vec<&str> = ids.iter().map(|id| id.as_str()).collect();
+        let artists = artist_names(&tx, &ids)?;
+        for id in ids {
+            tx.execute("DELETE FROM songs WHERE id = ?1", params![id])?;
+        }
+        delete_empty_albums(&tx)?;
+        refresh_artists(&tx, &artists)?;
+        tx.commit()
+    }
+
+    /// Removes every song, album, artist and playlist. The play counts stay,
+    /// like the history they come from.
+    pub fn clear(&mut self) -> rusqlite::Result<()> {
+        self.conn.execute_batch(
+            "
+            BEGIN;
+            DELETE FROM songs;
+            DELETE FROM albums;
+            DELETE FROM artists;
+            DELETE FROM playlists;
+            COMMIT;
+            ",
+        )
+    }
+
+    /// Stores analysis results on the songs of the file
+    pub fn save_analysis(&mut self, analysis: &SongAnalysis) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        let songs: Vec<Song> = tx
+            .prepare("SELECT data FROM songs WHERE path = ?1")?
+            .query_map(params![analysis.path], |row| row.get::<_, String>(0))?
+            .filter_map(|data| serde_json::from_str(&data.ok()?).ok())
+            .map(|mut song| {
+                analysis.apply(&mut song);
+                song
+            })
+            .collect();
+        upsert_songs(&tx, &songs)?;
+        tx.commit()
+    }
+
+    /// Replaces the tracks of an M3U playlist
+    pub fn save_playlist(
+        &mut self,
+        path: &str,
+        title: &str,
+        song_ids: &[String],
+    ) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT INTO playlists (path, title) VALUES (?1, ?2)
+             ON CONFLICT (path) DO UPDATE SET title = excluded.title",
+            params![path, title],
+        )?;
+        let playlist_id: i64 = tx.query_row(
+            "SELECT id FROM playlists WHERE path = ?1",
+            params![path],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "DELETE FROM playlist_tracks WHERE playlist_id = ?1",
+            params![playlist_id],
+        )?;
+        for (position, song_id) in song_ids.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO playlist_tracks (playlist_id, position, song_id) VALUES (?1, ?2, ?3)",
+                params![playlist_id, position as i64, song_id],
+            )?;
+        }
+        tx.commit()
+    }
+
+    pub fn record_listen(
+        &self,
+        song_id: &str,
+        outcome: ListenOutcome,
+        at: i64,
+    ) -> rusqlite::Result<()> {
+        let (plays, skips) = match outcome {
+            ListenOutcome::Play => (1, 0),
+            ListenOutcome::Skip => (0, 1),
+            ListenOutcome::Partial => (0, 0),
+        };
+        self.conn.execute(
+            "INSERT INTO history (song_id, play_count, skip_count, last_played_at)
+             VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (song_id) DO UPDATE SET
+                play_count = play_count + excluded.play_count,
+                skip_count = skip_count + excluded.skip_count,
+                last_played_at = excluded.last_played_at",
+            params![song_id, plays, skips, at],
+        )?;
+        Ok(())
+    }
+
+    /// Songs for the query, narrowed down by a smart query when there's one
+    pub fn songs(
+        &self,
+        query: &LibraryQuery,
+        filter: Option<&SqlFilter>,
+    ) -> Result<Page<Song>, String> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(search) = query.search.as_deref().filter(|s| !s.trim().is_empty()) {
+            conditions.push(
+                "(s.title LIKE ? ESCAPE '\\' OR s.artist LIKE ? ESCAPE '\\'
+                  OR s.album LIKE ? ESCAPE '\\' OR s.album_artist LIKE ? ESCAPE '\\')",
+            );
+            values.extend(std::iter::repeat(Value::from(like_pattern(search))).take(4));
+        }
+        if let Some(artist) = &query.artist {
+            conditions.push("(s.artist = ? COLLATE NOCASE OR s.album_artist = ? COLLATE NOCASE)");
+            values.extend([Value::from(artist.clone()), Value::from(artist.clone())]);
+        }
+        if let Some(album_id) = &query.album_id {
+            conditions.push("s.album_id = ?");
+            values.push(album_id.clone().into());
+        }
+        if let Some(filter) = filter {
+            conditions.push(filter.sql.as_str());
+            values.extend(filter.values.iter().cloned());
+        }
+        let order = order_by(
+            query,
+            "artist",
+            &[
+                ("artist", ARTIST_ORDER),
+                ("title", &["s.title COLLATE NOCASE"]),
+                (
+                    "album",
+                    &["s.album COLLATE NOCASE", "s.disc_number", "s.track_number"],
+                ),
+                ("albumArtist", ALBUM_ARTIST_ORDER),
+                ("year", &["s.year"]),
+                ("duration", &["s.duration"]),
+                ("dateAdded", &["s.date_added"]),
+                ("bpm", &["s.bpm"]),
+                ("key", &["s.key"]),
+                ("playCount", &["COALESCE(h.play_count, 0)"]),
+                ("lastPlayed", &["h.last_played_at"]),
+            ],
+            "s.id",
+        )?;
+        self.page(
+            "SELECT s.data FROM songs s LEFT JOIN history h ON h.song_id = s.id",
+            "SELECT COUNT(*) FROM songs s LEFT JOIN history h ON h.song_id = s.id",
+            &conditions,
+            values,
+            &order,
+            query,
+            |data: String| serde_json::from_str(&data).ok(),
+        )
+    }
+
+    pub fn albums(&self, query: &LibraryQuery) -> Result<Page<Album>, String> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(search) = query.search.as_deref().filter(|s| !s.trim().is_empty()) {
+            conditions.push("(a.title LIKE ? ESCAPE '\\' OR a.artist LIKE ? ESCAPE '\\')");
+            values.extend(std::iter::repeat(Value::from(like_pattern(search))).take(2));
+        }
+        if let Some(artist) = &query.artist {
+            conditions.push("a.artist = ? COLLATE NOCASE");
+            values.push(artist.clone().into());
+        }
+        let order = order_by(
+            query,
+            "artist",
+            &[
+                (
+                    "artist",
+                    &[
+                        "a.artist COLLATE NOCASE",
+                        "a.year",
+                        "a.title COLLATE NOCASE",
+                    ],
+                ),
+                ("title", &["a.title COLLATE NOCASE"]),
+                ("year", &["a.year"]),
+            ],
+            "a.id",
+        )?;
+        let mut page = self.page(
+            "SELECT a.data FROM albums a",
+            "SELECT COUNT(*) FROM albums a",
+            &conditions,
+            values,
+            &order,
+            query,
+            |data: String| serde_json::from_str::<Album>(&data).ok(),
+        )?;
+        // The songs are the source of truth for what's in an album
+        for album in page.items.iter_mut() {
+            album.tracks_ids = self.album_song_ids(&album.id).map_err(|e| e.to_string())?;
+        }
+        Ok(page)
+    }
+
+    pub fn artists(&self, query: &LibraryQuery) -> Result<Page<Artist>, String> {
+        let mut conditions = Vec::new();
+        let mut values: Vec<Value> = Vec::new();
+        if let Some(search) = query.search.as_deref().filter(|s| !s.trim().is_empty()) {
+            conditions.push("r.name LIKE ? ESCAPE '\\'");
+            values.push(like_pattern(search).into());
+        }
+        let order = order_by(
+            query,
+            "name",
+            &[
+                ("name", &["r.name COLLATE NOCASE"]),
+                ("songCount", &["r.song_count"]),
+                ("albumCount", &["r.album_count"]),
+            ],
+            "r.name",
+        )?;
+        self.page(
+            "SELECT r.name, r.song_count, r.album_count FROM artists r",
+            "SELECT COUNT(*) FROM artists r",
+            &conditions,
+            values,
+            &order,
+            query,
+            |(name, song_count, album_count): (String, i64, i64)| {
+                Some(Artist {
+                    name,
+                    song_count,
+                    album_count,
+                })
+            },
+        )
+    }
+
+    pub fn playlists(&self) -> rusqlite::Result<Vec<LibraryPlaylist>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT p.id, p.path, p.title, COUNT(t.song_id) FROM playlists p
+             LEFT JOIN playlist_tracks t ON t.playlist_id = p.id
+             GROUP BY p.id ORDER BY p.title COLLATE NOCASE",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok(LibraryPlaylist {
+                id: row.get(0)?,
+                path: row.get(1)?,
+                title: row.get(2)?,
+                track_count: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// In playlist order, skipping tracks that aren't in the library
+    pub fn playlist_songs(
+        &self,
+        playlist_id: i64,
+        query: &LibraryQuery,
+    ) -> Result<Page<Song>, String> {
+        self.page(
+            "SELECT s.data FROM playlist_tracks t JOIN songs s ON s.id = t.song_id",
+            "SELECT COUNT(*) FROM playlist_tracks t JOIN songs s ON s.id = t.song_id",
+            &["t.playlist_id = ?"],
+            vec![playlist_id.into()],
+            "t.position",
+            query,
+            |data: String| serde_json::from_str(&data).ok(),
+        )
+    }
+
+    /// Play counts by song id, including songs from outside the library (eg. beets)
+    pub fn play_stats(&self) -> rusqlite::Result<HashMap<String, PlayStats>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT song_id, play_count, skip_count, last_played_at FROM history")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get(0)?,
+                PlayStats {
+                    play_count: row.get(1)?,
+                    skip_count: row.get(2)?,
+                    last_played_at: row.get(3)?,
+                },
+            ))
+        })?;
+        rows.collect()
+    }
+
+    fn album_song_ids(&self, album_id: &str) -> rusqlite::Result<Vec<String>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id FROM songs WHERE album_id = ?1 ORDER BY disc_number, track_number",
+        )?;
+        let rows = stmt.query_map(params![album_id], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn page<R: FromRow, T>(
+        &self,
+        select: &str,
+        count: &str,
+        conditions: &[&str],
+        values: Vec<Value>,
+        order: &str,
+        query: &LibraryQuery,
+        to_item: impl Fn(R) -> Option<T>,
+    ) -> Result<Page<T>, String> {
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!(" WHERE {}", conditions.join(" AND "))
+        };
+        let total: i64 = self
+            .conn
+            .query_row(
+                &format!("{}{}", count, filter),
+                params_from_iter(values.iter()),
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+
+        let offset = query.offset.max(0);
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "{}{} ORDER BY {} LIMIT {} OFFSET {}",
+                select, filter, order, limit, offset
+            ))
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params_from_iter(values.iter()), |row| R::from_row(row))
+            .map_err(|e| e.to_string())?;
+        let mut items = Vec::new();
+        for row in rows {
+            if let Some(item) = to_item(row.map_err(|e| e.to_string())?) {
+                items.push(item);
+            }
+        }
+        Ok(Page {
+            items,
+            total,
+            offset,
+        })
+    }
+}
+
+/// What a page query reads from each row
+trait FromRow: Sized {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self>;
+}
+
+impl FromRow for String {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        row.get(0)
+    }
+}
+
+impl FromRow for (String, i64, i64) {
+    fn from_row(row: &rusqlite::Row) -> rusqlite::Result<Self> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    }
+}
+
+const ARTIST_ORDER: &[&str] = &[
+    "s.artist COLLATE NOCASE",
+    "s.year",
+    "s.album COLLATE NOCASE",
+    "s.disc_number",
+    "s.track_number",
+];
+const ALBUM_ARTIST_ORDER: &[&str] = &[
+    "COALESCE(s.album_artist, s.artist) COLLATE NOCASE",
+    "s.year",
+    "s.album COLLATE NOCASE",
+    "s.disc_number",
+    "s.track_number",
+];
+
+/// The ORDER BY for the query's sort, from a fixed list so nothing from the
+/// frontend ends up in the SQL. The tiebreak keeps paging stable.
+fn order_by(
+    query: &LibraryQuery,
+    default: &str,
+    columns: &[(&str, &[&str])],
+    tiebreak: &str,
+) -> Result<String, String> {
+    let sort_by = query.sort_by.as_deref().unwrap_or(default);
+    let (_, expressions) = columns
+        .iter()
+        .find(|(name, _)| *name == sort_by)
+        .ok_or_else(|| format!("Can't sort by {}", sort_by))?;
+    let direction = if query.descending { " DESC" } else { "" };
+    Ok(expressions
+        .iter()
+        .chain(std::iter::once(&tiebreak))
+        .map(|expression| format!("{}{}", expression, direction))
+        .collect::<Vec<_>>()
+        .join(", "))
+}
+
+pub fn like_pattern(search: &str) -> String {
+    let escaped = search
+        .trim()
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("%{}%", escaped)
+}
+
+/// A page of items sorted and filtered elsewhere, eg. songs from beets
+pub fn page_of<T>(items: Vec<T>, query: &LibraryQuery) -> Page<T> {
+    let offset = query.offset.max(0);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .clamp(1, MAX_PAGE_SIZE);
+    let total = items.len() as i64;
+    Page {
+        items: items
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .collect(),
+        total,
+        offset,
+    }
+}
+
+fn migrate(conn: &mut Connection, path: &Path) -> rusqlite::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version as usize >= MIGRATIONS.len() {
+        return Ok(());
+    }
+    // Databases can't be attached during a transaction
+    let history_path = path.with_file_name("history.db");
+    if history_path.exists() {
+        conn.execute(
+            "ATTACH DATABASE ?1 AS history_db",
+            params![history_path.to_string_lossy()],
+        )?;
+    } else {
+        conn.execute_batch(
+            "
+            ATTACH DATABASE ':memory:' AS history_db;
+            CREATE TABLE history_db.plays (
+                song_id TEXT NOT NULL,
+                started_at INTEGER NOT NULL,
+                ended_at INTEGER,
+                outcome TEXT NOT NULL
+            );
+            ",
+        )?;
+    }
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", (index + 1) as i64)?;
+        tx.commit()?;
+        info!("[Library] Migrated the database to version {}", index + 1);
+    }
+    conn.execute_batch("DETACH DATABASE history_db;")
+}
+
+fn upsert_songs(tx: &rusqlite::Transaction, songs: &[Song]) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT INTO songs (id, path, title, artist, album, album_id, album_artist, compilation,
+            year, genre, track_number, disc_number, duration, bpm, key, date_added, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)
+         ON CONFLICT (id) DO UPDATE SET
+            path = excluded.path, title = excluded.title, artist = excluded.artist,
+            album = excluded.album, album_id = excluded.album_id,
+            album_artist = excluded.album_artist, compilation = excluded.compilation,
+            year = excluded.year, genre = excluded.genre, track_number = excluded.track_number,
+            disc_number = excluded.disc_number, duration = excluded.duration, bpm = excluded.bpm,
+            key = excluded.key, date_added = COALESCE(songs.date_added, excluded.date_added),
+            data = excluded.data",
+    )?;
+    for song in songs {
+        // Raw tags are only read for the tagger
+        let mut stored = song.clone();
+        stored.metadata.clear();
+        stored.artwork = None;
+        let album_id = song.album_id.clone().unwrap_or_else(|| {
+            let folder = Path::new(&song.path)
+                .parent()
+                .map(|p| p.to_string_lossy().to_string())
+                .unwrap_or_default();
+            album_id(&folder, &song.album)
+        });
+        stored.album_id = Some(album_id.clone());
+        stmt.execute(params![
+            song.id,
+            song.path,
+            song.title,
+            song.artist,
+            song.album,
+            album_id,
+            song.album_artist,
+            song.compilation,
+            song.year,
+            song.genre.join(";"),
+            song.track_number,
+            song.disc_number,
+            song.file_info.duration,
+            song.bpm,
+            song.key,
+            song.date_added
+                .map(|date| date as i64)
+                .unwrap_or_else(now_millis),
+            serde_json::to_string(&stored).unwrap_or_default()
+        ])?;
+    }
+    Ok(())
+}
+
+fn upsert_albums(tx: &rusqlite::Transaction, albums: &[Album]) -> rusqlite::Result<()> {
+    let mut stmt = tx.prepare_cached(
+        "INSERT OR REPLACE INTO albums (id, title, artist, year, compilation, path, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    for album in albums {
+        stmt.execute(params![
+            album.id,
+            album.display_title,
+            album.artist,
+            album.year,
+            album.compilation,
+            album.path,
+            serde_json::to_string(album).unwrap_or_default()
+        ])?;
+    }
+    Ok(())
+}
+
+fn delete_empty_albums(tx: &rusqlite::Transaction) -> rusqlite::Result<()> {
+    tx.execute(
+        "DELETE FROM albums WHERE id NOT IN (SELECT album_id FROM songs WHERE album_id IS NOT NULL)",
+        [],
+    )?;
+    Ok(())
+}
+
+/// The artists the songs are counted under, see `refresh_artists`
+fn artist_names(
+    tx: &rusqlite::Transaction,
+    song_ids: &[&str],
+) -> rusqlite::Result<HashSet<String>> {
+    let mut stmt = tx.prepare_cached(
+        "SELECT COALESCE(NULLIF(album_artist, ''), artist) FROM songs WHERE id = ?1",
+    )?;
+    let mut names = HashSet::new();
+    for id in song_ids {
+        if let Some(name) = stmt.query_row(params![id], |row| row.get(0)).optional()? {
+            names.insert(name);
+        }
+    }
+    Ok(names)
+}
+
+/// Artists are counted from the songs, by album artist when there is one.
+/// Only the given ones are counted again.
+fn refresh_artists(tx: &rusqlite::Transaction, names: &HashSet<String>) -> rusqlite::Result<()> {
+    let mut delete = tx.prepare_cached("DELETE FROM artists WHERE name = ?1 COLLATE NOCASE")?;
+    let mut insert = tx.prepare_cached(
+        "INSERT INTO artists (name, song_count, album_count)
+            SELECT COALESCE(NULLIF(album_artist, ''), artist) AS name, COUNT(*), COUNT(DISTINCT album_id)
+            FROM songs WHERE name != '' AND name = ?1 COLLATE NOCASE GROUP BY name COLLATE NOCASE",
+    )?;
+    for name in names {
+        delete.execute(params![name])?;
+        insert.execute(params![name])?;
+    }
+    Ok(())
+}
+
+pub fn get_library_db_path(app: &AppHandle) -> Option<PathBuf> {
+    let data_dir = app.path().app_data_dir().ok()?;
+    if !data_dir.exists() {
+        std::fs::create_dir_all(&data_dir).ok()?;
+    }
+    Some(data_dir.join("library.db"))
+}
+
+pub fn open_library(app: &AppHandle) -> Result<LibraryStore, String> {
+    let path = get_library_db_path(app).ok_or("Library database not found")?;
+    LibraryStore::open(&path).map_err(|e| e.to_string())
+}
+
+pub fn open_library_read_only(app: &AppHandle) -> Result<LibraryStore, String> {
+    let path = get_library_db_path(app).ok_or("Library database not found")?;
+    if !path.exists() {
+        // Nothing has been scanned yet, this creates the tables
+        return LibraryStore::open(&path).map_err(|e| e.to_string());
+    }
+    LibraryStore::open_read_only(&path).map_err(|e| e.to_string())
+}
+
+/// Writes the songs and albums from a scan, logging failures since scans carry on regardless
+pub fn save_scan(app: &AppHandle, songs: &[Song], albums: &[Album]) {
+    if let Err(err) = open_library(app)
+        .and_then(|mut library| library.save(songs, albums).map_err(|e| e.to_string()))
+    {
+        error!("[Library] Error saving scanned songs: {}", err);
+    }
+}
+
+/// Writes a rescan's changes, logging failures since rescans carry on regardless
+pub fn save_delta(app: &AppHandle, delta: &ScanDelta) {
+    if let Err(err) = open_library(app)
+        .and_then(|mut library| library.apply_delta(delta).map_err(|e| e.to_string()))
+    {
+        error!("[Library] Error saving rescanned songs: {}", err);
+    }
+}
+
+/**
+ * Stores analysis results in the library and sends them to the frontend's
+ * library with "song_analysis". Logs failures, analyses carry on regardless.
+ */
+pub fn save_analysis(app: &AppHandle, analysis: &SongAnalysis) {
+    if let Err(err) = open_library(app)
+        .and_then(|mut library| library.save_analysis(analysis).map_err(|e| e.to_string()))
+    {
+        error!(
+            "[Library] Error saving analysis of {}: {}",
+            analysis.path, err
+        );
+    }
+    let _ = app.emit("song_analysis", analysis);
+}
+
+/// Saves the songs of an M3U playlist, and its tracks in order
+pub fn save_playlist(app: &AppHandle, path: &str, songs: &[Song]) {
+    let title = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let song_ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+    if let Err(err) = open_library(app).and_then(|mut library| {
+        library
+            .save(songs, &[])
+            .and_then(|_| library.save_playlist(path, &title, &song_ids))
+            .map_err(|e| e.to_string())
+    }) {
+        error!("[Library] Error saving playlist {}: {}", path, err);
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryQuery {
+    #[serde(default)]
+    pub offset: i64,
+    /// Defaults to 100
+    pub limit: Option<i64>,
+    /// eg. "artist", "title", "year", "dateAdded", "playCount"
+    pub sort_by: Option<String>,
+    #[serde(default)]
+    pub descending: bool,
+    /// Matches titles, artists and albums
+    pub search: Option<String>,
+    pub artist: Option<String>,
+    pub album_id: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Matching rows across all pages
+    pub total: i64,
+    pub offset: i64,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayStats {
+    pub play_count: i64,
+    pub skip_count: i64,
+    pub last_played_at: Option<i64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Artist {
+    pub name: String,
+    pub song_count: i64,
+    pub album_count: i64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryPlaylist {
+    pub id: i64,
+    pub path: String,
+    pub title: String,
+    pub track_count: i64,
+}
+
+#[tauri::command]
+pub async fn get_library_songs(
+    query: LibraryQuery,
+    app_handle: AppHandle,
+) -> Result<Page<Song>, String> {
+    open_library_read_only(&app_handle)?.songs(&query, None)
+}
+
+#[tauri::command]
+pub async fn get_library_albums(
+    query: LibraryQuery,
+    app_handle: AppHandle,
+) -> Result<Page<Album>, String> {
+    open_library_read_only(&app_handle)?.albums(&query)
+}
+
+#[tauri::command]
+pub async fn get_library_artists(
+    query: LibraryQuery,
+    app_handle: AppHandle,
+) -> Result<Page<Artist>, String> {
+    open_library_read_only(&app_handle)?.artists(&query)
+}
+
+#[tauri::command]
+pub async fn get_library_playlists(app_handle: AppHandle) -> Result<Vec<LibraryPlaylist>, String> {
+    open_library_read_only(&app_handle)?
+        .playlists()
+        .map_err(|e| e.to_string())
+}
+
+/// Called when songs are deleted from the frontend's library
+#[tauri::command]
+pub async fn delete_library_songs(ids: Vec<String>, app_handle: AppHandle) -> Result<(), String> {
+    open_library(&app_handle)?
+        .delete_songs(&ids)
+        .map_err(|e| e.to_string())
+}
+
+/// Called when the frontend's library is deleted
+#[tauri::command]
+pub async fn clear_library(app_handle: AppHandle) -> Result<(), String> {
+    open_library(&app_handle)?
+        .clear()
+        .map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+pub async fn get_library_playlist_songs(
+    playlist_id: i64,
+    query: LibraryQuery,
+    app_handle: AppHandle,
+) -> Result<Page<Song>, String> {
+    open_library_read_only(&app_handle)?.playlist_songs(playlist_id, &query)
+}
@@ -0,0 +1,522 @@
+//! Index of the files in the library, so rescans only read the tags of the
+//! files that are new or changed since the last scan
+
+use log::{error, info, warn};
+use rayon::prelude::*;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::cue;
+use crate::files::{file_inode, files_in, is_audio_file};
+use crate::identity::{audio_hash, remap_references};
+use crate::library;
+use crate::metadata::{process_new_album, Album, ArtworkOrigin, Song};
+use crate::store::load_settings;
+
+/// Only one rescan at a time, they'd both update the index
+static RESCAN: Mutex<()> = Mutex::new(());
+
+/// What tells us a file has changed without reading it
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Milliseconds since the epoch
+    pub modified: i64,
+    pub inode: Option<u64>,
+}
+
+impl FileStamp {
+    pub fn read(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(FileStamp {
+            size: metadata.len(),
+            modified: metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |duration| duration.as_millis() as i64),
+            inode: file_inode(path),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IndexedFile {
+    pub stamp: FileStamp,
+    /// A CUE sheet or a file with an embedded one has a song per track
+    pub song_ids: Vec<String>,
+    /// Recognises the file after a move to another drive, see `identity::audio_hash`
+    pub audio_hash: Option<String>,
+}
+
+pub struct LibraryIndex {
+    conn: Connection,
+}
+
+impl LibraryIndex {
+    pub fn open(path: &PathBuf) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        // Indexes from before audio hashes
+        if conn.prepare("SELECT song_ids FROM files LIMIT 0").is_ok()
+            && conn
+                .prepare("SELECT audio_hash FROM files LIMIT 0")
+                .is_err()
+        {
+            conn.execute_batch("ALTER TABLE files ADD COLUMN audio_hash TEXT;")?;
+        }
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS files (
+                path TEXT PRIMARY KEY,
+                size INTEGER NOT NULL,
+                modified INTEGER NOT NULL,
+                inode INTEGER,
+                song_ids TEXT NOT NULL,
+                audio_hash TEXT
+            );
+            CREATE INDEX IF NOT EXISTS files_audio_hash ON files (audio_hash);
+            ",
+        )?;
+        Ok(Self { conn })
+    }
+
+    /// The indexed files in the given folders, or the given files themselves
+    pub fn under(&self, roots: &[PathBuf]) -> rusqlite::Result<HashMap<String, IndexedFile>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT path, size, modified, inode, song_ids, audio_hash FROM files")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                IndexedFile {
+                    stamp: FileStamp {
+                        size: row.get::<_, i64>(1)? as u64,
+                        modified: row.get(2)?,
+                        inode: row.get::<_, Option<i64>>(3)?.map(|inode| inode as u64),
+                    },
+                    song_ids: serde_json::from_str(&row.get::<_, String>(4)?).unwrap_or_default(),
+                    audio_hash: row.get(5)?,
+                },
+            ))
+        })?;
+        let mut files = HashMap::new();
+        for row in rows {
+            let (path, file) = row?;
+            if roots.iter().any(|root| Path::new(&path).starts_with(root)) {
+                files.insert(path, file);
+            }
+        }
+        Ok(files)
+    }
+
+    pub fn update(
+        &mut self,
+        files: &[(String, IndexedFile)],
+        removed: &[String],
+    ) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        for (path, file) in files {
+            tx.execute(
+                "INSERT OR REPLACE INTO files (path, size, modified, inode, song_ids, audio_hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    path,
+                    file.stamp.size as i64,
+                    file.stamp.modified,
+                    file.stamp.inode.map(|inode| inode as i64),
+                    serde_json::to_string(&file.song_ids).unwrap_or_default(),
+                    file.audio_hash
+                ],
+            )?;
+        }
+        for path in removed {
+            tx.execute("DELETE FROM files WHERE path = ?1", params![path])?;
+        }
+        tx.commit()
+    }
+}
+
+pub fn get_library_index_db_path(app: &AppHandle) -> Option<PathBuf> {
+    let data_dir = app.path().app_data_dir().ok()?;
+    if !data_dir.exists() {
+        std::fs::create_dir_all(&data_dir).ok()?;
+    }
+    Some(data_dir.join("library_index.db"))
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RescanPathsEvent {
+    paths: Vec<String>,
+    is_cover_fullcheck: bool,
+    /// Read every file again, eg. after changing how tags are parsed
+    #[serde(default)]
+    full: bool,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ScanDelta {
+    pub added: Vec<Song>,
+    pub updated: Vec<Song>,
+    /// Songs whose files were renamed or moved, under their new id
+    pub moved: Vec<MovedSong>,
+    /// Ids of the songs whose files are gone
+    pub removed: Vec<String>,
+    /// Albums of the added and updated songs, with only those songs
+    pub albums: Vec<Album>,
+    /// Files that didn't need to be read
+    pub unchanged: usize,
+}
+
+impl ScanDelta {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.updated.is_empty()
+            && self.moved.is_empty()
+            && self.removed.is_empty()
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MovedSong {
+    /// The id the song had before the move
+    pub from: String,
+    pub from_path: String,
+    pub song: Song,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct RescanProgress {
+    total: usize,
+    done: usize,
+}
+
+/**
+ * Rescans the given folders and files, only reading the tags of new and changed
+ * files. Folders that can't be reached (eg. on an unmounted drive) are left
+ * alone rather than reported as removed.
+ * Emits "rescan_progress" while reading tags.
+ */
+#[tauri::command]
+pub async fn rescan_paths(
+    event: RescanPathsEvent,
+    app_handle: AppHandle,
+) -> Result<ScanDelta, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        rescan(
+            &event.paths,
+            event.is_cover_fullcheck,
+            event.full,
+            &app_handle,
+        )
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+pub fn rescan(
+    paths: &[String],
+    is_cover_fullcheck: bool,
+    full: bool,
+    app: &AppHandle,
+) -> Result<ScanDelta, String> {
+    let _guard = RESCAN.lock().unwrap_or_else(|e| e.into_inner());
+    let start = Instant::now();
+    let db_path = get_library_index_db_path(app).ok_or("Library index not found")?;
+    let mut index = LibraryIndex::open(&db_path).map_err(|e| e.to_string())?;
+
+    let roots: Vec<PathBuf> = paths
+        .iter()
+        .map(PathBuf::from)
+        .filter(|root| {
+            let reachable = is_reachable(root);
+            if !reachable {
+                warn!("[Library index] {:?} can't be reached, skipping", root);
+            }
+            reachable
+        })
+        .collect();
+    let indexed = index.under(&roots).map_err(|e| e.to_string())?;
+    let files = library_files(&roots);
+
+    let mut delta = ScanDelta::default();
+    let changed: Vec<&(PathBuf, FileStamp)> = files
+        .iter()
+        .filter(|(path, stamp)| {
+            full || indexed
+                .get(&*path.to_string_lossy())
+                .is_none_or(|file| file.stamp != *stamp)
+        })
+        .collect();
+    delta.unchanged = files.len() - changed.len();
+    info!(
+        "[Library index] {} files, {} new or changed",
+        files.len(),
+        changed.len()
+    );
+
+    let albums: Arc<Mutex<HashMap<String, Album>>> = Arc::new(Mutex::new(HashMap::new()));
+    let artwork_origins: Arc<Mutex<HashMap<String, ArtworkOrigin>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let settings = load_settings(app).ok();
+    let done = AtomicUsize::new(0);
+    let scanned: Vec<(String, FileStamp, Option<String>, Vec<Song>)> = changed
+        .par_iter()
+        .map(|(path, stamp)| {
+            let mut songs = cue::extract_songs(path, true, is_cover_fullcheck, app);
+            for song in songs.iter_mut() {
+                if let Some(album) = process_new_album(
+                    song,
+                    Some(&albums),
+                    None,
+                    Some(&artwork_origins),
+                    &settings,
+                    app,
+                ) {
+                    song.album_id = Some(album.id.clone());
+                    albums
+                        .lock()
+                        .unwrap()
+                        .entry(album.id.clone())
+                        .and_modify(|a| a.tracks_ids.push(song.id.clone()))
+                        .or_insert(album);
+                }
+                song.artwork = None;
+            }
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if done % 50 == 0 || done == changed.len() {
+                let _ = app.emit(
+                    "rescan_progress",
+                    RescanProgress {
+                        total: changed.len(),
+                        done,
+                    },
+                );
+            }
+            (
+                path.to_string_lossy().to_string(),
+                stamp.clone(),
+                audio_hash(path),
+                songs,
+            )
+        })
+        .collect();
+
+    let seen: HashSet<String> = files
+        .iter()
+        .map(|(path, _)| path.to_string_lossy().to_string())
+        .collect();
+    let mut gone = GoneFiles::new(
+        indexed
+            .iter()
+            .filter(|(path, _)| !seen.contains(*path))
+            .map(|(path, file)| (path.as_str(), file))
+            .collect(),
+    );
+
+    let mut updates = Vec::new();
+    for (path, stamp, audio_hash, songs) in scanned {
+        let song_ids: Vec<String> = songs.iter().map(|song| song.id.clone()).collect();
+        let file = IndexedFile {
+            stamp,
+            song_ids,
+            audio_hash,
+        };
+        if let Some(previous) = indexed.get(&path) {
+            // eg. tracks dropped from a CUE sheet
+            delta.removed.extend(
+                previous
+                    .song_ids
+                    .iter()
+                    .filter(|id| !file.song_ids.contains(id))
+                    .cloned(),
+            );
+            delta.updated.extend(songs);
+        } else if let Some((from_path, from)) = gone.take_moved_from(&file) {
+            for (from, song) in from.song_ids.iter().zip(songs) {
+                delta.moved.push(MovedSong {
+                    from: from.clone(),
+                    from_path: previous_path(&song.path, from_path, &path),
+                    song,
+                });
+            }
+        } else {
+            delta.added.extend(songs);
+        }
+        updates.push((path, file));
+    }
+
+    let mut removed_paths = Vec::new();
+    for (path, file) in gone.files {
+        delta.removed.extend(file.song_ids.iter().cloned());
+        removed_paths.push(path.to_string());
+    }
+
+    let track_numbers: HashMap<&str, i32> = delta
+        .added
+        .iter()
+        .chain(delta.updated.iter())
+        .chain(delta.moved.iter().map(|moved| &moved.song))
+        .map(|song| (song.id.as_str(), song.track_number))
+        .collect();
+    delta.albums = albums.lock().unwrap().values().cloned().collect();
+    for album in delta.albums.iter_mut() {
+        album
+            .tracks_ids
+            .sort_by_key(|id| track_numbers.get(id.as_str()).copied().unwrap_or_default());
+    }
+
+    if let Err(err) = index.update(&updates, &removed_paths) {
+        error!("[Library index] Error saving the index: {}", err);
+        return Err(err.to_string());
+    }
+    remap_references(&delta.moved, app);
+    library::save_delta(app, &delta);
+    info!(
+        "[Library index] {} added, {} updated, {} moved, {} removed, {} unchanged in {:.2} seconds",
+        delta.added.len(),
+        delta.updated.len(),
+        delta.moved.len(),
+        delta.removed.len(),
+        delta.unchanged,
+        start.elapsed().as_secs_f32()
+    );
+    Ok(delta)
+}
+
+/// Indexed files that weren't found again, looked up by inode and by audio
+struct GoneFiles<'a> {
+    files: HashMap<&'a str, &'a IndexedFile>,
+    /// Inode and size
+    by_inode: HashMap<(u64, u64), Vec<&'a str>>,
+    by_audio_hash: HashMap<&'a str, Vec<&'a str>>,
+}
+
+impl<'a> GoneFiles<'a> {
+    fn new(files: HashMap<&'a str, &'a IndexedFile>) -> Self {
+        let mut by_inode: HashMap<(u64, u64), Vec<&str>> = HashMap::new();
+        let mut by_audio_hash: HashMap<&str, Vec<&str>> = HashMap::new();
+        for (&path, &file) in files.iter() {
+            if let Some(inode) = file.stamp.inode {
+                by_inode
+                    .entry((inode, file.stamp.size))
+                    .or_default()
+                    .push(path);
+            }
+            if let Some(audio_hash) = file.audio_hash.as_deref() {
+                by_audio_hash.entry(audio_hash).or_default().push(path);
+            }
+        }
+        GoneFiles {
+            files,
+            by_inode,
+            by_audio_hash,
+        }
+    }
+
+    /// A new file is a file that's gone, moved, when it has the same inode and
+    /// size or the same audio. Only when it has as many songs, to pair them up.
+    fn take_moved_from(&mut self, file: &IndexedFile) -> Option<(&'a str, &'a IndexedFile)> {
+        // Inodes first, copies of the same audio are more likely than reused inodes
+        let candidates = file
+            .stamp
+            .inode
+            .and_then(|inode| self.by_inode.get(&(inode, file.stamp.size)))
+            .into_iter()
+            .chain(
+                file.audio_hash
+                    .as_deref()
+                    .and_then(|audio_hash| self.by_audio_hash.get(audio_hash)),
+            )
+            .flatten();
+        // Paths taken by an earlier file are still listed
+        let path = candidates.copied().find(|path| {
+            self.files
+                .get(path)
+                .is_some_and(|previous| previous.song_ids.len() == file.song_ids.len())
+        })?;
+        self.files.remove_entry(path)
+    }
+}
+
+/// Where a moved song's file was. The audio files of a CUE sheet are expected
+/// to have moved along with it.
+fn previous_path(song_path: &str, from: &str, to: &str) -> String {
+    if song_path == to {
+        return from.to_string();
+    }
+    let relative = Path::new(to)
+        .parent()
+        .and_then(|folder| Path::new(song_path).strip_prefix(folder).ok());
+    match (relative, Path::new(from).parent()) {
+        (Some(relative), Some(folder)) => folder.join(relative).to_string_lossy().to_string(),
+        _ => song_path.to_string(),
+    }
+}
+
+/// A folder on an unmounted drive looks just like a deleted one, so only files
+/// can be gone. An empty folder is most likely the mount point of a drive that
+/// isn't mounted.
+fn is_reachable(root: &Path) -> bool {
+    if root.is_dir() {
+        return std::fs::read_dir(root).is_ok_and(|mut entries| entries.next().is_some());
+    }
+    root.exists()
+        || ((is_audio_file(root) || cue::is_cue_file(root))
+            && root.parent().is_some_and(|parent| parent.is_dir()))
+}
+
+/// Audio files and CUE sheets in the roots, minus the audio files of the CUE
+/// sheets. A sheet's stamp covers its audio files, so retagging them rescans it.
+fn library_files(roots: &[PathBuf]) -> Vec<(PathBuf, FileStamp)> {
+    let mut seen = HashSet::new();
+    let paths: Vec<PathBuf> = roots
+        .iter()
+        .flat_map(|root| {
+            if root.is_dir() {
+                files_in(root, |path| is_audio_file(path) || cue::is_cue_file(path))
+            } else if root.is_file() {
+                vec![root.clone()]
+            } else {
+                vec![]
+            }
+        })
+        .filter(|path| seen.insert(path.clone()))
+        .collect();
+
+    let sheets: Vec<(&PathBuf, Vec<PathBuf>)> = paths
+        .iter()
+        .filter(|path| cue::is_cue_file(path))
+        .map(|path| (path, cue::referenced_files(path)))
+        .collect();
+    let cue_audio_files: HashSet<&PathBuf> = sheets.iter().flat_map(|(_, files)| files).collect();
+    let sheet_files: HashMap<&PathBuf, &Vec<PathBuf>> =
+        sheets.iter().map(|(path, files)| (*path, files)).collect();
+
+    paths
+        .par_iter()
+        .filter(|path| !cue_audio_files.contains(path))
+        .filter_map(|path| {
+            let mut stamp = FileStamp::read(path)?;
+            for file in sheet_files
+                .get(path)
+                .into_iter()
+                .flat_map(|files| files.iter())
+            {
+                if let Some(audio) = FileStamp::read(file) {
+                    stamp.size += audio.size;
+                    stamp.modified = stamp.modified.max(audio.modified);
+                }
+            }
+            Some((path.clone(), stamp))
+        })
+        .collect()
+}
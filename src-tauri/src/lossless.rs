@@ -0,0 +1,469 @@
+//! Checks that lossless files really are: lossy sources leave a sharp cutoff in
+//! the spectrum, upsampled ones a cutoff at the old Nyquist frequency, and
+//! padded ones low bits that are always zero
+
+use lofty::file::TaggedFileExt;
+use lofty::read_from_path;
+use lofty::tag::Accessor;
+use log::{info, warn};
+use rayon::prelude::*;
+use rustfft::FftPlanner;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tauri::{AppHandle, Emitter, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::decode::FileDecoder;
+use crate::files::expand_audio_paths;
+use crate::metadata::{album_id, get_file_info};
+use crate::player::AudioPlayer;
+use crate::tempo_key::Stft;
+
+/// The check's entry in the player's cancel tokens
+pub const CANCEL_TOKEN_KEY: &str = "lossless_check";
+/// Frames quieter than this are left out of the average spectrum
+const SILENCE_DB: f32 = -70.0;
+/// Spectrum smoothing, so single tones don't count as content
+const SMOOTHING_HZ: f32 = 150.0;
+/// Content has to be this far above the noise floor
+const CONTENT_DB: f32 = 10.0;
+/// Below this range between the music and the floor, there is no floor to speak of
+const MIN_RANGE_DB: f32 = 30.0;
+/// Drop across the cutoff that only a lowpass filter leaves
+const STEEP_DB: f32 = 25.0;
+/// Lossy encoders cut at 20.5 kHz at most
+const MAX_LOSSY_CUTOFF: f32 = 20700.0;
+/// Above this, converters and mastering filters roll off too, so only a much
+/// steeper drop counts as a lossy encoder's lowpass
+const NATURAL_ROLLOFF: f32 = 19600.0;
+/// Drop across a cutoff above `NATURAL_ROLLOFF` that still counts as lossy
+const VERY_STEEP_DB: f32 = 40.0;
+/// Cutoffs this close to Nyquist are the converter's own filter
+const FULL_BAND: f32 = 0.95;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Verdict {
+    /// Nothing to report
+    Lossless,
+    /// Silent or too short to tell
+    Inconclusive,
+    /// Not a lossless format in the first place
+    Lossy,
+    /// Only some of the album's tracks are suspicious
+    Mixed,
+    /// More bits than there is content, eg. 16-bit audio in a 24-bit file
+    PaddedBitDepth,
+    /// Converted from a lower sample rate
+    Upsampled,
+    /// Decoded from a lossy file (MP3, AAC...) and encoded again
+    LossyTranscode,
+}
+
+impl Verdict {
+    fn is_suspicious(&self) -> bool {
+        matches!(
+            self,
+            Verdict::PaddedBitDepth | Verdict::Upsampled | Verdict::LossyTranscode
+        )
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SongLosslessReport {
+    pub path: String,
+    pub album_id: String,
+    pub verdict: Verdict,
+    pub sample_rate: Option<u32>,
+    /// From `FileInfo`
+    pub bit_depth: Option<u8>,
+    /// Bits that carry audio, the rest are always zero
+    pub effective_bit_depth: Option<u8>,
+    /// Where the content stops, None when it reaches Nyquist
+    pub cutoff_frequency: Option<f32>,
+    /// Level drop across the cutoff (dB)
+    pub cutoff_steepness: Option<f32>,
+    /// eg. "MP3/AAC ~128 kbps", for lossy transcodes
+    pub likely_source: Option<String>,
+    /// For upsampled files
+    pub original_sample_rate: Option<u32>,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct AlbumLosslessReport {
+    pub album_id: String,
+    pub album: String,
+    pub path: String,
+    pub verdict: Verdict,
+    pub tracks: usize,
+    /// Tracks with a suspicious verdict
+    pub suspicious: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LosslessCheck {
+    pub songs: Vec<SongLosslessReport>,
+    pub albums: Vec<AlbumLosslessReport>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct LosslessCheckProgress {
+    total: usize,
+    done: usize,
+    song: SongLosslessReport,
+}
+
+/**
+ * Checks the given songs, and every song in the given folders, for lossy
+ * transcodes, upsampling and padded bit depth. Lossy formats are reported as
+ * such without decoding.
+ * Emits "lossless_check_progress" after each file. A new check cancels the previous one.
+ */
+#[tauri::command]
+pub async fn check_lossless(
+    paths: Vec<String>,
+    state: State<'_, AudioPlayer>,
+    app_handle: AppHandle,
+) -> Result<LosslessCheck, String> {
+    let token = CancellationToken::new();
+    if let Some(previous) = state
+        .cancel_tokens
+        .lock()
+        .await
+        .insert(CANCEL_TOKEN_KEY.to_string(), token.clone())
+    {
+        previous.cancel();
+    }
+
+    let thread_token = token.clone();
+    let songs = tauri::async_runtime::spawn_blocking(move || {
+        let files = expand_audio_paths(&paths);
+        info!("[Lossless] Checking {} files", files.len());
+        let done = AtomicUsize::new(0);
+        files
+            .par_iter()
+            .filter_map(|path| {
+                if thread_token.is_cancelled() {
+                    return None;
+                }
+                let (report, album) = check_file(path, &thread_token);
+                if thread_token.is_cancelled() {
+                    return None;
+                }
+                let _ = app_handle.emit(
+                    "lossless_check_progress",
+                    LosslessCheckProgress {
+                        total: files.len(),
+                        done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                        song: report.clone(),
+                    },
+                );
+                Some((report, album))
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if token.is_cancelled() {
+        return Err("Cancelled".into());
+    }
+    state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY);
+
+    let albums = album_reports(&songs);
+    Ok(LosslessCheck {
+        songs: songs.into_iter().map(|(report, _)| report).collect(),
+        albums,
+    })
+}
+
+#[tauri::command]
+pub async fn cancel_lossless_check(state: State<'_, AudioPlayer>) -> Result<(), String> {
+    if let Some(token) = state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY) {
+        token.cancel();
+    }
+    Ok(())
+}
+
+/// An album is suspicious when most of its tracks are, and Mixed when only some are
+fn album_reports(songs: &[(SongLosslessReport, String)]) -> Vec<AlbumLosslessReport> {
+    let mut albums: BTreeMap<&str, Vec<&(SongLosslessReport, String)>> = BTreeMap::new();
+    for song in songs {
+        albums
+            .entry(song.0.album_id.as_str())
+            .or_default()
+            .push(song);
+    }
+
+    albums
+        .into_iter()
+        .map(|(album_id, tracks)| {
+            let verdicts: Vec<Verdict> = tracks
+                .iter()
+                .map(|(report, _)| report.verdict)
+                .filter(|verdict| *verdict != Verdict::Inconclusive)
+                .collect();
+            let suspicious = verdicts.iter().filter(|v| v.is_suspicious()).count();
+            let mut counts: BTreeMap<Verdict, usize> = BTreeMap::new();
+            for verdict in verdicts.iter() {
+                *counts.entry(*verdict).or_default() += 1;
+            }
+            // Ties go to the most serious verdict
+            let most_common = counts
+                .iter()
+                .max_by_key(|(verdict, count)| (**count, **verdict))
+                .map(|(verdict, count)| (*verdict, *count));
+            let verdict = match most_common {
+                None => Verdict::Inconclusive,
+                Some((verdict, count)) if verdict.is_suspicious() && count * 2 > verdicts.len() => {
+                    verdict
+                }
+                Some(_) if suspicious > 0 => Verdict::Mixed,
+                Some((verdict, _)) => verdict,
+            };
+
+            let (first, album) = tracks[0];
+            AlbumLosslessReport {
+                album_id: album_id.to_string(),
+                album: album.clone(),
+                path: Path::new(&first.path)
+                    .parent()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_default(),
+                verdict,
+                tracks: tracks.len(),
+                suspicious,
+            }
+        })
+        .collect()
+}
+
+/// The report and the album title
+fn check_file(path: &Path, cancel_token: &CancellationToken) -> (SongLosslessReport, String) {
+    let folder = path
+        .parent()
+        .map(|p| p.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut report = SongLosslessReport {
+        path: path.to_string_lossy().to_string(),
+        album_id: album_id(&folder, ""),
+        verdict: Verdict::Inconclusive,
+        sample_rate: None,
+        bit_depth: None,
+        effective_bit_depth: None,
+        cutoff_frequency: None,
+        cutoff_steepness: None,
+        likely_source: None,
+        original_sample_rate: None,
+        error: None,
+    };
+
+    let tagged_file = match read_from_path(path) {
+        Ok(tagged_file) => tagged_file,
+        Err(err) => {
+            report.error = Some(err.to_string());
+            return (report, String::new());
+        }
+    };
+    let album = tagged_file
+        .primary_tag()
+        .or(tagged_file.first_tag())
+        .and_then(|tag| tag.album().map(|a| a.to_string()))
+        .unwrap_or_default();
+    report.album_id = album_id(&folder, &album);
+    let file_info = get_file_info(&tagged_file);
+    report.sample_rate = file_info.sample_rate;
+    report.bit_depth = file_info.bit_depth;
+    if !file_info.lossless {
+        report.verdict = Verdict::Lossy;
+        return (report, album);
+    }
+
+    let analysis = FileDecoder::open(path).and_then(|mut decoder| {
+        let mut analysis = Analysis::new(decoder.sample_rate, file_info.bit_depth);
+        decoder.for_each(cancel_token, |samples, channels| {
+            analysis.push(samples, channels)
+        })?;
+        Ok(analysis)
+    });
+    let analysis = match analysis {
+        Ok(analysis) => analysis,
+        Err(err) => {
+            if !cancel_token.is_cancelled() {
+                warn!("[Lossless] Error decoding {:?}: {}", path, err);
+            }
+            report.error = Some(err.to_string());
+            return (report, album);
+        }
+    };
+    if analysis.frames == 0 {
+        return (report, album);
+    }
+
+    report.verdict = Verdict::Lossless;
+    report.effective_bit_depth = analysis.effective_bit_depth();
+    if let (Some(bits), Some(effective)) = (report.bit_depth, report.effective_bit_depth) {
+        if effective < bits && effective <= 16 {
+            report.verdict = Verdict::PaddedBitDepth;
+        }
+    }
+
+    if let Some((cutoff, steepness)) = analysis.cutoff() {
+        report.cutoff_frequency = Some(cutoff);
+        report.cutoff_steepness = Some(steepness);
+        let nyquist = analysis.sample_rate / 2.0;
+        // A high-resolution file cut at the old Nyquist frequency was upsampled,
+        // whatever happened to it before
+        if nyquist > 24000.0 && cutoff > 19500.0 && cutoff <= 24500.0 {
+            if steepness >= STEEP_DB {
+                report.verdict = Verdict::Upsampled;
+                report.original_sample_rate = Some(if cutoff <= 22300.0 { 44100 } else { 48000 });
+            }
+        } else if cutoff <= NATURAL_ROLLOFF && steepness >= STEEP_DB
+            || cutoff <= MAX_LOSSY_CUTOFF && steepness >= VERY_STEEP_DB
+        {
+            report.verdict = Verdict::LossyTranscode;
+            report.likely_source = Some(likely_source(cutoff));
+        }
+    }
+    info!("[Lossless] {:?}: {:?}", path, report.verdict);
+    (report, album)
+}
+
+/// Typical lowpass of MP3 and AAC encoders at each bitrate
+fn likely_source(cutoff: f32) -> String {
+    let bitrate = match cutoff {
+        c if c <= 11500.0 => "64",
+        c if c <= 15500.0 => "96",
+        c if c <= 16500.0 => "128",
+        c if c <= 17500.0 => "160",
+        c if c <= 19600.0 => "192",
+        _ => "256-320",
+    };
+    format!("MP3/AAC ~{} kbps", bitrate)
+}
+
+struct Analysis {
+    sample_rate: f32,
+    stft: Stft,
+    /// Sum of the power of each bin over the frames with sound
+    power: Vec<f64>,
+    frames: usize,
+    /// Claimed bit depth, when the samples are exact integers at that depth
+    bit_depth: Option<u8>,
+    /// Every bit used by any sample
+    used_bits: u32,
+    mono: Vec<f32>,
+}
+
+impl Analysis {
+    fn new(sample_rate: u32, bit_depth: Option<u8>) -> Self {
+        let sample_rate = sample_rate.max(1);
+        // About 10 Hz per bin, whatever the sample rate
+        let size = (sample_rate as usize / 11).next_power_of_two();
+        Analysis {
+            sample_rate: sample_rate as f32,
+            stft: Stft::new(&mut FftPlanner::new(), size),
+            power: vec![0.0; size / 2],
+            frames: 0,
+            // Samples are only exact in an f32 up to 24 bits, and float files have no padding
+            bit_depth: bit_depth.filter(|bits| (17..=24).contains(bits)),
+            used_bits: 0,
+            mono: vec![],
+        }
+    }
+
+    fn push(&mut self, interleaved: &[f32], channels: usize) {
+        if let Some(bits) = self.bit_depth {
+            let scale = (1u32 << (bits - 1)) as f32;
+            for sample in interleaved {
+                self.used_bits |= (sample * scale).round() as i32 as u32;
+            }
+        }
+
+        let channels = channels.max(1);
+        self.mono.clear();
+        self.mono.extend(
+            interleaved
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+        let power = &mut self.power;
+        let frames = &mut self.frames;
+        let silence = 10f32.powf(SILENCE_DB / 10.0);
+        self.stft.push(&self.mono, |magnitudes| {
+            let frame_power: f32 = magnitudes.iter().map(|m| m * m).sum();
+            if frame_power < silence {
+                return;
+            }
+            for (total, magnitude) in power.iter_mut().zip(magnitudes) {
+                *total += (magnitude * magnitude) as f64;
+            }
+            *frames += 1;
+        });
+    }
+
+    /// Bits down to the lowest one any sample uses
+    fn effective_bit_depth(&self) -> Option<u8> {
+        let bits = self.bit_depth?;
+        if self.used_bits == 0 {
+            return None;
+        }
+        Some(bits - self.used_bits.trailing_zeros().min(bits as u32) as u8)
+    }
+
+    /// Frequency where the content stops, and how sharply, unless it goes up to Nyquist
+    fn cutoff(&self) -> Option<(f32, f32)> {
+        let bins = self.power.len();
+        let bin_width = self.sample_rate / 2.0 / bins as f32;
+        let levels: Vec<f32> = self
+            .power
+            .iter()
+            .map(|p| (10.0 * (p / self.frames as f64 + 1e-20).log10()) as f32)
+            .collect();
+
+        let radius = ((SMOOTHING_HZ / bin_width / 2.0) as usize).max(1);
+        let smoothed: Vec<f32> = (0..bins)
+            .map(|bin| {
+                let range = bin.saturating_sub(radius)..(bin + radius + 1).min(bins);
+                let len = range.len() as f32;
+                levels[range].iter().sum::<f32>() / len
+            })
+            .collect();
+
+        let bin_of = |frequency: f32| ((frequency / bin_width) as usize).min(bins - 1);
+        let mut sorted = smoothed[bin_of(1000.0)..].to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        let floor = sorted[sorted.len() / 20];
+        let mut music = smoothed[bin_of(1000.0)..bin_of(5000.0)].to_vec();
+        music.sort_by(|a, b| a.total_cmp(b));
+        let music = *music.get(music.len() / 2)?;
+        if music - floor < MIN_RANGE_DB {
+            return None;
+        }
+
+        let cutoff_bin = smoothed
+            .iter()
+            .rposition(|level| *level > floor + CONTENT_DB)?;
+        let cutoff = cutoff_bin as f32 * bin_width;
+        if cutoff >= self.sample_rate / 2.0 * FULL_BAND {
+            return None;
+        }
+
+        let mean = |from: f32, to: f32| {
+            let range = bin_of(from.max(0.0))..bin_of(to).max(bin_of(from.max(0.0)) + 1);
+            let len = range.len() as f32;
+            levels[range].iter().sum::<f32>() / len
+        };
+        let steepness =
+            mean(cutoff - 1500.0, cutoff - 500.0) - mean(cutoff + 300.0, cutoff + 1300.0);
+        Some((cutoff, steepness))
+    }
+}
@@ -6,6 +6,8 @@
 use log::info;
 #[cfg(target_os = "macos")]
 use mediakeys::RemoteCommandCenter;
+#[cfg(target_os = "linux")]
+use mpris::MprisServer;
 use std::collections::HashMap;
 use std::env;
 use std::sync::Mutex;
@@ -18,21 +20,49 @@ use crate::window::{handle_decorations, OpenedUrls, Payload};
 
 mod artwork;
 mod beets;
+mod broadcast;
+mod chapters;
+mod cli;
 mod constants;
+mod cue;
+mod decode;
 mod dsp;
+mod dynamics;
+mod encoder;
 mod equalizer;
 mod files;
+mod history;
+mod identity;
+mod library;
+mod library_index;
 mod logger;
+mod lossless;
 #[cfg(target_os = "macos")]
 mod mediakeys;
 mod metadata;
+mod meters;
+mod mpd;
+#[cfg(target_os = "linux")]
+mod mpris;
 mod output;
 mod player;
+mod precompute;
+mod recorder;
+mod remote;
+mod remote_api;
 mod resampler;
 mod scrape;
+mod scrobbler;
+mod similarity;
+mod smart_query;
+mod spectrogram;
 mod stem_separator;
 mod store;
+mod stream;
+mod tempo_key;
 mod updater;
+mod waveform;
+mod watcher;
 mod window;
 
 #[cfg(test)]
@@ -41,6 +71,10 @@ mod tests;
 fn main() {
     info!("Starting Musicat");
 
+    if let Some(code) = cli::run_client() {
+        std::process::exit(code);
+    }
+
     let streamer = player::AudioPlayer::create().unwrap();
 
     // Workaround for https://github.com/tauri-apps/tauri/issues/5143
@@ -53,7 +87,14 @@ fn main() {
             processes: Mutex::new(HashMap::new()),
         })
         .manage(PendingUpdate(Mutex::new(None)))
+        .manage(remote::RemoteState::new())
         .setup(|app| {
+            // Not forwarded by the single instance plugin, so nothing is running to control
+            if cli::is_control_invocation() {
+                eprintln!("Musicat is not running");
+                std::process::exit(1);
+            }
+
             let app_ = app.handle();
             let app2_ = app_.clone();
 
@@ -96,6 +137,16 @@ fn main() {
             let file_urls = opened_urls.inner().to_owned();
 
             state.init(app_.clone());
+            history::init(app_.clone(), state.playback_events.subscribe());
+            scrobbler::init(app_.clone(), state.playback_events.subscribe());
+            remote::init(app_.clone(), state.playback_events.subscribe());
+            mpd::init(app_.clone());
+            remote_api::init(app_.clone());
+            broadcast::init(app_.clone());
+            recorder::init(app_.clone());
+            precompute::init(app_.clone());
+            similarity::init(app_.clone(), state.playback_events.subscribe());
+            watcher::init(app_.clone());
             let strm1 = state.inner().to_owned();
             let strm2 = strm1.clone();
             let strm3 = strm1.clone();
@@ -194,11 +245,9 @@ fn main() {
             let app_next = app_.clone();
             let app_previous = app_.clone();
             let app_toggle = app_.clone();
-            // Prepare to set Now Playing info on Mac
-            #[cfg(target_os = "macos")]
+            // Prepare to set Now Playing info on Mac, and MPRIS on Linux
+            #[cfg(any(target_os = "macos", target_os = "linux"))]
             {
-                let mut command_center = RemoteCommandCenter::new();
-
                 // Define the handlers
                 let next_handler = move || {
                     println!("Next command received - custom handling logic here");
@@ -231,16 +280,33 @@ fn main() {
                 };
 
                 // Set the handlers
-                command_center.set_handlers(
-                    play_handler,
-                    pause_handler,
-                    toggle_handler,
-                    previous_handler,
-                    next_handler,
-                );
-
-                // Setup the remote command center
-                command_center.setup_remote_command_center();
+                #[cfg(target_os = "macos")]
+                {
+                    let mut command_center = RemoteCommandCenter::new();
+                    command_center.set_handlers(
+                        play_handler,
+                        pause_handler,
+                        toggle_handler,
+                        previous_handler,
+                        next_handler,
+                    );
+
+                    // Setup the remote command center
+                    command_center.setup_remote_command_center();
+                }
+
+                #[cfg(target_os = "linux")]
+                {
+                    let mut mpris_server = MprisServer::new();
+                    mpris_server.set_handlers(
+                        play_handler,
+                        pause_handler,
+                        toggle_handler,
+                        previous_handler,
+                        next_handler,
+                    );
+                    mpris_server.start(app_.clone());
+                }
             }
 
             Ok(())
@@ -335,6 +401,20 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             metadata::write_metadatas,
             metadata::scan_paths,
+            library_index::rescan_paths,
+            watcher::watch_library_folders,
+            watcher::unwatch_library_folders,
+            watcher::get_watched_library_folders,
+            library::get_library_songs,
+            library::get_library_albums,
+            library::get_library_artists,
+            library::get_library_playlists,
+            library::get_library_playlist_songs,
+            library::delete_library_songs,
+            library::clear_library,
+            smart_query::validate_smart_query,
+            smart_query::explain_smart_query,
+            smart_query::execute_smart_query,
             metadata::scan_playlist,
             metadata::get_song_metadata,
             metadata::get_artwork_file,
@@ -351,12 +431,23 @@ fn main() {
             player::playback_speed_control,
             player::analyzer_control,
             player::equalizer_control,
-            player::get_waveform,
+            waveform::get_waveform,
+            waveform::get_waveform_level,
+            waveform::get_waveform_cache_info,
+            waveform::clear_waveform_cache,
+            precompute::start_waveform_precompute,
+            precompute::pause_waveform_precompute,
+            precompute::resume_waveform_precompute,
+            precompute::cancel_waveform_precompute,
+            precompute::get_waveform_precompute_status,
             stem_separator::separate_stems,
             stem_separator::get_stems,
             stem_separator::get_all_stems,
             stem_separator::cancel_separation,
             player::loop_region,
+            player::next_chapter,
+            player::previous_chapter,
+            player::jump_to_chapter,
             player::change_audio_device,
             files::download_file,
             scrape::get_wikipedia,
@@ -367,12 +458,39 @@ fn main() {
             beets::search_beets_albums,
             beets::get_beets_album_tracks,
             beets::get_albums_by_id,
+            history::get_play_history,
+            history::get_most_played,
+            history::get_most_skipped,
+            scrobbler::get_scrobble_queue,
+            scrobbler::lastfm_get_token,
+            scrobbler::lastfm_get_session,
+            remote::remote_sync,
+            remote::remote_search_result,
+            recorder::start_recording,
+            recorder::stop_recording,
+            recorder::get_recording_status,
+            spectrogram::get_spectrogram,
+            spectrogram::cancel_spectrogram,
+            tempo_key::analyze_tempo_key,
+            tempo_key::cancel_tempo_key_analysis,
+            similarity::analyze_audio_features,
+            similarity::cancel_audio_features,
+            similarity::similar_tracks,
+            similarity::set_auto_dj,
+            similarity::is_auto_dj_enabled,
+            lossless::check_lossless,
+            lossless::cancel_lossless_check,
+            dynamics::get_dynamics_report,
+            dynamics::cancel_dynamics_report,
             updater::check_for_updates,
             updater::install_update
         ])
         .plugin(tauri_plugin_updater::Builder::new().build())
         .plugin(tauri_plugin_single_instance::init(|app, argv, cwd| {
             info!("{}, {argv:?}, {cwd}", app.package_info().name);
+            if cli::handle(app, &argv, &cwd) {
+                return;
+            }
             app.emit("single-instance", Payload { args: argv, cwd })
                 .unwrap();
         }))
@@ -1,6 +1,6 @@
 use chksum_md5::MD5;
 use lofty::config::{ParseOptions, WriteOptions};
-use lofty::file::{AudioFile, FileType, TaggedFileExt};
+use lofty::file::{AudioFile, FileType, TaggedFile, TaggedFileExt};
 use lofty::picture::{MimeType, Picture};
 use lofty::probe::Probe;
 use lofty::read_from_path;
@@ -10,18 +10,19 @@ use rayon::iter::IntoParallelRefIterator;
 use rayon::prelude::*;
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_m3u::Playlist;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::OsStr;
 use std::fs::{self, File};
 use std::io::{BufReader, ErrorKind};
 use std::ops::Mul;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use std::{thread, time};
 use tauri::{AppHandle, Emitter};
 
 use crate::artwork::{cache_artwork, look_for_art};
+use crate::chapters::{read_chapters, Chapter};
 use crate::store::{load_settings, UserSettings};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -63,6 +64,9 @@ pub struct WriteMetatadaEvent {
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct WriteMetatadasEvent {
     tracks: Vec<WriteMetatadaEvent>,
+    /// The tracks are library songs, update them in the library database
+    #[serde(default)]
+    save_to_library: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -73,11 +77,17 @@ pub struct ScanPathsEvent {
     process_m3u: bool,
     is_async: bool,
     is_cover_fullcheck: bool,
+    /// Store the songs in the library database, only for imports into the library
+    #[serde(default)]
+    save_to_library: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ScanPlaylistEvent {
     playlist: String,
+    /// Store the playlist and its songs in the library database
+    #[serde(default)]
+    save_to_library: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -95,6 +105,24 @@ pub struct FileInfo {
     pub codec: Option<String>,
 }
 
+/// Dynamic range and clipping, measured by decoding the whole file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DynamicsInfo {
+    /// DR score as shown by foobar2000's DR Meter
+    pub dr: u8,
+    /// The score before rounding
+    pub dr_precise: f32,
+    /// Highest sample (dBFS)
+    pub peak: f32,
+    /// Highest level between samples (dBTP)
+    pub true_peak: f32,
+    /// Samples at full scale
+    pub clipped_samples: u64,
+    /// Samples followed by a peak above full scale once reconstructed
+    pub inter_sample_overs: u64,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Artwork {
     pub data: Vec<u8>,
@@ -123,6 +151,9 @@ pub struct Song {
     pub path: String,
     pub file: String,
     pub file_info: FileInfo,
+    /// Only once analysed, see `dynamics::get_dynamics_report`
+    #[serde(default)]
+    pub dynamics: Option<DynamicsInfo>,
 
     /// The metadata from the file, only needed for the tagger
     /// Not stored in database, retrieved on request
@@ -153,6 +184,24 @@ pub struct Song {
     pub origin_country: Option<String>,
     pub origin_country_name: Option<String>,
     pub date_added: Option<u128>,
+
+    /// Virtual tracks from a CUE sheet (a .cue file, or the audio file itself when embedded)
+    pub cue_path: Option<String>,
+    pub cue_track: Option<u32>,
+    /// Track boundaries in seconds within the audio file, end is None for the last track
+    pub start_offset: Option<f64>,
+    pub end_offset: Option<f64>,
+
+    /// Chapter markers (audiobooks, podcasts), empty when the file has none
+    #[serde(default)]
+    pub chapters: Vec<Chapter>,
+
+    /// Beats per minute, from the tags or detected
+    #[serde(default)]
+    pub bpm: Option<f64>,
+    /// Musical key eg. "Am", from the tags or detected
+    #[serde(default)]
+    pub key: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -272,6 +321,9 @@ pub async fn write_metadatas(
         done: true,
         error: error,
     };
+    if event.save_to_library {
+        crate::library::save_scan(&app_handle, &to_import.songs, &to_import.albums);
+    }
     return to_import;
 }
 
@@ -308,6 +360,13 @@ pub async fn scan_paths(
     let artwork_origins: Arc<std::sync::Mutex<HashMap<String, ArtworkOrigin>>> =
         Arc::new(Mutex::new(HashMap::new()));
     let settings = load_settings(&app_handle).ok();
+    let cue_audio_files: HashSet<PathBuf> = event
+        .paths
+        .iter()
+        .map(Path::new)
+        .filter(|p| crate::cue::is_cue_file(p))
+        .flat_map(crate::cue::referenced_files)
+        .collect();
 
     event.paths.par_iter().for_each(|p| {
         let path = Path::new(p.as_str());
@@ -320,16 +379,13 @@ pub async fn scan_paths(
                         songs.lock().unwrap().extend(sub_results.songs);
                     }
                 }
+            } else if cue_audio_files.contains(path) {
+                // Imported through its CUE sheet instead
             } else {
                 // info!("path is file");
-                if let Some(mut song) = crate::metadata::extract_metadata(
-                    &path,
-                    true,
-                    event.is_cover_fullcheck,
-                    false,
-                    false,
-                    &app_handle,
-                ) {
+                for mut song in
+                    crate::cue::extract_songs(&path, true, event.is_cover_fullcheck, &app_handle)
+                {
                     if event.process_albums {
                         if let Some(album) = process_new_album(
                             &mut song,
@@ -386,6 +442,14 @@ pub async fn scan_paths(
             .sort_by_key(|id| songs.lock().unwrap().get(id).unwrap().track_number);
     }
 
+    if event.save_to_library {
+        crate::library::save_scan(
+            &app_handle,
+            &songs.lock().unwrap().values().cloned().collect::<Vec<_>>(),
+            &albums.lock().unwrap().values().cloned().collect::<Vec<_>>(),
+        );
+    }
+
     // First send all songs in chunks
     let length = songs.lock().unwrap().clone().len();
     if event.is_async && length > 500 {
@@ -518,7 +582,7 @@ pub async fn scan_playlist(
 ) -> Option<ToImportEvent> {
     let mut songs: Vec<Song> = vec![];
 
-    let read_result = std::fs::read_to_string(event.playlist);
+    let read_result = std::fs::read_to_string(&event.playlist);
 
     if let Ok(content) = read_result {
         let playlist: Playlist = Playlist::from(content.as_str());
@@ -537,6 +601,9 @@ pub async fn scan_playlist(
                 songs.push(song);
             }
         }
+        if event.save_to_library {
+            crate::library::save_playlist(&app_handle, &event.playlist, &songs);
+        }
     }
 
     Some(ToImportEvent {
@@ -619,7 +686,17 @@ pub async fn get_artwork_metadata(event: GetArtworkEvent, _app: AppHandle) -> Op
     None
 }
 
-fn process_new_album(
+/// Albums are told apart by their folder and title
+pub fn album_id(album_path: &str, album: &str) -> String {
+    MD5::hash(
+        format!("{} - {}", album_path, album)
+            .to_lowercase()
+            .as_bytes(),
+    )
+    .to_hex_lowercase()
+}
+
+pub fn process_new_album(
     song: &mut Song,
     new_albums: Option<&Arc<std::sync::Mutex<HashMap<String, Album>>>>,
     newest_albums: Option<&Arc<std::sync::Mutex<HashMap<String, Album>>>>,
@@ -632,12 +709,7 @@ fn process_new_album(
     // Strip song from path
     let song_path = song.path.clone();
     let album_path = Path::new(&song_path).parent().unwrap().to_str().unwrap();
-    let album_id = MD5::hash(
-        format!("{} - {}", album_path, song.album.as_str())
-            .to_lowercase()
-            .as_bytes(),
-    )
-    .to_hex_lowercase();
+    let album_id = album_id(album_path, &song.album);
     // info!("album: {} , {}", song.album, album_id);
 
     if let Some(album) = get_new_album(new_albums, album_id.clone(), song, artwork_origins) {
@@ -796,6 +868,8 @@ fn process_directory(
     let subalbums: Arc<std::sync::Mutex<HashMap<String, Album>>> =
         Arc::new(Mutex::new(HashMap::new()));
     let settings = load_settings(app).ok();
+    // Audio files covered by a CUE sheet are split into its tracks when the .cue is processed
+    let cue_audio_files = crate::cue::cue_audio_files(directory_path);
 
     fs::read_dir(directory_path)
         .par_iter_mut()
@@ -806,14 +880,12 @@ fn process_directory(
 
                     // info!("{:?}", entry.path());
                     if path.is_file() {
-                        if let Some(mut song) = crate::metadata::extract_metadata(
-                            &path,
-                            true,
-                            is_cover_fullcheck,
-                            false,
-                            false,
-                            &app,
-                        ) {
+                        if cue_audio_files.contains(&path) {
+                            return;
+                        }
+                        for mut song in
+                            crate::cue::extract_songs(&path, true, is_cover_fullcheck, &app)
+                        {
                             if process_albums {
                                 if let Some(album) = process_new_album(
                                     &mut song,
@@ -1170,6 +1242,7 @@ pub fn extract_metadata(
                 || ext_str.eq_ignore_ascii_case("ape")
                 || ext_str.eq_ignore_ascii_case("ogg")
                 || ext_str.eq_ignore_ascii_case("m4a")
+                || ext_str.eq_ignore_ascii_case("m4b")
             {
                 match read_from_path(&file_path) {
                     Ok(tagged_file) => {
@@ -1190,57 +1263,15 @@ pub fn extract_metadata(
                         let mut track_total = -1;
                         let mut disc_number = -1;
                         let mut disc_total = -1;
+                        let mut bpm = None;
+                        let mut key = None;
                         let duration;
                         let file_info;
                         let mut artwork = None;
                         let mut artwork_origin = None;
                         let mut metadata: HashMap<String, MetadataEntry> = HashMap::new();
 
-                        // info!("bit depth {:?}", tagged_file.properties().bit_depth());
-                        file_info = FileInfo {
-                            duration: Some(tagged_file.properties().duration().as_secs_f64()),
-                            duration_display: Some(seconds_to_hms(
-                                tagged_file.properties().duration().as_secs(),
-                            )),
-                            channels: tagged_file.properties().channels(),
-                            bit_depth: tagged_file.properties().bit_depth().or(Some(16)),
-                            sample_rate: tagged_file.properties().sample_rate(),
-                            audio_bitrate: tagged_file.properties().audio_bitrate(),
-                            overall_bitrate: tagged_file.properties().overall_bitrate(),
-                            lossless: vec![FileType::Flac, FileType::Wav]
-                                .iter()
-                                .any(|f| f.eq(&tagged_file.file_type())),
-                            tag_type: if let Some(tag) = tagged_file.primary_tag() {
-                                match tag.tag_type() {
-                                    TagType::VorbisComments => Some("vorbis".to_string()),
-                                    TagType::Id3v1 => Some("ID3v1".to_string()),
-                                    TagType::Id3v2 => Some("ID3v2".to_string()),
-                                    TagType::Mp4Ilst => Some("MP4".to_string()),
-                                    TagType::Ape | TagType::RiffInfo | TagType::AiffText => None,
-                                    _ => todo!(),
-                                }
-                            } else {
-                                match tagged_file.file_type() {
-                                    FileType::Flac | FileType::Vorbis => Some("vorbis".to_string()),
-                                    FileType::Wav => Some("ID3v2".to_string()),
-                                    FileType::Mpeg => Some("ID3v2".to_string()),
-                                    FileType::Ape | FileType::Opus | FileType::Speex => None,
-                                    _ => None,
-                                }
-                            },
-                            codec: match tagged_file.file_type() {
-                                FileType::Flac => Some("FLAC".to_string()),
-                                FileType::Mpeg => Some("MPEG".to_string()),
-                                FileType::Aiff => Some("AIFF".to_string()),
-                                FileType::Wav => Some("WAV".to_string()),
-                                FileType::Ape => Some("APE".to_string()),
-                                FileType::Opus => Some("Opus".to_string()),
-                                FileType::Speex => Some("Speex".to_string()),
-                                FileType::Vorbis => Some("Vorbis".to_string()),
-                                FileType::Mp4 => Some("MP4".to_string()),
-                                _ => None,
-                            },
-                        };
+                        file_info = get_file_info(&tagged_file);
 
                         duration = file_info.duration_display.clone().unwrap_or_default();
 
@@ -1309,6 +1340,19 @@ pub fn extract_metadata(
                                 if disc_total == -1 {
                                     disc_total = tag.disk_total().unwrap_or(0) as i32;
                                 }
+                                if bpm.is_none() {
+                                    bpm = tag
+                                        .get_string(&ItemKey::IntegerBpm)
+                                        .or(tag.get_string(&ItemKey::Bpm))
+                                        .and_then(|b| b.trim().parse::<f64>().ok())
+                                        .filter(|b| *b > 0.0);
+                                }
+                                if key.is_none() {
+                                    key = tag
+                                        .get_string(&ItemKey::InitialKey)
+                                        .map(|k| k.trim().to_string())
+                                        .filter(|k| !k.is_empty());
+                                }
 
                                 if include_raw_tags {
                                     // Lofty's ItemKey representation (we don't expose internal tag keys to the UI)
@@ -1400,6 +1444,8 @@ pub fn extract_metadata(
 
                         info!("artwork_origin: {:?}", artwork_origin);
 
+                        let chapters = read_chapters(&file_path, file_info.duration);
+
                         let start = SystemTime::now();
                         let since_the_epoch = start.duration_since(UNIX_EPOCH).unwrap().as_millis();
 
@@ -1422,6 +1468,7 @@ pub fn extract_metadata(
                             disc_total,
                             duration,
                             file_info,
+                            dynamics: None,
                             metadata,
                             artwork,
                             artwork_origin,
@@ -1434,6 +1481,13 @@ pub fn extract_metadata(
                             } else {
                                 None
                             },
+                            cue_path: None,
+                            cue_track: None,
+                            start_offset: None,
+                            end_offset: None,
+                            chapters,
+                            bpm,
+                            key,
                         });
                     }
                     Err(e) => {
@@ -1447,6 +1501,102 @@ pub fn extract_metadata(
     None
 }
 
+/// Properties of the audio stream, and which kind of tag the file uses
+pub fn get_file_info(tagged_file: &TaggedFile) -> FileInfo {
+    FileInfo {
+        duration: Some(tagged_file.properties().duration().as_secs_f64()),
+        duration_display: Some(seconds_to_hms(
+            tagged_file.properties().duration().as_secs(),
+        )),
+        channels: tagged_file.properties().channels(),
+        bit_depth: tagged_file.properties().bit_depth().or(Some(16)),
+        sample_rate: tagged_file.properties().sample_rate(),
+        audio_bitrate: tagged_file.properties().audio_bitrate(),
+        overall_bitrate: tagged_file.properties().overall_bitrate(),
+        lossless: vec![FileType::Flac, FileType::Wav]
+            .iter()
+            .any(|f| f.eq(&tagged_file.file_type())),
+        tag_type: if let Some(tag) = tagged_file.primary_tag() {
+            match tag.tag_type() {
+                TagType::VorbisComments => Some("vorbis".to_string()),
+                TagType::Id3v1 => Some("ID3v1".to_string()),
+                TagType::Id3v2 => Some("ID3v2".to_string()),
+                TagType::Mp4Ilst => Some("MP4".to_string()),
+                TagType::Ape | TagType::RiffInfo | TagType::AiffText => None,
+                _ => None,
+            }
+        } else {
+            match tagged_file.file_type() {
+                FileType::Flac | FileType::Vorbis => Some("vorbis".to_string()),
+                FileType::Wav => Some("ID3v2".to_string()),
+                FileType::Mpeg => Some("ID3v2".to_string()),
+                FileType::Ape | FileType::Opus | FileType::Speex => None,
+                _ => None,
+            }
+        },
+        codec: match tagged_file.file_type() {
+            FileType::Flac => Some("FLAC".to_string()),
+            FileType::Mpeg => Some("MPEG".to_string()),
+            FileType::Aiff => Some("AIFF".to_string()),
+            FileType::Wav => Some("WAV".to_string()),
+            FileType::Ape => Some("APE".to_string()),
+            FileType::Opus => Some("Opus".to_string()),
+            FileType::Speex => Some("Speex".to_string()),
+            FileType::Vorbis => Some("Vorbis".to_string()),
+            FileType::Mp4 => Some("MP4".to_string()),
+            _ => None,
+        },
+    }
+}
+
+/**
+ * Writes a detected tempo and key to the file: TBPM/TKEY for ID3,
+ * BPM/INITIALKEY for Vorbis comments, tmpo/initialkey for MP4.
+ * Other tags and the artwork are kept.
+ */
+pub fn write_tempo_key(
+    path: &Path,
+    bpm: Option<f64>,
+    key: Option<&str>,
+) -> Result<(), anyhow::Error> {
+    let tagged_file = read_from_path(path)?;
+    // ID3v2 TBPM and the MP4 tmpo atom only allow whole numbers
+    let (tag_type, bpm_key) = match tagged_file.primary_tag_type() {
+        TagType::Id3v1 | TagType::Id3v2 => ("ID3v2", "IntegerBpm"),
+        TagType::Mp4Ilst => ("mp4ilst", "IntegerBpm"),
+        TagType::VorbisComments => ("vorbis", "Bpm"),
+        other => return Err(anyhow::anyhow!("Can't write tempo and key to {:?}", other)),
+    };
+
+    let mut metadata = vec![];
+    if let Some(bpm) = bpm {
+        let value = if bpm_key == "IntegerBpm" {
+            (bpm.round() as u32).to_string()
+        } else {
+            bpm.to_string()
+        };
+        metadata.push(MetadataEntry {
+            id: bpm_key.to_string(),
+            value: Some(Some(value)),
+        });
+    }
+    if let Some(key) = key {
+        metadata.push(MetadataEntry {
+            id: "InitialKey".to_string(),
+            value: Some(Some(key.to_string())),
+        });
+    }
+    write_metadata_track(&WriteMetatadaEvent {
+        metadata,
+        tag_type: Some(tag_type.to_string()),
+        file_path: path.to_string_lossy().to_string(),
+        artwork_file: String::new(),
+        artwork_data: vec![],
+        artwork_data_mime_type: None,
+        delete_artwork: false,
+    })
+}
+
 fn write_metadata_track(v: &WriteMetatadaEvent) -> Result<(), anyhow::Error> {
     // info!("got event-name with payload {:?}", event.payload());
 
@@ -1594,7 +1744,7 @@ fn write_metadata_track(v: &WriteMetatadaEvent) -> Result<(), anyhow::Error> {
     // println("title:")
 }
 
-fn seconds_to_hms(seconds: u64) -> String {
+pub fn seconds_to_hms(seconds: u64) -> String {
     let hours = seconds / 3600;
     let minutes = (seconds % 3600) / 60;
     let seconds = seconds % 60;
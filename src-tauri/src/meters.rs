@@ -0,0 +1,363 @@
+//! Loudness (ITU-R BS.1770 / EBU R128), true-peak, VU and phase correlation meters
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use log::info;
+use symphonia::core::conv::IntoSample;
+use tokio::sync::Mutex;
+use webrtc::data_channel::RTCDataChannel;
+
+use crate::output::{AnalyzerState, AnalyzerType};
+
+/// Loudness is measured in 100 ms blocks
+const BLOCK_SECONDS: f64 = 0.1;
+/// 400 ms
+const MOMENTARY_BLOCKS: usize = 4;
+/// 3 s
+const SHORT_TERM_BLOCKS: usize = 30;
+/// VU meter integration time
+const VU_SECONDS: f64 = 0.3;
+pub const OVERSAMPLING: usize = 4;
+pub const TAPS_PER_PHASE: usize = 12;
+/// Reported instead of -inf for silence
+const FLOOR_DB: f32 = -120.0;
+/// Callbacks waiting for the meters thread, newer ones are dropped while it catches up
+const QUEUE_LENGTH: usize = 64;
+/// ~60fps, like the visualiser
+const SEND_INTERVAL: Duration = Duration::from_millis(16);
+
+#[derive(Clone, Copy, Default)]
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+
+    fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+}
+
+/// The two stage K-weighting filter from BS.1770, for any sample rate
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+    // Stage 1, high shelf modelling the head
+    let f0 = 1681.974450955533;
+    let gain = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b0: (vh + vb * k / q + k * k) / a0,
+        b1: 2.0 * (k * k - vh) / a0,
+        b2: (vh - vb * k / q + k * k) / a0,
+        a1: 2.0 * (k * k - 1.0) / a0,
+        a2: (1.0 - k / q + k * k) / a0,
+        ..Default::default()
+    };
+
+    // Stage 2, high pass (RLB weighting)
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / sample_rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b0: 1.0,
+        b1: -2.0,
+        b2: 1.0,
+        a1: 2.0 * (k * k - 1.0) / a0,
+        a2: (1.0 - k / q + k * k) / a0,
+        ..Default::default()
+    };
+
+    [shelf, high_pass]
+}
+
+/// Surround channels count 1.41x and the LFE not at all (5.1 is L R C LFE Ls Rs)
+fn channel_weight(channels: usize, index: usize) -> f64 {
+    match (channels, index) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+/// Windowed sinc split into polyphase filters for 4x oversampling
+pub fn interpolation_phases() -> [[f32; TAPS_PER_PHASE]; OVERSAMPLING] {
+    let taps = TAPS_PER_PHASE * OVERSAMPLING;
+    let center = (taps - 1) as f64 / 2.0;
+    let mut phases = [[0.0f32; TAPS_PER_PHASE]; OVERSAMPLING];
+    for (phase, coefficients) in phases.iter_mut().enumerate() {
+        let mut sum = 0.0;
+        for (tap, coefficient) in coefficients.iter_mut().enumerate() {
+            let n = (tap * OVERSAMPLING + phase) as f64;
+            let x = (n - center) / OVERSAMPLING as f64;
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x)
+            };
+            // Blackman window
+            let w = 2.0 * std::f64::consts::PI * n / (taps - 1) as f64;
+            let window = 0.42 - 0.5 * w.cos() + 0.08 * (2.0 * w).cos();
+            *coefficient = (sinc * window) as f32;
+            sum += sinc * window;
+        }
+        // Unity gain for every phase
+        for coefficient in coefficients.iter_mut() {
+            *coefficient /= sum as f32;
+        }
+    }
+    phases
+}
+
+fn to_db(amplitude: f64) -> f32 {
+    if amplitude <= 0.0 {
+        return FLOOR_DB;
+    }
+    (20.0 * amplitude.log10()).max(FLOOR_DB as f64) as f32
+}
+
+fn to_lufs(mean_square: f64) -> f32 {
+    if mean_square <= 0.0 {
+        return FLOOR_DB;
+    }
+    (-0.691 + 10.0 * mean_square.log10()).max(FLOOR_DB as f64) as f32
+}
+
+/**
+ * Meters fed with every sample played. Sent over the data channel as
+ * little-endian f32 values:
+ *
+ * 0. momentary loudness (LUFS, 400 ms)
+ * 1. short-term loudness (LUFS, 3 s)
+ * 2. true peak since the last update (dBTP)
+ * 3. maximum true peak since the song started (dBTP)
+ * 4. phase correlation (-1 to 1)
+ * 5. number of channels (N)
+ * 6. RMS of each channel with VU ballistics (dBFS), N values
+ */
+pub struct Meters {
+    channels: usize,
+    k_filters: Vec<[Biquad; 2]>,
+    block_len: usize,
+    block_pos: usize,
+    /// Weighted sum of K-filtered squares in the current block
+    block_energy: f64,
+    /// Mean square of the last blocks, newest last
+    blocks: VecDeque<f64>,
+    phases: [[f32; TAPS_PER_PHASE]; OVERSAMPLING],
+    /// Last input samples per channel for the interpolation, newest first
+    history: Vec<[f32; TAPS_PER_PHASE]>,
+    true_peak: f32,
+    true_peak_max: f32,
+    vu_alpha: f64,
+    mean_squares: Vec<f64>,
+    /// Smoothed L*R, L*L and R*R
+    correlation: (f64, f64, f64),
+}
+
+impl Meters {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        let sample_rate = sample_rate.max(1) as f64;
+        Meters {
+            channels,
+            k_filters: vec![k_weighting(sample_rate); channels],
+            block_len: ((sample_rate * BLOCK_SECONDS) as usize).max(1),
+            block_pos: 0,
+            block_energy: 0.0,
+            blocks: VecDeque::with_capacity(SHORT_TERM_BLOCKS),
+            phases: interpolation_phases(),
+            history: vec![[0.0; TAPS_PER_PHASE]; channels],
+            true_peak: 0.0,
+            true_peak_max: 0.0,
+            vu_alpha: 1.0 - (-1.0 / (VU_SECONDS * sample_rate)).exp(),
+            mean_squares: vec![0.0; channels],
+            correlation: (0.0, 0.0, 0.0),
+        }
+    }
+
+    /// Called when the song changes
+    pub fn reset(&mut self) {
+        for filters in self.k_filters.iter_mut() {
+            filters.iter_mut().for_each(Biquad::reset);
+        }
+        self.block_pos = 0;
+        self.block_energy = 0.0;
+        self.blocks.clear();
+        self.history
+            .iter_mut()
+            .for_each(|h| *h = [0.0; TAPS_PER_PHASE]);
+        self.true_peak = 0.0;
+        self.true_peak_max = 0.0;
+        self.mean_squares.iter_mut().for_each(|m| *m = 0.0);
+        self.correlation = (0.0, 0.0, 0.0);
+    }
+
+    pub fn process<T: IntoSample<f32> + Copy>(&mut self, interleaved: &[T]) {
+        for frame in interleaved.chunks_exact(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                let sample: f32 = (*sample).into_sample();
+                let x = sample as f64;
+
+                // Loudness
+                let [shelf, high_pass] = &mut self.k_filters[channel];
+                let weighted = high_pass.process(shelf.process(x));
+                self.block_energy += channel_weight(self.channels, channel) * weighted * weighted;
+
+                // VU
+                let mean_square = &mut self.mean_squares[channel];
+                *mean_square += (x * x - *mean_square) * self.vu_alpha;
+
+                // True peak
+                let history = &mut self.history[channel];
+                history.copy_within(0..TAPS_PER_PHASE - 1, 1);
+                history[0] = sample;
+                for coefficients in &self.phases {
+                    let interpolated: f32 = coefficients
+                        .iter()
+                        .zip(history.iter())
+                        .map(|(c, s)| c * s)
+                        .sum();
+                    self.true_peak = self.true_peak.max(interpolated.abs());
+                }
+            }
+
+            if self.channels >= 2 {
+                let (left, right) = (
+                    IntoSample::<f32>::into_sample(frame[0]) as f64,
+                    IntoSample::<f32>::into_sample(frame[1]) as f64,
+                );
+                let (lr, ll, rr) = &mut self.correlation;
+                *lr += (left * right - *lr) * self.vu_alpha;
+                *ll += (left * left - *ll) * self.vu_alpha;
+                *rr += (right * right - *rr) * self.vu_alpha;
+            }
+
+            self.block_pos += 1;
+            if self.block_pos == self.block_len {
+                if self.blocks.len() == SHORT_TERM_BLOCKS {
+                    self.blocks.pop_front();
+                }
+                self.blocks
+                    .push_back(self.block_energy / self.block_len as f64);
+                self.block_pos = 0;
+                self.block_energy = 0.0;
+            }
+        }
+        self.true_peak_max = self.true_peak_max.max(self.true_peak);
+    }
+
+    fn loudness(&self, blocks: usize) -> f32 {
+        let count = blocks.min(self.blocks.len());
+        if count == 0 {
+            return FLOOR_DB;
+        }
+        let sum: f64 = self.blocks.iter().rev().take(count).sum();
+        to_lufs(sum / count as f64)
+    }
+
+    fn phase_correlation(&self) -> f32 {
+        if self.channels < 2 {
+            return 1.0;
+        }
+        let (lr, ll, rr) = self.correlation;
+        let energy = (ll * rr).sqrt();
+        if energy < 1e-12 {
+            // Silence
+            return 0.0;
+        }
+        (lr / energy).clamp(-1.0, 1.0) as f32
+    }
+
+    /// The meter values, see above. Starts a new true peak window.
+    pub fn to_bytes(&mut self) -> Vec<u8> {
+        let mut values = vec![
+            self.loudness(MOMENTARY_BLOCKS),
+            self.loudness(SHORT_TERM_BLOCKS),
+            to_db(self.true_peak as f64),
+            to_db(self.true_peak_max as f64),
+            self.phase_correlation(),
+            self.channels as f32,
+        ];
+        values.extend(self.mean_squares.iter().map(|m| to_db(m.sqrt())));
+        self.true_peak = 0.0;
+
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+}
+
+pub enum MeterMessage<T> {
+    /// Whole frames as they are played, before the volume is applied
+    Samples(Vec<T>),
+    /// The song changed
+    Reset,
+}
+
+/**
+ * Runs the meters on their own thread, the output callback only hands over
+ * a copy of what it played. While the analyzer type is "meters" the values
+ * are sent over the data channel on their own clock. The thread ends with
+ * the output stream, when the sender is dropped.
+ */
+pub fn spawn<T>(
+    sample_rate: u32,
+    channels: usize,
+    analyzer_state: Arc<RwLock<AnalyzerState>>,
+    data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+) -> SyncSender<MeterMessage<T>>
+where
+    T: IntoSample<f32> + Copy + Send + 'static,
+{
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+    std::thread::spawn(move || {
+        let mut meters = Meters::new(sample_rate, channels);
+        let mut last_send = Instant::now();
+        while let Ok(message) = receiver.recv() {
+            let samples = match message {
+                MeterMessage::Samples(samples) => samples,
+                MeterMessage::Reset => {
+                    meters.reset();
+                    continue;
+                }
+            };
+            if !matches!(
+                analyzer_state.read().unwrap().analyzer_type,
+                Some(AnalyzerType::Meters)
+            ) {
+                continue;
+            }
+            meters.process(&samples);
+
+            if last_send.elapsed() >= SEND_INTERVAL {
+                last_send = Instant::now();
+                let data_channel = data_channel.blocking_lock().clone();
+                if let Some(data_channel) = data_channel {
+                    let bytes = Bytes::from(meters.to_bytes());
+                    let _ = tauri::async_runtime::block_on(data_channel.send(&bytes));
+                }
+            }
+        }
+        info!("[Meters] Output closed, stopping");
+    });
+    sender
+}
@@ -0,0 +1,860 @@
+use chksum_md5::MD5;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::TryRecvError;
+
+use crate::remote::{self, RemoteChange, RemoteSong, RemoteState};
+use crate::store::load_settings;
+
+const DEFAULT_PORT: u16 = 6600;
+const PROTOCOL_VERSION: &str = "0.23.5";
+
+/// Error codes from MPD's ack.h
+const ACK_ERROR_ARG: u32 = 2;
+const ACK_ERROR_UNKNOWN: u32 = 5;
+const ACK_ERROR_NO_EXIST: u32 = 50;
+
+/// Same as MPD's client input buffer, clients sending longer lines are disconnected
+pub(crate) const MAX_LINE_LENGTH: usize = 4096;
+
+const SUPPORTED_COMMANDS: &[&str] = &[
+    "add",
+    "addid",
+    "clearerror",
+    "close",
+    "command_list_begin",
+    "command_list_end",
+    "command_list_ok_begin",
+    "commands",
+    "currentsong",
+    "decoders",
+    "find",
+    "getvol",
+    "idle",
+    "next",
+    "noidle",
+    "notcommands",
+    "outputs",
+    "pause",
+    "ping",
+    "play",
+    "playid",
+    "playlistid",
+    "playlistinfo",
+    "plchanges",
+    "plchangesposid",
+    "previous",
+    "search",
+    "seek",
+    "seekcur",
+    "seekid",
+    "setvol",
+    "status",
+    "stop",
+    "tagtypes",
+    "urlhandlers",
+    "volume",
+];
+
+const TAG_TYPES: &[&str] = &[
+    "Artist",
+    "Album",
+    "AlbumArtist",
+    "Title",
+    "Track",
+    "Genre",
+    "Date",
+    "Disc",
+];
+
+#[derive(Debug)]
+pub(crate) struct Ack {
+    pub code: u32,
+    pub message: String,
+}
+
+impl Ack {
+    fn new(code: u32, message: impl Into<String>) -> Self {
+        Ack {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+type CommandResult = Result<String, Ack>;
+
+/// MPD song ids are numbers, ours are hashes
+#[derive(Default)]
+struct SongIds {
+    ids: HashMap<String, u32>,
+    next: u32,
+}
+
+impl SongIds {
+    fn id(&mut self, song_id: &str) -> u32 {
+        if let Some(id) = self.ids.get(song_id) {
+            return *id;
+        }
+        self.next += 1;
+        self.ids.insert(song_id.to_string(), self.next);
+        self.next
+    }
+}
+
+/// Reads lines without buffering more than MAX_LINE_LENGTH of a single line
+pub(crate) struct LineReader<R> {
+    reader: R,
+    /// Part of the current line, kept if reading is interrupted (eg. by a select)
+    buffer: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> LineReader<R> {
+    pub fn new(reader: R) -> Self {
+        LineReader {
+            reader,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Next line without its line ending, None at the end of the stream
+    pub async fn next_line(&mut self) -> std::io::Result<Option<String>> {
+        let limit = MAX_LINE_LENGTH.saturating_sub(self.buffer.len()) as u64;
+        (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.buffer)
+            .await?;
+        // Without a line ending, either the limit or the end of the stream was reached
+        if !self.buffer.ends_with(b"\n") && self.buffer.len() >= MAX_LINE_LENGTH {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Line too long",
+            ));
+        }
+        if self.buffer.is_empty() {
+            return Ok(None);
+        }
+        let line = String::from_utf8(std::mem::take(&mut self.buffer))
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+        Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()))
+    }
+}
+
+/**
+ * Starts the MPD protocol server if enabled in the settings.
+ */
+pub fn init(app: AppHandle) {
+    let Ok(settings) = load_settings(&app) else {
+        return;
+    };
+    if !settings.mpd_enabled.unwrap_or(false) {
+        return;
+    }
+    let port = settings.mpd_port.unwrap_or(DEFAULT_PORT);
+    let address = if settings.mpd_allow_lan.unwrap_or(false) {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    };
+
+    tauri::async_runtime::spawn(async move {
+        let listener = match TcpListener::bind((address, port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("[MPD] Error listening on port {}: {}", port, err);
+                return;
+            }
+        };
+        info!("[MPD] Listening on {}:{}", address, port);
+        let ids = Arc::new(Mutex::new(SongIds::default()));
+        loop {
+            match listener.accept().await {
+                Ok((socket, address)) => {
+                    info!("[MPD] Client connected: {}", address);
+                    let app = app.clone();
+                    let ids = ids.clone();
+                    tauri::async_runtime::spawn(async move {
+                        if let Err(err) = handle_client(socket, app, ids).await {
+                            warn!("[MPD] Client error: {}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("[MPD] Error accepting connection: {}", err),
+            }
+        }
+    });
+}
+
+async fn handle_client(
+    socket: TcpStream,
+    app: AppHandle,
+    ids: Arc<Mutex<SongIds>>,
+) -> std::io::Result<()> {
+    let (reader, mut writer) = socket.into_split();
+    let mut lines = LineReader::new(BufReader::new(reader));
+    // Changes since the last idle
+    let mut changes = app.state::<RemoteState>().subscribe();
+
+    writer
+        .write_all(format!("OK MPD {}\n", PROTOCOL_VERSION).as_bytes())
+        .await?;
+
+    // (list_OK after each command, commands)
+    let mut command_list: Option<(bool, Vec<String>)> = None;
+
+    while let Some(line) = lines.next_line().await? {
+        if let Some((list_ok, commands)) = command_list.as_mut() {
+            if line != "command_list_end" {
+                commands.push(line);
+                continue;
+            }
+            let mut response = String::new();
+            let mut failed = false;
+            for (idx, command) in commands.iter().enumerate() {
+                match run_line(command, &app, &ids).await {
+                    Ok(output) => {
+                        response.push_str(&output);
+                        if *list_ok {
+                            response.push_str("list_OK\n");
+                        }
+                    }
+                    Err((name, ack)) => {
+                        response.push_str(&format_ack(&ack, idx, &name));
+                        failed = true;
+                        break;
+                    }
+                }
+            }
+            if !failed {
+                response.push_str("OK\n");
+            }
+            command_list = None;
+            writer.write_all(response.as_bytes()).await?;
+            continue;
+        }
+
+        match line.as_str() {
+            "command_list_begin" => {
+                command_list = Some((false, vec![]));
+                continue;
+            }
+            "command_list_ok_begin" => {
+                command_list = Some((true, vec![]));
+                continue;
+            }
+            "close" => break,
+            _ => {}
+        }
+
+        let args = match tokenize(&line) {
+            Ok(args) => args,
+            Err(ack) => {
+                writer.write_all(format_ack(&ack, 0, "").as_bytes()).await?;
+                continue;
+            }
+        };
+
+        if args.first().map(String::as_str) == Some("idle") {
+            let subsystems: HashSet<String> = if args.len() > 1 {
+                args[1..].iter().cloned().collect()
+            } else {
+                ["player", "playlist", "mixer"]
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect()
+            };
+            let Some(response) = idle(&subsystems, &mut changes, &mut lines).await? else {
+                break;
+            };
+            writer.write_all(response.as_bytes()).await?;
+            continue;
+        }
+
+        let response = match run_line(&line, &app, &ids).await {
+            Ok(output) => output + "OK\n",
+            Err((name, ack)) => format_ack(&ack, 0, &name),
+        };
+        writer.write_all(response.as_bytes()).await?;
+    }
+    info!("[MPD] Client disconnected");
+    Ok(())
+}
+
+fn subsystem(change: RemoteChange) -> &'static str {
+    match change {
+        RemoteChange::Player => "player",
+        RemoteChange::Playlist => "playlist",
+        RemoteChange::Mixer => "mixer",
+    }
+}
+
+/**
+ * Waits for a change in one of the subsystems, or for `noidle`.
+ * Returns None when the client disconnects.
+ */
+async fn idle(
+    subsystems: &HashSet<String>,
+    changes: &mut tokio::sync::broadcast::Receiver<RemoteChange>,
+    lines: &mut LineReader<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+) -> std::io::Result<Option<String>> {
+    let mut changed: Vec<&'static str> = Vec::new();
+    let add = |change: RemoteChange, changed: &mut Vec<&'static str>| {
+        let name = subsystem(change);
+        if subsystems.contains(name) && !changed.contains(&name) {
+            changed.push(name);
+        }
+    };
+
+    // Changes that happened since the last command
+    loop {
+        match changes.try_recv() {
+            Ok(change) => add(change, &mut changed),
+            Err(TryRecvError::Lagged(_)) => continue,
+            Err(_) => break,
+        }
+    }
+
+    while changed.is_empty() {
+        tokio::select! {
+            change = changes.recv() => match change {
+                Ok(change) => add(change, &mut changed),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(_) => return Ok(None),
+            },
+            line = lines.next_line() => match line? {
+                Some(line) if line.trim() == "noidle" => return Ok(Some("OK\n".to_string())),
+                Some(line) => warn!("[MPD] Unexpected command while idle: {}", line),
+                None => return Ok(None),
+            },
+        }
+    }
+
+    let mut response: String = changed
+        .iter()
+        .map(|name| format!("changed: {}\n", name))
+        .collect();
+    response.push_str("OK\n");
+    Ok(Some(response))
+}
+
+pub(crate) fn format_ack(ack: &Ack, list_index: usize, command: &str) -> String {
+    format!(
+        "ACK [{}@{}] {{{}}} {}\n",
+        ack.code, list_index, command, ack.message
+    )
+}
+
+/// Splits a command line into arguments, handling "quoted strings" with backslash escapes
+pub(crate) fn tokenize(line: &str) -> Result<Vec<String>, Ack> {
+    let mut args = Vec::new();
+    let mut chars = line.chars().peekable();
+    loop {
+        while chars.peek().is_some_and(|c| c.is_whitespace()) {
+            chars.next();
+        }
+        let Some(&c) = chars.peek() else {
+            break;
+        };
+        let mut arg = String::new();
+        if c == '"' {
+            chars.next();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some(escaped) = chars.next() {
+                            arg.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    _ => arg.push(c),
+                }
+            }
+            if !closed {
+                return Err(Ack::new(ACK_ERROR_ARG, "Missing closing '\"'"));
+            }
+        } else {
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                arg.push(c);
+                chars.next();
+            }
+        }
+        args.push(arg);
+    }
+    Ok(args)
+}
+
+async fn run_line(
+    line: &str,
+    app: &AppHandle,
+    ids: &Arc<Mutex<SongIds>>,
+) -> Result<String, (String, Ack)> {
+    let args = tokenize(line).map_err(|ack| (String::new(), ack))?;
+    let Some(command) = args.first().cloned() else {
+        return Err((
+            String::new(),
+            Ack::new(ACK_ERROR_UNKNOWN, "No command given"),
+        ));
+    };
+    execute(&command, &args[1..], app, ids)
+        .await
+        .map_err(|ack| (command, ack))
+}
+
+fn song_info(song: &RemoteSong, position: Option<usize>, ids: &Mutex<SongIds>) -> String {
+    let mut info = format!("file: {}\n", song.path);
+    if !song.title.is_empty() {
+        info.push_str(&format!("Title: {}\n", song.title));
+    }
+    if !song.artist.is_empty() {
+        info.push_str(&format!("Artist: {}\n", song.artist));
+    }
+    if !song.album.is_empty() {
+        info.push_str(&format!("Album: {}\n", song.album));
+    }
+    if let Some(album_artist) = song.album_artist.as_ref().filter(|a| !a.is_empty()) {
+        info.push_str(&format!("AlbumArtist: {}\n", album_artist));
+    }
+    for genre in &song.genre {
+        info.push_str(&format!("Genre: {}\n", genre));
+    }
+    if song.year > 0 {
+        info.push_str(&format!("Date: {}\n", song.year));
+    }
+    if song.track_number > 0 {
+        info.push_str(&format!("Track: {}\n", song.track_number));
+    }
+    if song.disc_number > 0 {
+        info.push_str(&format!("Disc: {}\n", song.disc_number));
+    }
+    if let Some(duration) = song.duration {
+        info.push_str(&format!(
+            "Time: {}\nduration: {:.3}\n",
+            duration.round() as u64,
+            duration
+        ));
+    }
+    if let Some(position) = position {
+        info.push_str(&format!("Pos: {}\n", position));
+    }
+    info.push_str(&format!("Id: {}\n", ids.lock().unwrap().id(&song.id)));
+    info
+}
+
+fn parse_number<T: std::str::FromStr>(arg: Option<&String>) -> Result<T, Ack> {
+    let arg = arg.ok_or_else(|| Ack::new(ACK_ERROR_ARG, "Missing argument"))?;
+    arg.parse()
+        .map_err(|_| Ack::new(ACK_ERROR_ARG, format!("Invalid number: {}", arg)))
+}
+
+/// A range argument: "pos" or "start:end"
+pub(crate) fn parse_range(arg: &str, length: usize) -> Result<std::ops::Range<usize>, Ack> {
+    let invalid = || Ack::new(ACK_ERROR_ARG, format!("Invalid range: {}", arg));
+    match arg.split_once(':') {
+        Some((start, end)) => {
+            let start: usize = start.parse().map_err(|_| invalid())?;
+            let end = if end.is_empty() {
+                length
+            } else {
+                end.parse().map_err(|_| invalid())?
+            };
+            Ok(start.min(length)..end.min(length).max(start.min(length)))
+        }
+        None => {
+            let pos: usize = arg.parse().map_err(|_| invalid())?;
+            if pos >= length {
+                return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+            }
+            Ok(pos..pos + 1)
+        }
+    }
+}
+
+fn index_of_id(queue: &[RemoteSong], id: u32, ids: &Mutex<SongIds>) -> Result<usize, Ack> {
+    let mut ids = ids.lock().unwrap();
+    queue
+        .iter()
+        .position(|s| ids.id(&s.id) == id)
+        .ok_or_else(|| Ack::new(ACK_ERROR_NO_EXIST, "No such song"))
+}
+
+/// Converts an MPD uri to a path, relative uris aren't supported since there's no music directory
+fn uri_to_path(uri: &str) -> Result<String, Ack> {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+    if !path.starts_with('/') && !path.chars().nth(1).is_some_and(|c| c == ':') {
+        return Err(Ack::new(
+            ACK_ERROR_NO_EXIST,
+            "Only absolute paths can be added",
+        ));
+    }
+    Ok(path.to_string())
+}
+
+pub(crate) struct Filter {
+    pub tag: String,
+    pub op: String,
+    pub value: String,
+}
+
+/**
+ * Parses old style `tag value` pairs or a filter expression like
+ * `((artist == "foo") AND (album contains 'bar'))`.
+ */
+pub(crate) fn parse_filters(args: &[String]) -> Result<Vec<Filter>, Ack> {
+    if args.len() == 1 && args[0].starts_with('(') {
+        let expression = args[0].trim();
+        let mut filters = Vec::new();
+        for clause in expression.split(" AND ") {
+            let clause = clause.trim().trim_start_matches('(').trim_end_matches(')');
+            let tokens = tokenize_expression(clause)?;
+            let [tag, op, value] = tokens.as_slice() else {
+                return Err(Ack::new(
+                    ACK_ERROR_ARG,
+                    format!("Unsupported filter: {}", clause),
+                ));
+            };
+            filters.push(Filter {
+                tag: tag.to_lowercase(),
+                op: op.clone(),
+                value: value.clone(),
+            });
+        }
+        return Ok(filters);
+    }
+    if args.is_empty() || args.len() % 2 != 0 {
+        return Err(Ack::new(
+            ACK_ERROR_ARG,
+            "Incorrect number of filter arguments",
+        ));
+    }
+    Ok(args
+        .chunks(2)
+        .map(|pair| Filter {
+            tag: pair[0].to_lowercase(),
+            op: "==".to_string(),
+            value: pair[1].clone(),
+        })
+        .collect())
+}
+
+/// Like tokenize, but also accepts 'single quotes'
+fn tokenize_expression(clause: &str) -> Result<Vec<String>, Ack> {
+    let normalized = match clause.find('\'') {
+        Some(start) if clause.ends_with('\'') && clause.len() > start + 1 => format!(
+            "{}\"{}\"",
+            &clause[..start],
+            clause[start + 1..clause.len() - 1].replace('"', "\\\"")
+        ),
+        _ => clause.to_string(),
+    };
+    tokenize(&normalized)
+}
+
+fn tag_values(song: &RemoteSong, tag: &str) -> Vec<String> {
+    match tag {
+        "artist" => vec![song.artist.clone()],
+        "album" => vec![song.album.clone()],
+        "albumartist" => vec![song.album_artist.clone().unwrap_or_default()],
+        "title" => vec![song.title.clone()],
+        "genre" => song.genre.clone(),
+        "date" => vec![song.year.to_string()],
+        "track" => vec![song.track_number.to_string()],
+        "disc" => vec![song.disc_number.to_string()],
+        "file" | "base" => vec![song.path.clone()],
+        _ => {
+            let mut values = vec![
+                song.title.clone(),
+                song.artist.clone(),
+                song.album.clone(),
+                song.album_artist.clone().unwrap_or_default(),
+                song.path.clone(),
+            ];
+            values.extend(song.genre.clone());
+            values
+        }
+    }
+}
+
+/// `search` is case insensitive and matches substrings, `find` is exact
+fn matches(song: &RemoteSong, filter: &Filter, exact: bool) -> bool {
+    let values = tag_values(song, &filter.tag);
+    let value = filter.value.to_lowercase();
+    let found = values.iter().any(|v| match filter.op.as_str() {
+        "contains" => v.to_lowercase().contains(&value),
+        "starts_with" => v.to_lowercase().starts_with(&value),
+        _ if exact => *v == filter.value,
+        _ => v.to_lowercase().contains(&value),
+    });
+    if filter.op == "!=" {
+        !found
+    } else {
+        found
+    }
+}
+
+async fn execute(
+    command: &str,
+    args: &[String],
+    app: &AppHandle,
+    ids: &Arc<Mutex<SongIds>>,
+) -> CommandResult {
+    let state = app.state::<RemoteState>();
+    match command {
+        "ping" | "clearerror" | "noidle" => Ok(String::new()),
+        "status" => {
+            let status = state.status();
+            let queue = state.queue();
+            let mut out = format!(
+                "volume: {}\nrepeat: 0\nrandom: 0\nsingle: 0\nconsume: 0\nplaylist: {}\nplaylistlength: {}\n",
+                (status.volume * 100.0).round() as i64,
+                status.queue_version,
+                status.queue_length
+            );
+            let play_state = match (&status.song, status.is_playing) {
+                (None, _) => "stop",
+                (Some(_), true) => "play",
+                (Some(_), false) => "pause",
+            };
+            out.push_str(&format!("state: {}\n", play_state));
+            if let Some(idx) = status.current_index.filter(|idx| *idx < queue.len()) {
+                out.push_str(&format!(
+                    "song: {}\nsongid: {}\n",
+                    idx,
+                    ids.lock().unwrap().id(&queue[idx].id)
+                ));
+                if let Some(next) = queue.get(idx + 1) {
+                    out.push_str(&format!(
+                        "nextsong: {}\nnextsongid: {}\n",
+                        idx + 1,
+                        ids.lock().unwrap().id(&next.id)
+                    ));
+                }
+            }
+            if let Some(song) = &status.song {
+                let duration = song.duration.unwrap_or(0.0);
+                out.push_str(&format!(
+                    "time: {}:{}\nelapsed: {:.3}\nduration: {:.3}\n",
+                    status.position.round() as u64,
+                    duration.round() as u64,
+                    status.position,
+                    duration
+                ));
+            }
+            Ok(out)
+        }
+        "currentsong" => {
+            let status = state.status();
+            Ok(status
+                .song
+                .as_ref()
+                .map(|song| song_info(song, status.current_index, ids))
+                .unwrap_or_default())
+        }
+        "play" => {
+            match args.first() {
+                Some(_) => {
+                    let pos: usize = parse_number(args.first())?;
+                    if pos >= state.status().queue_length {
+                        return Err(Ack::new(ACK_ERROR_ARG, "Bad song index"));
+                    }
+                    remote::play_index(app, pos);
+                }
+                None => remote::play(app),
+            }
+            Ok(String::new())
+        }
+        "playid" => {
+            match args.first() {
+                Some(_) => {
+                    let id: u32 = parse_number(args.first())?;
+                    let idx = index_of_id(&state.queue(), id, ids)?;
+                    remote::play_index(app, idx);
+                }
+                None => remote::play(app),
+            }
+            Ok(String::new())
+        }
+        "pause" => {
+            match args.first().map(String::as_str) {
+                Some("1") => remote::pause(app),
+                Some("0") => remote::play(app),
+                _ => {
+                    if state.status().is_playing {
+                        remote::pause(app)
+                    } else {
+                        remote::play(app)
+                    }
+                }
+            }
+            Ok(String::new())
+        }
+        "stop" => {
+            remote::pause(app);
+            Ok(String::new())
+        }
+        "next" => {
+            remote::next(app);
+            Ok(String::new())
+        }
+        "previous" => {
+            remote::previous(app);
+            Ok(String::new())
+        }
+        "seek" | "seekid" => {
+            let status = state.status();
+            let idx = if command == "seek" {
+                parse_number(args.first())?
+            } else {
+                index_of_id(&state.queue(), parse_number(args.first())?, ids)?
+            };
+            let time: f64 = parse_number(args.get(1))?;
+            if status.current_index != Some(idx) {
+                return Err(Ack::new(
+                    ACK_ERROR_ARG,
+                    "Only seeking within the current song is supported",
+                ));
+            }
+            remote::seek(app, time);
+            Ok(String::new())
+        }
+        "seekcur" => {
+            let arg = args
+                .first()
+                .ok_or_else(|| Ack::new(ACK_ERROR_ARG, "Missing argument"))?;
+            let time: f64 = parse_number(Some(arg))?;
+            let position = if arg.starts_with('+') || arg.starts_with('-') {
+                state.status().position + time
+            } else {
+                time
+            };
+            remote::seek(app, position);
+            Ok(String::new())
+        }
+        "setvol" => {
+            let volume: i64 = parse_number(args.first())?;
+            if !(0..=100).contains(&volume) {
+                return Err(Ack::new(ACK_ERROR_ARG, "Invalid volume value"));
+            }
+            remote::set_volume(app, volume as f64 / 100.0);
+            Ok(String::new())
+        }
+        "volume" => {
+            let change: i64 = parse_number(args.first())?;
+            remote::set_volume(app, state.status().volume + change as f64 / 100.0);
+            Ok(String::new())
+        }
+        "getvol" => Ok(format!(
+            "volume: {}\n",
+            (state.status().volume * 100.0).round() as i64
+        )),
+        "playlistinfo" | "plchanges" => {
+            let queue = state.queue();
+            let range = match args.first() {
+                // plchanges {version}: we don't keep history, send everything if it changed
+                Some(version) if command == "plchanges" => {
+                    if version.parse::<u32>().ok() == Some(state.status().queue_version) {
+                        0..0
+                    } else {
+                        0..queue.len()
+                    }
+                }
+                Some(arg) => parse_range(arg, queue.len())?,
+                None => 0..queue.len(),
+            };
+            Ok(range
+                .map(|idx| song_info(&queue[idx], Some(idx), ids))
+                .collect())
+        }
+        "plchangesposid" => {
+            let queue = state.queue();
+            let mut ids = ids.lock().unwrap();
+            Ok(queue
+                .iter()
+                .enumerate()
+                .map(|(idx, song)| format!("cpos: {}\nId: {}\n", idx, ids.id(&song.id)))
+                .collect())
+        }
+        "playlistid" => {
+            let queue = state.queue();
+            match args.first() {
+                Some(_) => {
+                    let idx = index_of_id(&queue, parse_number(args.first())?, ids)?;
+                    Ok(song_info(&queue[idx], Some(idx), ids))
+                }
+                None => Ok(queue
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, song)| song_info(song, Some(idx), ids))
+                    .collect()),
+            }
+        }
+        "add" | "addid" => {
+            let uri = args
+                .first()
+                .ok_or_else(|| Ack::new(ACK_ERROR_ARG, "Missing argument"))?;
+            let path = uri_to_path(uri)?;
+            if !std::path::Path::new(&path).exists() {
+                return Err(Ack::new(ACK_ERROR_NO_EXIST, "No such directory"));
+            }
+            remote::enqueue(app, vec![path.clone()], false);
+            if command == "addid" {
+                // Song ids are derived from the path
+                let song_id = MD5::hash(path.as_bytes()).to_hex_lowercase();
+                return Ok(format!("Id: {}\n", ids.lock().unwrap().id(&song_id)));
+            }
+            Ok(String::new())
+        }
+        "search" | "find" => {
+            let filters = parse_filters(args)?;
+            // The library search covers title, artist and album
+            let query = filters
+                .iter()
+                .filter(|f| {
+                    f.op != "!="
+                        && matches!(
+                            f.tag.as_str(),
+                            "any" | "title" | "artist" | "album" | "albumartist"
+                        )
+                })
+                .map(|f| f.value.clone())
+                .max_by_key(|v| v.len())
+                .unwrap_or_default();
+            let exact = command == "find";
+            let songs = remote::search(app, &query).await;
+            Ok(songs
+                .iter()
+                .filter(|song| filters.iter().all(|f| matches(song, f, exact)))
+                .map(|song| song_info(song, None, ids))
+                .collect())
+        }
+        "outputs" => {
+            Ok("outputid: 0\noutputname: Musicat\nplugin: musicat\noutputenabled: 1\n".to_string())
+        }
+        "commands" => Ok(SUPPORTED_COMMANDS
+            .iter()
+            .map(|c| format!("command: {}\n", c))
+            .collect()),
+        "notcommands" | "decoders" | "urlhandlers" => Ok(String::new()),
+        // "tagtypes clear", "tagtypes enable ...": we always send all tags
+        "tagtypes" if !args.is_empty() => Ok(String::new()),
+        "tagtypes" => Ok(TAG_TYPES
+            .iter()
+            .map(|t| format!("tagtype: {}\n", t))
+            .collect()),
+        _ => Err(Ack::new(
+            ACK_ERROR_UNKNOWN,
+            format!("unknown command \"{}\"", command),
+        )),
+    }
+}
@@ -0,0 +1,575 @@
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+use tauri::{AppHandle, Emitter, Manager};
+use zbus::object_server::SignalEmitter;
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+use zbus::{connection, interface, Connection};
+
+use crate::metadata::{Artwork, Song};
+
+const BUS_NAME: &str = "org.mpris.MediaPlayer2.musicat";
+const OBJECT_PATH: &str = "/org/mpris/MediaPlayer2";
+const NO_TRACK: &str = "/org/mpris/MediaPlayer2/TrackList/NoTrack";
+/// Position jumps bigger than this (in seconds) are reported as a seek
+const SEEK_THRESHOLD: f64 = 2.0;
+/// Same range as the playback speed control
+const MIN_RATE: f64 = 0.3;
+const MAX_RATE: f64 = 3.0;
+
+static MPRIS: OnceLock<Mpris> = OnceLock::new();
+
+struct Mpris {
+    connection: Connection,
+    state: Arc<Mutex<MprisState>>,
+}
+
+#[derive(Default)]
+struct MprisState {
+    song: Option<Song>,
+    art_url: Option<String>,
+    is_playing: bool,
+    /// Seconds
+    position: f64,
+    /// 0 to 1
+    volume: f64,
+    /// Playback speed
+    rate: f64,
+}
+
+type Handler = Box<dyn Fn() + Send + Sync>;
+
+fn track_id(song: &Song) -> ObjectPath<'static> {
+    ObjectPath::try_from(format!("/org/musicat/track/{}", song.id))
+        .unwrap_or_else(|_| ObjectPath::from_static_str_unchecked(NO_TRACK))
+}
+
+fn owned<'a>(value: impl Into<Value<'a>>) -> OwnedValue {
+    // Only fails for file descriptors
+    OwnedValue::try_from(value.into()).unwrap()
+}
+
+fn to_micros(seconds: f64) -> i64 {
+    (seconds * 1_000_000.0) as i64
+}
+
+/**
+ * Artwork as a file URL: the cover file itself, or the embedded picture
+ * written to the cache directory.
+ */
+fn artwork_url(artwork: &Option<Artwork>, song: &Song, app_handle: &AppHandle) -> Option<String> {
+    let artwork = artwork.as_ref()?;
+    if let Some(src) = artwork.src.as_ref().filter(|s| !s.is_empty()) {
+        return url::Url::from_file_path(src).ok().map(|u| u.to_string());
+    }
+    if artwork.data.is_empty() {
+        return None;
+    }
+    let extension = if artwork.data.starts_with(&[0x89, b'P', b'N', b'G']) {
+        "png"
+    } else {
+        "jpg"
+    };
+    let dir: PathBuf = app_handle.path().app_cache_dir().ok()?.join("mpris");
+    std::fs::create_dir_all(&dir).ok()?;
+    let name = song.album_id.clone().unwrap_or(song.id.clone());
+    let path = dir.join(format!("{}.{}", name, extension));
+    if !path.exists() {
+        if let Err(err) = std::fs::write(&path, &artwork.data) {
+            warn!("[MPRIS] Error writing artwork: {}", err);
+            return None;
+        }
+    }
+    url::Url::from_file_path(path).ok().map(|u| u.to_string())
+}
+
+struct MediaPlayer2 {
+    app_handle: AppHandle,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2")]
+impl MediaPlayer2 {
+    fn raise(&self) {
+        if let Some(window) = self.app_handle.get_webview_window("main") {
+            let _ = window.show();
+            let _ = window.set_focus();
+        }
+    }
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> &str {
+        "Musicat"
+    }
+
+    #[zbus(property)]
+    fn desktop_entry(&self) -> &str {
+        "musicat"
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        vec!["file".into(), "http".into(), "https".into()]
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        [
+            "audio/mpeg",
+            "audio/flac",
+            "audio/x-wav",
+            "audio/x-aiff",
+            "audio/ogg",
+            "audio/mp4",
+            "audio/x-ape",
+        ]
+        .iter()
+        .map(|m| m.to_string())
+        .collect()
+    }
+}
+
+struct MediaPlayer2Player {
+    state: Arc<Mutex<MprisState>>,
+    app_handle: AppHandle,
+    play: Handler,
+    pause: Handler,
+    toggle: Handler,
+    previous: Handler,
+    next: Handler,
+}
+
+#[interface(name = "org.mpris.MediaPlayer2.Player")]
+impl MediaPlayer2Player {
+    fn next(&self) {
+        (self.next)();
+    }
+
+    fn previous(&self) {
+        (self.previous)();
+    }
+
+    fn pause(&self) {
+        (self.pause)();
+    }
+
+    fn play_pause(&self) {
+        (self.toggle)();
+    }
+
+    fn stop(&self) {
+        (self.pause)();
+    }
+
+    fn play(&self) {
+        (self.play)();
+    }
+
+    /// Offset in microseconds
+    fn seek(&self, offset: i64) {
+        let position = self.state.lock().unwrap().position;
+        let _ = self
+            .app_handle
+            .emit("seek_to", (position + offset as f64 / 1_000_000.0).max(0.0));
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        let is_current = self
+            .state
+            .lock()
+            .unwrap()
+            .song
+            .as_ref()
+            .is_some_and(|s| self::track_id(s).as_str() == track_id.as_str());
+        if is_current && position >= 0 {
+            let _ = self
+                .app_handle
+                .emit("seek_to", position as f64 / 1_000_000.0);
+        }
+    }
+
+    fn open_uri(&self, uri: String) {
+        info!("[MPRIS] OpenUri not supported: {}", uri);
+    }
+
+    #[zbus(signal)]
+    async fn seeked(emitter: &SignalEmitter<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> &str {
+        let state = self.state.lock().unwrap();
+        if state.song.is_none() {
+            "Stopped"
+        } else if state.is_playing {
+            "Playing"
+        } else {
+            "Paused"
+        }
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        self.state.lock().unwrap().rate
+    }
+
+    #[zbus(property)]
+    fn set_rate(&self, rate: f64) {
+        // A rate of 0 is not a valid speed, clients should call Pause instead
+        if rate <= 0.0 {
+            return;
+        }
+        let rate = rate.clamp(MIN_RATE, MAX_RATE);
+        self.state.lock().unwrap().rate = rate;
+        let _ = self.app_handle.emit("set_playback_speed", rate);
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        MIN_RATE
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        MAX_RATE
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let state = self.state.lock().unwrap();
+        let mut metadata = HashMap::new();
+        let Some(song) = &state.song else {
+            metadata.insert(
+                "mpris:trackid".into(),
+                owned(ObjectPath::from_static_str_unchecked(NO_TRACK)),
+            );
+            return metadata;
+        };
+        metadata.insert("mpris:trackid".into(), owned(track_id(song)));
+        if let Some(duration) = song.file_info.duration {
+            metadata.insert("mpris:length".into(), owned(to_micros(duration)));
+        }
+        if let Some(art_url) = &state.art_url {
+            metadata.insert("mpris:artUrl".into(), owned(art_url.clone()));
+        }
+        metadata.insert("xesam:title".into(), owned(song.title.clone()));
+        metadata.insert("xesam:album".into(), owned(song.album.clone()));
+        if !song.artist.is_empty() {
+            metadata.insert("xesam:artist".into(), owned(vec![song.artist.clone()]));
+        }
+        if let Some(album_artist) = &song.album_artist {
+            metadata.insert(
+                "xesam:albumArtist".into(),
+                owned(vec![album_artist.clone()]),
+            );
+        }
+        if !song.genre.is_empty() {
+            metadata.insert("xesam:genre".into(), owned(song.genre.clone()));
+        }
+        if !song.composer.is_empty() {
+            metadata.insert("xesam:composer".into(), owned(song.composer.clone()));
+        }
+        if song.track_number > 0 {
+            metadata.insert("xesam:trackNumber".into(), owned(song.track_number));
+        }
+        if song.disc_number > 0 {
+            metadata.insert("xesam:discNumber".into(), owned(song.disc_number));
+        }
+        if crate::stream::is_stream_url(&song.path) {
+            metadata.insert("xesam:url".into(), owned(song.path.clone()));
+        } else if let Ok(url) = url::Url::from_file_path(&song.path) {
+            metadata.insert("xesam:url".into(), owned(url.to_string()));
+        }
+        metadata
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.state.lock().unwrap().volume
+    }
+
+    #[zbus(property)]
+    fn set_volume(&self, volume: f64) {
+        let volume = volume.clamp(0.0, 1.0);
+        self.state.lock().unwrap().volume = volume;
+        let _ = self.app_handle.emit("set_volume", volume);
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        to_micros(self.state.lock().unwrap().position)
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        true
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .song
+            .as_ref()
+            .is_some_and(|s| s.file_info.duration.is_some())
+    }
+
+    #[zbus(property(emits_changed_signal = "const"))]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+pub struct MprisServer {
+    play: Option<Handler>,
+    pause: Option<Handler>,
+    toggle: Option<Handler>,
+    previous: Option<Handler>,
+    next: Option<Handler>,
+}
+
+impl MprisServer {
+    pub fn new() -> Self {
+        MprisServer {
+            play: None,
+            pause: None,
+            toggle: None,
+            previous: None,
+            next: None,
+        }
+    }
+
+    /**
+     * Same handlers as the macOS RemoteCommandCenter.
+     */
+    pub fn set_handlers<F, G, H, I, J>(
+        &mut self,
+        play: F,
+        pause: G,
+        toggle: H,
+        previous: I,
+        next: J,
+    ) where
+        F: Fn() + Send + Sync + 'static,
+        G: Fn() + Send + Sync + 'static,
+        H: Fn() + Send + Sync + 'static,
+        I: Fn() + Send + Sync + 'static,
+        J: Fn() + Send + Sync + 'static,
+    {
+        self.play = Some(Box::new(play));
+        self.pause = Some(Box::new(pause));
+        self.toggle = Some(Box::new(toggle));
+        self.previous = Some(Box::new(previous));
+        self.next = Some(Box::new(next));
+    }
+
+    /**
+     * Registers the service on the session bus.
+     */
+    pub fn start(self, app_handle: AppHandle) {
+        let noop = || Box::new(|| {}) as Handler;
+        let state = Arc::new(Mutex::new(MprisState {
+            volume: 1.0,
+            rate: 1.0,
+            ..Default::default()
+        }));
+        let player = MediaPlayer2Player {
+            state: state.clone(),
+            app_handle: app_handle.clone(),
+            play: self.play.unwrap_or_else(noop),
+            pause: self.pause.unwrap_or_else(noop),
+            toggle: self.toggle.unwrap_or_else(noop),
+            previous: self.previous.unwrap_or_else(noop),
+            next: self.next.unwrap_or_else(noop),
+        };
+        let root = MediaPlayer2 {
+            app_handle: app_handle.clone(),
+        };
+
+        tauri::async_runtime::spawn(async move {
+            let connection = connection::Builder::session()
+                .and_then(|b| b.name(BUS_NAME))
+                .and_then(|b| b.serve_at(OBJECT_PATH, root))
+                .and_then(|b| b.serve_at(OBJECT_PATH, player));
+            match connection {
+                Ok(builder) => match builder.build().await {
+                    Ok(connection) => {
+                        info!("[MPRIS] Registered {}", BUS_NAME);
+                        let _ = MPRIS.set(Mpris { connection, state });
+                    }
+                    Err(err) => error!("[MPRIS] Error connecting to the session bus: {}", err),
+                },
+                Err(err) => error!("[MPRIS] Error setting up the service: {}", err),
+            }
+        });
+    }
+}
+
+/// Sends PropertiesChanged for the player properties that depend on the state
+fn notify_changed(metadata: bool) {
+    let Some(mpris) = MPRIS.get() else {
+        return;
+    };
+    let connection = mpris.connection.clone();
+    tauri::async_runtime::spawn(async move {
+        let Ok(iface_ref) = connection
+            .object_server()
+            .interface::<_, MediaPlayer2Player>(OBJECT_PATH)
+            .await
+        else {
+            return;
+        };
+        let iface = iface_ref.get().await;
+        let emitter = iface_ref.signal_emitter();
+        let _ = iface.playback_status_changed(emitter).await;
+        if metadata {
+            let _ = iface.metadata_changed(emitter).await;
+            let _ = iface.can_seek_changed(emitter).await;
+        }
+    });
+}
+
+pub fn set_now_playing_info(song: &Song, app_handle: &AppHandle) {
+    let Some(mpris) = MPRIS.get() else {
+        return;
+    };
+    let art_url = artwork_url(&song.artwork, song, app_handle);
+    {
+        let mut state = mpris.state.lock().unwrap();
+        state.song = Some(song.clone());
+        state.art_url = art_url;
+        state.position = 0.0;
+    }
+    notify_changed(true);
+}
+
+pub fn set_playing() {
+    if let Some(mpris) = MPRIS.get() {
+        mpris.state.lock().unwrap().is_playing = true;
+        notify_changed(false);
+    }
+}
+
+pub fn set_paused() {
+    if let Some(mpris) = MPRIS.get() {
+        mpris.state.lock().unwrap().is_playing = false;
+        notify_changed(false);
+    }
+}
+
+/// Nothing left in the queue
+pub fn set_stopped() {
+    if let Some(mpris) = MPRIS.get() {
+        {
+            let mut state = mpris.state.lock().unwrap();
+            state.song = None;
+            state.art_url = None;
+            state.is_playing = false;
+            state.position = 0.0;
+        }
+        notify_changed(true);
+    }
+}
+
+/// Volume from 0 to 1, as set in the app
+pub fn set_volume(volume: f64) {
+    let Some(mpris) = MPRIS.get() else {
+        return;
+    };
+    let volume = volume.clamp(0.0, 1.0);
+    {
+        let mut state = mpris.state.lock().unwrap();
+        if state.volume == volume {
+            return;
+        }
+        state.volume = volume;
+    }
+    let connection = mpris.connection.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Ok(iface_ref) = connection
+            .object_server()
+            .interface::<_, MediaPlayer2Player>(OBJECT_PATH)
+            .await
+        {
+            let iface = iface_ref.get().await;
+            let _ = iface.volume_changed(iface_ref.signal_emitter()).await;
+        }
+    });
+}
+
+/// Playback speed, as set in the app
+pub fn set_rate(rate: f64) {
+    let Some(mpris) = MPRIS.get() else {
+        return;
+    };
+    {
+        let mut state = mpris.state.lock().unwrap();
+        if state.rate == rate {
+            return;
+        }
+        state.rate = rate;
+    }
+    let connection = mpris.connection.clone();
+    tauri::async_runtime::spawn(async move {
+        if let Ok(iface_ref) = connection
+            .object_server()
+            .interface::<_, MediaPlayer2Player>(OBJECT_PATH)
+            .await
+        {
+            let iface = iface_ref.get().await;
+            let _ = iface.rate_changed(iface_ref.signal_emitter()).await;
+        }
+    });
+}
+
+/// Position in seconds, clients are told about seeks with the Seeked signal
+pub fn set_position(position: f64) {
+    let Some(mpris) = MPRIS.get() else {
+        return;
+    };
+    let seeked = {
+        let mut state = mpris.state.lock().unwrap();
+        let seeked = (position - state.position).abs() > SEEK_THRESHOLD;
+        state.position = position;
+        seeked
+    };
+    if seeked {
+        let connection = mpris.connection.clone();
+        tauri::async_runtime::spawn(async move {
+            if let Ok(emitter) = SignalEmitter::new(&connection, OBJECT_PATH) {
+                let _ = MediaPlayer2Player::seeked(&emitter, to_micros(position)).await;
+            }
+        });
+    }
+}
@@ -15,6 +15,8 @@ use ::cpal::traits::{DeviceTrait, HostTrait};
 use ::cpal::{default_host, Device, DeviceId, SupportedStreamConfigRange};
 use rustfft::Fft;
 use rustfft::{num_complex::Complex, FftPlanner};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, OnceLock};
 
 use symphonia::core::audio::{AudioBufferRef, SignalSpec};
@@ -105,6 +107,8 @@ pub type Result<T> = result::Result<T, AudioOutputError>;
 // Global static instance
 static VISUALIZER: OnceLock<std::sync::Mutex<AudioProcessor>> = OnceLock::new();
 const MAX_FFT_SIZE: usize = 1024;
+/// Interleaved samples the frequency analyzer wants, follows its FFT size and channels
+static FREQUENCY_INPUT_LEN: AtomicUsize = AtomicUsize::new(MAX_FFT_SIZE * 2);
 
 pub fn get_visualizer() -> &'static std::sync::Mutex<AudioProcessor> {
     VISUALIZER.get_or_init(|| {
@@ -125,15 +129,19 @@ pub fn analyze_fft_freq(input: &[f32]) -> Vec<u8> {
 }
 
 pub mod cpal {
+    use std::sync::atomic::Ordering;
     use std::sync::{Arc, RwLock};
     use std::time::Duration;
 
+    use crate::broadcast;
     use crate::constants::BUFFER_SIZE;
     use crate::equalizer::Equalizer;
+    use crate::meters::{self, MeterMessage};
     use crate::output::{
         analyze_fft_freq, analyze_fft_time, get_device_by_id, get_visualizer, AnalyzerState,
-        AnalyzerType, AudioControlHandles, TimestampState, MAX_FFT_SIZE,
+        AnalyzerType, AudioControlHandles, TimestampState, FREQUENCY_INPUT_LEN, MAX_FFT_SIZE,
     };
+    use crate::recorder;
     use crate::resampler::Resampler;
 
     use super::{AudioOutput, AudioOutputError, PlaybackState, Result};
@@ -225,6 +233,7 @@ pub mod cpal {
                     _ => Layout::Stereo,
                 },
             );
+            processor.set_channels(device_spec.channels.count());
 
             // Prepare the sample buffer size based on the maximum number of frames per packet
             let duration = sample_buf_size;
@@ -247,6 +256,8 @@ pub mod cpal {
                             let bars: Vec<u8> = analyze_fft_freq(&data);
                             Bytes::from(bars)
                         }
+                        // Computed on the meters thread
+                        AnalyzerType::Meters => Bytes::new(),
                     },
                     vol,
                     analyzer_state,
@@ -309,6 +320,8 @@ pub mod cpal {
                             let bars: Vec<u8> = analyze_fft_freq(&data);
                             Bytes::from(bars)
                         }
+                        // Computed on the meters thread
+                        AnalyzerType::Meters => Bytes::new(),
                     },
                     vol,
                     analyzer_state,
@@ -329,8 +342,12 @@ pub mod cpal {
         resampler: Option<Resampler<T>>,
         equalizer: Option<Equalizer<T>>,
         sample_rate: u32,
+        channels: usize,
         name: String,
         time_base: TimeBase,
+        /// Shared with the stream callback so recordings get the same volume
+        volume_state: Arc<RwLock<f64>>,
+        volume_change: fn(T, f64) -> T,
     }
 
     impl<T: AudioOutputSample + Send + Sync> CpalAudioOutputImpl<T> {
@@ -368,6 +385,7 @@ pub mod cpal {
 
             // States
             let volume_state = Arc::new(RwLock::new(vol.unwrap()));
+            let recorder_volume_state = volume_state.clone();
             let frame_idx_state = Arc::new(RwLock::new(0.0f64));
             let elapsed_time_state = Arc::new(RwLock::new(0));
             let elapsed_frac_time_state = Arc::new(RwLock::new(0.0));
@@ -387,6 +405,12 @@ pub mod cpal {
             let rt = tokio::runtime::Runtime::new().unwrap();
             let mut viz_data = Vec::with_capacity(MAX_FFT_SIZE);
 
+            let meter_sender = meters::spawn::<T>(
+                config.sample_rate,
+                num_channels,
+                analyzer_state.clone(),
+                dc.as_ref().clone(),
+            );
             let mut last_viz_emit = std::time::Instant::now();
             let viz_interval = std::time::Duration::from_millis(16); // ~60fps
 
@@ -438,6 +462,7 @@ pub mod cpal {
                                 *frame_idx = 0.0;
                                 let mut elapsed_time = elapsed_time_state.write().unwrap();
                                 *elapsed_time = 0;
+                                let _ = meter_sender.try_send(MeterMessage::Reset);
 
                                 let ts_state = timestamp_state.write().unwrap();
                                 if ts_state.emit_to_client == 1 {
@@ -486,6 +511,25 @@ pub mod cpal {
                             // output.
                             let written = ring_buf_consumer.read(data).unwrap_or(0);
 
+                            // The frequency analyzer can be configured with a bigger FFT
+                            let (length, meters_on) =
+                                match analyzer_state.read().unwrap().analyzer_type {
+                                    Some(AnalyzerType::Frequency) => {
+                                        (FREQUENCY_INPUT_LEN.load(Ordering::Relaxed), false)
+                                    }
+                                    Some(AnalyzerType::Meters) => (MAX_FFT_SIZE, true),
+                                    _ => (MAX_FFT_SIZE, false),
+                                };
+
+                            // Meters see the signal before the volume is applied. A short
+                            // read can end mid-frame, which would shift the channels.
+                            // Only copied while they're shown, this is the audio thread.
+                            if meters_on {
+                                let whole_frames = written - written % num_channels;
+                                let _ = meter_sender
+                                    .try_send(MeterMessage::Samples(data[..whole_frames].to_vec()));
+                            }
+
                             let sample_offset = controls.sample_offset_rx.try_lock();
                             if let Ok(offset_lock) = sample_offset {
                                 if let Ok(offset) = offset_lock.try_recv() {
@@ -502,11 +546,9 @@ pub mod cpal {
                                 i += 1;
                             }
 
-                            let length = MAX_FFT_SIZE;
-
                             for d in &data[..written] {
                                 // We still want to keep viz_data at a manageable size (e.g. 1024 or 2048)
-                                if viz_data.len() < MAX_FFT_SIZE {
+                                if viz_data.len() < length {
                                     viz_data.push(*d);
                                 }
                             }
@@ -571,7 +613,8 @@ pub mod cpal {
                             }
                             // Every x samples - send viz data to frontend
                             // Check if it's time to send (60 FPS gate)
-                            if last_viz_emit.elapsed() >= viz_interval && viz_data.len() == length {
+                            if last_viz_emit.elapsed() >= viz_interval && viz_data.len() >= length {
+                                viz_data.truncate(length);
                                 let viz = viz_data.clone();
                                 viz_data.clear();
                                 last_viz_emit = std::time::Instant::now(); // Reset timer
@@ -656,9 +699,12 @@ pub mod cpal {
                 stream,
                 resampler: None,
                 sample_rate: config.sample_rate,
+                channels: num_channels,
                 equalizer: None,
                 name: device.id().unwrap().to_string(),
                 time_base: time_base.clone(),
+                volume_state: recorder_volume_state,
+                volume_change,
             })))
         }
     }
@@ -730,6 +776,18 @@ pub mod cpal {
                 eq.process(samples);
             }
 
+            // Extra sinks get the processed signal before the volume is applied
+            broadcast::tap(samples, self.channels, self.sample_rate);
+            if recorder::is_recording() {
+                let volume = *self.volume_state.read().unwrap();
+                let audible: Vec<T> = samples
+                    .iter()
+                    .map(|s| (self.volume_change)(*s, volume))
+                    .collect();
+                let buffered_frames = (self.ring_buf.count() / self.channels) as u64;
+                recorder::tap(&audible, self.channels, self.sample_rate, buffered_frames);
+            }
+
             // Write all samples to the ring buffer.
             // info!("Writing samples: {}", samples.len());
             while let Ok(Some(written)) = self
@@ -989,10 +1047,102 @@ pub fn enumerate_devices() -> Vec<DeviceWithConfig> {
         .collect()
 }
 
+/// Window applied before the FFT
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyzerWindow {
+    Hann,
+    Hamming,
+    Blackman,
+    Rectangular,
+}
+
+impl AnalyzerWindow {
+    pub fn coefficient(&self, i: usize, n: usize) -> f32 {
+        let phase = 2.0 * std::f32::consts::PI * i as f32 / (n as f32 - 1.0);
+        match self {
+            AnalyzerWindow::Hann => 0.5 * (1.0 - phase.cos()),
+            AnalyzerWindow::Hamming => 0.54 - 0.46 * phase.cos(),
+            AnalyzerWindow::Blackman => 0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos(),
+            AnalyzerWindow::Rectangular => 1.0,
+        }
+    }
+}
+
+/// How FFT bins are grouped into bars
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyzerScale {
+    Log,
+    Mel,
+    Linear,
+}
+
+impl AnalyzerScale {
+    pub fn to_scale(&self, freq: f32) -> f32 {
+        match self {
+            AnalyzerScale::Log => freq.log2(),
+            AnalyzerScale::Mel => 2595.0 * (1.0 + freq / 700.0).log10(),
+            AnalyzerScale::Linear => freq,
+        }
+    }
+
+    pub fn from_scale(&self, value: f32) -> f32 {
+        match self {
+            AnalyzerScale::Log => value.exp2(),
+            AnalyzerScale::Mel => 700.0 * (10f32.powf(value / 2595.0) - 1.0),
+            AnalyzerScale::Linear => value,
+        }
+    }
+}
+
+/**
+ * Frequency analyzer settings from analyzer_control, anything left out keeps
+ * its current value.
+ *
+ * The data channel carries one byte per bar: the bars of each channel
+ * (left then right when stereo), followed by the peaks in the same layout
+ * when peak hold is on.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AnalyzerConfig {
+    /// Number of bars, 1 to 512
+    pub bins: Option<usize>,
+    /// Power of two, 256 to 16384
+    pub fft_size: Option<usize>,
+    pub window: Option<AnalyzerWindow>,
+    pub scale: Option<AnalyzerScale>,
+    /// Level shown as an empty bar
+    pub min_db: Option<f32>,
+    /// Level shown as a full bar
+    pub max_db: Option<f32>,
+    /// 0 to 1, how fast bars rise
+    pub attack: Option<f32>,
+    /// 0 to 1, how fast bars fall
+    pub decay: Option<f32>,
+    /// Separate bars for left and right instead of a mono mix
+    pub stereo: Option<bool>,
+    pub peak_hold: Option<bool>,
+    /// How much a held peak falls per update, 0 to 1 of the full range
+    pub peak_decay: Option<f32>,
+}
+
 pub struct AudioProcessor {
     num_bins: usize,
     sample_rate: f32, // Added sample rate
-    prev_bins: Vec<f32>,
+    channels: usize,
+    fft_size: usize,
+    window: AnalyzerWindow,
+    scale: AnalyzerScale,
+    min_db: f32,
+    max_db: f32,
+    stereo: bool,
+    peak_hold: bool,
+    peak_decay: f32,
+    /// Smoothed magnitudes per channel
+    prev_bins: Vec<Vec<f32>>,
+    /// Held peaks per channel, 0 to 1
+    peaks: Vec<Vec<f32>>,
     fft: Arc<dyn Fft<f32>>,
     attack: f32,
     decay: f32,
@@ -1002,6 +1152,19 @@ pub struct AudioProcessor {
 pub enum AnalyzerType {
     Time,
     Frequency,
+    /// Loudness, true peak, VU and phase correlation, see meters.rs
+    Meters,
+}
+
+impl AnalyzerType {
+    /// "time", "meters", anything else is the frequency analyzer
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "time" => AnalyzerType::Time,
+            "meters" => AnalyzerType::Meters,
+            _ => AnalyzerType::Frequency,
+        }
+    }
 }
 
 impl AudioProcessor {
@@ -1012,7 +1175,17 @@ impl AudioProcessor {
         Self {
             num_bins,
             sample_rate: 44100.0, // just to initialize, will be set later
-            prev_bins: vec![0.0; num_bins],
+            channels: 2,
+            fft_size,
+            window: AnalyzerWindow::Hann,
+            scale: AnalyzerScale::Log,
+            min_db: -100.0,
+            max_db: 0.0,
+            stereo: false,
+            peak_hold: false,
+            peak_decay: 0.01,
+            prev_bins: vec![vec![0.0; num_bins]],
+            peaks: vec![vec![0.0; num_bins]],
             fft,
             attack: 0.9,
             decay: 0.5,
@@ -1024,23 +1197,129 @@ impl AudioProcessor {
         self.sample_rate = sample_rate;
     }
 
+    /// Channels of the interleaved input
+    pub fn set_channels(&mut self, channels: usize) {
+        self.channels = channels.max(1);
+        FREQUENCY_INPUT_LEN.store(self.frequency_input_len(), Ordering::Relaxed);
+    }
+
+    pub fn configure(&mut self, config: &AnalyzerConfig) {
+        let layout = (self.num_bins, self.stereo, self.peak_hold);
+        if let Some(bins) = config.bins {
+            self.num_bins = bins.clamp(1, 512);
+        }
+        if let Some(fft_size) = config.fft_size {
+            let fft_size = fft_size.clamp(256, 16384).next_power_of_two().min(16384);
+            if fft_size != self.fft_size {
+                self.fft_size = fft_size;
+                self.fft = FftPlanner::new().plan_fft_forward(fft_size);
+            }
+        }
+        if let Some(window) = config.window {
+            self.window = window;
+        }
+        if let Some(scale) = config.scale {
+            self.scale = scale;
+        }
+        if let Some(min_db) = config.min_db {
+            self.min_db = min_db;
+        }
+        if let Some(max_db) = config.max_db {
+            self.max_db = max_db;
+        }
+        if self.max_db <= self.min_db {
+            self.max_db = self.min_db + 1.0;
+        }
+        if let Some(attack) = config.attack {
+            self.attack = attack.clamp(0.0, 1.0);
+        }
+        if let Some(decay) = config.decay {
+            self.decay = decay.clamp(0.0, 1.0);
+        }
+        if let Some(stereo) = config.stereo {
+            self.stereo = stereo;
+        }
+        if let Some(peak_hold) = config.peak_hold {
+            self.peak_hold = peak_hold;
+        }
+        if let Some(peak_decay) = config.peak_decay {
+            self.peak_decay = peak_decay.clamp(0.0, 1.0);
+        }
+        // Only a new layout starts over, the same settings sent again keep the bars
+        if (self.num_bins, self.stereo, self.peak_hold) != layout {
+            self.prev_bins.clear();
+            self.peaks.clear();
+        }
+        FREQUENCY_INPUT_LEN.store(self.frequency_input_len(), Ordering::Relaxed);
+    }
+
+    /// Interleaved samples needed for one frequency analysis
+    fn frequency_input_len(&self) -> usize {
+        self.fft_size * self.channels
+    }
+
     pub fn compute_freq(&mut self, input: &[f32]) -> Vec<u8> {
-        let n = input.len();
-        if n == 0 {
-            return vec![0; self.num_bins];
+        let channels = self.channels;
+        let frames = input.len() / channels;
+        let outputs = if self.stereo { 2 } else { 1 };
+        if self.prev_bins.len() != outputs || self.prev_bins[0].len() != self.num_bins {
+            self.prev_bins = vec![vec![0.0; self.num_bins]; outputs];
+            self.peaks = vec![vec![0.0; self.num_bins]; outputs];
+        }
+        if frames == 0 {
+            return vec![0; self.num_bins * outputs * if self.peak_hold { 2 } else { 1 }];
         }
 
-        // 1. Windowing & FFT
-        let mut buffer: Vec<Complex<f32>> = input
-            .iter()
-            .enumerate()
-            .map(|(i, &val)| {
-                let window =
-                    0.5 * (1.0 - (2.0 * std::f32::consts::PI * i as f32 / (n as f32 - 1.0)).cos());
-                Complex {
-                    re: val * window,
-                    im: 0.0,
-                }
+        let mut bars = Vec::with_capacity(self.num_bins * outputs);
+        for output in 0..outputs {
+            // Mono mixes all channels, stereo takes the first two (a mono output goes to both sides)
+            let signal: Vec<f32> = input
+                .chunks_exact(channels)
+                .map(|frame| {
+                    if outputs == 2 {
+                        frame[output.min(channels - 1)]
+                    } else {
+                        frame.iter().sum::<f32>() / channels as f32
+                    }
+                })
+                .collect();
+            let mags = self.bar_magnitudes(&signal);
+
+            // Temporal smoothing & scaling
+            for (i, avg_mag) in mags.into_iter().enumerate() {
+                let prev = self.prev_bins[output][i];
+                let factor = if avg_mag > prev {
+                    self.attack
+                } else {
+                    self.decay
+                };
+                let smoothed = prev + (avg_mag - prev) * factor;
+                self.prev_bins[output][i] = smoothed;
+
+                let level = self.scale_to_level(smoothed);
+                let peak = &mut self.peaks[output][i];
+                *peak = (*peak - self.peak_decay).max(level);
+                bars.push((level * 255.0) as u8);
+            }
+        }
+
+        if self.peak_hold {
+            for peaks in &self.peaks {
+                bars.extend(peaks.iter().map(|peak| (peak * 255.0) as u8));
+            }
+        }
+        bars
+    }
+
+    /// Windowed FFT of one channel, grouped into bars
+    fn bar_magnitudes(&self, signal: &[f32]) -> Vec<f32> {
+        let n = self.fft_size;
+
+        // 1. Windowing & FFT, zero padded if there is not enough input
+        let mut buffer: Vec<Complex<f32>> = (0..n)
+            .map(|i| Complex {
+                re: signal.get(i).copied().unwrap_or(0.0) * self.window.coefficient(i, n),
+                im: 0.0,
             })
             .collect();
         self.fft.process(&mut buffer);
@@ -1051,7 +1330,9 @@ impl AudioProcessor {
         let mut current_mags = vec![0.0f32; self.num_bins];
         let mut counts = vec![0usize; self.num_bins];
         let min_freq = 20.0; // Sub-bass
-        let max_freq = 20000.0; // Top of human hearing
+        let max_freq = 20000.0f32.min(self.sample_rate / 2.0); // Top of human hearing
+        let scale_min = self.scale.to_scale(min_freq);
+        let scale_max = self.scale.to_scale(max_freq);
 
         for (i, c) in buffer[1..safe_limit].iter().enumerate() {
             let bin_idx_raw = i + 1;
@@ -1069,12 +1350,12 @@ impl AudioProcessor {
             let slope_factor = 10.0f32.powf((octaves * 3.0) / 20.0);
             let corrected_mag = mag * slope_factor;
 
-            // Map frequency to visual bin using a Logarithmic scale relative to our range
+            // Map frequency to visual bin on the chosen scale relative to our range
             // This ensures 20Hz is the first bar and 20kHz is the last.
-            let log_pos = ((freq / min_freq).log2() / (max_freq / min_freq).log2())
+            let pos = ((self.scale.to_scale(freq) - scale_min) / (scale_max - scale_min))
                 .clamp(0.0, 1.0 - f32::EPSILON);
 
-            let v_bin = (log_pos * self.num_bins as f32) as usize;
+            let v_bin = (pos * self.num_bins as f32) as usize;
 
             current_mags[v_bin] += corrected_mag.powi(2);
             counts[v_bin] += 1;
@@ -1098,34 +1379,20 @@ impl AudioProcessor {
             }
         }
 
-        // 4. Temporal smoothing & scaling
-        let mut output = Vec::with_capacity(self.num_bins);
-        for i in 0..self.num_bins {
-            let avg_mag = (current_mags[i] / counts[i] as f32).sqrt();
-            let factor = if avg_mag > self.prev_bins[i] {
-                self.attack
-            } else {
-                self.decay
-            };
-            let smoothed = self.prev_bins[i] + (avg_mag - self.prev_bins[i]) * factor;
-            self.prev_bins[i] = smoothed;
-
-            output.push(self.scale_to_u8(smoothed, n));
-        }
-
-        output
+        current_mags
+            .iter()
+            .zip(counts.iter())
+            .map(|(mag, count)| (mag / *count as f32).sqrt())
+            .collect()
     }
 
-    fn scale_to_u8(&self, mag: f32, n: usize) -> u8 {
-        let normalized = mag / n as f32;
+    /// 0 to 1 within the configured dB range
+    fn scale_to_level(&self, mag: f32) -> f32 {
+        let normalized = mag / self.fft_size as f32;
         let db = 20.0 * (normalized + 1e-9).log10();
 
-        // Standardized range
-        let min_db = -100.0;
-        let max_db = 0.0;
-
-        let res = (db - min_db) / (max_db - min_db);
-        (res.clamp(0.02, 1.0) * 255.0) as u8
+        let res = (db - self.min_db) / (self.max_db - self.min_db);
+        res.clamp(0.02, 1.0)
     }
 
     pub fn compute_time(&mut self, input: &[f32]) -> Vec<u8> {
@@ -6,20 +6,17 @@ use std::time::Instant;
 use atomic_wait::wake_all;
 use cpal::traits::{DeviceTrait, HostTrait};
 use log::{error, info, warn};
-use memmap2::Mmap;
 use serde::{Deserialize, Serialize};
-use serde_bytes::ByteBuf;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::Cursor;
 use std::path::Path;
 use std::sync::atomic::{AtomicBool, AtomicU32};
 use std::sync::Arc;
-use symphonia::core::audio::{Layout, SampleBuffer, SignalSpec};
+use symphonia::core::audio::{AsAudioBufferRef, Signal, SignalSpec};
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
 use symphonia::core::errors::Error::ResetRequired;
 use symphonia::core::formats::{FormatOptions, SeekTo, Track};
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
 use symphonia::core::units::Time;
@@ -41,17 +38,20 @@ use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 use webrtc::peer_connection::RTCPeerConnection;
 
+use crate::chapters::{chapter_at, chapter_target, ChapterRequest, CurrentChapter};
 use crate::constants::*;
-use crate::dsp;
-use crate::dsp::calculate_peak_value;
+use crate::history::{PlaybackEvent, PlaybackEvents};
 #[cfg(target_os = "macos")]
 use crate::mediakeys;
 use crate::metadata::{FileInfo, Song};
+#[cfg(target_os = "linux")]
+use crate::mpris;
 use crate::output::{
-    self, get_device_by_id, AnalyzerState, AnalyzerType, AudioOutput, DeviceWithConfig,
-    PlaybackState,
+    self, get_device_by_id, get_visualizer, AnalyzerConfig, AnalyzerState, AnalyzerType,
+    AudioOutput, DeviceWithConfig, PlaybackState,
 };
 use crate::store::load_settings;
+use crate::stream::{is_stream_url, stream_song, HttpStream};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PlayFileRequest {
@@ -60,17 +60,9 @@ pub struct PlayFileRequest {
     pub file_info: Option<FileInfo>,
     pub volume: Option<f64>,
     pub boot: Option<bool>,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-pub struct GetWaveformRequest {
-    pub path: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(rename_all = "camelCase")]
-struct GetWaveformResponse {
-    data: Option<ByteBuf>,
+    /// Set for virtual tracks from a CUE sheet, see [`crate::cue`]
+    pub cue_path: Option<String>,
+    pub cue_track: Option<u32>,
 }
 
 #[derive(Clone, Debug)]
@@ -105,6 +97,7 @@ pub enum PlayerControlEvent {
     ChangePlaybackSpeed(PlaybackSpeedControlEvent),
     ChangeAnalyzer(AnalyzerControlEvent),
     ChangeEqualizer(EqualizerControlEvent),
+    ChangeChapter(ChapterRequest),
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -121,6 +114,9 @@ pub struct PlaybackSpeedControlEvent {
 pub struct AnalyzerControlEvent {
     is_enabled: Option<bool>,
     analyzer_type: Option<String>,
+    /// Frequency analyzer settings, applied right away
+    #[serde(flatten)]
+    config: AnalyzerConfig,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -150,6 +146,32 @@ pub fn loop_region(
         .send(PlayerControlEvent::LoopRegion(event));
 }
 
+#[tauri::command]
+pub fn next_chapter(state: State<AudioPlayer>) {
+    info!("Next chapter");
+    let _ = state
+        .player_control_sender
+        .send(PlayerControlEvent::ChangeChapter(ChapterRequest::Next));
+}
+
+#[tauri::command]
+pub fn previous_chapter(state: State<AudioPlayer>) {
+    info!("Previous chapter");
+    let _ = state
+        .player_control_sender
+        .send(PlayerControlEvent::ChangeChapter(ChapterRequest::Previous));
+}
+
+#[tauri::command]
+pub fn jump_to_chapter(index: usize, state: State<AudioPlayer>) {
+    info!("Jump to chapter {}", index);
+    let _ = state
+        .player_control_sender
+        .send(PlayerControlEvent::ChangeChapter(ChapterRequest::Jump(
+            index,
+        )));
+}
+
 #[tauri::command]
 pub fn change_audio_device(
     event: ChangeAudioDeviceRequest,
@@ -168,6 +190,10 @@ pub fn change_audio_device(
 #[tauri::command]
 pub fn volume_control(event: VolumeControlEvent, state: State<AudioPlayer>) {
     info!("Received volume_control event");
+    #[cfg(target_os = "linux")]
+    if let Some(volume) = event.volume {
+        mpris::set_volume(volume);
+    }
     match state.volume_control_sender.send(event) {
         Ok(_) => {
             // info!("Sent control flow info");
@@ -181,6 +207,10 @@ pub fn volume_control(event: VolumeControlEvent, state: State<AudioPlayer>) {
 #[tauri::command]
 pub fn playback_speed_control(event: PlaybackSpeedControlEvent, state: State<AudioPlayer>) {
     info!("Received playback_speed_control event");
+    #[cfg(target_os = "linux")]
+    if let Some(speed) = event.playback_speed {
+        mpris::set_rate(speed);
+    }
 
     match state
         .player_control_sender
@@ -198,6 +228,7 @@ pub fn playback_speed_control(event: PlaybackSpeedControlEvent, state: State<Aud
 #[tauri::command]
 pub fn analyzer_control(event: AnalyzerControlEvent, state: State<AudioPlayer>) {
     info!("Received analyzer_control event");
+    get_visualizer().lock().unwrap().configure(&event.config);
 
     match state
         .player_control_sender
@@ -295,6 +326,7 @@ pub struct AudioPlayer<'a> {
     pub volume_control_receiver: Arc<Mutex<Receiver<VolumeControlEvent>>>,
     pub volume_control_sender: Sender<VolumeControlEvent>,
     pub waiting_for_boot: Arc<AtomicBool>,
+    pub playback_events: PlaybackEvents,
     phantom: PhantomData<&'a RTCPeerConnection>,
     phantom2: PhantomData<&'a RTCDataChannel>,
 }
@@ -322,6 +354,7 @@ impl<'a> AudioPlayer<'a> {
             volume_control_receiver: Arc::new(Mutex::new(receiver_vol)),
             volume_control_sender: sender_vol,
             waiting_for_boot: Arc::new(AtomicBool::new(true)),
+            playback_events: PlaybackEvents::default(),
             phantom: PhantomData,
             phantom2: PhantomData,
         })
@@ -333,6 +366,7 @@ impl<'a> AudioPlayer<'a> {
         let decoding_active = self.decoding_active.clone();
         let volume_control_receiver = self.volume_control_receiver.clone();
         let data_channel = self.data_channel.clone();
+        let playback_events = self.playback_events.clone();
 
         std::thread::spawn(move || {
             // AUDIO THREAD!
@@ -344,6 +378,7 @@ impl<'a> AudioPlayer<'a> {
                 &receiver,
                 &next_track_receiver,
                 data_channel,
+                &playback_events,
                 &app_handle,
             );
         });
@@ -577,6 +612,7 @@ pub fn start_audio(
     player_control_receiver: &Arc<Mutex<Receiver<PlayerControlEvent>>>,
     next_track_receiver: &Arc<Mutex<Receiver<PlayFileRequest>>>,
     data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+    playback_events: &PlaybackEvents,
     app_handle: &AppHandle,
 ) {
     let decoding_active = decoding_active.clone();
@@ -591,6 +627,7 @@ pub fn start_audio(
         next_track_receiver,
         decoding_active,
         data_channel,
+        playback_events,
         app_handle,
     );
 }
@@ -607,12 +644,15 @@ fn decode_loop(
     next_track_receiver: &Arc<Mutex<Receiver<PlayFileRequest>>>,
     decoding_active: Arc<AtomicU32>,
     data_channel: Arc<Mutex<Option<Arc<RTCDataChannel>>>>,
+    playback_events: &PlaybackEvents,
     app_handle: &AppHandle,
 ) {
     // These will be reset when changing tracks
     let mut path_str: Option<String> = None;
     let mut path_str_clone: Option<String>;
     let mut seek = None;
+    // Virtual track from a CUE sheet (cue path, track number)
+    let mut cue_track: Option<(String, u32)> = None;
 
     /* Previous seek and duration used to time song
      * info change during gapless transition */
@@ -691,6 +731,7 @@ fn decode_loop(
                 match result {
                     PlayerControlEvent::StreamFile(request) => {
                         info!("audio: got file request! {:?}", request);
+                        cue_track = request.cue_path.clone().zip(request.cue_track);
                         path_str.replace(request.path.unwrap());
                         prev_seek = seek.unwrap_or(0.0);
                         seek.replace(request.seek.unwrap());
@@ -716,6 +757,7 @@ fn decode_loop(
                         info!("audio: change playback speed! {:?}", request);
                         if let Some(speed) = request.playback_speed {
                             playback_speed = speed;
+                            playback_events.send(PlaybackEvent::SpeedChanged(speed));
                         }
                     }
                     PlayerControlEvent::ChangeAnalyzer(request) => {
@@ -725,11 +767,7 @@ fn decode_loop(
                         if let Some(request_type) = &request.analyzer_type {
                             let new_state = AnalyzerState {
                                 is_enabled: request.is_enabled.unwrap_or(true),
-                                analyzer_type: if request_type.eq("time") {
-                                    Some(AnalyzerType::Time)
-                                } else {
-                                    Some(AnalyzerType::Frequency)
-                                },
+                                analyzer_type: Some(AnalyzerType::from_name(request_type)),
                             };
 
                             analyzer_state.replace(new_state.clone());
@@ -742,6 +780,12 @@ fn decode_loop(
                         info!("audio: change equalizer settings! {:?}", request);
                         equalizer_settings.replace(request);
                     }
+                    PlayerControlEvent::ChangeChapter(request) => {
+                        info!(
+                            "audio: no track playing, ignoring chapter change {:?}",
+                            request
+                        );
+                    }
                 }
             }
         } else if let Some(ref p) = path_str.clone() {
@@ -749,24 +793,46 @@ fn decode_loop(
 
             // Create a hint to help the format registry guess what format reader is appropriate.
             let mut hint = Hint::new();
-            let fl = File::open(path);
+            let mut stream_station = None;
+
+            let source: Box<dyn MediaSource> = if is_stream_url(p) {
+                match HttpStream::open(p, app_handle.clone()) {
+                    Ok(stream) => {
+                        info!("source {:?} ({:?})", p, stream.content_type);
+                        if let Some(extension) = stream.extension_hint() {
+                            hint.with_extension(extension);
+                        }
+                        stream_station = stream.station.clone();
+                        Box::new(stream)
+                    }
+                    Err(err) => {
+                        path_str = None;
+                        error!("Error opening stream: {}", err);
+                        app_handle.emit("error", "Error opening stream").unwrap();
+                        continue;
+                    }
+                }
+            } else {
+                let fl = File::open(path);
 
-            if fl.is_err() {
-                path_str = None;
-                error!("Error opening file: {}", fl.err().unwrap());
-                continue;
-            }
+                if fl.is_err() {
+                    path_str = None;
+                    error!("Error opening file: {}", fl.err().unwrap());
+                    continue;
+                }
 
-            let source = Box::new(fl.unwrap());
-            info!("source {:?}", source);
+                let source = Box::new(fl.unwrap());
+                info!("source {:?}", source);
 
-            // Provide the file extension as a hint.
-            info!("extension: {:?}", path.extension());
-            if let Some(extension) = path.extension() {
-                if let Some(extension_str) = extension.to_str() {
-                    hint.with_extension(extension_str);
+                // Provide the file extension as a hint.
+                info!("extension: {:?}", path.extension());
+                if let Some(extension) = path.extension() {
+                    if let Some(extension_str) = extension.to_str() {
+                        hint.with_extension(extension_str);
+                    }
                 }
-            }
+                source
+            };
 
             // Create the media source stream using the boxed media source from above.
             let mss = MediaSourceStream::new(source, Default::default());
@@ -809,6 +875,7 @@ fn decode_loop(
                     if let Some(path) = request.path.clone() {
                         is_transition = true;
                         info!("player: next track received! {:?}", request);
+                        cue_track = request.cue_path.clone().zip(request.cue_track);
                         path_str.replace(path);
                         seek.replace(request.seek.unwrap());
                         volume.replace(request.volume.unwrap());
@@ -818,6 +885,8 @@ fn decode_loop(
 
                         info!("player: nothing else in the queue");
                         let _ = app_handle.emit("end_of_queue", Some(0.0f64));
+                        #[cfg(target_os = "linux")]
+                        mpris::set_stopped();
                     }
                 } else {
                     path_str = None;
@@ -839,13 +908,41 @@ fn decode_loop(
 
             let mut track_id = track.id;
 
+            let song = if let Some((cue_path, track_number)) = &cue_track {
+                crate::cue::get_cue_song(Path::new(cue_path), *track_number, &app_handle)
+            } else if is_stream_url(p) {
+                Some(stream_song(p, stream_station))
+            } else {
+                crate::metadata::extract_metadata(
+                    &Path::new(&p.clone().as_str()),
+                    false,
+                    false,
+                    true,
+                    false,
+                    &app_handle,
+                )
+            };
+
+            if prev_song.is_none() {
+                prev_song = song.clone();
+            }
+
+            // CUE tracks are a region of the file: seek positions and timestamps are relative to the track start
+            let track_start = song.as_ref().and_then(|s| s.start_offset).unwrap_or(0.0);
+            let track_end = song.as_ref().and_then(|s| s.end_offset);
+            let seek_time = match seek {
+                Some(sk) => Some(sk + track_start),
+                None if track_start > 0.0 => Some(track_start),
+                None => None,
+            };
+
             // If seeking, seek the reader to the time or timestamp specified and get the timestamp of the
             // seeked position. All packets with a timestamp < the seeked position will not be played.
             //
             // Note: This is a half-baked approach to seeking! After seeking the reader, packets should be
             // decoded and *samples* discarded up-to the exact *sample* indicated by required_ts. The
             // current approach will discard excess samples if seeking to a sample within a packet.
-            let seek_ts = if let Some(sk) = seek {
+            let seek_ts = if let Some(sk) = seek_time {
                 let seek_to = SeekTo::Time {
                     time: Time::from(sk),
                     track_id: Some(track_id),
@@ -880,7 +977,10 @@ fn decode_loop(
             let mut channels;
             let mut first_packet = None;
 
-            if path.extension().and_then(|ext| ext.to_str()) == Some("m4a") {
+            if matches!(
+                path.extension().and_then(|ext| ext.to_str()),
+                Some("m4a") | Some("m4b")
+            ) {
                 if let Ok(packet) = reader.next_packet() {
                     if let Ok(buffer) = decoder.decode(&packet) {
                         channels = buffer.spec().channels;
@@ -901,6 +1001,11 @@ fn decode_loop(
                 channels,
             };
 
+            let track_start_ts = (track_start * spec.rate as f64) as u64;
+            let track_end_ts = track_end.map(|end| (end * spec.rate as f64) as u64);
+            // Position reported to the output, relative to the start of the (virtual) track
+            let offset_ts = seek_ts.saturating_sub(track_start_ts);
+
             let mut should_reset_audio = false;
             let mut max_frames_changed = false;
 
@@ -1016,19 +1121,6 @@ fn decode_loop(
                 }
             }
 
-            let song = crate::metadata::extract_metadata(
-                &Path::new(&p.clone().as_str()),
-                false,
-                false,
-                true,
-                false,
-                &app_handle,
-            );
-
-            if prev_song.is_none() {
-                prev_song = song.clone();
-            }
-
             if audio_output.is_none() || should_reset_audio {
                 info!("player: Resetting audio device");
                 // Try to open the audio output.
@@ -1049,10 +1141,17 @@ fn decode_loop(
                                     // Send song change event
                                     if let Some(s) = &song {
                                         let _ = app_handle.emit("song_change", Some(s));
+                                        playback_events.send(PlaybackEvent::Ended);
+                                        playback_events.send(PlaybackEvent::Started {
+                                            song: s.clone(),
+                                            output_device: device_id.clone(),
+                                            playback_speed,
+                                            position: 0.0,
+                                        });
                                         let _ = reset_control_sender.send(true);
                                         let _ = sender_sample_offset.send(SampleOffsetEvent {
                                             sample_offset: Some(
-                                                seek_ts
+                                                offset_ts
                                                     * track.codec_params.channels.unwrap().count()
                                                         as u64,
                                             ),
@@ -1103,13 +1202,21 @@ fn decode_loop(
                 }
                 let _ = device_change_sender.send(clone_device_name);
                 let _ = app_handle.emit("audio_device_changed", clone_device_name2);
+                if let Some(s) = &song {
+                    playback_events.send(PlaybackEvent::Started {
+                        song: s.clone(),
+                        output_device: device_id.clone(),
+                        playback_speed,
+                        position: seek.unwrap_or(0.0),
+                    });
+                }
                 let _ = sender_sample_offset.send(SampleOffsetEvent {
-                    sample_offset: Some(seek_ts * previous_channels as u64),
+                    sample_offset: Some(offset_ts * previous_channels as u64),
                 });
             }
 
             let end_pos_frame_idx = if end_pos.is_some() {
-                (end_pos.unwrap() * previous_sample_rate as f64) as u64
+                ((end_pos.unwrap() + track_start) * previous_sample_rate as f64) as u64
             } else {
                 0
             };
@@ -1151,12 +1258,40 @@ fn decode_loop(
                         if let Some(s) = &song {
                             #[cfg(target_os = "macos")]
                             mediakeys::set_now_playing_info(s);
+                            #[cfg(target_os = "linux")]
+                            mpris::set_now_playing_info(s, app_handle);
                         }
 
+                        // Whether the OS media controls know we're playing, only reported on changes
+                        // (starting, seeking, resuming) rather than for every packet
+                        let mut reported_playing = false;
+
+                        // Chapter currently playing, reported when it changes. Starts out unreported
+                        // so a song without chapters clears the previous song's chapter
+                        let mut current_chapter: Option<Option<usize>> = None;
+
                         // Decode all packets, ignoring all decode errors.
                         let result = loop {
                             if let Ok(ts) = timestamp_receiver.try_recv() {
                                 timestamp = ts;
+                                playback_events.send(PlaybackEvent::Position(ts));
+                                #[cfg(target_os = "linux")]
+                                mpris::set_position(ts);
+
+                                let chapter =
+                                    song.as_ref().and_then(|s| chapter_at(&s.chapters, ts));
+                                if !is_transition && current_chapter != Some(chapter) {
+                                    current_chapter = Some(chapter);
+                                    let _ = app_handle.emit(
+                                        "chapter_change",
+                                        chapter.zip(song.as_ref()).map(|(index, s)| {
+                                            CurrentChapter {
+                                                index,
+                                                chapter: s.chapters[index].clone(),
+                                            }
+                                        }),
+                                    );
+                                }
                             }
                             let event = receiver.try_recv();
                             // debug!("audio: waiting for event {:?}", event);
@@ -1177,6 +1312,7 @@ fn decode_loop(
                                             guard.pause();
                                         }
 
+                                        cue_track = request.cue_path.clone().zip(request.cue_track);
                                         path_str.replace(request.path.unwrap());
                                         prev_seek = seek.unwrap();
                                         prev_song = song.clone();
@@ -1216,6 +1352,8 @@ fn decode_loop(
                                         info!("audio: change playback speed! {:?}", request);
                                         if let Some(speed) = request.playback_speed {
                                             playback_speed = speed;
+                                            playback_events
+                                                .send(PlaybackEvent::SpeedChanged(speed));
                                         }
                                         // while guard.has_remaining_samples() {
                                         //     guard.flush();
@@ -1240,11 +1378,9 @@ fn decode_loop(
                                         if let Some(request_type) = &request.analyzer_type {
                                             let new_state = AnalyzerState {
                                                 is_enabled: request.is_enabled.unwrap_or(true),
-                                                analyzer_type: if request_type.eq("time") {
-                                                    Some(AnalyzerType::Time)
-                                                } else {
-                                                    Some(AnalyzerType::Frequency)
-                                                },
+                                                analyzer_type: Some(AnalyzerType::from_name(
+                                                    request_type,
+                                                )),
                                             };
 
                                             analyzer_state.replace(new_state.clone());
@@ -1261,6 +1397,20 @@ fn decode_loop(
                                             request.is_enabled.unwrap_or(false),
                                         );
                                     }
+                                    PlayerControlEvent::ChangeChapter(request) => {
+                                        info!("audio: change chapter! {:?}", request);
+                                        let target = song.as_ref().and_then(|s| {
+                                            chapter_target(&s.chapters, timestamp, &request)
+                                        });
+                                        if let Some(target) = target {
+                                            path_str.replace(path_str_clone.clone().unwrap());
+                                            seek.replace(target);
+                                            end_pos = None;
+                                            guard.flush();
+                                            is_reset = true;
+                                            is_transition = false;
+                                        }
+                                    }
                                 }
                             }
 
@@ -1275,8 +1425,12 @@ fn decode_loop(
                                     playback_speed,
                                 });
                                 let _ = app_handle.emit("paused", {});
+                                playback_events.send(PlaybackEvent::Paused);
                                 #[cfg(target_os = "macos")]
                                 mediakeys::set_paused();
+                                #[cfg(target_os = "linux")]
+                                mpris::set_paused();
+                                reported_playing = false;
                             }
 
                             // waits while the value is PAUSED (0)
@@ -1316,6 +1470,8 @@ fn decode_loop(
                                                 "audio: source changed during decoding! {:?}",
                                                 request
                                             );
+                                            cue_track =
+                                                request.cue_path.clone().zip(request.cue_track);
                                             path_str.replace(request.path.unwrap());
                                             prev_seek = seek.unwrap_or(0.0);
                                             prev_song = song.clone();
@@ -1362,6 +1518,8 @@ fn decode_loop(
                                             info!("audio: change playback speed! {:?}", request);
                                             if let Some(speed) = request.playback_speed {
                                                 playback_speed = speed;
+                                                playback_events
+                                                    .send(PlaybackEvent::SpeedChanged(speed));
                                             }
                                             // while guard.has_remaining_samples() {
                                             //     guard.flush();
@@ -1384,11 +1542,9 @@ fn decode_loop(
                                             if let Some(request_type) = &request.analyzer_type {
                                                 let new_state = AnalyzerState {
                                                     is_enabled: request.is_enabled.unwrap_or(true),
-                                                    analyzer_type: if request_type.eq("time") {
-                                                        Some(AnalyzerType::Time)
-                                                    } else {
-                                                        Some(AnalyzerType::Frequency)
-                                                    },
+                                                    analyzer_type: Some(AnalyzerType::from_name(
+                                                        request_type,
+                                                    )),
                                                 };
 
                                                 analyzer_state.replace(new_state.clone());
@@ -1408,11 +1564,26 @@ fn decode_loop(
                                                 request.is_enabled.unwrap_or(false),
                                             );
                                         }
+                                        PlayerControlEvent::ChangeChapter(request) => {
+                                            info!("audio: change chapter! {:?}", request);
+                                            let target = song.as_ref().and_then(|s| {
+                                                chapter_target(&s.chapters, timestamp, &request)
+                                            });
+                                            if let Some(target) = target {
+                                                path_str.replace(path_str_clone.clone().unwrap());
+                                                seek.replace(target);
+                                                end_pos = None;
+                                                guard.flush();
+                                                is_reset = true;
+                                                is_transition = false;
+                                            }
+                                        }
                                     }
                                 }
 
                                 if should_resume {
                                     guard.resume();
+                                    playback_events.send(PlaybackEvent::Resumed);
                                 }
                             }
 
@@ -1424,9 +1595,14 @@ fn decode_loop(
                                 is_playing: true,
                                 playback_speed,
                             });
-                            let _ = app_handle.emit("playing", {});
-                            #[cfg(target_os = "macos")]
-                            mediakeys::set_playing();
+                            if !reported_playing {
+                                reported_playing = true;
+                                let _ = app_handle.emit("playing", {});
+                                #[cfg(target_os = "macos")]
+                                mediakeys::set_playing();
+                                #[cfg(target_os = "linux")]
+                                mpris::set_playing();
+                            }
 
                             let packet = if let Some(packet) = first_packet.take() {
                                 packet
@@ -1444,9 +1620,20 @@ fn decode_loop(
 
                             // Loop region mode: If this packet is past the loop region,
                             // seek the reader back to the start point
+                            // CUE track mode: the end of the track is the end of the stream,
+                            // so we move on to the next track instead of playing into it
+                            if track_end_ts.is_some_and(|end_ts| packet.ts >= end_ts) {
+                                break Err(symphonia::core::errors::Error::IoError(
+                                    std::io::Error::new(
+                                        std::io::ErrorKind::UnexpectedEof,
+                                        "end of stream",
+                                    ),
+                                ));
+                            }
+
                             if end_pos.is_some() && packet.ts > end_pos_frame_idx {
                                 let seek_to = SeekTo::Time {
-                                    time: Time::from(seek.unwrap()),
+                                    time: Time::from(seek.unwrap() + track_start),
                                     track_id: Some(track_id),
                                 };
                                 info!(
@@ -1489,15 +1676,18 @@ fn decode_loop(
                                         // but we use the previous track's seek and duration info for this logic
                                         // Check if seek position is within transition zone
                                         // If so - make transition shorter by delta
+                                        // Streams have no duration, so no transition zone to shorten
                                         let duration =
-                                            prev_song.clone().unwrap().file_info.duration.unwrap();
+                                            prev_song.as_ref().and_then(|s| s.file_info.duration);
                                         let seeked_to = prev_seek;
                                         let mut delta = 0.0;
 
-                                        if seeked_to > duration - BUFFER_SIZE
-                                            && seeked_to < duration
-                                        {
-                                            delta = duration - seeked_to;
+                                        if let Some(duration) = duration {
+                                            if seeked_to > duration - BUFFER_SIZE
+                                                && seeked_to < duration
+                                            {
+                                                delta = duration - seeked_to;
+                                            }
                                         }
 
                                         if transition_time.elapsed().as_secs_f64() * playback_speed
@@ -1512,7 +1702,7 @@ fn decode_loop(
                                                 let _ =
                                                     sender_sample_offset.send(SampleOffsetEvent {
                                                         sample_offset: Some(
-                                                            seek_ts * previous_channels as u64,
+                                                            offset_ts * previous_channels as u64,
                                                         ),
                                                     });
                                             }
@@ -1520,13 +1710,20 @@ fn decode_loop(
                                             if let Some(s) = &song {
                                                 if end_pos.is_none() {
                                                     let _ = app_handle.emit("song_change", Some(s));
+                                                    playback_events.send(PlaybackEvent::Ended);
+                                                    playback_events.send(PlaybackEvent::Started {
+                                                        song: s.clone(),
+                                                        output_device: device_id.clone(),
+                                                        playback_speed,
+                                                        position: 0.0,
+                                                    });
                                                 }
 
                                                 let _ = reset_control_sender.send(true);
                                                 let _ =
                                                     sender_sample_offset.send(SampleOffsetEvent {
                                                         sample_offset: Some(
-                                                            seek_ts * previous_channels as u64,
+                                                            offset_ts * previous_channels as u64,
                                                         ),
                                                     });
                                             } else {
@@ -1559,7 +1756,25 @@ fn decode_loop(
                                                     }
                                                 }
                                             }
-                                            guard.write(_decoded, ramp_up_smpls, ramp_down_smpls);
+                                            if let Some(end_ts) = track_end_ts
+                                                .filter(|end_ts| packet.ts + packet.dur > *end_ts)
+                                            {
+                                                // Only write up to the end of the CUE track
+                                                let mut trimmed = _decoded.make_equivalent::<f32>();
+                                                _decoded.convert(&mut trimmed);
+                                                trimmed.truncate((end_ts - packet.ts) as usize);
+                                                guard.write(
+                                                    trimmed.as_audio_buffer_ref(),
+                                                    ramp_up_smpls,
+                                                    ramp_down_smpls,
+                                                );
+                                            } else {
+                                                guard.write(
+                                                    _decoded,
+                                                    ramp_up_smpls,
+                                                    ramp_down_smpls,
+                                                );
+                                            }
                                         }
                                     }
 
@@ -1591,6 +1806,7 @@ fn decode_loop(
                                         is_transition = true;
                                         resampler_delay = guard.get_resampler_delay();
                                         info!("player: next track received! {:?}", request);
+                                        cue_track = request.cue_path.clone().zip(request.cue_track);
                                         path_str.replace(path);
                                         prev_seek = seek.unwrap_or(0.0);
                                         prev_song = song.clone();
@@ -1607,7 +1823,12 @@ fn decode_loop(
                                         }
                                         info!("Buffer is now empty. Pausing stream...");
                                         guard.pause();
+                                        playback_events.send(PlaybackEvent::Ended);
                                         let _ = app_handle.emit("end_of_queue", Some(0.0f64));
+                                        #[cfg(target_os = "macos")]
+                                        mediakeys::set_paused();
+                                        #[cfg(target_os = "linux")]
+                                        mpris::set_stopped();
                                     }
                                 }
                                 // Do not treat "end of stream" as a fatal error. It's the currently only way a
@@ -1631,213 +1852,6 @@ fn first_supported_track(tracks: &[Track]) -> Option<&Track> {
         .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
 }
 
-pub fn get_peaks(
-    event: GetWaveformRequest,
-    app_handle: &AppHandle,
-    cancel_token: CancellationToken,
-) -> Result<Vec<f32>, symphonia::core::errors::Error> {
-    let binding = event.path.unwrap();
-    let path = Path::new(binding.as_str());
-
-    // Create a hint to help the format registry guess what format reader is appropriate.
-    let mut hint = Hint::new();
-
-    // Provide the file extension as a hint.
-    info!("extension: {:?}", path.extension());
-    if let Some(extension) = path.extension() {
-        if let Some(extension_str) = extension.to_str() {
-            hint.with_extension(extension_str);
-        }
-    }
-
-    // let file = File::open(path)?;
-    // let buf_reader = std::io::BufReader::with_capacity(16 * 1024 * 1024, file);
-    // let source = ReadOnlySource::new(buf_reader);
-    // let mss = MediaSourceStream::new(Box::new(source), Default::default());
-
-    let file = File::open(path)?;
-    let mmap = unsafe { Mmap::map(&file)? };
-    // Wrap mmap inside Cursor and then Box it
-    let cursor = Cursor::new(mmap); // now Cursor owns the mmap
-    let mss = MediaSourceStream::new(Box::new(cursor), Default::default());
-
-    // Create the media source stream using the boxed media source from above.
-    // let mss = MediaSourceStream::new(source, Default::default());
-
-    // Use the default options for format readers other than for gapless playback.
-    let format_opts = FormatOptions {
-        enable_gapless: false,
-        ..Default::default()
-    };
-
-    // Use the default options for metadata readers.
-    let metadata_opts: MetadataOptions = MetadataOptions {
-        limit_metadata_bytes: symphonia::core::meta::Limit::Maximum(50),
-        limit_visual_bytes: symphonia::core::meta::Limit::Maximum(0),
-    };
-
-    // Get the value of the track option, if provided.
-    let probe_result = get_probe().format(&hint, mss, &format_opts, &metadata_opts);
-
-    if probe_result.is_err() {
-        return Err(probe_result.err().unwrap());
-    }
-    let mut reader = probe_result.unwrap().format;
-
-    let track = reader.default_track().unwrap().clone();
-
-    let track_id = track.id;
-
-    // Create a decoder for the track.
-    let mut decoder = symphonia::default::get_codecs()
-        .make(&track.codec_params, &DecoderOptions { verify: false })
-        .unwrap();
-
-    // Details
-    let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
-    let channels = track.codec_params.channels.map_or(2, |ch| ch.count());
-    let new_spec = SignalSpec::new_with_layout(44100, Layout::Stereo);
-
-    let estimated_peaks = if let Some(n_frames) = track.codec_params.n_frames {
-        (n_frames * channels as u64 / WAVEFORM_WINDOW_SIZE as u64) as usize
-    } else {
-        500
-    };
-
-    let duration = track.codec_params.n_frames.map_or(
-        estimated_peaks as f64 * WAVEFORM_WINDOW_SIZE as f64 / sample_rate as f64,
-        |n_frames| n_frames as f64 / sample_rate as f64,
-    );
-
-    println!("Info:");
-    println!("  Sample Rate: {} Hz", sample_rate);
-    println!("  Duration: {:.2} seconds", duration);
-    println!("  Channels: {}", channels);
-    println!("  Estimated Peaks: {}", estimated_peaks);
-    println!("  Window Size: {} samples", WAVEFORM_WINDOW_SIZE);
-    println!("  Peak function: {:?}", WAVEFORM_PEAK_METHOD);
-    println!();
-
-    let mut window: Vec<f32> = Vec::with_capacity(WAVEFORM_WINDOW_SIZE);
-    let mut peaks: Vec<f32> = Vec::new();
-    let mut processed_packets = 0;
-
-    let mut total_count = 0;
-    let n_frames = 0;
-
-    // reusable sample buffer, start empty
-    let mut reusable_buf: Option<SampleBuffer<f32>> = None;
-
-    let result = loop {
-        let packet = match reader.next_packet() {
-            Ok(packet) => packet,
-            Err(err) => break Err(err),
-        };
-
-        // If the packet does not belong to the selected track, skip over it.
-        if packet.track_id() != track_id {
-            continue;
-        }
-        // Decode the packet into audio samples.
-        match decoder.decode(&packet) {
-            Ok(decoded) => {
-                if cancel_token.is_cancelled() {
-                    break Err(symphonia::core::errors::Error::LimitError("cancelled"));
-                }
-                // // Create a raw sample buffer that matches the parameters of the decoded audio buffer.
-                // let mut sample_buf =
-                //     SampleBuffer::<f32>::new(_decoded.capacity() as u64, *_decoded.spec());
-                // sample_buf.copy_interleaved_ref(_decoded);
-
-                let mut sample_buf = if let Some(buf) = &mut reusable_buf {
-                    buf.copy_interleaved_ref(decoded);
-                    buf
-                } else {
-                    // first time: allocate
-                    let mut buf =
-                        SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
-                    buf.copy_interleaved_ref(decoded);
-                    reusable_buf = Some(buf);
-                    reusable_buf.as_mut().unwrap()
-                };
-
-                let processed_samples = dsp::process_samples(sample_buf.samples(), channels, false);
-
-                // Copy the contents of the decoded audio buffer into the sample buffer whilst performing
-                // any required conversions.
-
-                // Add samples to window and generate peaks
-                for sample in processed_samples {
-                    window.push(sample);
-                    if window.len() >= WAVEFORM_WINDOW_SIZE {
-                        peaks.push(calculate_peak_value(&window, WAVEFORM_PEAK_METHOD));
-                        window.clear();
-                    }
-                }
-
-                processed_packets += 1;
-                if processed_packets % 1000 == 0 {
-                    print!(
-                        "\rProcessed {} packets, generated {} peaks",
-                        processed_packets,
-                        peaks.len()
-                    );
-                }
-
-                total_count += 1;
-                if total_count > 100 {
-                    total_count = 0;
-                    let len = estimated_peaks.saturating_sub(peaks.len());
-                    // info!("expected peaks size: {}, len: {}, n_adds: {}", expected_peaks_size, peaks.len(), n_adds);
-                    let cln = [peaks.clone().as_slice(), vec![0f32; len].as_slice()].concat();
-                    let bytes = peaks_to_bytes(&cln);
-                    let _ = app_handle.emit("waveform", GetWaveformResponse { data: Some(bytes) });
-                }
-
-                // Get waveform here
-                continue;
-            }
-            Err(symphonia::core::errors::Error::DecodeError(err)) => {
-                info!("decode error: {}", err)
-            }
-            Err(err) => break Err(err),
-        }
-    };
-
-    // Return if a fatal error occured.
-    let res = match result {
-        Err(symphonia::core::errors::Error::IoError(err))
-            if err.kind() == std::io::ErrorKind::UnexpectedEof
-                && err.to_string() == "end of stream" =>
-        {
-            info!("End of stream!!");
-            info!(
-                "Number of frames: {} (actual), {} (expected)",
-                n_frames,
-                track.codec_params.n_frames.unwrap()
-            );
-            // Do not treat "end of stream" as a fatal error. It's the currently only way a
-            // format reader can indicate the media is complete.
-            Ok(peaks)
-        }
-        _ => result,
-    };
-    res
-}
-
-fn peaks_to_bytes(peaks: &[f32]) -> ByteBuf {
-    // Convert &[f32] into &[u8]
-    let byte_slice: &[u8] = unsafe {
-        std::slice::from_raw_parts(
-            peaks.as_ptr() as *const u8,
-            peaks.len() * std::mem::size_of::<f32>(),
-        )
-    };
-
-    let bytes = ByteBuf::from(byte_slice);
-    bytes
-}
-
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct AudioDevice {
     id: String,
@@ -1880,12 +1894,14 @@ pub async fn get_devices(_app_handle: tauri::AppHandle) -> Result<AudioDevices,
 pub fn play_file(event: PlayFileRequest, state: State<AudioPlayer>, _app_handle: tauri::AppHandle) {
     info!("Play file {:?}", event);
 
-    let fl = File::open(event.path.clone().unwrap());
-
-    if fl.is_err() {
-        error!("Error opening file: {}", fl.err().unwrap());
-        _app_handle.emit("error", "file-not-found").unwrap();
-        return;
+    // Streams are opened by the decoding thread, only local files can be checked up front
+    let path = event.path.clone().unwrap();
+    if !is_stream_url(&path) {
+        if let Err(err) = File::open(&path) {
+            error!("Error opening file: {}", err);
+            _app_handle.emit("error", "file-not-found").unwrap();
+            return;
+        }
     }
 
     let boot = event.boot.clone();
@@ -1928,32 +1944,6 @@ pub fn queue_next(
     let _ = state.next_track_sender.send(event);
 }
 
-#[tauri::command]
-pub fn get_waveform(
-    event: GetWaveformRequest,
-    state: State<AudioPlayer>,
-    _app_handle: tauri::AppHandle,
-) {
-    info!("Get waveform {:?}", event);
-
-    let evt = event.clone();
-    let token = CancellationToken::new();
-    let token_clone = token.clone();
-    if let Ok(mut tokens) = state.cancel_tokens.try_lock() {
-        tokens
-            .iter()
-            .filter(|t| t.0 != &event.clone().path.unwrap())
-            .for_each(|t| {
-                t.1.cancel();
-            });
-        tokens.insert(event.path.unwrap(), token);
-    }
-
-    std::thread::spawn(move || {
-        let _ = get_peaks(evt, &_app_handle, token_clone);
-    });
-}
-
 #[tauri::command]
 pub fn decode_control(event: FlowControlEvent, state: State<AudioPlayer>) {
     info!("Received decode control event: {:?}", event);
@@ -0,0 +1,244 @@
+//! Computes the waveforms of the whole library in the background, so they
+//! show up instantly when browsing
+
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Listener, Manager, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::files::library_audio_files;
+use crate::player::AudioPlayer;
+use crate::waveform;
+
+/// The job's entry in the player's cancel tokens
+pub const CANCEL_TOKEN_KEY: &str = "waveform_precompute";
+const MAX_THREADS: usize = 4;
+/// How often a paused job checks if it can carry on
+const PAUSE_POLL: Duration = Duration::from_millis(250);
+
+static JOB: Mutex<Option<Arc<Job>>> = Mutex::new(None);
+/// Kept up to date from the player's events
+static PLAYING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PrecomputeStatus {
+    pub running: bool,
+    /// Paused from the frontend
+    pub paused: bool,
+    /// Waiting for playback to stop
+    pub waiting_for_playback: bool,
+    pub total: usize,
+    /// Files handled so far, including the skipped and failed ones
+    pub done: usize,
+    /// Already in the cache
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+struct Job {
+    token: CancellationToken,
+    queue: Mutex<VecDeque<PathBuf>>,
+    paused: AtomicBool,
+    pause_during_playback: bool,
+    total: usize,
+    done: AtomicUsize,
+    skipped: AtomicUsize,
+    failed: AtomicUsize,
+    /// Worker threads still running
+    workers: AtomicUsize,
+}
+
+impl Job {
+    fn status(&self) -> PrecomputeStatus {
+        PrecomputeStatus {
+            running: !self.token.is_cancelled(),
+            paused: self.paused.load(Ordering::Relaxed),
+            waiting_for_playback: self.pause_during_playback && PLAYING.load(Ordering::Relaxed),
+            total: self.total,
+            done: self.done.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    fn is_held(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+            || (self.pause_during_playback && PLAYING.load(Ordering::Relaxed))
+    }
+
+    /// Blocks while paused or playing. Returns false once cancelled.
+    fn wait(&self) -> bool {
+        while self.is_held() && !self.token.is_cancelled() {
+            std::thread::sleep(PAUSE_POLL);
+        }
+        !self.token.is_cancelled()
+    }
+
+    fn next(&self) -> Option<PathBuf> {
+        self.queue.lock().unwrap().pop_front()
+    }
+}
+
+/**
+ * Keeps track of playback, so the job can step aside while music plays.
+ */
+pub fn init(app: AppHandle) {
+    app.listen_any("playing", |_| PLAYING.store(true, Ordering::Relaxed));
+    app.listen_any("paused", |_| PLAYING.store(false, Ordering::Relaxed));
+    app.listen_any("end_of_queue", |_| PLAYING.store(false, Ordering::Relaxed));
+}
+
+/**
+ * Starts computing the waveforms of the given files, or of every file in the
+ * watched folders. Files already in the cache are skipped, the least recently
+ * used ones make room for new ones. Runs on a single thread unless asked for more.
+ * Emits "waveform_precompute_progress" after each file and
+ * "waveform_precompute_done" at the end, both with the status.
+ */
+#[tauri::command]
+pub async fn start_waveform_precompute(
+    paths: Option<Vec<String>>,
+    threads: Option<usize>,
+    pause_during_playback: Option<bool>,
+    state: State<'_, AudioPlayer>,
+    app_handle: AppHandle,
+) -> Result<PrecomputeStatus, String> {
+    if JOB.lock().unwrap().is_some() {
+        return Err("Already running".into());
+    }
+
+    let scan_handle = app_handle.clone();
+    let files = tauri::async_runtime::spawn_blocking(move || match paths {
+        Some(paths) => paths.into_iter().map(PathBuf::from).collect(),
+        None => library_audio_files(&scan_handle),
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    let threads = threads.unwrap_or(1).clamp(1, MAX_THREADS);
+    // Its own pool, so the job never takes more than that from playback and the rest of the app
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("waveform-precompute-{}", index))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let job = Arc::new(Job {
+        token: CancellationToken::new(),
+        total: files.len(),
+        queue: Mutex::new(files.into()),
+        paused: AtomicBool::new(false),
+        pause_during_playback: pause_during_playback.unwrap_or(true),
+        done: AtomicUsize::new(0),
+        skipped: AtomicUsize::new(0),
+        failed: AtomicUsize::new(0),
+        workers: AtomicUsize::new(threads),
+    });
+    {
+        let mut current = JOB.lock().unwrap();
+        if current.is_some() {
+            return Err("Already running".into());
+        }
+        *current = Some(job.clone());
+    }
+    state
+        .cancel_tokens
+        .lock()
+        .await
+        .insert(CANCEL_TOKEN_KEY.to_string(), job.token.clone());
+
+    info!("[Precompute] {} files on {} threads", job.total, threads);
+    // The threads stay until the workers are done, after the pool is dropped
+    for _ in 0..threads {
+        let job = job.clone();
+        let app_handle = app_handle.clone();
+        pool.spawn(move || run_worker(&job, &app_handle));
+    }
+    Ok(job.status())
+}
+
+#[tauri::command]
+pub fn pause_waveform_precompute() -> Result<PrecomputeStatus, String> {
+    let job = current_job().ok_or("Not running")?;
+    job.paused.store(true, Ordering::Relaxed);
+    Ok(job.status())
+}
+
+#[tauri::command]
+pub fn resume_waveform_precompute() -> Result<PrecomputeStatus, String> {
+    let job = current_job().ok_or("Not running")?;
+    job.paused.store(false, Ordering::Relaxed);
+    Ok(job.status())
+}
+
+#[tauri::command]
+pub async fn cancel_waveform_precompute(state: State<'_, AudioPlayer>) -> Result<(), String> {
+    if let Some(token) = state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY) {
+        token.cancel();
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub fn get_waveform_precompute_status() -> PrecomputeStatus {
+    current_job().map(|job| job.status()).unwrap_or_default()
+}
+
+fn current_job() -> Option<Arc<Job>> {
+    JOB.lock().unwrap().clone()
+}
+
+fn run_worker(job: &Job, app_handle: &AppHandle) {
+    while job.wait() {
+        let Some(path) = job.next() else {
+            break;
+        };
+        process_file(job, &path, app_handle);
+        job.done.fetch_add(1, Ordering::Relaxed);
+        let _ = app_handle.emit("waveform_precompute_progress", job.status());
+    }
+
+    // The last worker out wraps up
+    if job.workers.fetch_sub(1, Ordering::Relaxed) == 1 {
+        job.token.cancel();
+        // Before letting a new job start, which would add its own token
+        app_handle
+            .state::<AudioPlayer>()
+            .cancel_tokens
+            .blocking_lock()
+            .remove(CANCEL_TOKEN_KEY);
+        JOB.lock().unwrap().take();
+        let status = job.status();
+        info!("[Precompute] Finished: {:?}", status);
+        let _ = app_handle.emit("waveform_precompute_done", status);
+    }
+}
+
+fn process_file(job: &Job, path: &Path, app_handle: &AppHandle) {
+    if waveform::is_cached(path, app_handle) {
+        job.skipped.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    // Progress is reported while decoding, which is where we hold still when paused
+    match waveform::compute(path, &job.token, |_| {
+        job.wait();
+    }) {
+        Ok(result) => match waveform::save(path, app_handle, &result) {
+            Ok(_) => waveform::prune_cache(app_handle),
+            Err(err) => {
+                warn!("[Precompute] Error caching {:?}: {}", path, err);
+                job.failed.fetch_add(1, Ordering::Relaxed);
+            }
+        },
+        Err(_) if job.token.is_cancelled() => {}
+        Err(err) => {
+            warn!("[Precompute] Error decoding {:?}: {}", path, err);
+            job.failed.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,342 @@
+use log::{error, info, warn};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use symphonia::core::conv::IntoSample;
+use tauri::{AppHandle, Emitter, Listener, Manager};
+
+use crate::cue::{write_cue, CueFile, CueSheet, CueTrack};
+use crate::encoder::{quantize, Encoder, EncoderFormat};
+use crate::remote::RemoteState;
+use crate::store::load_settings;
+
+const BITS_PER_SAMPLE: u32 = 24;
+/// Packets waiting to be written, the output waits when the disk can't keep up (a few seconds' worth)
+const QUEUE_LENGTH: usize = 256;
+
+static RECORDING: AtomicBool = AtomicBool::new(false);
+static SENDER: Mutex<Option<SyncSender<RecorderMessage>>> = Mutex::new(None);
+
+enum RecorderMessage {
+    Samples {
+        sample_rate: u32,
+        channels: usize,
+        samples: Vec<f32>,
+        /// Frames still in the output buffer ahead of these, not heard yet
+        buffered_frames: u64,
+    },
+    /// A new song starts at this point of the recording
+    Marker {
+        title: String,
+        performer: String,
+    },
+    Stop(Sender<Result<RecordingInfo, String>>),
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RecordingInfo {
+    pub path: String,
+    pub cue_path: String,
+    /// Seconds
+    pub duration: f64,
+    pub tracks: usize,
+}
+
+/**
+ * Called by the output with every packet as it will be played (after EQ,
+ * speed and volume), before it goes into the output buffer. Does nothing unless recording.
+ */
+pub fn tap<T: IntoSample<f32> + Copy>(
+    samples: &[T],
+    channels: usize,
+    sample_rate: u32,
+    buffered_frames: u64,
+) {
+    if !is_recording() {
+        return;
+    }
+    if let Some(sender) = SENDER.lock().unwrap().as_ref() {
+        let _ = sender.send(RecorderMessage::Samples {
+            sample_rate,
+            channels,
+            samples: samples.iter().map(|s| (*s).into_sample()).collect(),
+            buffered_frames,
+        });
+    }
+}
+
+pub fn is_recording() -> bool {
+    RECORDING.load(Ordering::Relaxed)
+}
+
+/**
+ * Adds a track marker whenever the song changes during a recording.
+ */
+pub fn init(app: AppHandle) {
+    app.listen_any("song_change", |event| {
+        if !is_recording() {
+            return;
+        }
+        let Ok(song) = serde_json::from_str::<serde_json::Value>(event.payload()) else {
+            return;
+        };
+        send(RecorderMessage::Marker {
+            title: song["title"].as_str().unwrap_or_default().to_string(),
+            performer: song["artist"].as_str().unwrap_or_default().to_string(),
+        });
+    });
+}
+
+fn send(message: RecorderMessage) {
+    if let Some(sender) = SENDER.lock().unwrap().as_ref() {
+        let _ = sender.send(message);
+    }
+}
+
+/**
+ * Starts recording the output to a WAV or FLAC file, with a CUE sheet of the
+ * songs played written next to it when stopped.
+ * Defaults to a timestamped file in the download location (or Music folder).
+ */
+#[tauri::command]
+pub fn start_recording(
+    path: Option<String>,
+    format: Option<String>,
+    app_handle: AppHandle,
+) -> Result<String, String> {
+    if is_recording() {
+        return Err("Already recording".into());
+    }
+
+    let path = match path {
+        Some(path) => PathBuf::from(path),
+        None => {
+            let directory = load_settings(&app_handle)
+                .ok()
+                .and_then(|settings| settings.download_location)
+                .map(PathBuf::from)
+                .or_else(|| app_handle.path().audio_dir().ok())
+                .ok_or("No folder to record to")?;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|time| time.as_secs())
+                .unwrap_or_default();
+            let format = EncoderFormat::from_setting(format.as_deref());
+            directory.join(format!("Recording {}.{}", timestamp, format.extension()))
+        }
+    };
+    // The extension wins over the format argument
+    let format = match path.extension().and_then(|e| e.to_str()) {
+        Some(extension) if extension.eq_ignore_ascii_case("flac") => EncoderFormat::Flac,
+        Some(extension) if extension.eq_ignore_ascii_case("wav") => EncoderFormat::Wav,
+        _ => EncoderFormat::from_setting(format.as_deref()),
+    };
+    let file = File::create(&path).map_err(|e| format!("Error creating {:?}: {}", path, e))?;
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+    // Whatever is playing right now is the first track
+    if let Some(song) = app_handle.state::<RemoteState>().status().song {
+        let _ = sender.send(RecorderMessage::Marker {
+            title: song.title,
+            performer: song.artist,
+        });
+    }
+    *SENDER.lock().unwrap() = Some(sender);
+    RECORDING.store(true, Ordering::Relaxed);
+
+    let thread_path = path.clone();
+    std::thread::Builder::new()
+        .name("recorder".into())
+        .spawn(move || {
+            let result = write_recording(file, &thread_path, format, receiver);
+            SENDER.lock().unwrap().take();
+            RECORDING.store(false, Ordering::Relaxed);
+            match result {
+                // Stopped from the frontend, the result was returned from stop_recording
+                Ok(None) => {}
+                // The output format changed, we can't keep writing the same file
+                Ok(Some(info)) => {
+                    warn!("[Recorder] Recording stopped: {:?}", info);
+                    let _ = app_handle.emit("recording_stopped", info);
+                }
+                Err(err) => {
+                    error!("[Recorder] {}", err);
+                    let _ = app_handle.emit("recording_error", err);
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+
+    info!("[Recorder] Recording to {:?}", path);
+    Ok(path.to_string_lossy().to_string())
+}
+
+#[tauri::command]
+pub fn stop_recording() -> Result<RecordingInfo, String> {
+    if !is_recording() {
+        return Err("Not recording".into());
+    }
+    let (sender, receiver) = mpsc::channel();
+    send(RecorderMessage::Stop(sender));
+    receiver
+        .recv()
+        .map_err(|_| "Recording already stopped".to_string())?
+}
+
+#[tauri::command]
+pub fn get_recording_status() -> bool {
+    is_recording()
+}
+
+struct Recording {
+    writer: BufWriter<File>,
+    encoder: Option<(Encoder, u32, usize)>,
+    frames: u64,
+    /// Frame of the recording being heard right now. The output is tapped when
+    /// the samples are buffered, songs change when they are heard.
+    playing_frame: u64,
+    markers: Vec<(u64, String, String)>,
+}
+
+/**
+ * Runs on the recorder thread until stopped. Returns the info when it had to
+ * stop on its own, None when stopped with stop_recording.
+ */
+fn write_recording(
+    file: File,
+    path: &Path,
+    format: EncoderFormat,
+    receiver: Receiver<RecorderMessage>,
+) -> Result<Option<RecordingInfo>, String> {
+    let mut recording = Recording {
+        writer: BufWriter::new(file),
+        encoder: None,
+        frames: 0,
+        playing_frame: 0,
+        markers: vec![],
+    };
+
+    while let Ok(message) = receiver.recv() {
+        match message {
+            RecorderMessage::Samples {
+                sample_rate,
+                channels,
+                samples,
+                buffered_frames,
+            } => {
+                recording.playing_frame = recording.frames.saturating_sub(buffered_frames);
+                let encoder = match &mut recording.encoder {
+                    Some((encoder, rate, count)) if *rate == sample_rate && *count == channels => {
+                        encoder
+                    }
+                    Some(_) => {
+                        warn!("[Recorder] Output format changed, stopping");
+                        return finish(recording, path).map(Some);
+                    }
+                    None => {
+                        let encoder = Encoder::new(format, sample_rate, channels, BITS_PER_SAMPLE);
+                        // Placeholder until we know the length
+                        recording
+                            .writer
+                            .write_all(&encoder.header(None))
+                            .map_err(|e| e.to_string())?;
+                        &mut recording.encoder.insert((encoder, sample_rate, channels)).0
+                    }
+                };
+                let quantized: Vec<i32> = samples
+                    .iter()
+                    .map(|s| quantize(*s, BITS_PER_SAMPLE))
+                    .collect();
+                recording
+                    .writer
+                    .write_all(&encoder.encode(&quantized))
+                    .map_err(|e| e.to_string())?;
+                recording.frames += (samples.len() / channels) as u64;
+            }
+            RecorderMessage::Marker { title, performer } => {
+                // Markers without audio in between replace each other
+                if recording
+                    .markers
+                    .last()
+                    .is_some_and(|(frames, _, _)| *frames >= recording.playing_frame)
+                {
+                    recording.markers.pop();
+                }
+                recording
+                    .markers
+                    .push((recording.playing_frame, title, performer));
+            }
+            RecorderMessage::Stop(reply) => {
+                let _ = reply.send(finish(recording, path));
+                return Ok(None);
+            }
+        }
+    }
+    finish(recording, path).map(Some)
+}
+
+/**
+ * Flushes the encoder, rewrites the header with the final length
+ * and writes the CUE sheet.
+ */
+fn finish(mut recording: Recording, path: &Path) -> Result<RecordingInfo, String> {
+    let Some((mut encoder, sample_rate, _)) = recording.encoder.take() else {
+        drop(recording);
+        let _ = std::fs::remove_file(path);
+        return Err("Nothing was recorded".into());
+    };
+    recording
+        .writer
+        .write_all(&encoder.finish())
+        .map_err(|e| e.to_string())?;
+    recording
+        .writer
+        .seek(SeekFrom::Start(0))
+        .and_then(|_| {
+            recording
+                .writer
+                .write_all(&encoder.header(Some(recording.frames)))
+        })
+        .and_then(|_| recording.writer.flush())
+        .map_err(|e| e.to_string())?;
+    let duration = recording.frames as f64 / sample_rate as f64;
+
+    let tracks = recording
+        .markers
+        .iter()
+        .enumerate()
+        .map(|(index, (frames, title, performer))| CueTrack {
+            number: index as u32 + 1,
+            title: Some(title.clone()),
+            performer: Some(performer.clone()),
+            songwriter: None,
+            start: *frames as f64 / sample_rate as f64,
+        })
+        .collect();
+    let sheet = CueSheet {
+        title: path.file_stem().map(|s| s.to_string_lossy().to_string()),
+        files: vec![CueFile {
+            name: path
+                .file_name()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            tracks,
+        }],
+        ..Default::default()
+    };
+    std::fs::write(path.with_extension("cue"), write_cue(&sheet)).map_err(|e| e.to_string())?;
+
+    info!("[Recorder] Finished {:?}, {:.1}s", path, duration);
+    Ok(RecordingInfo {
+        path: path.to_string_lossy().to_string(),
+        cue_path: path.with_extension("cue").to_string_lossy().to_string(),
+        duration,
+        tracks: recording.markers.len(),
+    })
+}
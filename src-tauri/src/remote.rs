@@ -0,0 +1,299 @@
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio::sync::{broadcast, oneshot};
+
+use crate::history::PlaybackEvent;
+use crate::metadata::Song;
+
+/// How long to wait for the frontend to answer a library search
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A song as seen by remote clients, the queue is synced from the frontend
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RemoteSong {
+    pub id: String,
+    pub path: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: Option<String>,
+    pub genre: Vec<String>,
+    pub year: i32,
+    pub track_number: i32,
+    pub disc_number: i32,
+    /// Seconds
+    pub duration: Option<f64>,
+}
+
+impl From<&Song> for RemoteSong {
+    fn from(song: &Song) -> Self {
+        RemoteSong {
+            id: song.id.clone(),
+            path: song.path.clone(),
+            title: song.title.clone(),
+            artist: song.artist.clone(),
+            album: song.album.clone(),
+            album_artist: song.album_artist.clone(),
+            genre: song.genre.clone(),
+            year: song.year,
+            track_number: song.track_number,
+            disc_number: song.disc_number,
+            duration: song.file_info.duration,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteChange {
+    /// Song changed, playback started, paused or seeked
+    Player,
+    /// Queue changed
+    Playlist,
+    /// Volume changed
+    Mixer,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayerStatus {
+    /// Bumped every time the queue changes
+    pub queue_version: u32,
+    pub queue_length: usize,
+    pub current_index: Option<usize>,
+    pub song: Option<RemoteSong>,
+    pub is_playing: bool,
+    /// Seconds
+    pub position: f64,
+    /// 0 to 1
+    pub volume: f64,
+}
+
+/**
+ * Shared state for remote control (MPD, HTTP API, command line).
+ * The queue and the library live in the frontend, which syncs them here
+ * and handles the control events we emit back.
+ */
+pub struct RemoteState {
+    status: Mutex<PlayerStatus>,
+    queue: Mutex<Vec<RemoteSong>>,
+    changes: broadcast::Sender<RemoteChange>,
+    searches: Mutex<HashMap<u32, oneshot::Sender<Vec<RemoteSong>>>>,
+    next_search_id: AtomicU32,
+}
+
+impl RemoteState {
+    pub fn new() -> Self {
+        let (changes, _) = broadcast::channel(64);
+        RemoteState {
+            status: Mutex::new(PlayerStatus::default()),
+            queue: Mutex::new(Vec::new()),
+            changes,
+            searches: Mutex::new(HashMap::new()),
+            next_search_id: AtomicU32::new(1),
+        }
+    }
+
+    pub fn status(&self) -> PlayerStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    pub fn queue(&self) -> Vec<RemoteSong> {
+        self.queue.lock().unwrap().clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RemoteChange> {
+        self.changes.subscribe()
+    }
+
+    fn changed(&self, change: RemoteChange) {
+        // No receivers is fine
+        let _ = self.changes.send(change);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RemoteSyncEvent {
+    queue: Option<Vec<RemoteSong>>,
+    current_index: Option<i64>,
+    is_playing: Option<bool>,
+    volume: Option<f64>,
+}
+
+/**
+ * Called by the frontend when the queue or player state changes.
+ */
+#[tauri::command]
+pub fn remote_sync(event: RemoteSyncEvent, state: State<RemoteState>) {
+    let mut changes = Vec::new();
+    {
+        let mut status = state.status.lock().unwrap();
+        if let Some(queue) = event.queue {
+            status.queue_version = status.queue_version.wrapping_add(1);
+            status.queue_length = queue.len();
+            *state.queue.lock().unwrap() = queue;
+            changes.push(RemoteChange::Playlist);
+        }
+        if let Some(index) = event.current_index {
+            let index = usize::try_from(index).ok();
+            if status.current_index != index {
+                status.current_index = index;
+                changes.push(RemoteChange::Player);
+            }
+        }
+        if let Some(is_playing) = event.is_playing {
+            if status.is_playing != is_playing {
+                status.is_playing = is_playing;
+                changes.push(RemoteChange::Player);
+            }
+        }
+        if let Some(volume) = event.volume {
+            if status.volume != volume {
+                status.volume = volume;
+                changes.push(RemoteChange::Mixer);
+            }
+        }
+    }
+    for change in changes {
+        state.changed(change);
+    }
+}
+
+#[tauri::command]
+pub fn remote_search_result(id: u32, songs: Vec<RemoteSong>, state: State<RemoteState>) {
+    if let Some(sender) = state.searches.lock().unwrap().remove(&id) {
+        let _ = sender.send(songs);
+    }
+}
+
+/**
+ * Follows the player to keep the current song and position up to date.
+ */
+pub fn init(app: AppHandle, receiver: Receiver<PlaybackEvent>) {
+    std::thread::spawn(move || {
+        let state = app.state::<RemoteState>();
+        while let Ok(event) = receiver.recv() {
+            match event {
+                PlaybackEvent::Started { song, position, .. } => {
+                    {
+                        let mut status = state.status.lock().unwrap();
+                        status.song = Some(RemoteSong::from(&song));
+                        status.position = position;
+                    }
+                    state.changed(RemoteChange::Player);
+                }
+                PlaybackEvent::Position(position) => {
+                    let seeked = {
+                        let mut status = state.status.lock().unwrap();
+                        let seeked = (position - status.position).abs() > 2.0;
+                        status.position = position;
+                        seeked
+                    };
+                    if seeked {
+                        state.changed(RemoteChange::Player);
+                    }
+                }
+                PlaybackEvent::Ended => {
+                    state.status.lock().unwrap().position = 0.0;
+                    state.changed(RemoteChange::Player);
+                }
+                PlaybackEvent::SpeedChanged(_) | PlaybackEvent::Paused | PlaybackEvent::Resumed => {
+                }
+            }
+        }
+    });
+}
+
+pub fn play(app: &AppHandle) {
+    if !app.state::<RemoteState>().status().is_playing {
+        let _ = app.emit("toggle_play", ());
+    }
+}
+
+pub fn pause(app: &AppHandle) {
+    if app.state::<RemoteState>().status().is_playing {
+        let _ = app.emit("toggle_play", ());
+    }
+}
+
+pub fn toggle(app: &AppHandle) {
+    let _ = app.emit("toggle_play", ());
+}
+
+pub fn next(app: &AppHandle) {
+    let _ = app.emit("play_next", ());
+}
+
+pub fn previous(app: &AppHandle) {
+    let _ = app.emit("play_previous", ());
+}
+
+/// Plays the song at this position in the queue
+pub fn play_index(app: &AppHandle, index: usize) {
+    let _ = app.emit("remote_play_index", index);
+}
+
+/// Seconds
+pub fn seek(app: &AppHandle, position: f64) {
+    let _ = app.emit("seek_to", position.max(0.0));
+}
+
+/// 0 to 1
+pub fn set_volume(app: &AppHandle, volume: f64) {
+    let _ = app.emit("set_volume", volume.clamp(0.0, 1.0));
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EnqueuePayload {
+    paths: Vec<String>,
+    play: bool,
+}
+
+/// Adds files or folders to the end of the queue
+pub fn enqueue(app: &AppHandle, paths: Vec<String>, play: bool) {
+    info!("[Remote] Enqueue {:?}", paths);
+    let _ = app.emit("remote_enqueue", EnqueuePayload { paths, play });
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SearchPayload {
+    id: u32,
+    query: String,
+}
+
+/**
+ * Searches the library (title, artist, album) through the frontend.
+ */
+pub async fn search(app: &AppHandle, query: &str) -> Vec<RemoteSong> {
+    let state = app.state::<RemoteState>();
+    let id = state.next_search_id.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = oneshot::channel();
+    state.searches.lock().unwrap().insert(id, sender);
+
+    let _ = app.emit(
+        "remote_search",
+        SearchPayload {
+            id,
+            query: query.to_string(),
+        },
+    );
+
+    match tokio::time::timeout(SEARCH_TIMEOUT, receiver).await {
+        Ok(Ok(songs)) => songs,
+        _ => {
+            warn!("[Remote] Search timed out: {}", query);
+            state.searches.lock().unwrap().remove(&id);
+            vec![]
+        }
+    }
+}
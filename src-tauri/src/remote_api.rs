@@ -0,0 +1,292 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::{Path, Query, Request, State};
+use axum::http::{header, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use tauri::{AppHandle, Listener, Manager};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::remote::{self, RemoteSong, RemoteState};
+use crate::store::load_settings;
+
+const DEFAULT_PORT: u16 = 7373;
+
+/// App events that are pushed to WebSocket clients as they are
+const PUSHED_EVENTS: &[&str] = &[
+    "song_change",
+    "playing",
+    "paused",
+    "timestamp",
+    "end_of_queue",
+];
+
+#[derive(Serialize, Clone, Debug)]
+struct ApiEvent {
+    event: &'static str,
+    payload: serde_json::Value,
+}
+
+#[derive(Clone)]
+struct ApiState {
+    app: AppHandle,
+    token: Arc<String>,
+    events: broadcast::Sender<ApiEvent>,
+}
+
+#[derive(Deserialize)]
+struct SeekRequest {
+    /// Seconds
+    position: f64,
+}
+
+#[derive(Deserialize, Serialize)]
+struct Volume {
+    /// 0 to 1
+    volume: f64,
+}
+
+#[derive(Deserialize)]
+struct EnqueueRequest {
+    paths: Vec<String>,
+    #[serde(default)]
+    play: bool,
+}
+
+#[derive(Deserialize)]
+struct SearchQuery {
+    q: String,
+}
+
+/**
+ * Starts the HTTP/WebSocket remote control API if enabled in the settings.
+ *
+ * REST endpoints live under /api, /api/events is a WebSocket that pushes
+ * player events. Every request needs the token from the settings, either as
+ * an `Authorization: Bearer` header or a `token` query parameter.
+ */
+pub fn init(app: AppHandle) {
+    let Ok(settings) = load_settings(&app) else {
+        return;
+    };
+    if !settings.remote_api_enabled.unwrap_or(false) {
+        return;
+    }
+    let token = match settings.remote_api_token {
+        Some(token) if !token.trim().is_empty() => token.trim().to_string(),
+        _ => {
+            error!("[RemoteApi] No token set, not starting");
+            return;
+        }
+    };
+    let port = settings.remote_api_port.unwrap_or(DEFAULT_PORT);
+    let address = if settings.remote_api_allow_lan.unwrap_or(false) {
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED)
+    } else {
+        IpAddr::V4(Ipv4Addr::LOCALHOST)
+    };
+
+    let (events, _) = broadcast::channel(256);
+    for &name in PUSHED_EVENTS {
+        let events = events.clone();
+        app.listen_any(name, move |event| {
+            let payload = serde_json::from_str(event.payload()).unwrap_or(serde_json::Value::Null);
+            // No clients connected is fine
+            let _ = events.send(ApiEvent {
+                event: name,
+                payload,
+            });
+        });
+    }
+
+    let state = ApiState {
+        app,
+        token: Arc::new(token),
+        events,
+    };
+    let router = Router::new()
+        .route("/api/status", get(status))
+        .route("/api/play", post(play))
+        .route("/api/pause", post(pause))
+        .route("/api/toggle", post(toggle))
+        .route("/api/next", post(next))
+        .route("/api/previous", post(previous))
+        .route("/api/seek", post(seek))
+        .route("/api/volume", get(volume).put(set_volume))
+        .route("/api/queue", get(queue).post(enqueue))
+        .route("/api/queue/{index}/play", post(play_index))
+        .route("/api/search", get(search))
+        .route("/api/events", get(events_socket))
+        .layer(middleware::from_fn_with_state(state.clone(), authorize))
+        .with_state(state);
+
+    tauri::async_runtime::spawn(async move {
+        let listener = match TcpListener::bind((address, port)).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                error!("[RemoteApi] Error listening on port {}: {}", port, err);
+                return;
+            }
+        };
+        info!("[RemoteApi] Listening on {}:{}", address, port);
+        if let Err(err) = axum::serve(listener, router).await {
+            error!("[RemoteApi] Server error: {}", err);
+        }
+    });
+}
+
+/// Compares in constant time, so response times don't tell how much of a guess was right
+fn tokens_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (given.as_bytes(), expected.as_bytes());
+    given.len() == expected.len()
+        && given
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn authorize(State(api): State<ApiState>, request: Request, next: Next) -> Response {
+    let header_token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+    // Browsers can't set headers on WebSocket requests
+    let query_token = request.uri().query().and_then(|query| {
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == "token")
+            .map(|(_, value)| value.into_owned())
+    });
+
+    let given = header_token.or(query_token).unwrap_or_default();
+    if !tokens_match(&given, &api.token) {
+        warn!("[RemoteApi] Unauthorized request: {}", request.uri().path());
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn status(State(api): State<ApiState>) -> impl IntoResponse {
+    Json(api.app.state::<RemoteState>().status())
+}
+
+async fn play(State(api): State<ApiState>) -> StatusCode {
+    remote::play(&api.app);
+    StatusCode::NO_CONTENT
+}
+
+async fn pause(State(api): State<ApiState>) -> StatusCode {
+    remote::pause(&api.app);
+    StatusCode::NO_CONTENT
+}
+
+async fn toggle(State(api): State<ApiState>) -> StatusCode {
+    remote::toggle(&api.app);
+    StatusCode::NO_CONTENT
+}
+
+async fn next(State(api): State<ApiState>) -> StatusCode {
+    remote::next(&api.app);
+    StatusCode::NO_CONTENT
+}
+
+async fn previous(State(api): State<ApiState>) -> StatusCode {
+    remote::previous(&api.app);
+    StatusCode::NO_CONTENT
+}
+
+async fn seek(State(api): State<ApiState>, Json(request): Json<SeekRequest>) -> StatusCode {
+    remote::seek(&api.app, request.position);
+    StatusCode::NO_CONTENT
+}
+
+async fn volume(State(api): State<ApiState>) -> Json<Volume> {
+    Json(Volume {
+        volume: api.app.state::<RemoteState>().status().volume,
+    })
+}
+
+async fn set_volume(State(api): State<ApiState>, Json(request): Json<Volume>) -> StatusCode {
+    remote::set_volume(&api.app, request.volume);
+    StatusCode::NO_CONTENT
+}
+
+async fn queue(State(api): State<ApiState>) -> Json<Vec<RemoteSong>> {
+    Json(api.app.state::<RemoteState>().queue())
+}
+
+async fn enqueue(State(api): State<ApiState>, Json(request): Json<EnqueueRequest>) -> StatusCode {
+    if request.paths.is_empty() {
+        return StatusCode::BAD_REQUEST;
+    }
+    remote::enqueue(&api.app, request.paths, request.play);
+    StatusCode::ACCEPTED
+}
+
+async fn play_index(State(api): State<ApiState>, Path(index): Path<usize>) -> StatusCode {
+    if index >= api.app.state::<RemoteState>().status().queue_length {
+        return StatusCode::NOT_FOUND;
+    }
+    remote::play_index(&api.app, index);
+    StatusCode::NO_CONTENT
+}
+
+async fn search(
+    State(api): State<ApiState>,
+    Query(query): Query<SearchQuery>,
+) -> Json<Vec<RemoteSong>> {
+    Json(remote::search(&api.app, &query.q).await)
+}
+
+async fn events_socket(ws: WebSocketUpgrade, State(api): State<ApiState>) -> Response {
+    ws.on_upgrade(move |socket| push_events(socket, api))
+}
+
+/**
+ * Sends the current status, then every pushed event until the client goes away.
+ */
+async fn push_events(mut socket: WebSocket, api: ApiState) {
+    let mut receiver = api.events.subscribe();
+    let initial = serde_json::json!({
+        "event": "status",
+        "payload": api.app.state::<RemoteState>().status(),
+    });
+    if socket
+        .send(Message::Text(initial.to_string().into()))
+        .await
+        .is_err()
+    {
+        return;
+    }
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    let Ok(text) = serde_json::to_string(&event) else {
+                        continue;
+                    };
+                    if socket.send(Message::Text(text.into())).await.is_err() {
+                        break;
+                    }
+                }
+                // Slow client, skip what it missed
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                _ => {}
+            },
+        }
+    }
+}
@@ -0,0 +1,738 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use chksum_md5::MD5;
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::history::{
+    now_millis, ListenOutcome, ListenSession, ListenThreshold, ListenTracker, ListenUpdate,
+    PlaybackEvent,
+};
+use crate::metadata::Song;
+use crate::store::load_settings;
+
+const LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org";
+const LASTFM_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+const LASTFM_AUTH_URL: &str = "https://www.last.fm/api/auth/";
+const QUEUE_FILE: &str = "scrobble_queue.json";
+const SCROBBLER_LOG_FILE: &str = ".scrobbler.log";
+
+/// Both services agree on this: half the track, or 4 minutes for long tracks
+const SCROBBLE_THRESHOLD: ListenThreshold = ListenThreshold {
+    fraction: 0.5,
+    max_seconds: Some(240.0),
+};
+/// Tracks shorter than this are never scrobbled
+const MIN_SCROBBLE_DURATION: f64 = 30.0;
+
+/// How often the queue is checked for submissions that are due for a retry
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const MIN_BACKOFF_SECS: i64 = 30;
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ScrobbleService {
+    ListenBrainz,
+    LastFm,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Listen {
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<i32>,
+    /// Duration in seconds
+    pub duration: Option<f64>,
+    /// Unix timestamp in seconds of when the track started playing
+    pub listened_at: i64,
+}
+
+impl Listen {
+    fn from_song(song: &Song, listened_at: i64) -> Self {
+        Self {
+            artist: song.artist.clone(),
+            title: song.title.clone(),
+            album: Some(song.album.clone()).filter(|a| !a.is_empty()),
+            album_artist: song.album_artist.clone().filter(|a| !a.is_empty()),
+            track_number: Some(song.track_number).filter(|t| *t > 0),
+            duration: song.file_info.duration,
+            listened_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedScrobble {
+    pub service: ScrobbleService,
+    pub listen: Listen,
+    pub attempts: u32,
+    /// Unix timestamp in seconds
+    pub next_attempt_at: i64,
+}
+
+#[derive(Clone, Debug)]
+struct ListenBrainzConfig {
+    url: String,
+    token: String,
+}
+
+#[derive(Clone, Debug)]
+struct LastFmConfig {
+    url: String,
+    api_key: String,
+    api_secret: String,
+    session_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct ScrobblerConfig {
+    listenbrainz: Option<ListenBrainzConfig>,
+    lastfm: Option<LastFmConfig>,
+    /// Directory to write the Rockbox-style `.scrobbler.log` to
+    log_location: Option<PathBuf>,
+}
+
+fn non_empty(value: Option<String>) -> Option<String> {
+    value.filter(|v| !v.trim().is_empty())
+}
+
+impl ScrobblerConfig {
+    fn load(app: &AppHandle) -> Self {
+        let Ok(settings) = load_settings(app) else {
+            return Self::default();
+        };
+        let listenbrainz = non_empty(settings.listenbrainz_token).map(|token| ListenBrainzConfig {
+            url: non_empty(settings.listenbrainz_url)
+                .unwrap_or(LISTENBRAINZ_URL.to_string())
+                .trim_end_matches('/')
+                .to_string(),
+            token,
+        });
+        let lastfm = match (
+            non_empty(settings.lastfm_api_key),
+            non_empty(settings.lastfm_api_secret),
+        ) {
+            (Some(api_key), Some(api_secret)) => Some(LastFmConfig {
+                url: non_empty(settings.lastfm_url).unwrap_or(LASTFM_URL.to_string()),
+                api_key,
+                api_secret,
+                session_key: non_empty(settings.lastfm_session_key),
+            }),
+            _ => None,
+        };
+        Self {
+            listenbrainz,
+            lastfm,
+            log_location: non_empty(settings.scrobbler_log_location).map(PathBuf::from),
+        }
+    }
+
+    /// Services we can submit to right now
+    fn services(&self) -> Vec<ScrobbleService> {
+        let mut services = Vec::new();
+        if self.listenbrainz.is_some() {
+            services.push(ScrobbleService::ListenBrainz);
+        }
+        if self
+            .lastfm
+            .as_ref()
+            .is_some_and(|l| l.session_key.is_some())
+        {
+            services.push(ScrobbleService::LastFm);
+        }
+        services
+    }
+
+    /// What a service authenticates with, to notice when it's been replaced
+    fn credentials(&self, service: ScrobbleService) -> Option<String> {
+        match service {
+            ScrobbleService::ListenBrainz => self.listenbrainz.as_ref().map(|l| l.token.clone()),
+            ScrobbleService::LastFm => self.lastfm.as_ref().map(|l| {
+                format!(
+                    "{}:{}",
+                    l.api_key,
+                    l.session_key.clone().unwrap_or_default()
+                )
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum SubmitError {
+    /// Network errors, rate limiting, server errors - worth trying again later
+    Retry(String),
+    /// The submission was rejected, retrying won't help
+    Fatal(String),
+    /// The token or session is invalid, nothing will go through until it's replaced
+    Auth(String),
+}
+
+impl std::fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SubmitError::Retry(msg) => write!(f, "Temporary error: {}", msg),
+            SubmitError::Fatal(msg) => write!(f, "Rejected: {}", msg),
+            SubmitError::Auth(msg) => write!(f, "Not authorised: {}", msg),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SubmitError {
+    fn from(err: reqwest::Error) -> Self {
+        SubmitError::Retry(err.to_string())
+    }
+}
+
+fn now_secs() -> i64 {
+    now_millis() / 1000
+}
+
+fn backoff_secs(attempts: u32) -> i64 {
+    let exp = attempts.saturating_sub(1).min(10);
+    (MIN_BACKOFF_SECS << exp).min(MAX_BACKOFF_SECS)
+}
+
+async fn listenbrainz_submit(
+    client: &Client,
+    config: &ListenBrainzConfig,
+    listen: &Listen,
+    now_playing: bool,
+) -> Result<(), SubmitError> {
+    let mut additional_info = json!({
+        "media_player": "Musicat",
+        "submission_client": "Musicat",
+        "submission_client_version": env!("CARGO_PKG_VERSION"),
+    });
+    if let Some(duration) = listen.duration {
+        additional_info["duration_ms"] = json!((duration * 1000.0) as i64);
+    }
+    if let Some(track_number) = listen.track_number {
+        additional_info["tracknumber"] = json!(track_number);
+    }
+    let mut track_metadata = json!({
+        "artist_name": listen.artist,
+        "track_name": listen.title,
+        "additional_info": additional_info,
+    });
+    if let Some(album) = &listen.album {
+        track_metadata["release_name"] = json!(album);
+    }
+
+    let body = if now_playing {
+        json!({
+            "listen_type": "playing_now",
+            "payload": [{ "track_metadata": track_metadata }],
+        })
+    } else {
+        json!({
+            "listen_type": "single",
+            "payload": [{ "listened_at": listen.listened_at, "track_metadata": track_metadata }],
+        })
+    };
+
+    let response = client
+        .post(format!("{}/1/submit-listens", config.url))
+        .header("Authorization", format!("Token {}", config.token))
+        .header("Content-Type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await?;
+
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let text = response.text().await.unwrap_or_default();
+    if status.as_u16() == 429 || status.is_server_error() {
+        Err(SubmitError::Retry(format!("{} {}", status, text)))
+    } else if status.as_u16() == 401 {
+        Err(SubmitError::Auth(format!("{} {}", status, text)))
+    } else {
+        Err(SubmitError::Fatal(format!("{} {}", status, text)))
+    }
+}
+
+/**
+ * Signs a Last.fm API call: all the parameters sorted by name,
+ * concatenated as <name><value>, with the secret appended, MD5'd.
+ */
+fn lastfm_signature(params: &[(&str, String)], secret: &str) -> String {
+    let mut sorted = params.to_vec();
+    sorted.sort_by(|a, b| a.0.cmp(b.0));
+    let mut raw = String::new();
+    for (key, value) in sorted {
+        raw.push_str(key);
+        raw.push_str(&value);
+    }
+    raw.push_str(secret);
+    MD5::hash(raw.as_bytes()).to_hex_lowercase()
+}
+
+async fn lastfm_call(
+    client: &Client,
+    config: &LastFmConfig,
+    method: &str,
+    mut params: Vec<(&str, String)>,
+) -> Result<Value, SubmitError> {
+    params.push(("method", method.to_string()));
+    params.push(("api_key", config.api_key.clone()));
+    let signature = lastfm_signature(&params, &config.api_secret);
+    params.push(("api_sig", signature));
+    params.push(("format", "json".to_string()));
+
+    let response = client.post(&config.url).form(&params).send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    let value: Value = serde_json::from_str(&text).unwrap_or(Value::Null);
+
+    if let Some(code) = value.get("error").and_then(|e| e.as_i64()) {
+        let message = value
+            .get("message")
+            .and_then(|m| m.as_str())
+            .unwrap_or_default();
+        // 11: service offline, 16: temporarily unavailable, 29: rate limit exceeded
+        // 4, 14: bad auth token, 9: bad session key, 10, 26: bad or suspended API key
+        return match code {
+            11 | 16 | 29 => Err(SubmitError::Retry(format!("{} {}", code, message))),
+            4 | 9 | 10 | 14 | 26 => Err(SubmitError::Auth(format!("{} {}", code, message))),
+            _ => Err(SubmitError::Fatal(format!("{} {}", code, message))),
+        };
+    }
+    if status.as_u16() == 429 || status.is_server_error() {
+        return Err(SubmitError::Retry(format!("{} {}", status, text)));
+    }
+    if !status.is_success() {
+        return Err(SubmitError::Fatal(format!("{} {}", status, text)));
+    }
+    Ok(value)
+}
+
+fn lastfm_track_params(listen: &Listen) -> Vec<(&'static str, String)> {
+    let mut params = vec![
+        ("artist", listen.artist.clone()),
+        ("track", listen.title.clone()),
+    ];
+    if let Some(album) = &listen.album {
+        params.push(("album", album.clone()));
+    }
+    if let Some(album_artist) = &listen.album_artist {
+        params.push(("albumArtist", album_artist.clone()));
+    }
+    if let Some(track_number) = listen.track_number {
+        params.push(("trackNumber", track_number.to_string()));
+    }
+    if let Some(duration) = listen.duration {
+        params.push(("duration", (duration.round() as i64).to_string()));
+    }
+    params
+}
+
+async fn lastfm_submit(
+    client: &Client,
+    config: &LastFmConfig,
+    listen: &Listen,
+    now_playing: bool,
+) -> Result<(), SubmitError> {
+    let Some(session_key) = &config.session_key else {
+        return Err(SubmitError::Retry(
+            "Not authenticated with Last.fm".to_string(),
+        ));
+    };
+    let mut params = lastfm_track_params(listen);
+    params.push(("sk", session_key.clone()));
+    let method = if now_playing {
+        "track.updateNowPlaying"
+    } else {
+        params.push(("timestamp", listen.listened_at.to_string()));
+        "track.scrobble"
+    };
+    lastfm_call(client, config, method, params)
+        .await
+        .map(|_| ())
+}
+
+/// Submissions waiting to be sent, persisted so they survive restarts and offline periods
+struct ScrobbleQueue {
+    path: Option<PathBuf>,
+    items: Vec<QueuedScrobble>,
+}
+
+impl ScrobbleQueue {
+    fn load(path: Option<PathBuf>) -> Self {
+        let items = path
+            .as_ref()
+            .and_then(|p| fs::read_to_string(p).ok())
+            .and_then(|data| serde_json::from_str(&data).ok())
+            .unwrap_or_default();
+        Self { path, items }
+    }
+
+    fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        match serde_json::to_string(&self.items) {
+            Ok(data) => {
+                if let Err(err) = fs::write(path, data) {
+                    error!("[Scrobbler] Error saving queue: {}", err);
+                }
+            }
+            Err(err) => error!("[Scrobbler] Error serializing queue: {}", err),
+        }
+    }
+}
+
+fn get_queue_path(app: &AppHandle) -> Option<PathBuf> {
+    let data_dir = app.path().app_data_dir().ok()?;
+    if !data_dir.exists() {
+        fs::create_dir_all(&data_dir).ok()?;
+    }
+    Some(data_dir.join(QUEUE_FILE))
+}
+
+fn is_scrobblable(song: &Song) -> bool {
+    !song.artist.trim().is_empty()
+        && !song.title.trim().is_empty()
+        && song
+            .file_info
+            .duration
+            .map_or(true, |d| d >= MIN_SCROBBLE_DURATION)
+}
+
+/**
+ * Appends a line to the Rockbox-style `.scrobbler.log` (Audioscrobbler portable player format),
+ * which can be uploaded manually with tools that support it.
+ * Format: artist, album, title, track number, duration, rating (L = listened, S = skipped),
+ * timestamp and MusicBrainz track id, separated by tabs.
+ */
+fn write_scrobbler_log(
+    dir: &PathBuf,
+    session: &ListenSession,
+    outcome: ListenOutcome,
+    version: &str,
+) -> std::io::Result<()> {
+    let path = dir.join(SCROBBLER_LOG_FILE);
+    let is_new = !path.exists();
+    let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+    if is_new {
+        writeln!(file, "#AUDIOSCROBBLER/1.1")?;
+        writeln!(file, "#TZ/UTC")?;
+        writeln!(file, "#CLIENT/Musicat {}", version)?;
+    }
+
+    let clean = |s: &str| s.replace(['\t', '\n', '\r'], " ");
+    let song = &session.song;
+    let track_number = if song.track_number > 0 {
+        song.track_number.to_string()
+    } else {
+        String::new()
+    };
+    let rating = match outcome {
+        ListenOutcome::Play => "L",
+        _ => "S",
+    };
+    writeln!(
+        file,
+        "{}\t{}\t{}\t{}\t{}\t{}\t{}\t",
+        clean(&song.artist),
+        clean(&song.album),
+        clean(&song.title),
+        track_number,
+        song.file_info.duration.unwrap_or(0.0).round() as i64,
+        rating,
+        session.started_at / 1000
+    )
+}
+
+struct Scrobbler {
+    app: AppHandle,
+    client: Client,
+    config: ScrobblerConfig,
+    queue: ScrobbleQueue,
+    /// Services whose credentials were rejected, paused until they change
+    unauthorised: Vec<ScrobbleService>,
+}
+
+impl Scrobbler {
+    fn reload_config(&mut self) {
+        let config = ScrobblerConfig::load(&self.app);
+        let previous = std::mem::replace(&mut self.config, config);
+        let config = &self.config;
+        self.unauthorised
+            .retain(|service| previous.credentials(*service) == config.credentials(*service));
+    }
+
+    /// Configured services that haven't rejected their credentials
+    fn services(&self) -> Vec<ScrobbleService> {
+        self.config
+            .services()
+            .into_iter()
+            .filter(|service| !self.unauthorised.contains(service))
+            .collect()
+    }
+
+    /// Keeps the queue as is and stops submitting to the service until it's re-authorised
+    fn pause_unauthorised(&mut self, service: ScrobbleService, err: &str) {
+        error!(
+            "[Scrobbler] {:?} rejected our credentials: {}",
+            service, err
+        );
+        if self.unauthorised.contains(&service) {
+            return;
+        }
+        self.unauthorised.push(service);
+        let name = match service {
+            ScrobbleService::ListenBrainz => "ListenBrainz",
+            ScrobbleService::LastFm => "Last.fm",
+        };
+        let _ = self.app.emit(
+            "error",
+            format!(
+                "{} rejected the scrobbler credentials, scrobbles are kept until you sign in again",
+                name
+            ),
+        );
+    }
+
+    fn handle(&mut self, update: ListenUpdate) {
+        match update {
+            ListenUpdate::Started(session) => {
+                if !is_scrobblable(&session.song) {
+                    return;
+                }
+                let listen = Listen::from_song(&session.song, session.started_at / 1000);
+                for service in self.services() {
+                    match self.submit(service, &listen, true) {
+                        Ok(()) => {}
+                        Err(SubmitError::Auth(err)) => self.pause_unauthorised(service, &err),
+                        Err(err) => {
+                            warn!("[Scrobbler] {:?} now playing failed: {}", service, err)
+                        }
+                    }
+                }
+            }
+            ListenUpdate::ThresholdReached(session) => {
+                if !is_scrobblable(&session.song) {
+                    return;
+                }
+                let listen = Listen::from_song(&session.song, session.started_at / 1000);
+                info!(
+                    "[Scrobbler] Queueing scrobble: {} - {}",
+                    listen.artist, listen.title
+                );
+                for service in self.config.services() {
+                    self.queue.items.push(QueuedScrobble {
+                        service,
+                        listen: listen.clone(),
+                        attempts: 0,
+                        next_attempt_at: 0,
+                    });
+                }
+                self.queue.save();
+                self.flush();
+            }
+            ListenUpdate::Finished(session, outcome) => {
+                if let Some(dir) = &self.config.log_location {
+                    let version = self.app.package_info().version.to_string();
+                    if let Err(err) = write_scrobbler_log(dir, &session, outcome, &version) {
+                        error!("[Scrobbler] Error writing {}: {}", SCROBBLER_LOG_FILE, err);
+                    }
+                }
+            }
+        }
+    }
+
+    fn submit(
+        &self,
+        service: ScrobbleService,
+        listen: &Listen,
+        now_playing: bool,
+    ) -> Result<(), SubmitError> {
+        tauri::async_runtime::block_on(async {
+            match service {
+                ScrobbleService::ListenBrainz => match &self.config.listenbrainz {
+                    Some(config) => {
+                        listenbrainz_submit(&self.client, config, listen, now_playing).await
+                    }
+                    None => Err(SubmitError::Retry(
+                        "ListenBrainz not configured".to_string(),
+                    )),
+                },
+                ScrobbleService::LastFm => match &self.config.lastfm {
+                    Some(config) => lastfm_submit(&self.client, config, listen, now_playing).await,
+                    None => Err(SubmitError::Retry("Last.fm not configured".to_string())),
+                },
+            }
+        })
+    }
+
+    /// Send everything that's due, backing off exponentially on temporary errors
+    fn flush(&mut self) {
+        let now = now_secs();
+        let available = self.services();
+        // Once a service fails, don't hammer it with the rest of the queue
+        let mut failed: Vec<ScrobbleService> = Vec::new();
+        let mut changed = false;
+
+        let items = std::mem::take(&mut self.queue.items);
+        for mut item in items {
+            if item.next_attempt_at > now
+                || !available.contains(&item.service)
+                || failed.contains(&item.service)
+            {
+                self.queue.items.push(item);
+                continue;
+            }
+            changed = true;
+            match self.submit(item.service, &item.listen, false) {
+                Ok(()) => {
+                    info!(
+                        "[Scrobbler] {:?} scrobbled: {} - {}",
+                        item.service, item.listen.artist, item.listen.title
+                    );
+                }
+                Err(SubmitError::Retry(err)) => {
+                    item.attempts += 1;
+                    item.next_attempt_at = now + backoff_secs(item.attempts);
+                    warn!(
+                        "[Scrobbler] {:?} scrobble failed (attempt {}), retrying later: {}",
+                        item.service, item.attempts, err
+                    );
+                    failed.push(item.service);
+                    self.queue.items.push(item);
+                }
+                Err(SubmitError::Fatal(err)) => {
+                    error!(
+                        "[Scrobbler] {:?} rejected scrobble for {} - {}: {}",
+                        item.service, item.listen.artist, item.listen.title, err
+                    );
+                }
+                Err(SubmitError::Auth(err)) => {
+                    self.pause_unauthorised(item.service, &err);
+                    failed.push(item.service);
+                    self.queue.items.push(item);
+                }
+            }
+        }
+
+        if changed {
+            self.queue.save();
+            let _ = self.app.emit("scrobble_queue", self.queue.items.len());
+        }
+    }
+}
+
+/// Start the scrobbler thread, consuming events from the decoding thread.
+pub fn init(app: AppHandle, receiver: Receiver<PlaybackEvent>) {
+    std::thread::spawn(move || {
+        let mut scrobbler = Scrobbler {
+            config: ScrobblerConfig::load(&app),
+            queue: ScrobbleQueue::load(get_queue_path(&app)),
+            unauthorised: Vec::new(),
+            client: Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .unwrap_or_default(),
+            app,
+        };
+        let mut tracker = ListenTracker::new(SCROBBLE_THRESHOLD);
+
+        // Anything left over from last time
+        scrobbler.flush();
+
+        loop {
+            match receiver.recv_timeout(RETRY_INTERVAL) {
+                Ok(event) => {
+                    if let PlaybackEvent::Started { .. } = event {
+                        scrobbler.reload_config();
+                    }
+                    for update in tracker.handle(&event) {
+                        scrobbler.handle(update);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    if !scrobbler.queue.items.is_empty() {
+                        scrobbler.flush();
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+    });
+}
+
+#[tauri::command]
+pub fn get_scrobble_queue(app_handle: AppHandle) -> Result<Vec<QueuedScrobble>, String> {
+    Ok(ScrobbleQueue::load(get_queue_path(&app_handle)).items)
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LastFmToken {
+    token: String,
+    /// The user needs to open this and allow access before requesting a session
+    auth_url: String,
+}
+
+fn lastfm_config(app: &AppHandle) -> Result<LastFmConfig, String> {
+    ScrobblerConfig::load(app)
+        .lastfm
+        .ok_or_else(|| "Last.fm API key and secret are not set".to_string())
+}
+
+/**
+ * First step of the Last.fm desktop auth flow.
+ * Once the user has allowed access at the auth URL, call `lastfm_get_session` with the token.
+ */
+#[tauri::command]
+pub async fn lastfm_get_token(app_handle: AppHandle) -> Result<LastFmToken, String> {
+    let config = lastfm_config(&app_handle)?;
+    let response = lastfm_call(&Client::new(), &config, "auth.getToken", vec![])
+        .await
+        .map_err(|e| e.to_string())?;
+    let token = response
+        .get("token")
+        .and_then(|t| t.as_str())
+        .ok_or_else(|| "No token in Last.fm response".to_string())?
+        .to_string();
+    Ok(LastFmToken {
+        auth_url: format!(
+            "{}?api_key={}&token={}",
+            LASTFM_AUTH_URL, config.api_key, token
+        ),
+        token,
+    })
+}
+
+/// Returns the session key, to be stored in the settings as `lastfmSessionKey`
+#[tauri::command]
+pub async fn lastfm_get_session(token: String, app_handle: AppHandle) -> Result<String, String> {
+    let config = lastfm_config(&app_handle)?;
+    let response = lastfm_call(
+        &Client::new(),
+        &config,
+        "auth.getSession",
+        vec![("token", token)],
+    )
+    .await
+    .map_err(|e| e.to_string())?;
+    response
+        .pointer("/session/key")
+        .and_then(|k| k.as_str())
+        .map(|k| k.to_string())
+        .ok_or_else(|| "No session in Last.fm response".to_string())
+}
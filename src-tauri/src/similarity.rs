@@ -0,0 +1,666 @@
+//! Audio features for "radio from this song": a compact vector per track,
+//! nearest neighbours in the library, and an auto-DJ that keeps the queue going
+
+use chksum_md5::MD5;
+use log::{error, info, warn};
+use rayon::prelude::*;
+use rusqlite::{params, Connection, OpenFlags};
+use rustfft::FftPlanner;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::decode::FileDecoder;
+use crate::files::{expand_audio_paths, library_audio_files};
+use crate::history::PlaybackEvent;
+use crate::player::AudioPlayer;
+use crate::tempo_key::{estimate_tempo, spectral_flux, Stft};
+
+/// The scan's entry in the player's cancel tokens
+pub const CANCEL_TOKEN_KEY: &str = "audio_features";
+/// Bumped when the features change, older vectors are computed again
+const FEATURES_VERSION: i64 = 1;
+const MFCC_COEFFICIENTS: usize = 13;
+const MEL_FILTERS: usize = 26;
+const MEL_MIN_FREQUENCY: f32 = 20.0;
+const MEL_MAX_FREQUENCY: f32 = 8000.0;
+/// Share of the spectral energy below the rolloff frequency
+const ROLLOFF: f32 = 0.85;
+/// Blocks below this level are silence, and left out of the dynamic range
+const SILENCE_DB: f32 = -70.0;
+const DEFAULT_QUEUE_AHEAD: usize = 3;
+
+/// Spectral centroid, rolloff, the MFCC means, energy, tempo and dynamic range
+const FEATURE_COUNT: usize = MFCC_COEFFICIENTS + 5;
+/// How much each feature counts in the distance. The MFCCs share theirs, as
+/// they all describe the timbre.
+const WEIGHTS: [f32; FEATURE_COUNT] = [
+    1.0, 1.0, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 0.3, 1.0, 1.5, 1.0,
+];
+
+static AUTO_DJ: Mutex<Option<AutoDj>> = Mutex::new(None);
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct FeatureScanStatus {
+    pub total: usize,
+    pub done: usize,
+    /// Already analysed and unchanged since
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SimilarTrack {
+    pub song_id: String,
+    pub path: String,
+    /// 0 for identical features
+    pub distance: f32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AutoDjQueue {
+    /// The song the tracks were picked for
+    seed_song_id: String,
+    tracks: Vec<SimilarTrack>,
+}
+
+struct AutoDj {
+    queue_ahead: usize,
+    /// Never suggested again this session
+    played: HashSet<String>,
+    /// Suggested and not played yet
+    pending: VecDeque<String>,
+}
+
+pub struct FeatureStore {
+    conn: Connection,
+}
+
+impl FeatureStore {
+    pub fn open(path: &PathBuf) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE IF NOT EXISTS features (
+                song_id TEXT PRIMARY KEY,
+                path TEXT NOT NULL,
+                modified INTEGER NOT NULL,
+                version INTEGER NOT NULL,
+                vector BLOB NOT NULL
+            );
+            ",
+        )?;
+        Ok(Self { conn })
+    }
+
+    pub fn open_read_only(path: &PathBuf) -> rusqlite::Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        Ok(Self { conn })
+    }
+
+    /// Whether the stored features are from this version of the file
+    pub fn is_current(&self, song_id: &str, modified: i64) -> bool {
+        self.conn
+            .query_row(
+                "SELECT 1 FROM features WHERE song_id = ?1 AND modified = ?2 AND version = ?3",
+                params![song_id, modified, FEATURES_VERSION],
+                |_| Ok(()),
+            )
+            .is_ok()
+    }
+
+    pub fn upsert(
+        &self,
+        song_id: &str,
+        path: &str,
+        modified: i64,
+        features: &[f32],
+    ) -> rusqlite::Result<()> {
+        let vector: Vec<u8> = features.iter().flat_map(|f| f.to_le_bytes()).collect();
+        self.conn.execute(
+            "INSERT OR REPLACE INTO features (song_id, path, modified, version, vector)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![song_id, path, modified, FEATURES_VERSION, vector],
+        )?;
+        Ok(())
+    }
+
+    /// Keeps the features of a song that was moved or renamed
+    pub fn remap_song(&self, from: &str, to: &str, path: &str) -> rusqlite::Result<usize> {
+        self.conn.execute(
+            "UPDATE OR REPLACE features SET song_id = ?2, path = ?3 WHERE song_id = ?1",
+            params![from, to, path],
+        )
+    }
+
+    /// Song id, path and features of every analysed song
+    pub fn all(&self) -> rusqlite::Result<Vec<(String, String, Vec<f32>)>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT song_id, path, vector FROM features WHERE version = ?1")?;
+        let rows = stmt.query_map([FEATURES_VERSION], |row| {
+            let vector: Vec<u8> = row.get(2)?;
+            Ok((
+                row.get(0)?,
+                row.get(1)?,
+                vector
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect(),
+            ))
+        })?;
+        rows.collect()
+    }
+}
+
+pub fn get_features_db_path(app: &AppHandle) -> Option<PathBuf> {
+    let data_dir = app.path().app_data_dir().ok()?;
+    if !data_dir.exists() {
+        std::fs::create_dir_all(&data_dir).ok()?;
+    }
+    Some(data_dir.join("features.db"))
+}
+
+/// Start the auto-DJ thread, consuming events from the decoding thread.
+pub fn init(app: AppHandle, receiver: Receiver<PlaybackEvent>) {
+    std::thread::spawn(move || {
+        while let Ok(event) = receiver.recv() {
+            if let PlaybackEvent::Started { song, .. } = event {
+                if let Err(err) = on_song_started(&song.id, &app) {
+                    error!("[AutoDJ] Error picking the next tracks: {}", err);
+                }
+            }
+        }
+    });
+}
+
+/**
+ * Computes the features of the given files and folders, or of every file in
+ * the watched folders. Unchanged files that were already analysed are skipped.
+ * Emits "audio_features_progress" with the status after each file.
+ */
+#[tauri::command]
+pub async fn analyze_audio_features(
+    paths: Option<Vec<String>>,
+    state: State<'_, AudioPlayer>,
+    app_handle: AppHandle,
+) -> Result<FeatureScanStatus, String> {
+    let token = CancellationToken::new();
+    if let Some(previous) = state
+        .cancel_tokens
+        .lock()
+        .await
+        .insert(CANCEL_TOKEN_KEY.to_string(), token.clone())
+    {
+        previous.cancel();
+    }
+
+    let thread_token = token.clone();
+    let result =
+        tauri::async_runtime::spawn_blocking(move || scan(paths, &app_handle, &thread_token))
+            .await
+            .map_err(|e| e.to_string())?;
+
+    if !token.is_cancelled() {
+        state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY);
+    }
+    result
+}
+
+#[tauri::command]
+pub async fn cancel_audio_features(state: State<'_, AudioPlayer>) -> Result<(), String> {
+    if let Some(token) = state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY) {
+        token.cancel();
+    }
+    Ok(())
+}
+
+/**
+ * The `n` analysed songs that sound the most like the given one, closest first.
+ */
+#[tauri::command]
+pub async fn similar_tracks(
+    song_id: String,
+    n: usize,
+    app_handle: AppHandle,
+) -> Result<Vec<SimilarTrack>, String> {
+    tauri::async_runtime::spawn_blocking(move || nearest(&song_id, n, &HashSet::new(), &app_handle))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+/**
+ * In auto-DJ mode, whenever a song starts, tracks that sound like it are sent
+ * with "auto_dj_queue" for the frontend to append to the queue, keeping
+ * `queue_ahead` of them ahead.
+ */
+#[tauri::command]
+pub fn set_auto_dj(enabled: bool, queue_ahead: Option<usize>) {
+    info!("[AutoDJ] {}", if enabled { "On" } else { "Off" });
+    *AUTO_DJ.lock().unwrap() = enabled.then(|| AutoDj {
+        queue_ahead: queue_ahead.unwrap_or(DEFAULT_QUEUE_AHEAD).max(1),
+        played: HashSet::new(),
+        pending: VecDeque::new(),
+    });
+}
+
+#[tauri::command]
+pub fn is_auto_dj_enabled() -> bool {
+    AUTO_DJ.lock().unwrap().is_some()
+}
+
+fn on_song_started(song_id: &str, app: &AppHandle) -> Result<(), String> {
+    let (count, exclude) = {
+        let mut auto_dj = AUTO_DJ.lock().unwrap();
+        let Some(auto_dj) = auto_dj.as_mut() else {
+            return Ok(());
+        };
+        auto_dj.played.insert(song_id.to_string());
+        if auto_dj.pending.front().is_some_and(|id| id == song_id) {
+            auto_dj.pending.pop_front();
+        } else {
+            // Something else was picked, follow that instead
+            auto_dj.pending.clear();
+        }
+        let count = auto_dj.queue_ahead.saturating_sub(auto_dj.pending.len());
+        let exclude: HashSet<String> = auto_dj
+            .played
+            .iter()
+            .chain(auto_dj.pending.iter())
+            .cloned()
+            .collect();
+        (count, exclude)
+    };
+    if count == 0 {
+        return Ok(());
+    }
+
+    let tracks = nearest(song_id, count, &exclude, app)?;
+    if tracks.is_empty() {
+        return Ok(());
+    }
+    if let Some(auto_dj) = AUTO_DJ.lock().unwrap().as_mut() {
+        auto_dj
+            .pending
+            .extend(tracks.iter().map(|t| t.song_id.clone()));
+    }
+    info!("[AutoDJ] Queueing {} tracks", tracks.len());
+    let _ = app.emit(
+        "auto_dj_queue",
+        AutoDjQueue {
+            seed_song_id: song_id.to_string(),
+            tracks,
+        },
+    );
+    Ok(())
+}
+
+/// Closest songs by weighted distance, once every feature is scaled to the library's spread
+fn nearest(
+    song_id: &str,
+    n: usize,
+    exclude: &HashSet<String>,
+    app: &AppHandle,
+) -> Result<Vec<SimilarTrack>, String> {
+    let path = get_features_db_path(app).ok_or("Features database not found")?;
+    if !path.exists() {
+        return Err("No songs analysed yet".into());
+    }
+    let store = FeatureStore::open_read_only(&path).map_err(|e| e.to_string())?;
+    let rows: Vec<_> = store
+        .all()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|(_, _, features)| features.len() == FEATURE_COUNT)
+        .collect();
+    let seed = rows
+        .iter()
+        .find(|(id, _, _)| id == song_id)
+        .map(|(_, _, features)| features.clone())
+        .ok_or("Song not analysed")?;
+
+    let count = rows.len() as f32;
+    let mut means = [0.0f32; FEATURE_COUNT];
+    let mut deviations = [0.0f32; FEATURE_COUNT];
+    for (_, _, features) in rows.iter() {
+        for (mean, value) in means.iter_mut().zip(features) {
+            *mean += value / count;
+        }
+    }
+    for (_, _, features) in rows.iter() {
+        for (i, value) in features.iter().enumerate() {
+            deviations[i] += (value - means[i]).powi(2) / count;
+        }
+    }
+    let scales: Vec<f32> = deviations
+        .iter()
+        .zip(WEIGHTS)
+        .map(|(variance, weight)| {
+            if *variance > 1e-9 {
+                weight / variance.sqrt()
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    let mut tracks: Vec<SimilarTrack> = rows
+        .into_iter()
+        .filter(|(id, _, _)| id != song_id && !exclude.contains(id))
+        .map(|(id, path, features)| SimilarTrack {
+            distance: features
+                .iter()
+                .zip(&seed)
+                .zip(&scales)
+                .map(|((a, b), scale)| ((a - b) * scale).powi(2))
+                .sum::<f32>()
+                .sqrt(),
+            song_id: id,
+            path,
+        })
+        .collect();
+    tracks.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+    // Files may have gone since they were analysed
+    Ok(tracks
+        .into_iter()
+        .filter(|t| Path::new(&t.path).exists())
+        .take(n)
+        .collect())
+}
+
+fn scan(
+    paths: Option<Vec<String>>,
+    app: &AppHandle,
+    cancel_token: &CancellationToken,
+) -> Result<FeatureScanStatus, String> {
+    let files = match paths {
+        Some(paths) => expand_audio_paths(&paths),
+        None => library_audio_files(app),
+    };
+    let db_path = get_features_db_path(app).ok_or("App data directory not available")?;
+    let store = Mutex::new(FeatureStore::open(&db_path).map_err(|e| e.to_string())?);
+    info!("[Features] Analysing {} files", files.len());
+
+    let total = files.len();
+    let done = AtomicUsize::new(0);
+    let skipped = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    let status = || FeatureScanStatus {
+        total,
+        done: done.load(Ordering::Relaxed),
+        skipped: skipped.load(Ordering::Relaxed),
+        failed: failed.load(Ordering::Relaxed),
+    };
+
+    files.par_iter().for_each(|path| {
+        if cancel_token.is_cancelled() {
+            return;
+        }
+        // Same as the library's song ids
+        let song_id = MD5::hash(path.to_string_lossy().as_bytes()).to_hex_lowercase();
+        let modified = modified_secs(path);
+        if store.lock().unwrap().is_current(&song_id, modified) {
+            skipped.fetch_add(1, Ordering::Relaxed);
+        } else {
+            match extract(path, cancel_token) {
+                Ok(features) => {
+                    let path = path.to_string_lossy();
+                    if let Err(err) = store
+                        .lock()
+                        .unwrap()
+                        .upsert(&song_id, &path, modified, &features)
+                    {
+                        error!("[Features] Error saving {}: {}", path, err);
+                        failed.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                Err(_) if cancel_token.is_cancelled() => return,
+                Err(err) => {
+                    warn!("[Features] Error analysing {:?}: {}", path, err);
+                    failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        done.fetch_add(1, Ordering::Relaxed);
+        let _ = app.emit("audio_features_progress", status());
+    });
+
+    if cancel_token.is_cancelled() {
+        return Err("Cancelled".into());
+    }
+    let status = status();
+    info!("[Features] Finished: {:?}", status);
+    Ok(status)
+}
+
+fn modified_secs(path: &Path) -> i64 {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |duration| duration.as_secs() as i64)
+}
+
+fn extract(path: &Path, cancel_token: &CancellationToken) -> Result<Vec<f32>, String> {
+    let mut decoder = FileDecoder::open(path).map_err(|e| e.to_string())?;
+    let mut extractor = Extractor::new(decoder.sample_rate);
+    decoder
+        .for_each(cancel_token, |samples, channels| {
+            extractor.push(samples, channels)
+        })
+        .map_err(|e| e.to_string())?;
+    extractor
+        .finish()
+        .ok_or_else(|| "Too short or silent".into())
+}
+
+/// Triangular filters evenly spaced on the mel scale, as (bin, weight) pairs
+fn mel_filters(bins: usize, bin_width: f32) -> Vec<Vec<(usize, f32)>> {
+    let to_mel = |frequency: f32| 2595.0 * (1.0 + frequency / 700.0).log10();
+    let from_mel = |mel: f32| 700.0 * (10f32.powf(mel / 2595.0) - 1.0);
+    let max_frequency = MEL_MAX_FREQUENCY.min(bins as f32 * bin_width);
+    let (min_mel, max_mel) = (to_mel(MEL_MIN_FREQUENCY), to_mel(max_frequency));
+    let edges: Vec<f32> = (0..MEL_FILTERS + 2)
+        .map(|i| from_mel(min_mel + (max_mel - min_mel) * i as f32 / (MEL_FILTERS + 1) as f32))
+        .collect();
+
+    edges
+        .windows(3)
+        .map(|edge| {
+            (0..bins)
+                .filter_map(|bin| {
+                    let frequency = bin as f32 * bin_width;
+                    let weight = if frequency <= edge[1] {
+                        (frequency - edge[0]) / (edge[1] - edge[0])
+                    } else {
+                        (edge[2] - frequency) / (edge[2] - edge[1])
+                    };
+                    (weight > 0.0).then_some((bin, weight))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Feature sums over a whole file, gathered in one pass
+struct Extractor {
+    sample_rate: f32,
+    stft: Stft,
+    bin_width: f32,
+    mel_filters: Vec<Vec<(usize, f32)>>,
+    /// Log magnitudes of the previous frame, for the onsets
+    previous: Vec<f32>,
+    onset: Vec<f32>,
+    /// Frames with sound in them
+    frames: usize,
+    centroid: f64,
+    rolloff: f64,
+    mfcc: [f64; MFCC_COEFFICIENTS],
+    /// Sum of squares and sample count of the current one second block
+    block: (f64, usize),
+    /// Level of each block, dB
+    blocks: Vec<f32>,
+    /// Sum of squares of the whole file
+    energy: f64,
+    samples: usize,
+    mono: Vec<f32>,
+}
+
+impl Extractor {
+    fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate.max(1);
+        // About 23 ms, whatever the sample rate
+        let size = (sample_rate as usize / 64).next_power_of_two();
+        let bin_width = sample_rate as f32 / size as f32;
+        Extractor {
+            sample_rate: sample_rate as f32,
+            stft: Stft::new(&mut FftPlanner::new(), size),
+            bin_width,
+            mel_filters: mel_filters(size / 2, bin_width),
+            previous: vec![0.0; size / 2],
+            onset: vec![],
+            frames: 0,
+            centroid: 0.0,
+            rolloff: 0.0,
+            mfcc: [0.0; MFCC_COEFFICIENTS],
+            block: (0.0, 0),
+            blocks: vec![],
+            energy: 0.0,
+            samples: 0,
+            mono: vec![],
+        }
+    }
+
+    fn push(&mut self, interleaved: &[f32], channels: usize) {
+        let channels = channels.max(1);
+        self.mono.clear();
+        self.mono.extend(
+            interleaved
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+
+        let block_len = self.sample_rate as usize;
+        for sample in self.mono.iter() {
+            let square = (*sample as f64).powi(2);
+            self.energy += square;
+            self.block.0 += square;
+            self.block.1 += 1;
+            if self.block.1 == block_len {
+                self.blocks.push(to_db(self.block.0 / block_len as f64));
+                self.block = (0.0, 0);
+            }
+        }
+        self.samples += self.mono.len();
+
+        let bin_width = self.bin_width;
+        let mel_filters = &self.mel_filters;
+        let previous = &mut self.previous;
+        let onset = &mut self.onset;
+        let frames = &mut self.frames;
+        let centroid_sum = &mut self.centroid;
+        let rolloff_sum = &mut self.rolloff;
+        let mfcc_sums = &mut self.mfcc;
+        self.stft.push(&self.mono, |magnitudes| {
+            onset.push(spectral_flux(magnitudes, previous));
+
+            let power: Vec<f32> = magnitudes.iter().map(|m| m * m).collect();
+            let total: f32 = power.iter().sum();
+            if total < 1e-10 {
+                return;
+            }
+            *frames += 1;
+
+            let weighted: f32 = power
+                .iter()
+                .enumerate()
+                .map(|(bin, p)| bin as f32 * p)
+                .sum();
+            *centroid_sum += (weighted / total * bin_width) as f64;
+
+            let mut cumulative = 0.0;
+            let rolloff_bin = power
+                .iter()
+                .position(|p| {
+                    cumulative += p;
+                    cumulative >= total * ROLLOFF
+                })
+                .unwrap_or(power.len());
+            *rolloff_sum += (rolloff_bin as f32 * bin_width) as f64;
+
+            let log_energies: Vec<f32> = mel_filters
+                .iter()
+                .map(|filter| {
+                    let energy: f32 = filter.iter().map(|(bin, w)| power[*bin] * w).sum();
+                    (energy + 1e-10).ln()
+                })
+                .collect();
+            // DCT-II, leaving out the first coefficient which is just the level
+            for (k, sum) in mfcc_sums.iter_mut().enumerate() {
+                let coefficient: f32 = log_energies
+                    .iter()
+                    .enumerate()
+                    .map(|(i, e)| {
+                        e * (std::f32::consts::PI * (k + 1) as f32 * (i as f32 + 0.5)
+                            / MEL_FILTERS as f32)
+                            .cos()
+                    })
+                    .sum();
+                *sum += coefficient as f64;
+            }
+        });
+    }
+
+    fn finish(&self) -> Option<Vec<f32>> {
+        if self.frames == 0 || self.samples == 0 {
+            return None;
+        }
+        let frames = self.frames as f64;
+        let frame_rate = self.sample_rate / self.stft.hop as f32;
+        // Without a beat, somewhere in the middle of the range
+        let bpm = estimate_tempo(&self.onset, frame_rate).map_or(100.0, |(bpm, _)| bpm);
+
+        let mut levels: Vec<f32> = self
+            .blocks
+            .iter()
+            .cloned()
+            .filter(|level| *level > SILENCE_DB)
+            .collect();
+        levels.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: f32| levels[((levels.len() - 1) as f32 * p).round() as usize];
+        let dynamic_range = if levels.is_empty() {
+            0.0
+        } else {
+            percentile(0.95) - percentile(0.1)
+        };
+
+        let mut features = Vec::with_capacity(FEATURE_COUNT);
+        // In octaves, like it's heard
+        features.push(((self.centroid / frames).max(1.0) as f32).log2());
+        features.push(((self.rolloff / frames).max(1.0) as f32).log2());
+        features.extend(self.mfcc.iter().map(|sum| (sum / frames) as f32));
+        features.push(to_db(self.energy / self.samples as f64));
+        features.push((bpm as f32).log2());
+        features.push(dynamic_range);
+        Some(features)
+    }
+}
+
+fn to_db(mean_square: f64) -> f32 {
+    if mean_square <= 0.0 {
+        return -120.0;
+    }
+    (10.0 * mean_square.log10()).max(-120.0) as f32
+}
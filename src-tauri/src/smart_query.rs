@@ -0,0 +1,1087 @@
+//! Smart playlist queries, eg. `genre:jazz year:1955..1965 -artist:"Miles Davis" plays>3 added<30d`
+//!
+//! Terms are matched together, `OR` (or `|`) matches either side, `-` excludes
+//! and brackets group. A query is parsed into an expression that's compiled to
+//! SQL for the library or beets, or checked song by song for what the database
+//! doesn't have (eg. play counts for beets).
+
+use log::info;
+use rusqlite::types::Value;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use tauri::AppHandle;
+
+use crate::beets::{get_beets_db_path, query_beets_filtered};
+use crate::history::now_millis;
+use crate::library::{
+    like_pattern, open_library_read_only, page_of, LibraryQuery, Page, PlayStats,
+};
+use crate::metadata::Song;
+
+const HOUR: i64 = 60 * 60 * 1000;
+const DAY: i64 = 24 * HOUR;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    /// Bare words, matched against the title, artist and album
+    Any,
+    Title,
+    Artist,
+    AlbumArtist,
+    Album,
+    Genre,
+    Composer,
+    Path,
+    Codec,
+    Key,
+    Year,
+    Track,
+    Disc,
+    /// Seconds
+    Duration,
+    Bpm,
+    /// kbps
+    Bitrate,
+    SampleRate,
+    BitDepth,
+    Plays,
+    Skips,
+    Added,
+    /// Last played
+    Played,
+    Lossless,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Text,
+    Number,
+    Date,
+    Bool,
+}
+
+const FIELDS: &[(&str, Field)] = &[
+    ("title", Field::Title),
+    ("artist", Field::Artist),
+    ("albumartist", Field::AlbumArtist),
+    ("album", Field::Album),
+    ("genre", Field::Genre),
+    ("composer", Field::Composer),
+    ("path", Field::Path),
+    ("codec", Field::Codec),
+    ("format", Field::Codec),
+    ("key", Field::Key),
+    ("year", Field::Year),
+    ("track", Field::Track),
+    ("disc", Field::Disc),
+    ("duration", Field::Duration),
+    ("length", Field::Duration),
+    ("bpm", Field::Bpm),
+    ("bitrate", Field::Bitrate),
+    ("samplerate", Field::SampleRate),
+    ("bitdepth", Field::BitDepth),
+    ("plays", Field::Plays),
+    ("skips", Field::Skips),
+    ("added", Field::Added),
+    ("played", Field::Played),
+    ("lossless", Field::Lossless),
+];
+
+impl Field {
+    fn parse(name: &str) -> Option<Field> {
+        FIELDS
+            .iter()
+            .find(|(field_name, _)| field_name.eq_ignore_ascii_case(name))
+            .map(|(_, field)| *field)
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Any => "title, artist or album",
+            field => FIELDS
+                .iter()
+                .find(|(_, other)| *other == field)
+                .map(|(name, _)| *name)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Field::Any
+            | Field::Title
+            | Field::Artist
+            | Field::AlbumArtist
+            | Field::Album
+            | Field::Genre
+            | Field::Composer
+            | Field::Path
+            | Field::Codec
+            | Field::Key => Kind::Text,
+            Field::Added | Field::Played => Kind::Date,
+            Field::Lossless => Kind::Bool,
+            _ => Kind::Number,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// Every one of them, or everything when empty
+    All(Vec<Expr>),
+    Either(Vec<Expr>),
+    Not(Box<Expr>),
+    Test(Field, Test),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Test {
+    /// Case insensitive, `field:value`
+    Contains(String),
+    /// Case insensitive, `field=value`
+    Is(String),
+    Bool(bool),
+    Range {
+        min: Option<Bound>,
+        max: Option<Bound>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bound {
+    pub value: Number,
+    pub inclusive: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Number {
+    Plain(f64),
+    /// Unix time in ms of the start of a day, eg. "2024-05-01"
+    Date(i64, String),
+    /// This long before the query runs, in ms, eg. "30d"
+    Ago(i64, String),
+}
+
+impl Bound {
+    /// The value to compare with and whether it's included, dates covering the whole day
+    fn resolve(&self, now: i64, is_max: bool) -> (f64, bool) {
+        match &self.value {
+            Number::Date(millis, _) if self.inclusive == is_max => {
+                ((millis + DAY) as f64, !self.inclusive)
+            }
+            Number::Date(millis, _) => (*millis as f64, self.inclusive),
+            Number::Ago(millis, _) => ((now - millis) as f64, self.inclusive),
+            Number::Plain(value) => (*value, self.inclusive),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryError {
+    pub message: String,
+    /// Character offsets in the query
+    pub start: usize,
+    pub end: usize,
+}
+
+impl QueryError {
+    fn new(message: impl Into<String>, start: usize, end: usize) -> Self {
+        Self {
+            message: message.into(),
+            start,
+            end,
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (at {})", self.message, self.start)
+    }
+}
+
+/**
+ * Parses a query, an empty one matching everything.
+ */
+pub fn parse(query: &str) -> Result<Expr, QueryError> {
+    let to_chars = |offset: usize| query[..offset].chars().count();
+    parse_tokens(tokenize(query)?)
+        .map_err(|err| QueryError::new(err.message, to_chars(err.start), to_chars(err.end)))
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Or,
+    Not,
+    Word {
+        /// Name, operator and where the value starts
+        field: Option<(String, &'static str, usize)>,
+        value: String,
+    },
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: TokenKind,
+    /// Byte offsets until the error is reported
+    start: usize,
+    end: usize,
+}
+
+const OPERATORS: &[&str] = &["<=", ">=", ":", "=", "<", ">"];
+
+fn tokenize(query: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut position = 0;
+    while let Some(c) = query[position..].chars().next() {
+        let start = position;
+        position += c.len_utf8();
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '(' => TokenKind::Open,
+            ')' => TokenKind::Close,
+            '|' => TokenKind::Or,
+            '-' => TokenKind::Not,
+            _ => {
+                let (kind, end) = read_word(query, start)?;
+                position = end;
+                kind
+            }
+        };
+        tokens.push(Token {
+            kind,
+            start,
+            end: position,
+        });
+    }
+    Ok(tokens)
+}
+
+/// A bare or quoted word, or `field`, an operator and a value
+fn read_word(query: &str, start: usize) -> Result<(TokenKind, usize), QueryError> {
+    if query[start..].starts_with('"') {
+        let (value, end) = read_quoted(query, start)?;
+        return Ok((TokenKind::Word { field: None, value }, end));
+    }
+    let name_end = run_end(query, start, |c| {
+        c.is_whitespace() || "()\"".contains(c) || ":=<>".contains(c)
+    });
+    let name = &query[start..name_end];
+    let Some(operator) = OPERATORS
+        .iter()
+        .find(|operator| query[name_end..].starts_with(**operator))
+    else {
+        let kind = if name == "OR" {
+            TokenKind::Or
+        } else {
+            TokenKind::Word {
+                field: None,
+                value: name.to_string(),
+            }
+        };
+        return Ok((kind, name_end));
+    };
+    if name.is_empty() {
+        return Err(QueryError::new(
+            format!("Missing a field before {}", operator),
+            start,
+            start + operator.len(),
+        ));
+    }
+
+    let value_start = name_end + operator.len();
+    let (value, end) = if query[value_start..].starts_with('"') {
+        read_quoted(query, value_start)?
+    } else {
+        let end = run_end(query, value_start, |c| {
+            c.is_whitespace() || "()".contains(c)
+        });
+        (query[value_start..end].to_string(), end)
+    };
+    if value.is_empty() {
+        return Err(QueryError::new(
+            format!("Missing a value for {}", name),
+            start,
+            end,
+        ));
+    }
+    Ok((
+        TokenKind::Word {
+            field: Some((name.to_string(), operator, value_start)),
+            value,
+        },
+        end,
+    ))
+}
+
+fn run_end(query: &str, start: usize, is_end: impl Fn(char) -> bool) -> usize {
+    query[start..]
+        .char_indices()
+        .find(|(_, c)| is_end(*c))
+        .map(|(offset, _)| start + offset)
+        .unwrap_or(query.len())
+}
+
+/// A value in double quotes, with `\"` for quotes inside it
+fn read_quoted(query: &str, start: usize) -> Result<(String, usize), QueryError> {
+    let mut value = String::new();
+    let mut chars = query[start + 1..].char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '"' => return Ok((value, start + 1 + offset + 1)),
+            '\\' => {
+                if let Some((_, escaped)) = chars.next() {
+                    value.push(escaped);
+                }
+            }
+            c => value.push(c),
+        }
+    }
+    Err(QueryError::new(
+        "Missing a closing quote",
+        start,
+        query.len(),
+    ))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+fn parse_tokens(tokens: Vec<Token>) -> Result<Expr, QueryError> {
+    let mut parser = Parser {
+        tokens,
+        position: 0,
+    };
+    let expr = parser.either()?;
+    match parser.peek() {
+        Some(token) => Err(QueryError::new("Unmatched )", token.start, token.end)),
+        None => Ok(expr),
+    }
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<&Token> {
+        self.position += 1;
+        self.tokens.get(self.position - 1)
+    }
+
+    /// Where an error about a missing term goes
+    fn here(&self) -> (usize, usize) {
+        match self.peek() {
+            Some(token) => (token.start, token.end),
+            None => {
+                let end = self.tokens.last().map(|t| t.end).unwrap_or_default();
+                (end, end)
+            }
+        }
+    }
+
+    fn at_term(&self) -> bool {
+        self.peek()
+            .is_some_and(|token| !matches!(token.kind, TokenKind::Close | TokenKind::Or))
+    }
+
+    fn either(&mut self) -> Result<Expr, QueryError> {
+        let mut terms = vec![self.all()?];
+        while self.peek().is_some_and(|t| t.kind == TokenKind::Or) {
+            let (start, end) = self.here();
+            self.next();
+            if !self.at_term() || terms.last() == Some(&Expr::All(vec![])) {
+                return Err(QueryError::new(
+                    "OR needs something on both sides",
+                    start,
+                    end,
+                ));
+            }
+            terms.push(self.all()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::Either(terms)
+        })
+    }
+
+    fn all(&mut self) -> Result<Expr, QueryError> {
+        let mut terms = vec![];
+        while self.at_term() {
+            terms.push(self.unary()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::All(terms)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        if self.peek().is_some_and(|t| t.kind == TokenKind::Not) {
+            let (start, end) = self.here();
+            self.next();
+            if !self.at_term() {
+                return Err(QueryError::new("Nothing to exclude after -", start, end));
+            }
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<Expr, QueryError> {
+        let (start, end) = self.here();
+        match self.next().map(|token| &token.kind) {
+            Some(TokenKind::Open) => {
+                let expr = self.either()?;
+                if self.next().map(|t| &t.kind) != Some(&TokenKind::Close) {
+                    return Err(QueryError::new("Missing a closing )", start, end));
+                }
+                if expr == Expr::All(vec![]) {
+                    return Err(QueryError::new("Nothing in the brackets", start, end));
+                }
+                Ok(expr)
+            }
+            Some(TokenKind::Word { field, value }) => {
+                let (field, value) = (field.clone(), value.clone());
+                condition(field, value, start, end)
+            }
+            _ => Err(QueryError::new("Expected a search term", start, end)),
+        }
+    }
+}
+
+fn condition(
+    field: Option<(String, &'static str, usize)>,
+    value: String,
+    start: usize,
+    end: usize,
+) -> Result<Expr, QueryError> {
+    let Some((name, operator, value_start)) = field else {
+        return Ok(Expr::Test(Field::Any, Test::Contains(value)));
+    };
+    let field = Field::parse(&name).ok_or_else(|| {
+        QueryError::new(format!("Unknown field {}", name), start, start + name.len())
+    })?;
+    let value_error = |message: String| QueryError::new(message, value_start, end);
+
+    let test = match field.kind() {
+        Kind::Text => match operator {
+            ":" => Test::Contains(value),
+            "=" => Test::Is(value),
+            _ => {
+                return Err(QueryError::new(
+                    format!("{} can only be matched with : or =", name),
+                    start,
+                    end,
+                ))
+            }
+        },
+        Kind::Bool => match (operator, value.to_lowercase().as_str()) {
+            (":" | "=", "true" | "yes" | "1") => Test::Bool(true),
+            (":" | "=", "false" | "no" | "0") => Test::Bool(false),
+            _ => return Err(value_error(format!("{} is either true or false", name))),
+        },
+        Kind::Number | Kind::Date if operator == ":" && value.contains("..") => {
+            let (low, high) = value.split_once("..").unwrap_or_default();
+            let low = (!low.is_empty())
+                .then(|| parse_number(field, low))
+                .transpose()
+                .map_err(value_error)?;
+            let high = (!high.is_empty())
+                .then(|| parse_number(field, high))
+                .transpose()
+                .map_err(value_error)?;
+            if low.is_none() && high.is_none() {
+                return Err(value_error("Missing both ends of the range".into()));
+            }
+            // Ranges of ages go from the most recent, eg. 7d..30d is from 30 to 7 days ago
+            let ages = [&low, &high]
+                .iter()
+                .all(|end| matches!(end, None | Some(Number::Ago(..))));
+            if ages {
+                between(high, low)
+            } else {
+                between(low, high)
+            }
+        }
+        Kind::Number | Kind::Date => {
+            let number = parse_number(field, &value).map_err(value_error)?;
+            // Further back is smaller, so ages compare the other way round
+            let is_age = matches!(number, Number::Ago(..));
+            let (is_min, inclusive) = match operator {
+                ">" => (!is_age, false),
+                ">=" => (!is_age, true),
+                "<" => (is_age, false),
+                "<=" => (is_age, true),
+                // Within that long
+                _ if is_age => (true, true),
+                _ => {
+                    return Ok(Expr::Test(
+                        field,
+                        between(Some(number.clone()), Some(number)),
+                    ))
+                }
+            };
+            let bound = Some(Bound {
+                value: number,
+                inclusive,
+            });
+            if is_min {
+                Test::Range {
+                    min: bound,
+                    max: None,
+                }
+            } else {
+                Test::Range {
+                    min: None,
+                    max: bound,
+                }
+            }
+        }
+    };
+    Ok(Expr::Test(field, test))
+}
+
+/// Both ends included
+fn between(min: Option<Number>, max: Option<Number>) -> Test {
+    let bound = |value| Bound {
+        value,
+        inclusive: true,
+    };
+    Test::Range {
+        min: min.map(bound),
+        max: max.map(bound),
+    }
+}
+
+fn parse_number(field: Field, value: &str) -> Result<Number, String> {
+    if field.kind() == Kind::Date {
+        return parse_age(value)
+            .or_else(|| parse_date(value))
+            .ok_or_else(|| {
+                format!(
+                    "{} isn't a date (eg. 2024-05-01) or an age (eg. 30d)",
+                    value
+                )
+            });
+    }
+    // Durations can be written as minutes:seconds
+    let number = match value.split_once(':') {
+        Some((minutes, seconds)) if field == Field::Duration => minutes
+            .parse::<f64>()
+            .ok()
+            .zip(seconds.parse::<f64>().ok())
+            .map(|(minutes, seconds)| minutes * 60.0 + seconds),
+        _ => value.parse::<f64>().ok(),
+    };
+    number
+        .filter(|number| number.is_finite())
+        .map(Number::Plain)
+        .ok_or_else(|| format!("{} isn't a number", value))
+}
+
+/// eg. 12h, 30d, 2w, 6m, 1y
+fn parse_age(value: &str) -> Option<Number> {
+    let unit = match value.chars().last()? {
+        'h' => HOUR,
+        'd' => DAY,
+        'w' => 7 * DAY,
+        'm' => 30 * DAY,
+        'y' => 365 * DAY,
+        _ => return None,
+    };
+    let amount: f64 = value[..value.len() - 1].parse().ok()?;
+    (amount.is_finite() && amount >= 0.0)
+        .then(|| Number::Ago((amount * unit as f64) as i64, value.to_string()))
+}
+
+/// eg. 2024-05-01, in UTC
+fn parse_date(value: &str) -> Option<Number> {
+    let mut parts = value.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (parts.next()??, parts.next()??, parts.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    Some(Number::Date(
+        days_from_civil(year, month, day) * DAY,
+        value.to_string(),
+    ))
+}
+
+/// Days since 1970-01-01 in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+impl Expr {
+    /**
+     * Checks a song from the source against the query, with its play counts
+     * when it has any.
+     */
+    pub fn matches(
+        &self,
+        song: &Song,
+        stats: Option<&PlayStats>,
+        source: QuerySource,
+        now: i64,
+    ) -> bool {
+        match self {
+            Expr::All(terms) => terms
+                .iter()
+                .all(|term| term.matches(song, stats, source, now)),
+            Expr::Either(terms) => terms
+                .iter()
+                .any(|term| term.matches(song, stats, source, now)),
+            Expr::Not(term) => !term.matches(song, stats, source, now),
+            Expr::Test(field, test) => match test {
+                Test::Contains(value) => {
+                    let value = value.to_lowercase();
+                    text_values(*field, song)
+                        .iter()
+                        .any(|text| text.to_lowercase().contains(&value))
+                }
+                Test::Is(value) => text_values(*field, song)
+                    .iter()
+                    .any(|text| text.to_lowercase() == value.to_lowercase()),
+                Test::Bool(value) => song.file_info.lossless == *value,
+                Test::Range { min, max } => {
+                    let Some(number) = number_value(*field, song, stats, source) else {
+                        return false;
+                    };
+                    let above = min
+                        .as_ref()
+                        .is_none_or(|min| match min.resolve(now, false) {
+                            (min, true) => number >= min,
+                            (min, false) => number > min,
+                        });
+                    let below = max.as_ref().is_none_or(|max| match max.resolve(now, true) {
+                        (max, true) => number <= max,
+                        (max, false) => number < max,
+                    });
+                    above && below
+                }
+            },
+        }
+    }
+
+    /**
+     * The WHERE clause for the source's database, None when a field isn't in it.
+     */
+    pub fn to_sql(&self, source: QuerySource, now: i64) -> Option<SqlFilter> {
+        let mut values = vec![];
+        let sql = compile(self, source, now, &mut values)?;
+        Some(SqlFilter { sql, values })
+    }
+
+    /// What can run as SQL, and what's left to check song by song
+    fn split(&self, source: QuerySource, now: i64) -> (Option<SqlFilter>, Option<Expr>) {
+        if let Some(filter) = self.to_sql(source, now) {
+            return (Some(filter), None);
+        }
+        let Expr::All(terms) = self else {
+            return (None, Some(self.clone()));
+        };
+        let (compiled, rest): (Vec<Expr>, Vec<Expr>) = terms
+            .iter()
+            .cloned()
+            .partition(|term| term.to_sql(source, now).is_some());
+        let filter = (!compiled.is_empty())
+            .then(|| Expr::All(compiled).to_sql(source, now))
+            .flatten();
+        (filter, Some(Expr::All(rest)))
+    }
+}
+
+fn text_values(field: Field, song: &Song) -> Vec<&str> {
+    match field {
+        Field::Any => vec![&song.title, &song.artist, &song.album],
+        Field::Title => vec![&song.title],
+        Field::Artist => vec![&song.artist],
+        Field::AlbumArtist => song.album_artist.iter().map(String::as_str).collect(),
+        Field::Album => vec![&song.album],
+        Field::Genre => song.genre.iter().map(String::as_str).collect(),
+        Field::Composer => song.composer.iter().map(String::as_str).collect(),
+        Field::Path => vec![&song.path],
+        Field::Codec => song.file_info.codec.iter().map(String::as_str).collect(),
+        Field::Key => song.key.iter().map(String::as_str).collect(),
+        _ => vec![],
+    }
+}
+
+fn number_value(
+    field: Field,
+    song: &Song,
+    stats: Option<&PlayStats>,
+    source: QuerySource,
+) -> Option<f64> {
+    match field {
+        // 0 is unknown
+        Field::Year => Some(song.year as f64).filter(|year| *year > 0.0),
+        Field::Track => Some(song.track_number as f64),
+        Field::Disc => Some(song.disc_number as f64),
+        Field::Duration => song.file_info.duration,
+        Field::Bpm => song.bpm,
+        Field::Bitrate => song.file_info.overall_bitrate.map(|bitrate| match source {
+            // beets has bits per second, rounded down to kbps like in its SQL
+            QuerySource::Beets => f64::from(bitrate / 1000),
+            QuerySource::Library => f64::from(bitrate),
+        }),
+        Field::SampleRate => song.file_info.sample_rate.map(f64::from),
+        Field::BitDepth => song.file_info.bit_depth.map(f64::from),
+        Field::Plays => Some(stats.map(|s| s.play_count).unwrap_or(0) as f64),
+        Field::Skips => Some(stats.map(|s| s.skip_count).unwrap_or(0) as f64),
+        Field::Added => song.date_added.map(|date| date as f64),
+        Field::Played => stats.and_then(|s| s.last_played_at).map(|at| at as f64),
+        _ => None,
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum QuerySource {
+    /// The native library database
+    Library,
+    Beets,
+}
+
+enum Column {
+    Scalar(&'static str),
+    /// A JSON array in the song data
+    List(&'static str),
+}
+
+impl QuerySource {
+    fn column(self, field: Field) -> Option<Column> {
+        use Column::*;
+        match self {
+            QuerySource::Library => Some(match field {
+                // Expanded to the title, artist and album
+                Field::Any => return None,
+                Field::Title => Scalar("s.title"),
+                Field::Artist => Scalar("s.artist"),
+                Field::AlbumArtist => Scalar("s.album_artist"),
+                Field::Album => Scalar("s.album"),
+                Field::Genre => List("$.genre"),
+                Field::Composer => List("$.composer"),
+                Field::Path => Scalar("s.path"),
+                Field::Codec => Scalar("json_extract(s.data, '$.fileInfo.codec')"),
+                Field::Key => Scalar("s.key"),
+                Field::Year => Scalar("NULLIF(s.year, 0)"),
+                Field::Track => Scalar("s.track_number"),
+                Field::Disc => Scalar("s.disc_number"),
+                Field::Duration => Scalar("s.duration"),
+                Field::Bpm => Scalar("s.bpm"),
+                Field::Bitrate => Scalar("json_extract(s.data, '$.fileInfo.overallBitrate')"),
+                Field::SampleRate => Scalar("json_extract(s.data, '$.fileInfo.sampleRate')"),
+                Field::BitDepth => Scalar("json_extract(s.data, '$.fileInfo.bitDepth')"),
+                Field::Plays => Scalar("COALESCE(h.play_count, 0)"),
+                Field::Skips => Scalar("COALESCE(h.skip_count, 0)"),
+                Field::Added => Scalar("s.date_added"),
+                Field::Played => Scalar("h.last_played_at"),
+                Field::Lossless => Scalar("json_extract(s.data, '$.fileInfo.lossless')"),
+            }),
+            QuerySource::Beets => Some(match field {
+                Field::Title => Scalar("title"),
+                Field::Artist => Scalar("artist"),
+                Field::AlbumArtist => Scalar("albumartist"),
+                Field::Album => Scalar("album"),
+                Field::Genre => Scalar("genre"),
+                Field::Composer => Scalar("composer"),
+                Field::Path => Scalar("CAST(path AS TEXT)"),
+                Field::Codec => Scalar("format"),
+                Field::Key => Scalar("NULLIF(initial_key, '')"),
+                Field::Year => Scalar("NULLIF(year, 0)"),
+                Field::Track => Scalar("track"),
+                Field::Disc => Scalar("disc"),
+                Field::Duration => Scalar("length"),
+                Field::Bpm => Scalar("NULLIF(bpm, 0)"),
+                Field::Bitrate => Scalar("bitrate / 1000"),
+                Field::SampleRate => Scalar("samplerate"),
+                Field::BitDepth => Scalar("bitdepth"),
+                Field::Added => Scalar("added * 1000"),
+                Field::Lossless => Scalar("LOWER(format) IN ('flac', 'alac', 'wav', 'aiff')"),
+                // Play counts are only in our history
+                Field::Plays | Field::Skips | Field::Played => return None,
+                Field::Any => return None,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SqlFilter {
+    /// With `?` for the values
+    pub sql: String,
+    pub values: Vec<Value>,
+}
+
+fn compile(expr: &Expr, source: QuerySource, now: i64, values: &mut Vec<Value>) -> Option<String> {
+    match expr {
+        Expr::All(terms) => compile_terms(terms, " AND ", "1", source, now, values),
+        Expr::Either(terms) => compile_terms(terms, " OR ", "0", source, now, values),
+        // A missing value fails the test, so it's kept when the test is
+        // excluded, as with `Expr::matches`
+        Expr::Not(term) => Some(format!(
+            "NOT COALESCE({}, 0)",
+            compile(term, source, now, values)?
+        )),
+        Expr::Test(Field::Any, test) => {
+            let any: Vec<Expr> = [Field::Title, Field::Artist, Field::Album]
+                .into_iter()
+                .map(|field| Expr::Test(field, test.clone()))
+                .collect();
+            compile_terms(&any, " OR ", "0", source, now, values)
+        }
+        Expr::Test(field, test) => Some(compile_test(source.column(*field)?, test, now, values)),
+    }
+}
+
+fn compile_terms(
+    terms: &[Expr],
+    separator: &str,
+    empty: &str,
+    source: QuerySource,
+    now: i64,
+    values: &mut Vec<Value>,
+) -> Option<String> {
+    if terms.is_empty() {
+        return Some(empty.to_string());
+    }
+    let terms = terms
+        .iter()
+        .map(|term| compile(term, source, now, values))
+        .collect::<Option<Vec<_>>>()?;
+    Some(format!("({})", terms.join(separator)))
+}
+
+fn compile_test(column: Column, test: &Test, now: i64, values: &mut Vec<Value>) -> String {
+    let compare = |operator: &str| match column {
+        Column::Scalar(column) => format!("{} {}", column, operator),
+        Column::List(path) => format!(
+            "EXISTS (SELECT 1 FROM json_each(s.data, '{}') WHERE value {})",
+            path, operator
+        ),
+    };
+    match test {
+        Test::Contains(value) => {
+            values.push(like_pattern(value).into());
+            compare("LIKE ? ESCAPE '\\'")
+        }
+        Test::Is(value) => {
+            values.push(value.clone().into());
+            compare("= ? COLLATE NOCASE")
+        }
+        Test::Bool(value) => {
+            values.push((*value as i64).into());
+            compare("= ?")
+        }
+        Test::Range { min, max } => {
+            let mut sides = vec![];
+            if let Some(min) = min {
+                let (value, inclusive) = min.resolve(now, false);
+                values.push(value.into());
+                sides.push(compare(if inclusive { ">= ?" } else { "> ?" }));
+            }
+            if let Some(max) = max {
+                let (value, inclusive) = max.resolve(now, true);
+                values.push(value.into());
+                sides.push(compare(if inclusive { "<= ?" } else { "< ?" }));
+            }
+            format!("({})", sides.join(" AND "))
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let grouped = |term: &Expr| match term {
+            Expr::All(terms) | Expr::Either(terms) if terms.len() > 1 => format!("({})", term),
+            term => term.to_string(),
+        };
+        match self {
+            Expr::All(terms) if terms.is_empty() => write!(f, "everything"),
+            Expr::All(terms) => {
+                let terms: Vec<String> = terms.iter().map(grouped).collect();
+                write!(f, "{}", terms.join(" and "))
+            }
+            Expr::Either(terms) => {
+                let terms: Vec<String> = terms.iter().map(grouped).collect();
+                write!(f, "{}", terms.join(" or "))
+            }
+            Expr::Not(term) => write!(f, "not {}", grouped(term)),
+            Expr::Test(field, test) => describe_test(f, *field, test),
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Number::Plain(value) => write!(f, "{}", value),
+            Number::Date(_, text) => write!(f, "{}", text),
+            Number::Ago(_, text) => write!(f, "{} ago", text),
+        }
+    }
+}
+
+fn describe_test(f: &mut fmt::Formatter<'_>, field: Field, test: &Test) -> fmt::Result {
+    let name = field.name();
+    match test {
+        Test::Contains(value) => write!(f, "{} contains {:?}", name, value),
+        Test::Is(value) => write!(f, "{} is {:?}", name, value),
+        Test::Bool(value) => write!(f, "{} is {}", name, value),
+        Test::Range {
+            min: Some(min),
+            max: Some(max),
+        } if min == max => {
+            let on = if field.kind() == Kind::Date {
+                "on"
+            } else {
+                "is"
+            };
+            write!(f, "{} {} {}", name, on, min.value)
+        }
+        Test::Range {
+            min: Some(min),
+            max: Some(max),
+        } if min.inclusive && max.inclusive => {
+            write!(f, "{} from {} to {}", name, min.value, max.value)
+        }
+        Test::Range { min, max } => {
+            let is_date = field.kind() == Kind::Date;
+            let mut sides = vec![];
+            if let Some(min) = min {
+                let words = match (is_date, min.inclusive) {
+                    (true, true) => "on or after",
+                    (true, false) => "after",
+                    (false, true) => "at least",
+                    (false, false) => "more than",
+                };
+                sides.push(format!("{} {} {}", name, words, min.value));
+            }
+            if let Some(max) = max {
+                let words = match (is_date, max.inclusive) {
+                    (true, true) => "on or before",
+                    (true, false) => "before",
+                    (false, true) => "at most",
+                    (false, false) => "less than",
+                };
+                sides.push(format!("{} {} {}", name, words, max.value));
+            }
+            write!(f, "{}", sides.join(" and "))
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryExplanation {
+    /// The query in words
+    pub description: String,
+    /// The WHERE clause run on the database, if any of it can be
+    pub sql: Option<String>,
+    pub parameters: Vec<serde_json::Value>,
+    /// What's checked song by song, for fields the database doesn't have
+    pub evaluated: Option<String>,
+}
+
+/**
+ * Checks a query, returning where it's wrong if it is.
+ */
+#[tauri::command]
+pub async fn validate_smart_query(query: String) -> Result<(), QueryError> {
+    parse(&query).map(|_| ())
+}
+
+#[tauri::command]
+pub async fn explain_smart_query(
+    query: String,
+    source: QuerySource,
+) -> Result<QueryExplanation, QueryError> {
+    let expr = parse(&query)?;
+    let (filter, rest) = expr.split(source, now_millis());
+    let parameters = filter
+        .as_ref()
+        .map(|filter| filter.values.iter().map(json_value).collect())
+        .unwrap_or_default();
+    Ok(QueryExplanation {
+        description: expr.to_string(),
+        sql: filter.map(|filter| filter.sql),
+        parameters,
+        evaluated: rest.map(|rest| rest.to_string()),
+    })
+}
+
+fn json_value(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Null => serde_json::Value::Null,
+        Value::Integer(value) => (*value).into(),
+        Value::Real(value) => (*value).into(),
+        Value::Text(value) => value.clone().into(),
+        Value::Blob(value) => value.len().into(),
+    }
+}
+
+/**
+ * Runs a query on the library or beets, sorted and paged with the options
+ * (only sortBy, descending, offset and limit apply).
+ */
+#[tauri::command]
+pub async fn execute_smart_query(
+    query: String,
+    source: QuerySource,
+    options: Option<LibraryQuery>,
+    app_handle: AppHandle,
+) -> Result<Page<Song>, String> {
+    info!("[Smart query] Running {} on {:?}", query, source);
+    let expr = parse(&query).map_err(|e| e.to_string())?;
+    let options = LibraryQuery {
+        search: None,
+        artist: None,
+        album_id: None,
+        ..options.unwrap_or_default()
+    };
+    let now = now_millis();
+    let (filter, rest) = expr.split(source, now);
+
+    match source {
+        QuerySource::Library => {
+            let filter = filter
+                .filter(|_| rest.is_none())
+                .ok_or("Query can't run on the library")?;
+            open_library_read_only(&app_handle)?.songs(&options, Some(&filter))
+        }
+        QuerySource::Beets => {
+            let db_path = get_beets_db_path(&app_handle)
+                .ok_or_else(|| "Beets database not found".to_string())?;
+            let mut songs = query_beets_filtered(
+                &db_path,
+                filter.as_ref(),
+                options.sort_by.as_deref().unwrap_or("artist"),
+                options.descending,
+            )
+            .map_err(|e| e.to_string())?;
+            if let Some(rest) = rest {
+                // Songs played from beets are in our history under their beets id
+                let stats: HashMap<String, PlayStats> = open_library_read_only(&app_handle)
+                    .and_then(|library| library.play_stats().map_err(|e| e.to_string()))
+                    .unwrap_or_default();
+                songs.retain(|song| rest.matches(song, stats.get(&song.id), source, now));
+            }
+            Ok(page_of(songs, &options))
+        }
+    }
+}
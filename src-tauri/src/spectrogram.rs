@@ -0,0 +1,573 @@
+//! Whole-file spectrograms, to inspect masters and spot lossy sources
+
+use chksum_md5::MD5;
+use filetime::FileTime;
+use log::{info, warn};
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio_util::sync::CancellationToken;
+use zune_png::zune_core::bit_depth::BitDepth;
+use zune_png::zune_core::colorspace::ColorSpace;
+use zune_png::zune_core::options::EncoderOptions;
+use zune_png::PngEncoder;
+
+use crate::decode::FileDecoder;
+use crate::output::{AnalyzerScale, AnalyzerWindow};
+use crate::player::AudioPlayer;
+use crate::waveform;
+
+const DEFAULT_WIDTH: usize = 1024;
+const DEFAULT_HEIGHT: usize = 512;
+const DEFAULT_FFT_SIZE: usize = 4096;
+const DEFAULT_MIN_DB: f32 = -120.0;
+const DEFAULT_MAX_DB: f32 = 0.0;
+/// Bottom of the log and mel scales, linear starts at 0 Hz
+const MIN_FREQUENCY: f32 = 20.0;
+const CACHE_DIR: &str = "spectrograms";
+/// Our entries in the player's cancel tokens, which are otherwise keyed by path
+pub const CANCEL_TOKEN_PREFIX: &str = "spectrogram:";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SpectrogramFormat {
+    /// Colour mapped image
+    Png,
+    /// One byte per pixel from 0 (min_db) to 255 (max_db)
+    Raw,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SpectrogramRequest {
+    pub path: String,
+    /// Columns, spread over the whole file
+    pub width: Option<usize>,
+    /// Rows, from the lowest frequency at the bottom to Nyquist at the top
+    pub height: Option<usize>,
+    /// Power of two, 256 to 32768
+    pub fft_size: Option<usize>,
+    pub scale: Option<AnalyzerScale>,
+    pub window: Option<AnalyzerWindow>,
+    /// Level shown as the coldest colour
+    pub min_db: Option<f32>,
+    /// Level shown as the hottest colour
+    pub max_db: Option<f32>,
+    pub format: Option<SpectrogramFormat>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SpectrogramInfo {
+    pub width: usize,
+    pub height: usize,
+    pub sample_rate: u32,
+    /// Seconds
+    pub duration: f64,
+    /// Frequency of the bottom row
+    pub min_frequency: f32,
+    /// Frequency of the top row
+    pub max_frequency: f32,
+    pub scale: AnalyzerScale,
+    pub min_db: f32,
+    pub max_db: f32,
+    pub format: SpectrogramFormat,
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Spectrogram {
+    #[serde(flatten)]
+    pub info: SpectrogramInfo,
+    /// The PNG file, or the raw levels with the top row first
+    pub data: ByteBuf,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SpectrogramProgress {
+    path: String,
+    /// 0 to 1
+    progress: f32,
+}
+
+/// The request with defaults filled in and values kept in range
+#[derive(Debug)]
+struct Options {
+    width: usize,
+    height: usize,
+    fft_size: usize,
+    scale: AnalyzerScale,
+    window: AnalyzerWindow,
+    min_db: f32,
+    max_db: f32,
+    format: SpectrogramFormat,
+}
+
+impl From<&SpectrogramRequest> for Options {
+    fn from(request: &SpectrogramRequest) -> Self {
+        let min_db = request.min_db.unwrap_or(DEFAULT_MIN_DB);
+        let max_db = request.max_db.unwrap_or(DEFAULT_MAX_DB);
+        Options {
+            width: request.width.unwrap_or(DEFAULT_WIDTH).clamp(16, 8192),
+            height: request.height.unwrap_or(DEFAULT_HEIGHT).clamp(16, 4096),
+            fft_size: request
+                .fft_size
+                .unwrap_or(DEFAULT_FFT_SIZE)
+                .clamp(256, 32768)
+                .next_power_of_two(),
+            scale: request.scale.unwrap_or(AnalyzerScale::Linear),
+            window: request.window.unwrap_or(AnalyzerWindow::Hann),
+            min_db,
+            max_db: if max_db > min_db {
+                max_db
+            } else {
+                min_db + 1.0
+            },
+            format: request.format.unwrap_or(SpectrogramFormat::Png),
+        }
+    }
+}
+
+/**
+ * Generates the spectrogram of a whole file, or returns it from the cache.
+ * Cancelled by a new request for the same file or with cancel_spectrogram.
+ * Emits "spectrogram_progress" while decoding.
+ */
+#[tauri::command]
+pub async fn get_spectrogram(
+    request: SpectrogramRequest,
+    state: State<'_, AudioPlayer>,
+    app_handle: AppHandle,
+) -> Result<Spectrogram, String> {
+    info!("[Spectrogram] {:?}", request);
+    let key = format!("{}{}", CANCEL_TOKEN_PREFIX, request.path);
+    let token = CancellationToken::new();
+    if let Some(previous) = state
+        .cancel_tokens
+        .lock()
+        .await
+        .insert(key.clone(), token.clone())
+    {
+        previous.cancel();
+    }
+
+    let thread_token = token.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        get_or_generate(&request, &app_handle, &thread_token)
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    // A cancelled token was already removed or replaced
+    if !token.is_cancelled() {
+        state.cancel_tokens.lock().await.remove(&key);
+    }
+    result
+}
+
+#[tauri::command]
+pub async fn cancel_spectrogram(path: String, state: State<'_, AudioPlayer>) -> Result<(), String> {
+    let key = format!("{}{}", CANCEL_TOKEN_PREFIX, path);
+    if let Some(token) = state.cancel_tokens.lock().await.remove(&key) {
+        token.cancel();
+    }
+    Ok(())
+}
+
+fn get_or_generate(
+    request: &SpectrogramRequest,
+    app_handle: &AppHandle,
+    cancel_token: &CancellationToken,
+) -> Result<Spectrogram, String> {
+    let options = Options::from(request);
+    let path = Path::new(&request.path);
+    let cache_path = cache_path(path, &options, app_handle);
+
+    if let Some(spectrogram) = cache_path.as_deref().and_then(read_cache) {
+        info!("[Spectrogram] From cache: {:?}", path);
+        return Ok(spectrogram);
+    }
+
+    let mut decoder = FileDecoder::open(path).map_err(|e| e.to_string())?;
+    let expected_frames = decoder.n_frames;
+    let mut analysis = Analysis::new(&options, decoder.sample_rate, expected_frames);
+    let mut decoded_frames = 0u64;
+    let mut reported = 0.0;
+    decoder
+        .for_each(cancel_token, |samples, channels| {
+            analysis.push(samples, channels);
+            decoded_frames += (samples.len() / channels.max(1)) as u64;
+            if let Some(total) = expected_frames.filter(|total| *total > 0) {
+                let progress = (decoded_frames as f32 / total as f32).min(1.0);
+                if progress - reported >= 0.01 {
+                    reported = progress;
+                    let _ = app_handle.emit(
+                        "spectrogram_progress",
+                        SpectrogramProgress {
+                            path: request.path.clone(),
+                            progress,
+                        },
+                    );
+                }
+            }
+        })
+        .map_err(|e| e.to_string())?;
+
+    let levels = analysis.finish();
+    let info = SpectrogramInfo {
+        width: options.width,
+        height: options.height,
+        sample_rate: decoder.sample_rate,
+        duration: decoded_frames as f64 / decoder.sample_rate.max(1) as f64,
+        min_frequency: analysis.min_frequency,
+        max_frequency: analysis.max_frequency,
+        scale: options.scale,
+        min_db: options.min_db,
+        max_db: options.max_db,
+        format: options.format,
+    };
+    let data = match options.format {
+        SpectrogramFormat::Png => encode_png(options.width, options.height, &levels),
+        SpectrogramFormat::Raw => levels,
+    };
+    let spectrogram = Spectrogram {
+        info,
+        data: ByteBuf::from(data),
+    };
+
+    if let Some(cache_path) = cache_path {
+        match write_cache(&cache_path, &spectrogram) {
+            // Shares the limit of the waveform cache
+            Ok(_) => waveform::prune_cache(app_handle),
+            Err(err) => warn!("[Spectrogram] Error writing cache: {}", err),
+        }
+    }
+    Ok(spectrogram)
+}
+
+/**
+ * Cache file for this file and these options, without extension.
+ * Changes to the file give a new key, so stale entries are never read.
+ */
+fn cache_path(path: &Path, options: &Options, app_handle: &AppHandle) -> Option<PathBuf> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let key = format!(
+        "{}|{}|{}|{:?}",
+        path.to_string_lossy(),
+        metadata.len(),
+        modified,
+        options
+    );
+    Some(cache_dir(app_handle)?.join(MD5::hash(key.as_bytes()).to_hex_lowercase()))
+}
+
+fn cache_dir(app_handle: &AppHandle) -> Option<PathBuf> {
+    Some(app_handle.path().app_cache_dir().ok()?.join(CACHE_DIR))
+}
+
+/**
+ * Image, size of the image and its description, and last use of every
+ * cached spectrogram, for the waveform cache which prunes both.
+ */
+pub fn cache_files(app_handle: &AppHandle) -> Vec<(PathBuf, u64, FileTime)> {
+    let Some(entries) = cache_dir(app_handle).and_then(|dir| std::fs::read_dir(dir).ok()) else {
+        return vec![];
+    };
+    entries
+        .flatten()
+        .filter(|entry| {
+            matches!(
+                entry.path().extension().and_then(|e| e.to_str()),
+                Some("png" | "raw")
+            )
+        })
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            let description = std::fs::metadata(entry.path().with_extension("json"))
+                .map(|metadata| metadata.len())
+                .unwrap_or_default();
+            Some((
+                entry.path(),
+                metadata.len() + description,
+                FileTime::from_last_modification_time(&metadata),
+            ))
+        })
+        .collect()
+}
+
+/// Deletes a spectrogram from the cache, the description first so it is never read half deleted
+pub fn remove_cached(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path.with_extension("json")) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    std::fs::remove_file(path)
+}
+
+fn read_cache(cache_path: &Path) -> Option<Spectrogram> {
+    let info: SpectrogramInfo =
+        serde_json::from_slice(&std::fs::read(cache_path.with_extension("json")).ok()?).ok()?;
+    let data_path = cache_path.with_extension(extension(info.format));
+    let data = std::fs::read(&data_path).ok()?;
+    // The least recently used files are pruned first
+    let _ = filetime::set_file_mtime(&data_path, FileTime::now());
+    Some(Spectrogram {
+        info,
+        data: ByteBuf::from(data),
+    })
+}
+
+fn write_cache(cache_path: &Path, spectrogram: &Spectrogram) -> std::io::Result<()> {
+    if let Some(dir) = cache_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(
+        cache_path.with_extension(extension(spectrogram.info.format)),
+        &spectrogram.data,
+    )?;
+    // Written last, an entry without it is incomplete
+    std::fs::write(
+        cache_path.with_extension("json"),
+        serde_json::to_vec(&spectrogram.info)?,
+    )
+}
+
+fn extension(format: SpectrogramFormat) -> &'static str {
+    match format {
+        SpectrogramFormat::Png => "png",
+        SpectrogramFormat::Raw => "raw",
+    }
+}
+
+/**
+ * Short-time Fourier transform of the mono mix, averaged into columns.
+ * When the length of the file is unknown, columns are merged in pairs
+ * whenever there are too many.
+ */
+struct Analysis {
+    fft: Arc<dyn Fft<f32>>,
+    fft_size: usize,
+    hop: usize,
+    window: Vec<f32>,
+    /// Power of a full scale sine, which is 0 dB
+    reference: f32,
+    /// FFT bins of each row, bottom row first
+    rows: Vec<Range<usize>>,
+    width: usize,
+    min_db: f32,
+    max_db: f32,
+    min_frequency: f32,
+    max_frequency: f32,
+    /// Mono samples waiting for a full FFT
+    pending: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    frames_per_column: usize,
+    column: Vec<f32>,
+    column_frames: usize,
+    /// Mean power per row
+    columns: Vec<Vec<f32>>,
+}
+
+impl Analysis {
+    fn new(options: &Options, sample_rate: u32, expected_frames: Option<u64>) -> Self {
+        let fft_size = options.fft_size;
+        let hop = fft_size / 4;
+        let window: Vec<f32> = (0..fft_size)
+            .map(|i| options.window.coefficient(i, fft_size))
+            .collect();
+        let reference = (window.iter().sum::<f32>() / 2.0).powi(2);
+
+        let max_frequency = sample_rate as f32 / 2.0;
+        let min_frequency = match options.scale {
+            AnalyzerScale::Linear => 0.0,
+            _ => MIN_FREQUENCY,
+        };
+        let bin_width = sample_rate as f32 / fft_size as f32;
+        let bins = fft_size / 2 + 1;
+        let scale_min = options.scale.to_scale(min_frequency);
+        let scale_max = options.scale.to_scale(max_frequency);
+        let rows = (0..options.height)
+            .map(|row| {
+                let frequency = |row: usize| {
+                    options.scale.from_scale(
+                        scale_min + (scale_max - scale_min) * row as f32 / options.height as f32,
+                    )
+                };
+                let start = ((frequency(row) / bin_width).round() as usize).min(bins - 1);
+                let end =
+                    ((frequency(row + 1) / bin_width).round() as usize).clamp(start + 1, bins);
+                start..end
+            })
+            .collect();
+
+        let frames_per_column = expected_frames
+            .map(|frames| frames as usize / hop / options.width)
+            .unwrap_or(1)
+            .max(1);
+
+        Analysis {
+            fft: FftPlanner::new().plan_fft_forward(fft_size),
+            fft_size,
+            hop,
+            window,
+            reference,
+            rows,
+            width: options.width,
+            min_db: options.min_db,
+            max_db: options.max_db,
+            min_frequency,
+            max_frequency,
+            pending: Vec::with_capacity(fft_size * 2),
+            buffer: vec![Complex::default(); fft_size],
+            frames_per_column,
+            column: vec![0.0; options.height],
+            column_frames: 0,
+            columns: Vec::with_capacity(options.width * 2),
+        }
+    }
+
+    fn push(&mut self, samples: &[f32], channels: usize) {
+        let channels = channels.max(1);
+        self.pending.extend(
+            samples
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+        let mut offset = 0;
+        while self.pending.len() - offset >= self.fft_size {
+            self.analyse(offset);
+            offset += self.hop;
+        }
+        self.pending.drain(..offset);
+    }
+
+    fn analyse(&mut self, offset: usize) {
+        for (i, value) in self.buffer.iter_mut().enumerate() {
+            *value = Complex {
+                re: self.pending.get(offset + i).copied().unwrap_or(0.0) * self.window[i],
+                im: 0.0,
+            };
+        }
+        self.fft.process(&mut self.buffer);
+
+        for (row, bins) in self.rows.iter().enumerate() {
+            // The loudest bin, so tones and cutoffs stay sharp on wide rows
+            let power = self.buffer[bins.clone()]
+                .iter()
+                .map(|c| c.norm_sqr())
+                .fold(0.0, f32::max);
+            self.column[row] += power;
+        }
+        self.column_frames += 1;
+        if self.column_frames == self.frames_per_column {
+            self.end_column();
+        }
+    }
+
+    fn end_column(&mut self) {
+        let frames = self.column_frames as f32;
+        let column = self.column.iter().map(|power| power / frames).collect();
+        self.columns.push(column);
+        self.column.iter_mut().for_each(|power| *power = 0.0);
+        self.column_frames = 0;
+
+        if self.columns.len() == self.width * 2 {
+            self.columns = self
+                .columns
+                .chunks_exact(2)
+                .map(|pair| {
+                    pair[0]
+                        .iter()
+                        .zip(&pair[1])
+                        .map(|(a, b)| (a + b) / 2.0)
+                        .collect()
+                })
+                .collect();
+            self.frames_per_column *= 2;
+        }
+    }
+
+    /// Levels of every pixel, top row first
+    fn finish(&mut self) -> Vec<u8> {
+        // Files shorter than one FFT are zero padded
+        if self.columns.is_empty() && self.column_frames == 0 {
+            self.analyse(0);
+        }
+        if self.column_frames > 0 {
+            self.end_column();
+        }
+
+        let height = self.column.len();
+        let count = self.columns.len();
+        let mut levels = vec![0u8; self.width * height];
+        for x in 0..self.width {
+            // Average the columns that fall into this pixel, or repeat the nearest one
+            let start = x * count / self.width;
+            let end = ((x + 1) * count / self.width).clamp(start + 1, count);
+            for row in 0..height {
+                let power = self.columns[start..end]
+                    .iter()
+                    .map(|column| column[row])
+                    .sum::<f32>()
+                    / (end - start) as f32;
+                let db = 10.0 * (power / self.reference).max(1e-30).log10();
+                let level = ((db - self.min_db) / (self.max_db - self.min_db)).clamp(0.0, 1.0);
+                levels[(height - 1 - row) * self.width + x] = (level * 255.0).round() as u8;
+            }
+        }
+        levels
+    }
+}
+
+/// Colour map from silence to full scale, like "inferno"
+const COLOR_STOPS: [(f32, [f32; 3]); 5] = [
+    (0.0, [0.0, 0.0, 4.0]),
+    (0.25, [87.0, 16.0, 110.0]),
+    (0.5, [188.0, 55.0, 84.0]),
+    (0.75, [249.0, 142.0, 9.0]),
+    (1.0, [252.0, 255.0, 164.0]),
+];
+
+fn palette() -> Vec<u8> {
+    (0..256)
+        .flat_map(|level| {
+            let t = level as f32 / 255.0;
+            let i = COLOR_STOPS
+                .iter()
+                .rposition(|(stop, _)| *stop <= t)
+                .unwrap_or(0)
+                .min(COLOR_STOPS.len() - 2);
+            let (t0, c0) = COLOR_STOPS[i];
+            let (t1, c1) = COLOR_STOPS[i + 1];
+            let mix = (t - t0) / (t1 - t0);
+            (0..3).map(move |c| (c0[c] + (c1[c] - c0[c]) * mix).round() as u8)
+        })
+        .collect()
+}
+
+/// RGB PNG of the levels through the colour map
+fn encode_png(width: usize, height: usize, levels: &[u8]) -> Vec<u8> {
+    let palette = palette();
+    let pixels: Vec<u8> = levels
+        .iter()
+        .flat_map(|level| {
+            let start = *level as usize * 3;
+            palette[start..start + 3].iter().copied()
+        })
+        .collect();
+    let options = EncoderOptions::new(width, height, ColorSpace::RGB, BitDepth::Eight);
+    PngEncoder::new(&pixels, options).encode()
+}
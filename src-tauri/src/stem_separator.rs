@@ -119,6 +119,22 @@ pub fn get_all_stems(app_handle: tauri::AppHandle) -> Vec<Stem> {
     stems
 }
 
+/// Keeps the stems of a song that was moved or renamed, they're stored under its id
+pub fn move_stems(from: &str, to: &str, app_handle: &tauri::AppHandle) -> std::io::Result<()> {
+    let Some(stems_directory) = load_settings(app_handle)
+        .ok()
+        .and_then(|settings| settings.generated_stems_location)
+    else {
+        return Ok(());
+    };
+    let previous = std::path::Path::new(&stems_directory).join(from);
+    let current = std::path::Path::new(&stems_directory).join(to);
+    if previous.is_dir() && !current.exists() {
+        std::fs::rename(previous, current)?;
+    }
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn separate_stems(
     event: SeparateStemsEvent,
@@ -13,10 +13,47 @@ pub struct UserSettings {
     pub is_artists_toolkit_enabled: bool,
     pub download_location: Option<String>,
     pub generated_stems_location: Option<String>,
+    pub playlists_location: Option<String>,
     pub theme: String,
     pub output_device: Option<String>,
     pub follow_system_output: bool,
     pub beets_db_location: Option<String>,
+    /// Look harder for album artwork in folders when scanning
+    pub is_cover_full_check_enabled: Option<bool>,
+    /// Share of a track (0 to 1) that has to be played for it to count as a play
+    pub play_count_threshold: Option<f64>,
+    pub listenbrainz_token: Option<String>,
+    /// Defaults to https://api.listenbrainz.org, can point to any compatible server
+    pub listenbrainz_url: Option<String>,
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    pub lastfm_session_key: Option<String>,
+    /// Defaults to https://ws.audioscrobbler.com/2.0/
+    pub lastfm_url: Option<String>,
+    /// Folder to write a Rockbox-style .scrobbler.log to
+    pub scrobbler_log_location: Option<String>,
+    /// Serve the MPD protocol so MPD clients can control the player
+    pub mpd_enabled: Option<bool>,
+    /// Defaults to 6600
+    pub mpd_port: Option<u16>,
+    /// Listen on all interfaces instead of just localhost, eg. for MPD apps on a phone
+    pub mpd_allow_lan: Option<bool>,
+    /// Serve the HTTP/WebSocket remote control API
+    pub remote_api_enabled: Option<bool>,
+    /// Defaults to 7373
+    pub remote_api_port: Option<u16>,
+    /// Clients have to send this as a bearer token, the API won't start without one
+    pub remote_api_token: Option<String>,
+    /// Listen on all interfaces instead of just localhost
+    pub remote_api_allow_lan: Option<bool>,
+    /// Serve what's playing over HTTP so other devices can listen along
+    pub broadcast_enabled: Option<bool>,
+    /// Defaults to 8000
+    pub broadcast_port: Option<u16>,
+    /// "wav" (default) or "flac"
+    pub broadcast_format: Option<String>,
+    /// Megabytes of computed waveforms and spectrograms to keep, defaults to 512
+    pub waveform_cache_size: Option<u64>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -0,0 +1,598 @@
+use chksum_md5::MD5;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use symphonia::core::io::MediaSource;
+use tauri::{AppHandle, Emitter};
+
+use crate::metadata::{FileInfo, Song};
+
+/// Bytes to buffer before handing data to the decoder
+const PREBUFFER_BYTES: usize = 64 * 1024;
+/// The fetcher stops reading ahead once this much is buffered
+const MAX_BUFFER_BYTES: usize = 4 * 1024 * 1024;
+const MAX_RECONNECTS: u32 = 10;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const READ_TIMEOUT: Duration = Duration::from_secs(15);
+/// Playlists pointing to playlists
+const MAX_PLAYLIST_DEPTH: u32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamMetadata {
+    pub url: String,
+    /// icy-name header
+    pub station: Option<String>,
+    /// Raw StreamTitle, usually "Artist - Title"
+    pub stream_title: Option<String>,
+    pub artist: Option<String>,
+    pub title: Option<String>,
+}
+
+pub fn is_stream_url(path: &str) -> bool {
+    let lower = path.to_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// A song for a stream URL, until ICY metadata arrives
+pub fn stream_song(url: &str, station: Option<String>) -> Song {
+    Song {
+        id: MD5::hash(url.as_bytes()).to_hex_lowercase(),
+        path: url.to_string(),
+        file: url.to_string(),
+        file_info: FileInfo {
+            duration: None,
+            duration_display: None,
+            overall_bitrate: None,
+            audio_bitrate: None,
+            sample_rate: None,
+            bit_depth: None,
+            channels: None,
+            lossless: false,
+            tag_type: None,
+            codec: None,
+        },
+        dynamics: None,
+        metadata: HashMap::new(),
+        title: station.unwrap_or_else(|| url.to_string()),
+        artist: String::new(),
+        album: String::new(),
+        album_id: None,
+        album_artist: None,
+        compilation: 0,
+        year: 0,
+        genre: vec![],
+        composer: vec![],
+        track_number: -1,
+        track_total: -1,
+        disc_number: -1,
+        disc_total: -1,
+        duration: String::new(),
+        artwork: None,
+        artwork_origin: None,
+        origin_country: None,
+        origin_country_name: None,
+        date_added: None,
+        cue_path: None,
+        cue_track: None,
+        start_offset: None,
+        end_offset: None,
+        chapters: vec![],
+        bpm: None,
+        key: None,
+    }
+}
+
+fn is_playlist(url: &str, content_type: Option<&str>) -> bool {
+    let path = url
+        .split(['?', '#'])
+        .next()
+        .unwrap_or_default()
+        .to_lowercase();
+    matches!(
+        content_type,
+        Some("audio/x-scpls")
+            | Some("audio/x-mpegurl")
+            | Some("audio/mpegurl")
+            | Some("application/pls+xml")
+    ) || path.ends_with(".pls")
+        || path.ends_with(".m3u")
+        || path.ends_with(".m3u8")
+}
+
+/**
+ * Stream URLs from a PLS or M3U playlist, in order.
+ */
+pub fn parse_radio_playlist(text: &str) -> Vec<String> {
+    let text = text.trim_start_matches('\u{feff}');
+    if text.trim_start().to_lowercase().starts_with("[playlist]") {
+        // PLS: File1=http://...
+        let mut entries: Vec<(u32, String)> = text
+            .lines()
+            .filter_map(|line| {
+                let (key, value) = line.trim().split_once('=')?;
+                let number = key
+                    .trim()
+                    .to_lowercase()
+                    .strip_prefix("file")?
+                    .parse()
+                    .ok()?;
+                Some((number, value.trim().to_string()))
+            })
+            .collect();
+        entries.sort_by_key(|(number, _)| *number);
+        entries.into_iter().map(|(_, url)| url).collect()
+    } else {
+        text.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+fn header(response: &reqwest::Response, name: &str) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+}
+
+enum IcyState {
+    Audio(usize),
+    Length,
+    Metadata(usize),
+}
+
+/// Strips the ICY metadata blocks interleaved with the audio every `metaint` bytes
+struct IcyReader {
+    metaint: Option<usize>,
+    state: IcyState,
+    metadata: Vec<u8>,
+}
+
+impl IcyReader {
+    fn new(metaint: Option<usize>) -> Self {
+        IcyReader {
+            metaint,
+            state: IcyState::Audio(metaint.unwrap_or(0)),
+            metadata: Vec::new(),
+        }
+    }
+
+    /// Returns the audio bytes and the latest StreamTitle, if one was in this chunk
+    fn feed(&mut self, data: &[u8]) -> (Vec<u8>, Option<String>) {
+        let Some(metaint) = self.metaint else {
+            return (data.to_vec(), None);
+        };
+        let mut audio = Vec::with_capacity(data.len());
+        let mut stream_title = None;
+        let mut i = 0;
+        while i < data.len() {
+            match self.state {
+                IcyState::Audio(remaining) => {
+                    let n = remaining.min(data.len() - i);
+                    audio.extend_from_slice(&data[i..i + n]);
+                    i += n;
+                    self.state = if n == remaining {
+                        IcyState::Length
+                    } else {
+                        IcyState::Audio(remaining - n)
+                    };
+                }
+                IcyState::Length => {
+                    let len = data[i] as usize * 16;
+                    i += 1;
+                    self.metadata.clear();
+                    self.state = if len == 0 {
+                        IcyState::Audio(metaint)
+                    } else {
+                        IcyState::Metadata(len)
+                    };
+                }
+                IcyState::Metadata(remaining) => {
+                    let n = remaining.min(data.len() - i);
+                    self.metadata.extend_from_slice(&data[i..i + n]);
+                    i += n;
+                    if n == remaining {
+                        stream_title = parse_stream_title(&self.metadata).or(stream_title);
+                        self.state = IcyState::Audio(metaint);
+                    } else {
+                        self.state = IcyState::Metadata(remaining - n);
+                    }
+                }
+            }
+        }
+        (audio, stream_title)
+    }
+}
+
+/// StreamTitle='Artist - Title';StreamUrl='...';
+fn parse_stream_title(metadata: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(metadata);
+    let text = text.trim_end_matches('\0');
+    let start = text.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &text[start..];
+    let end = rest.find("';").unwrap_or(rest.len());
+    Some(rest[..end].trim().to_string())
+}
+
+struct Shared {
+    buffer: VecDeque<u8>,
+    /// Stream offset of the front of the buffer
+    position: u64,
+    /// Bumped on seek, the fetcher restarts the request from `position`
+    generation: u64,
+    /// The server finished sending (or we gave up reconnecting)
+    finished: bool,
+    /// The reader has been dropped
+    closed: bool,
+}
+
+struct StreamState {
+    shared: Mutex<Shared>,
+    cond: Condvar,
+}
+
+/**
+ * A buffering media source for HTTP(S) audio: direct files, and Icecast/Shoutcast streams.
+ * Data is fetched on a background thread which reconnects when the connection drops.
+ */
+pub struct HttpStream {
+    state: Arc<StreamState>,
+    read_pos: u64,
+    content_length: Option<u64>,
+    seekable: bool,
+    prebuffered: bool,
+    pub content_type: Option<String>,
+    pub station: Option<String>,
+}
+
+impl HttpStream {
+    pub fn open(url: &str, app_handle: AppHandle) -> Result<HttpStream, String> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(CONNECT_TIMEOUT)
+            .user_agent("Musicat")
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let mut url = url.to_string();
+        let mut depth = 0;
+        let response = loop {
+            info!("[Stream] Connecting to {}", url);
+            let response =
+                tauri::async_runtime::block_on(client.get(&url).header("Icy-MetaData", "1").send())
+                    .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("HTTP {} for {}", response.status(), url));
+            }
+            let content_type = header(&response, "content-type")
+                .map(|c| c.split(';').next().unwrap_or_default().to_lowercase());
+            if !is_playlist(&url, content_type.as_deref()) {
+                break response;
+            }
+
+            // Radio playlist, play the first stream in it
+            let text =
+                tauri::async_runtime::block_on(response.text()).map_err(|e| e.to_string())?;
+            if text.contains("#EXT-X-") {
+                return Err("HLS streams are not supported".to_string());
+            }
+            depth += 1;
+            url = parse_radio_playlist(&text)
+                .into_iter()
+                .find(|entry| is_stream_url(entry))
+                .filter(|_| depth <= MAX_PLAYLIST_DEPTH)
+                .ok_or("No streams found in playlist")?;
+        };
+
+        let content_type = header(&response, "content-type")
+            .map(|c| c.split(';').next().unwrap_or_default().to_lowercase());
+        let metaint = header(&response, "icy-metaint").and_then(|m| m.parse::<usize>().ok());
+        let station = header(&response, "icy-name").filter(|n| !n.is_empty());
+        // Live streams have no length, and can't be seeked
+        let content_length = response.content_length().filter(|_| metaint.is_none());
+        let seekable = content_length.is_some()
+            && header(&response, "accept-ranges").is_some_and(|r| r.eq_ignore_ascii_case("bytes"));
+        info!(
+            "[Stream] {:?}, length: {:?}, icy-metaint: {:?}, seekable: {}",
+            content_type, content_length, metaint, seekable
+        );
+
+        let state = Arc::new(StreamState {
+            shared: Mutex::new(Shared {
+                buffer: VecDeque::new(),
+                position: 0,
+                generation: 0,
+                finished: false,
+                closed: false,
+            }),
+            cond: Condvar::new(),
+        });
+
+        let metadata = StreamMetadata {
+            url: url.clone(),
+            station: station.clone(),
+            ..Default::default()
+        };
+        let thread_state = state.clone();
+        std::thread::Builder::new()
+            .name("http-stream".into())
+            .spawn(move || {
+                tauri::async_runtime::block_on(fetch(
+                    client,
+                    url,
+                    response,
+                    thread_state,
+                    seekable,
+                    content_length.is_some(),
+                    metadata,
+                    app_handle,
+                ))
+            })
+            .map_err(|e| e.to_string())?;
+
+        Ok(HttpStream {
+            state,
+            read_pos: 0,
+            content_length,
+            seekable,
+            prebuffered: false,
+            content_type,
+            station,
+        })
+    }
+
+    /// Format hint from the Content-Type
+    pub fn extension_hint(&self) -> Option<&'static str> {
+        match self.content_type.as_deref()? {
+            "audio/mpeg" | "audio/mp3" | "audio/mpeg3" => Some("mp3"),
+            "audio/aac" | "audio/aacp" | "audio/x-aac" => Some("aac"),
+            "audio/flac" | "audio/x-flac" => Some("flac"),
+            "audio/ogg" | "application/ogg" | "audio/vorbis" => Some("ogg"),
+            "audio/wav" | "audio/x-wav" | "audio/wave" => Some("wav"),
+            "audio/mp4" | "audio/x-m4a" | "audio/m4a" => Some("m4a"),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Fetches the stream into the shared buffer, reconnecting on errors and restarting
+ * the request (with a Range) when the reader seeks outside the buffer.
+ */
+#[allow(clippy::too_many_arguments)]
+async fn fetch(
+    client: reqwest::Client,
+    url: String,
+    response: reqwest::Response,
+    state: Arc<StreamState>,
+    seekable: bool,
+    is_finite: bool,
+    mut metadata: StreamMetadata,
+    app_handle: AppHandle,
+) {
+    let metaint = |r: &reqwest::Response| header(r, "icy-metaint").and_then(|m| m.parse().ok());
+    let mut icy = IcyReader::new(metaint(&response));
+    let mut response = Some(response);
+    let mut generation = 0;
+    let mut attempts = 0;
+
+    loop {
+        let (closed, current_generation, position, end) = {
+            let shared = state.shared.lock().unwrap();
+            (
+                shared.closed,
+                shared.generation,
+                shared.position,
+                shared.position + shared.buffer.len() as u64,
+            )
+        };
+        if closed {
+            break;
+        }
+
+        if current_generation != generation || response.is_none() {
+            // Seeked: start from the new position, reconnect: carry on where we left off
+            let start = if current_generation != generation {
+                position
+            } else {
+                end
+            };
+            generation = current_generation;
+            if is_finite && !seekable && start > 0 {
+                // Can't resume without range requests
+                error!("[Stream] Connection lost and the server doesn't support ranges");
+                let mut shared = state.shared.lock().unwrap();
+                shared.finished = true;
+                state.cond.notify_all();
+                break;
+            }
+            let mut request = client.get(&url).header("Icy-MetaData", "1");
+            if seekable && start > 0 {
+                request = request.header("Range", format!("bytes={}-", start));
+            }
+            match request.send().await {
+                Ok(r) if r.status().is_success() => {
+                    info!("[Stream] Connected at byte {}", start);
+                    icy = IcyReader::new(metaint(&r));
+                    response = Some(r);
+                }
+                Ok(r) => {
+                    warn!("[Stream] HTTP {}", r.status());
+                    response = None;
+                }
+                Err(err) => {
+                    warn!("[Stream] Error connecting: {}", err);
+                    response = None;
+                }
+            }
+            if response.is_none() {
+                attempts += 1;
+                if attempts > MAX_RECONNECTS {
+                    error!("[Stream] Giving up after {} attempts", MAX_RECONNECTS);
+                    let mut shared = state.shared.lock().unwrap();
+                    shared.finished = true;
+                    state.cond.notify_all();
+                    break;
+                }
+                let _ = app_handle.emit("stream_reconnecting", attempts);
+                tokio::time::sleep(Duration::from_secs(attempts.min(5) as u64)).await;
+                continue;
+            }
+        }
+
+        let r = response.as_mut().unwrap();
+        match tokio::time::timeout(READ_TIMEOUT, r.chunk()).await {
+            Ok(Ok(Some(bytes))) => {
+                attempts = 0;
+                let (audio, stream_title) = icy.feed(&bytes);
+                if let Some(stream_title) = stream_title {
+                    if metadata.stream_title.as_ref() != Some(&stream_title) {
+                        info!("[Stream] Now playing: {}", stream_title);
+                        let (artist, title) = match stream_title.split_once(" - ") {
+                            Some((artist, title)) => (
+                                Some(artist.trim().to_string()),
+                                Some(title.trim().to_string()),
+                            ),
+                            None => (None, Some(stream_title.clone())),
+                        };
+                        metadata.stream_title = Some(stream_title);
+                        metadata.artist = artist;
+                        metadata.title = title;
+                        let _ = app_handle.emit("stream_metadata", metadata.clone());
+                    }
+                }
+
+                let mut shared = state.shared.lock().unwrap();
+                while shared.buffer.len() > MAX_BUFFER_BYTES
+                    && !shared.closed
+                    && shared.generation == generation
+                {
+                    shared = state.cond.wait(shared).unwrap();
+                }
+                if shared.generation == generation {
+                    shared.buffer.extend(audio);
+                    state.cond.notify_all();
+                }
+            }
+            Ok(Ok(None)) if is_finite => {
+                info!("[Stream] Finished downloading");
+                response = None;
+                let mut shared = state.shared.lock().unwrap();
+                shared.finished = true;
+                state.cond.notify_all();
+                // Wait for a seek, or for the reader to go away
+                while !shared.closed && shared.generation == generation {
+                    shared = state.cond.wait(shared).unwrap();
+                }
+            }
+            Ok(Ok(None)) => {
+                warn!("[Stream] Server closed the connection, reconnecting");
+                response = None;
+            }
+            Ok(Err(err)) => {
+                warn!("[Stream] Error reading stream: {}, reconnecting", err);
+                response = None;
+            }
+            Err(_) => {
+                warn!("[Stream] Timed out reading stream, reconnecting");
+                response = None;
+            }
+        }
+    }
+    info!("[Stream] Closed {}", url);
+}
+
+impl Read for HttpStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut shared = self.state.shared.lock().unwrap();
+        let needed = if self.prebuffered { 1 } else { PREBUFFER_BYTES };
+        while shared.buffer.len() < needed && !shared.finished {
+            shared = self.state.cond.wait(shared).unwrap();
+        }
+        self.prebuffered = true;
+
+        let n = buf.len().min(shared.buffer.len());
+        for (dst, src) in buf.iter_mut().zip(shared.buffer.drain(..n)) {
+            *dst = src;
+        }
+        shared.position += n as u64;
+        self.read_pos = shared.position;
+        self.state.cond.notify_all();
+        Ok(n)
+    }
+}
+
+impl Seek for HttpStream {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(delta) => self.read_pos as i64 + delta,
+            SeekFrom::End(delta) => match self.content_length {
+                Some(length) => length as i64 + delta,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        "stream length is unknown",
+                    ))
+                }
+            },
+        };
+        if target < 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek before the start of the stream",
+            ));
+        }
+        let target = target as u64;
+        if target == self.read_pos {
+            return Ok(target);
+        }
+
+        let mut shared = self.state.shared.lock().unwrap();
+        let buffered_end = shared.position + shared.buffer.len() as u64;
+        if target >= shared.position && target <= buffered_end {
+            // Already buffered
+            let skip = (target - shared.position) as usize;
+            shared.buffer.drain(..skip);
+            shared.position = target;
+        } else if self.seekable {
+            shared.buffer.clear();
+            shared.position = target;
+            shared.generation += 1;
+            shared.finished = false;
+            self.prebuffered = false;
+        } else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "stream is not seekable",
+            ));
+        }
+        self.state.cond.notify_all();
+        self.read_pos = target;
+        Ok(target)
+    }
+}
+
+impl MediaSource for HttpStream {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.content_length
+    }
+}
+
+impl Drop for HttpStream {
+    fn drop(&mut self) {
+        let mut shared = self.state.shared.lock().unwrap();
+        shared.closed = true;
+        self.state.cond.notify_all();
+    }
+}
@@ -0,0 +1,500 @@
+//! Tempo (BPM) and musical key detection, for DJ sorting and harmonic mixing
+
+use log::{info, warn};
+use rayon::prelude::*;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tauri::{AppHandle, Emitter, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::decode::FileDecoder;
+use crate::files::expand_audio_paths;
+use crate::library::{save_analysis, SongAnalysis};
+use crate::metadata::write_tempo_key;
+use crate::output::AnalyzerWindow;
+use crate::player::AudioPlayer;
+
+/// The job's entry in the player's cancel tokens
+pub const CANCEL_TOKEN_KEY: &str = "tempo_key";
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+/// Ambiguous tempos (half or double time) lean towards this one
+const PREFERRED_BPM: f64 = 120.0;
+/// Octaves around the preferred tempo where the preference halves
+const TEMPO_SPREAD: f64 = 1.0;
+/// Too short to tell the tempo
+const MIN_SECONDS: f32 = 5.0;
+/// Range of the chroma, bass notes are too close together to tell apart
+const MIN_PITCH_FREQUENCY: f32 = 65.0;
+const MAX_PITCH_FREQUENCY: f32 = 2100.0;
+/// Below this correlation with the key profiles the music is atonal or silent
+const MIN_KEY_CORRELATION: f32 = 0.3;
+
+const KEY_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+/// Krumhansl-Kessler key profiles, from C
+const MAJOR_PROFILE: [f32; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f32; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct TempoKey {
+    pub path: String,
+    /// Rounded to 0.1
+    pub bpm: Option<f64>,
+    /// 0 to 1, how strongly the beat repeats at that tempo
+    pub bpm_confidence: f32,
+    /// eg. "C" or "F#m"
+    pub key: Option<String>,
+    /// The key in Camelot notation eg. "8B", for harmonic mixing
+    pub camelot: Option<String>,
+    /// Correlation with the key profile, up to 1
+    pub key_confidence: f32,
+    pub tags_written: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TempoKeyProgress {
+    total: usize,
+    done: usize,
+    result: TempoKey,
+}
+
+/**
+ * Detects the tempo and key of the given songs, and of every song in the given
+ * folders. Results are saved in the library, and with `write_tags` also in the
+ * files as TBPM/TKEY (ID3), BPM/INITIALKEY (Vorbis comments) or tmpo/initialkey (MP4).
+ * Emits "tempo_key_progress" after each file. A new request cancels the previous one.
+ */
+#[tauri::command]
+pub async fn analyze_tempo_key(
+    paths: Vec<String>,
+    write_tags: Option<bool>,
+    state: State<'_, AudioPlayer>,
+    app_handle: AppHandle,
+) -> Result<Vec<TempoKey>, String> {
+    let token = CancellationToken::new();
+    if let Some(previous) = state
+        .cancel_tokens
+        .lock()
+        .await
+        .insert(CANCEL_TOKEN_KEY.to_string(), token.clone())
+    {
+        previous.cancel();
+    }
+
+    let write_tags = write_tags.unwrap_or(false);
+    let thread_token = token.clone();
+    let results = tauri::async_runtime::spawn_blocking(move || {
+        let files = expand_audio_paths(&paths);
+        info!("[TempoKey] Analysing {} files", files.len());
+        let done = AtomicUsize::new(0);
+        files
+            .par_iter()
+            .filter_map(|path| {
+                if thread_token.is_cancelled() {
+                    return None;
+                }
+                let result = analyze_file(path, write_tags, &thread_token, &app_handle);
+                if thread_token.is_cancelled() {
+                    return None;
+                }
+                let _ = app_handle.emit(
+                    "tempo_key_progress",
+                    TempoKeyProgress {
+                        total: files.len(),
+                        done: done.fetch_add(1, Ordering::Relaxed) + 1,
+                        result: result.clone(),
+                    },
+                );
+                Some(result)
+            })
+            .collect::<Vec<_>>()
+    })
+    .await
+    .map_err(|e| e.to_string())?;
+
+    if token.is_cancelled() {
+        return Err("Cancelled".into());
+    }
+    state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY);
+    Ok(results)
+}
+
+#[tauri::command]
+pub async fn cancel_tempo_key_analysis(state: State<'_, AudioPlayer>) -> Result<(), String> {
+    if let Some(token) = state.cancel_tokens.lock().await.remove(CANCEL_TOKEN_KEY) {
+        token.cancel();
+    }
+    Ok(())
+}
+
+fn analyze_file(
+    path: &Path,
+    write_tags: bool,
+    cancel_token: &CancellationToken,
+    app_handle: &AppHandle,
+) -> TempoKey {
+    let mut result = TempoKey {
+        path: path.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+
+    let analysis = FileDecoder::open(path).and_then(|mut decoder| {
+        let mut analysis = Analysis::new(decoder.sample_rate);
+        decoder.for_each(cancel_token, |samples, channels| {
+            analysis.push(samples, channels)
+        })?;
+        Ok(analysis)
+    });
+    let analysis = match analysis {
+        Ok(analysis) => analysis,
+        Err(err) => {
+            if !cancel_token.is_cancelled() {
+                warn!("[TempoKey] Error decoding {:?}: {}", path, err);
+            }
+            result.error = Some(err.to_string());
+            return result;
+        }
+    };
+
+    if let Some((bpm, confidence)) = analysis.tempo() {
+        result.bpm = Some((bpm * 10.0).round() / 10.0);
+        result.bpm_confidence = confidence;
+    }
+    if let Some((tonic, minor, confidence)) = analysis.key() {
+        result.key = Some(key_name(tonic, minor));
+        result.camelot = Some(camelot(tonic, minor));
+        result.key_confidence = confidence;
+    }
+    info!(
+        "[TempoKey] {:?}: {:?} BPM, key {:?}",
+        path, result.bpm, result.key
+    );
+
+    if result.bpm.is_none() && result.key.is_none() {
+        return result;
+    }
+    save_analysis(
+        app_handle,
+        &SongAnalysis {
+            path: result.path.clone(),
+            bpm: result.bpm,
+            key: result.key.clone(),
+            ..Default::default()
+        },
+    );
+    if write_tags {
+        match write_tempo_key(path, result.bpm, result.key.as_deref()) {
+            Ok(()) => result.tags_written = true,
+            Err(err) => {
+                warn!("[TempoKey] Error writing tags to {:?}: {}", path, err);
+                result.error = Some(err.to_string());
+            }
+        }
+    }
+    result
+}
+
+/// eg. "Eb" or "F#m"
+pub(crate) fn key_name(tonic: usize, minor: bool) -> String {
+    format!("{}{}", KEY_NAMES[tonic], if minor { "m" } else { "" })
+}
+
+/// Neighbouring numbers are a fifth apart, a key and its relative minor share the number
+pub(crate) fn camelot(tonic: usize, minor: bool) -> String {
+    let major_tonic = if minor { (tonic + 3) % 12 } else { tonic };
+    let number = (major_tonic * 7 + 7) % 12 + 1;
+    format!("{}{}", number, if minor { "A" } else { "B" })
+}
+
+/// A running FFT over the mono mix, keeping the windowed magnitudes of each frame
+pub struct Stft {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    /// Samples between the starts of two frames
+    pub hop: usize,
+    /// Samples not analysed yet, the start of the next frame first
+    pending: Vec<f32>,
+    scratch: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+}
+
+impl Stft {
+    pub fn new(planner: &mut FftPlanner<f32>, size: usize) -> Self {
+        Stft {
+            fft: planner.plan_fft_forward(size),
+            window: (0..size)
+                .map(|i| AnalyzerWindow::Hann.coefficient(i, size))
+                .collect(),
+            hop: size / 2,
+            pending: Vec::with_capacity(size * 2),
+            scratch: vec![Complex::default(); size],
+            magnitudes: vec![0.0; size / 2],
+        }
+    }
+
+    /// Calls `on_frame` with the magnitudes of every complete frame
+    pub fn push(&mut self, samples: &[f32], mut on_frame: impl FnMut(&[f32])) {
+        self.pending.extend_from_slice(samples);
+        let size = self.window.len();
+        let mut start = 0;
+        while start + size <= self.pending.len() {
+            for (i, value) in self.scratch.iter_mut().enumerate() {
+                *value = Complex::new(self.pending[start + i] * self.window[i], 0.0);
+            }
+            self.fft.process(&mut self.scratch);
+            let scale = 2.0 / size as f32;
+            for (magnitude, value) in self.magnitudes.iter_mut().zip(&self.scratch) {
+                *magnitude = value.norm() * scale;
+            }
+            on_frame(&self.magnitudes);
+            start += self.hop;
+        }
+        self.pending.drain(..start);
+    }
+}
+
+/**
+ * Everything needed from the audio, gathered in one pass: the onset strength
+ * for the tempo, and the pitch class profile (chroma) for the key.
+ */
+struct Analysis {
+    onset_stft: Stft,
+    chroma_stft: Stft,
+    /// Onset frames per second
+    frame_rate: f32,
+    /// Log magnitudes of the previous onset frame
+    previous: Vec<f32>,
+    /// Spectral flux per onset frame
+    onset: Vec<f32>,
+    /// Pitch class of each chroma bin, None outside the pitch range
+    pitch_classes: Vec<Option<usize>>,
+    chroma: [f32; 12],
+    mono: Vec<f32>,
+}
+
+impl Analysis {
+    fn new(sample_rate: u32) -> Self {
+        let sample_rate = sample_rate.max(1);
+        let mut planner = FftPlanner::new();
+        // About 23 ms for the onsets and 190 ms for the pitch, whatever the sample rate
+        let onset_size = (sample_rate as usize / 64).next_power_of_two();
+        let chroma_size = (sample_rate as usize / 8).next_power_of_two();
+        let onset_stft = Stft::new(&mut planner, onset_size);
+        let chroma_stft = Stft::new(&mut planner, chroma_size);
+
+        let bin_width = sample_rate as f32 / chroma_size as f32;
+        let pitch_classes = (0..chroma_size / 2)
+            .map(|bin| {
+                let frequency = bin as f32 * bin_width;
+                if !(MIN_PITCH_FREQUENCY..=MAX_PITCH_FREQUENCY).contains(&frequency) {
+                    return None;
+                }
+                // A4 is 440 Hz, and A is 9 semitones above C
+                let semitones = (12.0 * (frequency / 440.0).log2()).round() as i32 + 9;
+                Some(semitones.rem_euclid(12) as usize)
+            })
+            .collect();
+
+        Analysis {
+            frame_rate: sample_rate as f32 / onset_stft.hop as f32,
+            previous: vec![0.0; onset_size / 2],
+            onset_stft,
+            chroma_stft,
+            onset: vec![],
+            pitch_classes,
+            chroma: [0.0; 12],
+            mono: vec![],
+        }
+    }
+
+    fn push(&mut self, interleaved: &[f32], channels: usize) {
+        let channels = channels.max(1);
+        self.mono.clear();
+        self.mono.extend(
+            interleaved
+                .chunks_exact(channels)
+                .map(|frame| frame.iter().sum::<f32>() / channels as f32),
+        );
+
+        let previous = &mut self.previous;
+        let onset = &mut self.onset;
+        self.onset_stft.push(&self.mono, |magnitudes| {
+            onset.push(spectral_flux(magnitudes, previous));
+        });
+
+        let pitch_classes = &self.pitch_classes;
+        let chroma = &mut self.chroma;
+        self.chroma_stft.push(&self.mono, |magnitudes| {
+            let mut frame = [0.0f32; 12];
+            for (magnitude, pitch_class) in magnitudes.iter().zip(pitch_classes) {
+                if let Some(pitch_class) = pitch_class {
+                    frame[*pitch_class] += magnitude;
+                }
+            }
+            // Every frame counts the same, loud passages shouldn't decide the key alone
+            let max = frame.iter().cloned().fold(0.0, f32::max);
+            if max > 1e-4 {
+                for (total, value) in chroma.iter_mut().zip(frame) {
+                    *total += value / max;
+                }
+            }
+        });
+    }
+
+    /// BPM and confidence
+    fn tempo(&self) -> Option<(f64, f32)> {
+        estimate_tempo(&self.onset, self.frame_rate)
+    }
+
+    /// Tonic (0 is C), whether it's minor, and the correlation with the key profile
+    fn key(&self) -> Option<(usize, bool, f32)> {
+        if self.chroma.iter().all(|value| *value <= 0.0) {
+            return None;
+        }
+        let mut best: Option<(usize, bool, f32)> = None;
+        for tonic in 0..12 {
+            for (profile, minor) in [(&MAJOR_PROFILE, false), (&MINOR_PROFILE, true)] {
+                let rotated: Vec<f32> = (0..12).map(|pc| profile[(pc + 12 - tonic) % 12]).collect();
+                let correlation = pearson(&self.chroma, &rotated);
+                if best.map_or(true, |(_, _, best)| correlation > best) {
+                    best = Some((tonic, minor, correlation));
+                }
+            }
+        }
+        best.filter(|(_, _, correlation)| *correlation >= MIN_KEY_CORRELATION)
+    }
+}
+
+/**
+ * BPM and confidence from the onset strength (spectral flux per frame),
+ * using the autocorrelation of the onsets.
+ */
+pub fn estimate_tempo(onset: &[f32], frame_rate: f32) -> Option<(f64, f32)> {
+    if (onset.len() as f32) < frame_rate * MIN_SECONDS {
+        return None;
+    }
+    let onset = detrend(onset, (frame_rate / 2.0) as usize);
+    let energy = autocorrelation(&onset, 0);
+    if energy <= 0.0 {
+        return None;
+    }
+
+    let frame_rate = frame_rate as f64;
+    let min_lag = (frame_rate * 60.0 / MAX_BPM).floor().max(1.0) as usize;
+    let max_lag = (frame_rate * 60.0 / MIN_BPM).ceil() as usize;
+    // Beats also line up at twice the period, which helps tell the tempo from its half
+    let scores: Vec<f32> = (min_lag..=max_lag)
+        .map(|lag| {
+            let bpm = frame_rate * 60.0 / lag as f64;
+            let preference =
+                (-0.5 * ((bpm / PREFERRED_BPM).log2() / TEMPO_SPREAD).powi(2)).exp() as f32;
+            preference * (autocorrelation(&onset, lag) + 0.5 * autocorrelation(&onset, lag * 2))
+        })
+        .collect();
+    let best = scores
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(index, _)| index)?;
+    let lag = best + min_lag;
+
+    // The period is sharper a few beats later, where a frame of error is spread over more beats
+    let mut period = refine_peak(&onset, lag);
+    for beats in [2, 4, 8] {
+        if lag * beats * 2 > onset.len() {
+            break;
+        }
+        period = refine_peak(&onset, (period * beats as f64).round() as usize) / beats as f64;
+    }
+
+    let bpm = frame_rate * 60.0 / period;
+    let confidence = (autocorrelation(&onset, lag) / energy).clamp(0.0, 1.0);
+    Some((bpm, confidence))
+}
+
+/// Rise in log magnitude since the previous frame, which is updated
+pub fn spectral_flux(magnitudes: &[f32], previous: &mut [f32]) -> f32 {
+    // Log compression, so quiet attacks count too
+    let mut flux = 0.0;
+    for (magnitude, previous) in magnitudes.iter().zip(previous.iter_mut()) {
+        let level = (1.0 + 1000.0 * magnitude).ln();
+        flux += (level - *previous).max(0.0);
+        *previous = level;
+    }
+    flux
+}
+
+/// Removes the local average and keeps what rises above it
+fn detrend(values: &[f32], radius: usize) -> Vec<f32> {
+    let mut sums = Vec::with_capacity(values.len() + 1);
+    sums.push(0.0f64);
+    for value in values {
+        sums.push(sums.last().unwrap() + *value as f64);
+    }
+    (0..values.len())
+        .map(|i| {
+            let start = i.saturating_sub(radius);
+            let end = (i + radius + 1).min(values.len());
+            let mean = (sums[end] - sums[start]) / (end - start) as f64;
+            (values[i] - mean as f32).max(0.0)
+        })
+        .collect()
+}
+
+/// Mean of the products at this lag
+fn autocorrelation(values: &[f32], lag: usize) -> f32 {
+    if lag >= values.len() {
+        return 0.0;
+    }
+    let sum: f32 = values.iter().zip(&values[lag..]).map(|(a, b)| a * b).sum();
+    sum / (values.len() - lag) as f32
+}
+
+/// The autocorrelation peak nearest to `lag`, with sub-frame precision
+fn refine_peak(values: &[f32], lag: usize) -> f64 {
+    let lag = (lag.saturating_sub(1)..=lag + 1)
+        .filter(|lag| *lag >= 1)
+        .max_by(|a, b| autocorrelation(values, *a).total_cmp(&autocorrelation(values, *b)))
+        .unwrap_or(lag.max(1));
+    // Parabola through the peak and its neighbours
+    let left = autocorrelation(values, lag - 1) as f64;
+    let center = autocorrelation(values, lag) as f64;
+    let right = autocorrelation(values, lag + 1) as f64;
+    let curvature = left - 2.0 * center + right;
+    if curvature >= 0.0 {
+        return lag as f64;
+    }
+    lag as f64 + (0.5 * (left - right) / curvature).clamp(-0.5, 0.5)
+}
+
+fn pearson(a: &[f32], b: &[f32]) -> f32 {
+    let n = a.len() as f32;
+    let mean_a = a.iter().sum::<f32>() / n;
+    let mean_b = b.iter().sum::<f32>() / n;
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for (x, y) in a.iter().zip(b) {
+        covariance += (x - mean_a) * (y - mean_b);
+        variance_a += (x - mean_a).powi(2);
+        variance_b += (y - mean_b).powi(2);
+    }
+    let denominator = (variance_a * variance_b).sqrt();
+    if denominator <= 0.0 {
+        return 0.0;
+    }
+    covariance / denominator
+}
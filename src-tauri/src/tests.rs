@@ -5,6 +5,18 @@ use lofty::{
     tag::{Accessor, ItemKey, ItemValue, TagExt, TagItem, TagType},
 };
 use log::info;
+use rusqlite::types::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::cue::{parse_cue, resolve_cue_file, write_cue, CueFile, CueSheet, CueTrack};
+use crate::encoder::{utf8_number, Encoder, EncoderFormat};
+use crate::history::{ListenOutcome, ListenThreshold, ListenTracker, ListenUpdate, PlaybackEvent};
+use crate::library::PlayStats;
+use crate::metadata::{FileInfo, Song};
+use crate::mpd::{format_ack, parse_filters, parse_range, tokenize, LineReader, MAX_LINE_LENGTH};
+use crate::smart_query::{parse, QuerySource};
+use crate::tempo_key::{camelot, estimate_tempo, key_name};
 
 #[test]
 fn write_track_number() {
@@ -40,3 +52,658 @@ fn write_track_number() {
         }
     }
 }
+
+fn test_song(id: &str, duration: f64) -> Song {
+    Song {
+        id: id.to_string(),
+        path: format!("/music/{}.flac", id),
+        file: format!("{}.flac", id),
+        file_info: FileInfo {
+            duration: Some(duration),
+            duration_display: None,
+            overall_bitrate: Some(900),
+            audio_bitrate: None,
+            sample_rate: Some(44100),
+            bit_depth: Some(16),
+            channels: Some(2),
+            lossless: true,
+            tag_type: None,
+            codec: Some(String::from("FLAC")),
+        },
+        dynamics: None,
+        metadata: HashMap::new(),
+        title: format!("Song {}", id),
+        artist: String::from("Artist"),
+        album: String::from("Album"),
+        album_id: None,
+        album_artist: None,
+        compilation: 0,
+        year: 1959,
+        genre: vec![String::from("Jazz")],
+        composer: vec![],
+        track_number: 1,
+        track_total: 1,
+        disc_number: 1,
+        disc_total: 1,
+        duration: String::new(),
+        artwork: None,
+        artwork_origin: None,
+        origin_country: None,
+        origin_country_name: None,
+        date_added: None,
+        cue_path: None,
+        cue_track: None,
+        start_offset: None,
+        end_offset: None,
+        chapters: vec![],
+        bpm: None,
+        key: None,
+    }
+}
+
+fn started(song: &Song, position: f64) -> PlaybackEvent {
+    PlaybackEvent::Started {
+        song: song.clone(),
+        output_device: String::from("default"),
+        playback_speed: 1.0,
+        position,
+    }
+}
+
+fn finished(updates: &[ListenUpdate]) -> Vec<(String, ListenOutcome, f64)> {
+    updates
+        .iter()
+        .filter_map(|update| match update {
+            ListenUpdate::Finished(session, outcome) => Some((
+                session.song.id.clone(),
+                *outcome,
+                session.played_seconds.round(),
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+const HALF: ListenThreshold = ListenThreshold {
+    fraction: 0.5,
+    max_seconds: None,
+};
+
+#[test]
+fn listen_tracker_counts_playing_the_same_song_again() {
+    let song = test_song("a", 100.0);
+    let start = Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+    let mut tracker = ListenTracker::new(HALF);
+
+    tracker.handle_at(&started(&song, 0.0), at(0.0));
+    let updates = tracker.handle_at(&PlaybackEvent::Position(60.0), at(60.0));
+    assert!(matches!(updates[..], [ListenUpdate::ThresholdReached(_)]));
+    tracker.handle_at(&PlaybackEvent::Ended, at(100.0));
+
+    let updates = tracker.handle_at(&started(&song, 0.0), at(100.0));
+    assert!(matches!(updates[..], [ListenUpdate::Started(_)]));
+    let updates = tracker.handle_at(&started(&song, 0.0), at(110.0));
+    assert_eq!(
+        finished(&updates),
+        vec![(String::from("a"), ListenOutcome::Skip, 10.0)]
+    );
+}
+
+#[test]
+fn listen_tracker_counts_time_playing_not_position() {
+    let song = test_song("a", 100.0);
+    let start = Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+    let mut tracker = ListenTracker::new(HALF);
+
+    tracker.handle_at(&started(&song, 0.0), at(0.0));
+    tracker.handle_at(&PlaybackEvent::Position(10.0), at(10.0));
+    // Seeking to the end doesn't count as having listened to it
+    let updates = tracker.handle_at(&started(&song, 90.0), at(11.0));
+    assert!(updates.is_empty());
+    assert!(tracker
+        .handle_at(&PlaybackEvent::Position(95.0), at(16.0))
+        .is_empty());
+
+    // Nor does time spent paused
+    tracker.handle_at(&PlaybackEvent::Paused, at(16.0));
+    tracker.handle_at(&PlaybackEvent::Resumed, at(300.0));
+    let updates = tracker.handle_at(&PlaybackEvent::Ended, at(304.0));
+    assert_eq!(
+        finished(&updates),
+        vec![(String::from("a"), ListenOutcome::Partial, 20.0)]
+    );
+}
+
+#[test]
+fn listen_tracker_finishes_the_previous_song() {
+    let (a, b) = (test_song("a", 100.0), test_song("b", 100.0));
+    let start = Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+    let mut tracker = ListenTracker::new(ListenThreshold {
+        fraction: 0.5,
+        max_seconds: Some(30.0),
+    });
+
+    tracker.handle_at(&started(&a, 0.0), at(0.0));
+    let updates = tracker.handle_at(&PlaybackEvent::Position(31.0), at(31.0));
+    assert!(matches!(updates[..], [ListenUpdate::ThresholdReached(_)]));
+    let updates = tracker.handle_at(&started(&b, 0.0), at(40.0));
+    assert_eq!(
+        finished(&updates),
+        vec![(String::from("a"), ListenOutcome::Play, 40.0)]
+    );
+    assert!(matches!(updates[1], ListenUpdate::Started(ref s) if s.song.id == "b"));
+}
+
+const CUE_SHEET: &str = "\u{feff}REM GENRE \"Jazz\"
+REM DATE 1959
+PERFORMER \"Miles Davis\"
+TITLE \"Kind of Blue\"
+FILE \"Kind of Blue.flac\" WAVE
+  TRACK 01 AUDIO
+    TITLE \"So What\"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE \"Freddie Freeloader\"
+    PERFORMER \"Miles Davis Sextet\"
+    INDEX 00 09:20:50
+    INDEX 01 09:22:37
+";
+
+#[test]
+fn parse_cue_sheet() {
+    let sheet = parse_cue(CUE_SHEET);
+    assert_eq!(sheet.title.as_deref(), Some("Kind of Blue"));
+    assert_eq!(sheet.performer.as_deref(), Some("Miles Davis"));
+    assert_eq!(sheet.genre.as_deref(), Some("Jazz"));
+    assert_eq!(sheet.date.as_deref(), Some("1959"));
+    assert_eq!(sheet.files.len(), 1);
+    assert_eq!(sheet.files[0].name, "Kind of Blue.flac");
+
+    let tracks = &sheet.files[0].tracks;
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[0].number, 1);
+    assert_eq!(tracks[0].title.as_deref(), Some("So What"));
+    assert_eq!(tracks[0].performer, None);
+    assert_eq!(tracks[1].performer.as_deref(), Some("Miles Davis Sextet"));
+    // INDEX 01, not the pregap, 37 frames of 1/75s
+    assert!((tracks[1].start - (9.0 * 60.0 + 22.0 + 37.0 / 75.0)).abs() < 1e-9);
+}
+
+#[test]
+fn write_cue_sheet_reads_back() {
+    let sheet = CueSheet {
+        title: Some(String::from("Live \"at\" the Plugged Nickel")),
+        performer: Some(String::from("Miles Davis")),
+        songwriter: None,
+        genre: None,
+        date: Some(String::from("1965")),
+        files: vec![CueFile {
+            name: String::from("Recording.wav"),
+            tracks: vec![
+                CueTrack {
+                    number: 1,
+                    title: Some(String::from("If I Were a Bell")),
+                    start: 0.0,
+                    ..Default::default()
+                },
+                CueTrack {
+                    number: 2,
+                    title: Some(String::from("Stella by Starlight")),
+                    songwriter: Some(String::from("Victor Young")),
+                    start: 3723.5,
+                    ..Default::default()
+                },
+            ],
+        }],
+    };
+    let written = write_cue(&sheet);
+    assert!(written.contains("    INDEX 01 62:03:38\n"));
+
+    let read = parse_cue(&written);
+    // Quotes can't be escaped, they're swapped for single quotes
+    assert_eq!(read.title.as_deref(), Some("Live 'at' the Plugged Nickel"));
+    assert_eq!(read.performer, sheet.performer);
+    assert_eq!(read.date, sheet.date);
+    assert_eq!(read.files[0].name, "Recording.wav");
+    let tracks = &read.files[0].tracks;
+    assert_eq!(tracks.len(), 2);
+    assert_eq!(tracks[1].number, 2);
+    assert_eq!(tracks[1].title.as_deref(), Some("Stella by Starlight"));
+    assert_eq!(tracks[1].songwriter.as_deref(), Some("Victor Young"));
+    // Rounded to the nearest CD frame
+    assert!((tracks[1].start - 3723.5).abs() < 1.0 / 75.0);
+}
+
+#[test]
+fn resolve_re_encoded_cue_file_with_dots() {
+    let dir = std::env::temp_dir().join(format!("musicat-cue-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let cue_path = dir.join("album.cue");
+    let flac = dir.join("01. Miles Davis - So What.flac");
+    std::fs::write(&flac, b"").unwrap();
+
+    let resolved = resolve_cue_file(&cue_path, "01. Miles Davis - So What.wav");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(resolved, Some(flac));
+}
+
+#[test]
+fn mpd_tokenize_commands() {
+    assert_eq!(
+        tokenize("add \"/music/Miles Davis/So What.flac\"").unwrap(),
+        vec!["add", "/music/Miles Davis/So What.flac"]
+    );
+    assert_eq!(
+        tokenize("  find  artist \"Say \\\"Hi\\\"\" \"\"").unwrap(),
+        vec!["find", "artist", "Say \"Hi\"", ""]
+    );
+    assert!(tokenize("").unwrap().is_empty());
+
+    let ack = tokenize("find \"artist").unwrap_err();
+    assert_eq!(
+        format_ack(&ack, 1, "find"),
+        "ACK [2@1] {find} Missing closing '\"'\n"
+    );
+}
+
+#[test]
+fn mpd_parse_ranges() {
+    assert_eq!(parse_range("2", 5).unwrap(), 2..3);
+    assert_eq!(parse_range("1:3", 5).unwrap(), 1..3);
+    assert_eq!(parse_range("3:", 5).unwrap(), 3..5);
+    // Ranges are clamped to the queue, single positions aren't
+    assert_eq!(parse_range("4:10", 5).unwrap(), 4..5);
+    assert_eq!(parse_range("5", 5).unwrap_err().message, "Bad song index");
+    assert!(parse_range("a:b", 5).is_err());
+}
+
+#[test]
+fn mpd_parse_filters() {
+    let pairs: Vec<String> = vec![
+        "Artist".into(),
+        "Miles Davis".into(),
+        "album".into(),
+        "Kind".into(),
+    ];
+    let filters = parse_filters(&pairs).unwrap();
+    assert_eq!(filters.len(), 2);
+    assert_eq!(
+        (
+            filters[0].tag.as_str(),
+            filters[0].op.as_str(),
+            filters[0].value.as_str()
+        ),
+        ("artist", "==", "Miles Davis")
+    );
+    assert!(parse_filters(&pairs[..3]).is_err());
+
+    let expression = vec![String::from(
+        "((artist == \"Miles Davis\") AND (album contains 'Kind of'))",
+    )];
+    let filters = parse_filters(&expression).unwrap();
+    assert_eq!(
+        (
+            filters[1].tag.as_str(),
+            filters[1].op.as_str(),
+            filters[1].value.as_str()
+        ),
+        ("album", "contains", "Kind of")
+    );
+}
+
+#[test]
+fn mpd_line_reader_limits_line_length() {
+    tauri::async_runtime::block_on(async {
+        let input = b"status\r\ncurrentsong\nclose";
+        let mut lines = LineReader::new(&input[..]);
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("status"));
+        assert_eq!(
+            lines.next_line().await.unwrap().as_deref(),
+            Some("currentsong")
+        );
+        assert_eq!(lines.next_line().await.unwrap().as_deref(), Some("close"));
+        assert_eq!(lines.next_line().await.unwrap(), None);
+
+        let long = format!("add \"{}\"\nstatus\n", "a".repeat(MAX_LINE_LENGTH));
+        let mut lines = LineReader::new(long.as_bytes());
+        let err = lines.next_line().await.unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    });
+}
+
+/// Encodes a few seconds of a stereo signal and decodes it again with Symphonia
+fn encode_and_decode(format: EncoderFormat, bits_per_sample: u32) -> (Vec<i32>, Vec<i32>) {
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::meta::MetadataOptions;
+    use symphonia::core::probe::Hint;
+
+    let sample_rate = 44100;
+    let frames = 10_000;
+    let max = (1i64 << (bits_per_sample - 1)) as f64 - 1.0;
+    // A sine on the left, a louder one with a step on the right
+    let samples: Vec<i32> = (0..frames)
+        .flat_map(|i| {
+            let t = i as f64 / sample_rate as f64;
+            let left = (t * 440.0 * std::f64::consts::TAU).sin() * max * 0.5;
+            let right = if i < frames / 2 {
+                (t * 1000.0 * std::f64::consts::TAU).sin() * max
+            } else {
+                -max
+            };
+            [left.round() as i32, right.round() as i32]
+        })
+        .collect();
+
+    let mut encoder = Encoder::new(format, sample_rate, 2, bits_per_sample);
+    let mut bytes = encoder.header(Some(frames as u64));
+    // In uneven chunks, like the output callback would
+    for chunk in samples.chunks(1234 * 2) {
+        bytes.extend(encoder.encode(chunk));
+    }
+    bytes.extend(encoder.finish());
+
+    let mss = MediaSourceStream::new(Box::new(std::io::Cursor::new(bytes)), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(format.extension());
+    let mut reader = symphonia::default::get_probe()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .unwrap()
+        .format;
+    let track = reader.default_track().unwrap();
+    assert_eq!(track.codec_params.sample_rate, Some(sample_rate));
+    assert_eq!(track.codec_params.channels.map(|c| c.count()), Some(2));
+    let mut decoder = symphonia::default::get_codecs()
+        .make(&track.codec_params, &DecoderOptions { verify: true })
+        .unwrap();
+
+    let mut decoded = Vec::new();
+    while let Ok(packet) = reader.next_packet() {
+        let buffer = decoder.decode(&packet).unwrap();
+        let mut interleaved = SampleBuffer::<i32>::new(buffer.capacity() as u64, *buffer.spec());
+        interleaved.copy_interleaved_ref(buffer);
+        // Symphonia scales everything to 32 bits
+        decoded.extend(
+            interleaved
+                .samples()
+                .iter()
+                .map(|s| s >> (32 - bits_per_sample)),
+        );
+    }
+    (samples, decoded)
+}
+
+#[test]
+fn encoder_wav_round_trip() {
+    let (samples, decoded) = encode_and_decode(EncoderFormat::Wav, 16);
+    assert_eq!(decoded, samples);
+}
+
+#[test]
+fn encoder_flac_round_trip() {
+    for bits_per_sample in [16, 24] {
+        let (samples, decoded) = encode_and_decode(EncoderFormat::Flac, bits_per_sample);
+        assert_eq!(decoded.len(), samples.len());
+        assert!(
+            decoded == samples,
+            "{} bit FLAC isn't lossless",
+            bits_per_sample
+        );
+    }
+}
+
+#[test]
+fn encoder_utf8_frame_numbers() {
+    assert_eq!(utf8_number(0), vec![0x00]);
+    assert_eq!(utf8_number(0x7F), vec![0x7F]);
+    // Same as UTF-8 for anything that is a valid char
+    for value in [0x80u32, 0x7FF, 0x800, 0xFFFF, 0x10000, 0x10FFFF] {
+        let mut expected = [0u8; 4];
+        let expected = char::from_u32(value)
+            .unwrap()
+            .encode_utf8(&mut expected)
+            .as_bytes()
+            .to_vec();
+        assert_eq!(utf8_number(value as u64), expected, "{:#x}", value);
+    }
+    // Frame numbers go up to 31 bits, 6 bytes
+    assert_eq!(
+        utf8_number(0x7FFF_FFFF),
+        vec![0xFD, 0xBF, 0xBF, 0xBF, 0xBF, 0xBF]
+    );
+}
+
+#[test]
+fn tempo_key_names() {
+    assert_eq!(key_name(0, false), "C");
+    assert_eq!(key_name(1, false), "Db");
+    assert_eq!(key_name(6, true), "F#m");
+    assert_eq!(key_name(10, true), "Bbm");
+}
+
+#[test]
+fn tempo_key_camelot() {
+    assert_eq!(camelot(0, false), "8B");
+    // Relative minor shares the number
+    assert_eq!(camelot(9, true), "8A");
+    // A fifth up is the next number
+    assert_eq!(camelot(7, false), "9B");
+    assert_eq!(camelot(4, true), "9A");
+    assert_eq!(camelot(1, false), "3B");
+    assert_eq!(camelot(6, true), "11A");
+}
+
+/// 30 seconds of one onset per beat
+fn beat_onsets(bpm: f64, frame_rate: f32) -> Vec<f32> {
+    let period = frame_rate as f64 * 60.0 / bpm;
+    (0..frame_rate as usize * 30)
+        .map(|frame| {
+            if (frame as f64 / period).fract() < 1.0 / period {
+                1.0
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn tempo_key_estimate_tempo() {
+    let frame_rate = 86.0;
+    for bpm in [90.0, 120.0, 128.0] {
+        let (estimate, confidence) = estimate_tempo(&beat_onsets(bpm, frame_rate), frame_rate)
+            .unwrap_or_else(|| panic!("No tempo for {} BPM", bpm));
+        assert!((estimate - bpm).abs() < 0.5, "{} BPM as {}", bpm, estimate);
+        assert!(confidence > 0.5);
+    }
+    // Under 5 seconds
+    assert_eq!(estimate_tempo(&[1.0; 100], frame_rate), None);
+}
+
+/// 2024-05-01 00:00 UTC
+const QUERY_NOW: i64 = 1_714_521_600_000;
+const QUERY_DAY: i64 = 24 * 60 * 60 * 1000;
+
+#[test]
+fn smart_query_matches_example() {
+    let query =
+        parse(r#"genre:jazz year:1955..1965 -artist:"Miles Davis" plays>3 added<30d"#).unwrap();
+    assert_eq!(
+        query.to_string(),
+        r#"genre contains "jazz" and year from 1955 to 1965 and not artist contains "Miles Davis" and plays more than 3 and added after 30d ago"#
+    );
+
+    let mut song = test_song("1", 300.0);
+    song.date_added = Some((QUERY_NOW - 10 * QUERY_DAY) as u128);
+    let plays = |play_count| PlayStats {
+        play_count,
+        ..Default::default()
+    };
+    assert!(query.matches(&song, Some(&plays(4)), QuerySource::Library, QUERY_NOW));
+    assert!(!query.matches(&song, Some(&plays(3)), QuerySource::Library, QUERY_NOW));
+    // Never played
+    assert!(!query.matches(&song, None, QuerySource::Library, QUERY_NOW));
+
+    let mut miles = song.clone();
+    miles.artist = String::from("Miles Davis");
+    assert!(!query.matches(&miles, Some(&plays(4)), QuerySource::Library, QUERY_NOW));
+    let mut later = song.clone();
+    later.year = 1966;
+    assert!(!query.matches(&later, Some(&plays(4)), QuerySource::Library, QUERY_NOW));
+    let mut rock = song.clone();
+    rock.genre = vec![String::from("Rock")];
+    assert!(!query.matches(&rock, Some(&plays(4)), QuerySource::Library, QUERY_NOW));
+    let mut old = song.clone();
+    old.date_added = Some((QUERY_NOW - 31 * QUERY_DAY) as u128);
+    assert!(!query.matches(&old, Some(&plays(4)), QuerySource::Library, QUERY_NOW));
+}
+
+#[test]
+fn smart_query_date_boundaries() {
+    let added = |query: &str, date_added: i64| {
+        let mut song = test_song("1", 300.0);
+        song.date_added = Some(date_added as u128);
+        parse(query)
+            .unwrap()
+            .matches(&song, None, QuerySource::Library, QUERY_NOW)
+    };
+    let days_ago = |days: i64| QUERY_NOW - days * QUERY_DAY;
+
+    assert!(added("added<30d", days_ago(30) + 1));
+    assert!(!added("added<30d", days_ago(30)));
+    assert!(added("added>30d", days_ago(30) - 1));
+    assert!(!added("added>30d", days_ago(30)));
+
+    // The whole day, in UTC
+    let day = days_ago(0);
+    assert!(added("added=2024-05-01", day));
+    assert!(added("added=2024-05-01", day + QUERY_DAY - 1));
+    assert!(!added("added=2024-05-01", day + QUERY_DAY));
+    assert!(!added("added=2024-05-01", day - 1));
+    assert!(added("added<=2024-05-01", day + QUERY_DAY - 1));
+    assert!(!added("added>2024-05-01", day + QUERY_DAY - 1));
+    assert!(added("added>2024-05-01", day + QUERY_DAY));
+
+    // From 30 to 7 days ago
+    assert!(added("added:7d..30d", days_ago(30)));
+    assert!(added("added:7d..30d", days_ago(7)));
+    assert!(!added("added:7d..30d", days_ago(30) - 1));
+    assert!(!added("added:7d..30d", days_ago(7) + 1));
+}
+
+#[test]
+fn smart_query_error_spans() {
+    let error = |query: &str| {
+        let err = parse(query).unwrap_err();
+        (err.message, err.start, err.end)
+    };
+    assert_eq!(
+        error("genre:jazz foo:bar"),
+        (String::from("Unknown field foo"), 11, 14)
+    );
+    assert_eq!(
+        error("year:19x5"),
+        (String::from("19x5 isn't a number"), 5, 9)
+    );
+    assert_eq!(
+        error("title<3"),
+        (String::from("title can only be matched with : or ="), 0, 7)
+    );
+    assert_eq!(
+        error(r#"artist:"Miles"#),
+        (String::from("Missing a closing quote"), 7, 13)
+    );
+    assert_eq!(error("(jazz"), (String::from("Missing a closing )"), 0, 1));
+    assert_eq!(error("jazz)"), (String::from("Unmatched )"), 4, 5));
+    assert_eq!(
+        error("jazz OR"),
+        (String::from("OR needs something on both sides"), 5, 7)
+    );
+    assert_eq!(
+        error("jazz -"),
+        (String::from("Nothing to exclude after -"), 5, 6)
+    );
+    // In characters, not bytes
+    assert_eq!(
+        error("ünï:x jazz"),
+        (String::from("Unknown field ünï"), 0, 3)
+    );
+}
+
+#[test]
+fn smart_query_to_sql() {
+    let query = parse("artist:davis year>=1960 -genre=bop").unwrap();
+    let values = vec![
+        Value::Text(String::from("%davis%")),
+        Value::Real(1960.0),
+        Value::Text(String::from("bop")),
+    ];
+    let library = query.to_sql(QuerySource::Library, QUERY_NOW).unwrap();
+    assert_eq!(
+        library.sql,
+        r"(s.artist LIKE ? ESCAPE '\' AND (NULLIF(s.year, 0) >= ?) AND NOT COALESCE(EXISTS (SELECT 1 FROM json_each(s.data, '$.genre') WHERE value = ? COLLATE NOCASE), 0))"
+    );
+    assert_eq!(library.values, values);
+    let beets = query.to_sql(QuerySource::Beets, QUERY_NOW).unwrap();
+    assert_eq!(
+        beets.sql,
+        r"(artist LIKE ? ESCAPE '\' AND (NULLIF(year, 0) >= ?) AND NOT COALESCE(genre = ? COLLATE NOCASE, 0))"
+    );
+    assert_eq!(beets.values, values);
+
+    let played = parse("plays>3 added<30d").unwrap();
+    let library = played.to_sql(QuerySource::Library, QUERY_NOW).unwrap();
+    assert_eq!(
+        library.sql,
+        "((COALESCE(h.play_count, 0) > ?) AND (s.date_added > ?))"
+    );
+    assert_eq!(
+        library.values,
+        vec![
+            Value::Real(3.0),
+            Value::Real((QUERY_NOW - 30 * QUERY_DAY) as f64)
+        ]
+    );
+    // Play counts are only in our history
+    assert!(played.to_sql(QuerySource::Beets, QUERY_NOW).is_none());
+}
+
+#[test]
+fn smart_query_beets_bitrate_when_evaluated() {
+    let query = parse("plays>3 | bitrate>256").unwrap();
+    // Play counts are only in our history, so this is checked song by song
+    assert!(query.to_sql(QuerySource::Beets, QUERY_NOW).is_none());
+
+    let beets_song = |bits_per_second| {
+        let mut song = test_song("1", 300.0);
+        song.file_info.overall_bitrate = Some(bits_per_second);
+        song
+    };
+    let plays = PlayStats {
+        play_count: 4,
+        ..Default::default()
+    };
+    assert!(query.matches(&beets_song(320_000), None, QuerySource::Beets, QUERY_NOW));
+    assert!(!query.matches(&beets_song(256_999), None, QuerySource::Beets, QUERY_NOW));
+    assert!(!query.matches(&beets_song(128_000), None, QuerySource::Beets, QUERY_NOW));
+    assert!(query.matches(
+        &beets_song(128_000),
+        Some(&plays),
+        QuerySource::Beets,
+        QUERY_NOW
+    ));
+    // Our own songs are in kbps already
+    let mut song = test_song("1", 300.0);
+    song.file_info.overall_bitrate = Some(320);
+    assert!(query.matches(&song, None, QuerySource::Library, QUERY_NOW));
+}
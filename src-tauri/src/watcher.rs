@@ -0,0 +1,293 @@
+//! Watches the library folders, keeping the library up to date as files are
+//! added, changed, moved or removed
+
+use log::{error, info, warn};
+use notify_debouncer_full::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_full::{new_debouncer, DebounceEventResult, Debouncer, RecommendedCache};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter};
+use tokio_util::sync::CancellationToken;
+
+use crate::cue;
+use crate::files::is_audio_file;
+use crate::library_index;
+use crate::store::load_settings;
+
+/// Events for the same file closer than this are merged
+const DEBOUNCE: Duration = Duration::from_secs(2);
+/// Changes are held until it's been quiet this long, eg. while an album is copied in
+const SETTLE: Duration = Duration::from_secs(3);
+/// Don't hold changes for longer than this while files keep changing
+const MAX_SETTLE: Duration = Duration::from_secs(30);
+/// How often we check for folders going away or coming back (eg. drives)
+const ROOT_POLL: Duration = Duration::from_secs(15);
+
+static WATCHER: Mutex<Option<LibraryWatcher>> = Mutex::new(None);
+
+struct LibraryWatcher {
+    debouncer: Debouncer<RecommendedWatcher, RecommendedCache>,
+    roots: Vec<WatchedFolder>,
+    token: CancellationToken,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchedFolder {
+    pub path: PathBuf,
+    /// False while the folder can't be reached, eg. on an unmounted drive
+    pub available: bool,
+}
+
+/**
+ * Starts watching the folders from the settings.
+ */
+pub fn init(app: AppHandle) {
+    let Ok(settings) = load_settings(&app) else {
+        return;
+    };
+    if let Err(err) = start(&app, settings.folders_to_watch) {
+        error!("[Watcher] Error starting: {}", err);
+    }
+}
+
+/**
+ * Watches the given folders, or the ones from the settings, instead of the
+ * ones watched so far.
+ * Emits "library_changed" with the added, updated, moved and removed songs, and
+ * "library_folder_availability" when a folder goes away or comes back.
+ */
+#[tauri::command]
+pub fn watch_library_folders(
+    paths: Option<Vec<String>>,
+    app_handle: AppHandle,
+) -> Result<Vec<WatchedFolder>, String> {
+    let paths = match paths {
+        Some(paths) => paths,
+        None => {
+            load_settings(&app_handle)
+                .map_err(|e| e.to_string())?
+                .folders_to_watch
+        }
+    };
+    start(&app_handle, paths)
+}
+
+#[tauri::command]
+pub fn unwatch_library_folders() {
+    stop();
+}
+
+#[tauri::command]
+pub fn get_watched_library_folders() -> Vec<WatchedFolder> {
+    WATCHER
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|watcher| watcher.roots.clone())
+        .unwrap_or_default()
+}
+
+fn stop() {
+    if let Some(watcher) = WATCHER.lock().unwrap().take() {
+        watcher.token.cancel();
+        info!("[Watcher] Stopped");
+    }
+}
+
+fn start(app: &AppHandle, paths: Vec<String>) -> Result<Vec<WatchedFolder>, String> {
+    stop();
+    if paths.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let (sender, receiver) = channel();
+    let mut debouncer =
+        new_debouncer(
+            DEBOUNCE,
+            None,
+            move |result: DebounceEventResult| match result {
+                Ok(events) => {
+                    let paths: Vec<PathBuf> = events
+                        .into_iter()
+                        .filter(|event| !event.event.kind.is_access())
+                        .flat_map(|event| event.event.paths)
+                        .collect();
+                    let _ = sender.send(paths);
+                }
+                Err(errors) => {
+                    for err in errors {
+                        warn!("[Watcher] {}", err);
+                    }
+                }
+            },
+        )
+        .map_err(|e| e.to_string())?;
+
+    let roots: Vec<WatchedFolder> = paths
+        .iter()
+        .map(|path| {
+            let path = PathBuf::from(path);
+            let available = path.is_dir()
+                && match debouncer.watch(&path, RecursiveMode::Recursive) {
+                    Ok(()) => true,
+                    Err(err) => {
+                        warn!("[Watcher] Can't watch {:?}: {}", path, err);
+                        false
+                    }
+                };
+            WatchedFolder { path, available }
+        })
+        .collect();
+    info!("[Watcher] Watching {:?}", roots);
+
+    let token = CancellationToken::new();
+    let root_paths: Vec<PathBuf> = roots.iter().map(|root| root.path.clone()).collect();
+    let changes_handle = app.clone();
+    std::thread::Builder::new()
+        .name("library-watcher".into())
+        .spawn(move || process_changes(&changes_handle, receiver, &root_paths))
+        .map_err(|e| e.to_string())?;
+    let poll_handle = app.clone();
+    let poll_token = token.clone();
+    std::thread::Builder::new()
+        .name("library-watcher-roots".into())
+        .spawn(move || poll_roots(&poll_handle, &poll_token))
+        .map_err(|e| e.to_string())?;
+
+    *WATCHER.lock().unwrap() = Some(LibraryWatcher {
+        debouncer,
+        roots: roots.clone(),
+        token,
+    });
+    Ok(roots)
+}
+
+/// Gathers changes until things settle down, then rescans where they happened.
+/// Ends when the watcher is dropped.
+fn process_changes(app: &AppHandle, receiver: Receiver<Vec<PathBuf>>, roots: &[PathBuf]) {
+    while let Ok(mut changed) = receiver.recv() {
+        let started = Instant::now();
+        while started.elapsed() < MAX_SETTLE {
+            match receiver.recv_timeout(SETTLE) {
+                Ok(paths) => changed.extend(paths),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        rescan(app, &rescan_targets(&changed, roots));
+    }
+}
+
+/// Where to rescan for the changed paths: the paths themselves, or the closest
+/// folder still there for the ones that are gone, so their files are removed.
+/// Nothing for folders that can't be reached, which would look like everything
+/// in them was deleted.
+fn rescan_targets(changed: &[PathBuf], roots: &[PathBuf]) -> Vec<String> {
+    let mut targets: Vec<&Path> = changed
+        .iter()
+        .filter_map(|path| {
+            let root = roots.iter().find(|root| path.starts_with(root))?;
+            if !root.is_dir() {
+                return None;
+            }
+            if path.is_dir() || is_library_file(path) {
+                return Some(path.as_path());
+            }
+            if path.exists() {
+                // Artwork, playlists...
+                return None;
+            }
+            path.ancestors()
+                .skip(1)
+                .take_while(|ancestor| ancestor.starts_with(root))
+                .find(|ancestor| ancestor.is_dir())
+        })
+        .collect();
+
+    // Rescanning a folder covers what's in it
+    targets.sort();
+    targets.dedup();
+    let mut kept: Vec<&Path> = Vec::new();
+    for target in targets {
+        if !kept.iter().any(|folder| target.starts_with(folder)) {
+            kept.retain(|other| !other.starts_with(target));
+            kept.push(target);
+        }
+    }
+    kept.iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect()
+}
+
+fn is_library_file(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .is_some_and(|name| name.to_string_lossy().starts_with('.'));
+    !hidden && (is_audio_file(path) || cue::is_cue_file(path))
+}
+
+fn rescan(app: &AppHandle, targets: &[String]) {
+    if targets.is_empty() {
+        return;
+    }
+    let is_cover_fullcheck = load_settings(app)
+        .ok()
+        .and_then(|settings| settings.is_cover_full_check_enabled)
+        .unwrap_or(false);
+    match library_index::rescan(targets, is_cover_fullcheck, false, app) {
+        Ok(delta) if !delta.is_empty() => {
+            info!(
+                "[Watcher] {} added, {} updated, {} moved, {} removed",
+                delta.added.len(),
+                delta.updated.len(),
+                delta.moved.len(),
+                delta.removed.len()
+            );
+            let _ = app.emit("library_changed", delta);
+        }
+        Ok(_) => {}
+        Err(err) => error!("[Watcher] Error rescanning {:?}: {}", targets, err),
+    }
+}
+
+/// Stops watching folders that went away and watches them again when they're
+/// back, catching up with what changed in the meantime
+fn poll_roots(app: &AppHandle, token: &CancellationToken) {
+    loop {
+        std::thread::sleep(ROOT_POLL);
+        let mut returned = Vec::new();
+        {
+            let mut current = WATCHER.lock().unwrap();
+            let Some(watcher) = current.as_mut().filter(|_| !token.is_cancelled()) else {
+                return;
+            };
+            for root in watcher.roots.iter_mut() {
+                let available = root.path.is_dir();
+                if available == root.available {
+                    continue;
+                }
+                if available {
+                    if let Err(err) = watcher
+                        .debouncer
+                        .watch(&root.path, RecursiveMode::Recursive)
+                    {
+                        warn!("[Watcher] Can't watch {:?}: {}", root.path, err);
+                        continue;
+                    }
+                    info!("[Watcher] {:?} is back", root.path);
+                    returned.push(root.path.to_string_lossy().to_string());
+                } else {
+                    let _ = watcher.debouncer.unwatch(&root.path);
+                    warn!("[Watcher] {:?} can't be reached", root.path);
+                }
+                root.available = available;
+                let _ = app.emit("library_folder_availability", root.clone());
+            }
+        }
+        rescan(app, &returned);
+    }
+}
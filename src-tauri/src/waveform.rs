@@ -0,0 +1,643 @@
+//! Waveform peaks at several zoom levels, cached on disk
+
+use chksum_md5::MD5;
+use filetime::FileTime;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tauri::{AppHandle, Emitter, Manager, State};
+use tokio_util::sync::CancellationToken;
+
+use crate::constants::{WAVEFORM_PEAK_METHOD, WAVEFORM_WINDOW_SIZE};
+use crate::decode::FileDecoder;
+use crate::dsp::{calculate_peak_value, PeakMethod};
+use crate::dynamics;
+use crate::lossless;
+use crate::player::AudioPlayer;
+use crate::precompute;
+use crate::similarity;
+use crate::spectrogram;
+use crate::store::load_settings;
+use crate::tempo_key;
+
+/// Frames per peak of each zoom level, finest first. The last one is the overview.
+pub const ZOOM_LEVELS: [usize; 3] = [
+    WAVEFORM_WINDOW_SIZE / 16,
+    WAVEFORM_WINDOW_SIZE / 4,
+    WAVEFORM_WINDOW_SIZE,
+];
+const CACHE_DIR: &str = "waveforms";
+const CACHE_EXTENSION: &str = "peaks";
+/// Megabytes
+const DEFAULT_CACHE_SIZE: u64 = 512;
+const MAGIC: &[u8; 4] = b"MWF1";
+/// How often new overview peaks are sent while decoding
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+static PARTIAL_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GetWaveformRequest {
+    pub path: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct GetWaveformResponse {
+    /// Overview peaks as f32, all of them when complete, else those new since the last event
+    data: Option<ByteBuf>,
+    /// Index of the first peak in data
+    offset: usize,
+    /// Expected number of peaks, 0 when unknown
+    length: usize,
+    complete: bool,
+}
+
+/// Overview peaks decoded since the last progress
+pub struct OverviewProgress<'a> {
+    /// Index of the first peak
+    pub offset: usize,
+    pub peaks: &'a [f32],
+    /// Expected number of peaks, 0 when unknown
+    pub length: usize,
+}
+
+/**
+ * Min, max and RMS of every channel for each block of `frames_per_peak`
+ * frames, interleaved: min, max, rms of channel 0, then channel 1, ...
+ * Scaled to i16.
+ */
+#[derive(Clone, Debug)]
+pub struct WaveformLevel {
+    pub frames_per_peak: usize,
+    pub peaks: Vec<i16>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Waveform {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub frames: u64,
+    /// One for each of ZOOM_LEVELS
+    pub levels: Vec<WaveformLevel>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WaveformLevelResponse {
+    pub sample_rate: u32,
+    pub channels: usize,
+    pub frames: u64,
+    pub frames_per_peak: usize,
+    /// i16 little-endian, see [`WaveformLevel`]
+    pub data: ByteBuf,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct WaveformCacheInfo {
+    pub files: usize,
+    pub bytes: u64,
+    pub limit_bytes: u64,
+}
+
+impl Waveform {
+    /// One value per peak of the coarsest level, for the player's waveform
+    pub fn overview(&self, method: PeakMethod) -> Vec<f32> {
+        let Some(level) = self.levels.last() else {
+            return vec![];
+        };
+        level
+            .peaks
+            .chunks_exact(self.channels * 3)
+            .map(|peak| {
+                let stats: Vec<[f32; 3]> = peak
+                    .chunks_exact(3)
+                    .map(|s| [from_i16(s[0]), from_i16(s[1]), from_i16(s[2])])
+                    .collect();
+                calculate_peak_value(&stats, method)
+            })
+            .collect()
+    }
+
+    fn level(&self, frames_per_peak: usize) -> Option<&WaveformLevel> {
+        // The coarsest level that still has the detail asked for
+        self.levels
+            .iter()
+            .rev()
+            .find(|level| level.frames_per_peak <= frames_per_peak)
+            .or(self.levels.first())
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(self.sample_rate.to_le_bytes());
+        bytes.extend((self.channels as u16).to_le_bytes());
+        bytes.extend(self.frames.to_le_bytes());
+        bytes.extend((self.levels.len() as u16).to_le_bytes());
+        for level in &self.levels {
+            bytes.extend((level.frames_per_peak as u32).to_le_bytes());
+            bytes.extend((level.peaks.len() as u32).to_le_bytes());
+            bytes.extend(level.peaks.iter().flat_map(|p| p.to_le_bytes()));
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let mut reader = ByteReader { bytes, offset: 0 };
+        if reader.take(4)? != MAGIC {
+            return None;
+        }
+        let sample_rate = u32::from_le_bytes(reader.take(4)?.try_into().ok()?);
+        let channels = u16::from_le_bytes(reader.take(2)?.try_into().ok()?) as usize;
+        let frames = u64::from_le_bytes(reader.take(8)?.try_into().ok()?);
+        let count = u16::from_le_bytes(reader.take(2)?.try_into().ok()?);
+        let mut levels = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let frames_per_peak = u32::from_le_bytes(reader.take(4)?.try_into().ok()?) as usize;
+            let len = u32::from_le_bytes(reader.take(4)?.try_into().ok()?) as usize;
+            let peaks = reader
+                .take(len * 2)?
+                .chunks_exact(2)
+                .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                .collect();
+            levels.push(WaveformLevel {
+                frames_per_peak,
+                peaks,
+            });
+        }
+        // Cached with different zoom levels
+        if channels == 0
+            || levels
+                .iter()
+                .map(|l| l.frames_per_peak)
+                .ne(ZOOM_LEVELS.iter().copied())
+        {
+            return None;
+        }
+        Some(Waveform {
+            sample_rate,
+            channels,
+            frames,
+            levels,
+        })
+    }
+}
+
+struct ByteReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.bytes.get(self.offset..self.offset + len)?;
+        self.offset += len;
+        Some(slice)
+    }
+}
+
+fn to_i16(value: f32) -> i16 {
+    (value * i16::MAX as f32)
+        .round()
+        .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+}
+
+fn from_i16(value: i16) -> f32 {
+    value as f32 / i16::MAX as f32
+}
+
+/// Collects the peaks of one zoom level while decoding
+struct LevelBuilder {
+    frames_per_peak: usize,
+    channels: usize,
+    frames: usize,
+    /// min, max and sum of squares per channel
+    current: Vec<[f32; 3]>,
+    peaks: Vec<[f32; 3]>,
+}
+
+impl LevelBuilder {
+    fn new(frames_per_peak: usize, channels: usize) -> Self {
+        LevelBuilder {
+            frames_per_peak,
+            channels,
+            frames: 0,
+            current: vec![[f32::INFINITY, f32::NEG_INFINITY, 0.0]; channels],
+            peaks: Vec::new(),
+        }
+    }
+
+    /// Returns true when a peak was completed
+    fn push_frame(&mut self, frame: &[f32]) -> bool {
+        for (stats, sample) in self.current.iter_mut().zip(frame) {
+            stats[0] = stats[0].min(*sample);
+            stats[1] = stats[1].max(*sample);
+            stats[2] += sample * sample;
+        }
+        self.frames += 1;
+        if self.frames < self.frames_per_peak {
+            return false;
+        }
+        self.end_peak();
+        true
+    }
+
+    fn end_peak(&mut self) {
+        let frames = self.frames.max(1) as f32;
+        for stats in self.current.iter_mut() {
+            if stats[0] > stats[1] {
+                *stats = [0.0; 3];
+            }
+            self.peaks
+                .push([stats[0], stats[1], (stats[2] / frames).sqrt()]);
+            *stats = [f32::INFINITY, f32::NEG_INFINITY, 0.0];
+        }
+        self.frames = 0;
+    }
+
+    /// The last completed peak
+    fn last(&self) -> &[[f32; 3]] {
+        &self.peaks[self.peaks.len() - self.channels..]
+    }
+
+    fn finish(mut self) -> WaveformLevel {
+        if self.frames > 0 {
+            self.end_peak();
+        }
+        WaveformLevel {
+            frames_per_peak: self.frames_per_peak,
+            peaks: self
+                .peaks
+                .iter()
+                .flat_map(|stats| stats.map(to_i16))
+                .collect(),
+        }
+    }
+}
+
+/**
+ * Decodes the file and computes every zoom level. `on_progress` gets the
+ * overview peaks decoded since it was last called.
+ */
+pub fn compute(
+    path: &Path,
+    cancel_token: &CancellationToken,
+    mut on_progress: impl FnMut(OverviewProgress),
+) -> Result<Waveform, symphonia::core::errors::Error> {
+    let mut decoder = FileDecoder::open(path)?;
+    let channels = decoder.channels;
+    let mut builders: Vec<LevelBuilder> = ZOOM_LEVELS
+        .iter()
+        .map(|frames_per_peak| LevelBuilder::new(*frames_per_peak, channels))
+        .collect();
+
+    let overview_len = decoder
+        .n_frames
+        .map_or(0, |frames| frames as usize / WAVEFORM_WINDOW_SIZE);
+    let mut overview = Vec::with_capacity(overview_len);
+    let mut sent = 0;
+    let mut last_progress = Instant::now();
+    let mut frames = 0u64;
+
+    decoder.for_each(cancel_token, |samples, packet_channels| {
+        for frame in samples.chunks_exact(packet_channels) {
+            for (index, builder) in builders.iter_mut().enumerate() {
+                if builder.push_frame(frame) && index == ZOOM_LEVELS.len() - 1 {
+                    overview.push(calculate_peak_value(builder.last(), WAVEFORM_PEAK_METHOD));
+                }
+            }
+        }
+        frames += (samples.len() / packet_channels) as u64;
+
+        if last_progress.elapsed() >= PROGRESS_INTERVAL {
+            last_progress = Instant::now();
+            on_progress(OverviewProgress {
+                offset: sent,
+                peaks: &overview[sent..],
+                length: overview_len,
+            });
+            sent = overview.len();
+        }
+    })?;
+
+    Ok(Waveform {
+        sample_rate: decoder.sample_rate,
+        channels,
+        frames,
+        levels: builders.into_iter().map(LevelBuilder::finish).collect(),
+    })
+}
+
+/**
+ * The waveform from the cache, or decoded and cached.
+ */
+pub fn load_or_compute(
+    path: &Path,
+    app_handle: &AppHandle,
+    cancel_token: &CancellationToken,
+    on_progress: impl FnMut(OverviewProgress),
+) -> Result<Waveform, symphonia::core::errors::Error> {
+    if let Some(waveform) = cache_path(path, app_handle).as_deref().and_then(read_cache) {
+        return Ok(waveform);
+    }
+
+    let waveform = compute(path, cancel_token, on_progress)?;
+    match save(path, app_handle, &waveform) {
+        Ok(_) => prune_cache(app_handle),
+        Err(err) => warn!("[Waveform] Error writing cache: {}", err),
+    }
+    Ok(waveform)
+}
+
+/// Whether the waveform of the file, as it is now, is in the cache
+pub fn is_cached(path: &Path, app_handle: &AppHandle) -> bool {
+    cache_path(path, app_handle).is_some_and(|cache_path| cache_path.exists())
+}
+
+/**
+ * Writes the waveform to the cache without making room for it.
+ * Returns the size of the cache file.
+ */
+pub fn save(path: &Path, app_handle: &AppHandle, waveform: &Waveform) -> std::io::Result<u64> {
+    let cache_path = cache_path(path, app_handle).ok_or(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "No cache location",
+    ))?;
+    write_cache(&cache_path, waveform)?;
+    Ok(std::fs::metadata(&cache_path)?.len())
+}
+
+/// Keeps the waveform of a file that was moved or renamed
+pub fn move_cached(from: &Path, to: &Path, app_handle: &AppHandle) -> std::io::Result<()> {
+    let (Some(previous), Some(current)) = (
+        cache_path_as(to, from, app_handle),
+        cache_path(to, app_handle),
+    ) else {
+        return Ok(());
+    };
+    if previous.exists() && !current.exists() {
+        std::fs::rename(previous, current)?;
+    }
+    Ok(())
+}
+
+/// Bytes used by the cache, spectrograms included, and the limit from the settings
+pub fn cache_usage(app_handle: &AppHandle) -> (u64, u64) {
+    (
+        cache_files(app_handle).iter().map(|(_, len, _)| len).sum(),
+        cache_limit(app_handle),
+    )
+}
+
+/**
+ * Sends the overview of the file with "waveform" events, as it is decoded
+ * or straight from the cache.
+ */
+pub fn get_peaks(
+    event: GetWaveformRequest,
+    app_handle: &AppHandle,
+    cancel_token: CancellationToken,
+) -> Result<Vec<f32>, symphonia::core::errors::Error> {
+    let path = event.path.unwrap_or_default();
+    let started = Instant::now();
+    let waveform = load_or_compute(Path::new(&path), app_handle, &cancel_token, |progress| {
+        let _ = app_handle.emit(
+            "waveform",
+            GetWaveformResponse {
+                data: Some(peaks_to_bytes(progress.peaks)),
+                offset: progress.offset,
+                length: progress.length,
+                complete: false,
+            },
+        );
+    })?;
+    let peaks = waveform.overview(WAVEFORM_PEAK_METHOD);
+    info!(
+        "[Waveform] {} peaks for {} in {:?}",
+        peaks.len(),
+        path,
+        started.elapsed()
+    );
+    let _ = app_handle.emit(
+        "waveform",
+        GetWaveformResponse {
+            data: Some(peaks_to_bytes(&peaks)),
+            offset: 0,
+            length: peaks.len(),
+            complete: true,
+        },
+    );
+    Ok(peaks)
+}
+
+fn peaks_to_bytes(peaks: &[f32]) -> ByteBuf {
+    ByteBuf::from(
+        peaks
+            .iter()
+            .flat_map(|peak| peak.to_le_bytes())
+            .collect::<Vec<u8>>(),
+    )
+}
+
+#[tauri::command]
+pub fn get_waveform(
+    event: GetWaveformRequest,
+    state: State<AudioPlayer>,
+    _app_handle: tauri::AppHandle,
+) {
+    info!("Get waveform {:?}", event);
+
+    let evt = event.clone();
+    let token = CancellationToken::new();
+    let token_clone = token.clone();
+    if let Ok(mut tokens) = state.cancel_tokens.try_lock() {
+        tokens
+            .iter()
+            .filter(|t| {
+                t.0 != &event.clone().path.unwrap()
+                    && !t.0.starts_with(spectrogram::CANCEL_TOKEN_PREFIX)
+                    && t.0 != precompute::CANCEL_TOKEN_KEY
+                    && t.0 != tempo_key::CANCEL_TOKEN_KEY
+                    && t.0 != similarity::CANCEL_TOKEN_KEY
+                    && t.0 != lossless::CANCEL_TOKEN_KEY
+                    && t.0 != dynamics::CANCEL_TOKEN_KEY
+            })
+            .for_each(|t| {
+                t.1.cancel();
+            });
+        tokens.insert(event.path.unwrap(), token);
+    }
+
+    std::thread::spawn(move || {
+        let _ = get_peaks(evt, &_app_handle, token_clone);
+    });
+}
+
+/**
+ * Peaks of the zoom level closest to `frames_per_peak` without less detail,
+ * for drawing a zoomed in waveform.
+ */
+#[tauri::command]
+pub async fn get_waveform_level(
+    path: String,
+    frames_per_peak: usize,
+    app_handle: AppHandle,
+) -> Result<WaveformLevelResponse, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        let waveform = load_or_compute(
+            Path::new(&path),
+            &app_handle,
+            &CancellationToken::new(),
+            |_| {},
+        )
+        .map_err(|e| e.to_string())?;
+        let level = waveform
+            .level(frames_per_peak)
+            .ok_or("No waveform".to_string())?;
+        Ok(WaveformLevelResponse {
+            sample_rate: waveform.sample_rate,
+            channels: waveform.channels,
+            frames: waveform.frames,
+            frames_per_peak: level.frames_per_peak,
+            data: ByteBuf::from(
+                level
+                    .peaks
+                    .iter()
+                    .flat_map(|p| p.to_le_bytes())
+                    .collect::<Vec<u8>>(),
+            ),
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub fn get_waveform_cache_info(app_handle: AppHandle) -> Result<WaveformCacheInfo, String> {
+    let (bytes, limit_bytes) = cache_usage(&app_handle);
+    Ok(WaveformCacheInfo {
+        files: cache_files(&app_handle).len(),
+        bytes,
+        limit_bytes,
+    })
+}
+
+#[tauri::command]
+pub fn clear_waveform_cache(app_handle: AppHandle) -> Result<(), String> {
+    for (path, _, _) in cache_files(&app_handle) {
+        remove_cached(&path).map_err(|e| format!("Error deleting {:?}: {}", path, e))?;
+    }
+    info!("[Waveform] Cache cleared");
+    Ok(())
+}
+
+fn cache_dir(app_handle: &AppHandle) -> Option<PathBuf> {
+    Some(app_handle.path().app_cache_dir().ok()?.join(CACHE_DIR))
+}
+
+/// Keyed by path, size and modification time, so edited files are decoded again
+fn cache_path(path: &Path, app_handle: &AppHandle) -> Option<PathBuf> {
+    cache_path_as(path, path, app_handle)
+}
+
+/// Cache path of the file as if it was at the given path, eg. before it was moved
+fn cache_path_as(file: &Path, path: &Path, app_handle: &AppHandle) -> Option<PathBuf> {
+    let metadata = std::fs::metadata(file).ok()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+    let key = format!("{}|{}|{}", path.to_string_lossy(), metadata.len(), modified);
+    Some(
+        cache_dir(app_handle)?
+            .join(MD5::hash(key.as_bytes()).to_hex_lowercase())
+            .with_extension(CACHE_EXTENSION),
+    )
+}
+
+fn read_cache(cache_path: &Path) -> Option<Waveform> {
+    let waveform = Waveform::from_bytes(&std::fs::read(cache_path).ok()?)?;
+    // The least recently used files are pruned first
+    let _ = filetime::set_file_mtime(cache_path, FileTime::now());
+    Some(waveform)
+}
+
+fn write_cache(cache_path: &Path, waveform: &Waveform) -> std::io::Result<()> {
+    if let Some(dir) = cache_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    // Never leave a half written file behind, or two writers in the same one
+    let partial = cache_path.with_extension(format!(
+        "{}.partial",
+        PARTIAL_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&partial, waveform.to_bytes())?;
+    std::fs::rename(&partial, cache_path)
+}
+
+fn cache_limit(app_handle: &AppHandle) -> u64 {
+    load_settings(app_handle)
+        .ok()
+        .and_then(|settings| settings.waveform_cache_size)
+        .unwrap_or(DEFAULT_CACHE_SIZE)
+        * 1024
+        * 1024
+}
+
+/// Path, size and last use of every cached waveform and spectrogram, they share the limit
+fn cache_files(app_handle: &AppHandle) -> Vec<(PathBuf, u64, FileTime)> {
+    let mut files = spectrogram::cache_files(app_handle);
+    let Some(entries) = cache_dir(app_handle).and_then(|dir| std::fs::read_dir(dir).ok()) else {
+        return files;
+    };
+    files.extend(
+        entries
+            .flatten()
+            .filter(|entry| {
+                entry.path().extension().and_then(|e| e.to_str()) == Some(CACHE_EXTENSION)
+            })
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((
+                    entry.path(),
+                    metadata.len(),
+                    FileTime::from_last_modification_time(&metadata),
+                ))
+            }),
+    );
+    files
+}
+
+fn remove_cached(path: &Path) -> std::io::Result<()> {
+    if path.extension().and_then(|e| e.to_str()) == Some(CACHE_EXTENSION) {
+        std::fs::remove_file(path)
+    } else {
+        spectrogram::remove_cached(path)
+    }
+}
+
+/// Deletes the least recently used waveforms and spectrograms until the cache fits the limit
+pub fn prune_cache(app_handle: &AppHandle) {
+    let limit = cache_limit(app_handle);
+    let mut files = cache_files(app_handle);
+    let mut total: u64 = files.iter().map(|(_, len, _)| len).sum();
+    if total <= limit {
+        return;
+    }
+    files.sort_by_key(|(_, _, used)| *used);
+    for (path, len, _) in files {
+        if total <= limit {
+            break;
+        }
+        if remove_cached(&path).is_ok() {
+            total -= len;
+        }
+    }
+    info!("[Waveform] Cache pruned to {} bytes", total);
+}
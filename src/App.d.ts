@@ -40,6 +40,20 @@ interface FileInfo {
     codec: string;
 }
 
+interface StreamMetadata {
+    url: string;
+    station?: string;
+    streamTitle?: string;
+    artist?: string;
+    title?: string;
+}
+
+interface Chapter {
+    title: string;
+    start: number; // seconds
+    end: number; // seconds
+}
+
 interface Song {
     /**
      * A hash of the filepath
@@ -85,10 +99,40 @@ interface Song {
     isFavourite: boolean;
     markers: Marker[];
     dateAdded?: number; // unix timestamp
+    // Virtual tracks from a CUE sheet
+    cuePath?: string;
+    cueTrack?: number;
+    startOffset?: number; // seconds
+    endOffset?: number; // seconds
+    chapters?: Chapter[];
+    bpm?: number; // from the tags or detected
+    key?: string; // eg. "Am", from the tags or detected
+    dynamics?: {
+        dr: number; // DR score like foobar's DR Meter
+        drPrecise: number;
+        peak: number; // dBFS
+        truePeak: number; // dBTP
+        clippedSamples: number;
+        interSampleOvers: number;
+    }; // only once analysed
     tags: string[];
     stems: Stem[]; // for stem separation feature
 }
 
+/** Songs picked by the auto DJ, to append to the queue */
+interface AutoDjQueue {
+    seedSongId: string;
+    tracks: { songId: string; path: string; distance: number }[];
+}
+
+/** What an analysis found out about a file, only what is set changes */
+interface SongAnalysis {
+    path: string;
+    bpm?: number;
+    key?: string;
+    dynamics?: Song["dynamics"];
+}
+
 interface Album {
     id: string; // Hash of artist + album name
     title: string; // We store the title in lower case for indexed case insensitive searches
@@ -189,12 +233,35 @@ interface UserSettings {
     geniusApiKey?: string;
     discogsApiKey?: string;
     beetsDbLocation?: string;
+    playCountThreshold?: number;
+    listenbrainzToken?: string;
+    listenbrainzUrl?: string;
+    lastfmApiKey?: string;
+    lastfmApiSecret?: string;
+    lastfmSessionKey?: string;
+    lastfmUrl?: string;
+    scrobblerLogLocation?: string;
+    mpdEnabled?: boolean;
+    mpdPort?: number;
+    mpdAllowLan?: boolean;
+    remoteApiEnabled?: boolean;
+    remoteApiPort?: number;
+    remoteApiToken?: string;
+    remoteApiAllowLan?: boolean;
+    broadcastEnabled?: boolean;
+    broadcastPort?: number;
+    broadcastFormat?: "wav" | "flac";
+    waveformCacheSize?: number;
 }
 
 type AnalyzerType = "time" | "frequency";
 interface AudioAnalyzer {
     isEnabled: boolean;
     analyzerType: AnalyzerType;
+    /** Separate bars for the left and right channel */
+    stereo?: boolean;
+    /** Show the recent peak of each bar */
+    peakHold?: boolean;
 }
 
 interface EqualizerBand {
@@ -319,6 +386,61 @@ interface ToImport {
     error?: string;
 }
 
+interface ScanDelta {
+    added: Song[];
+    updated: Song[];
+    moved: { from: string; song: Song }[]; // from the old song id
+    removed: string[]; // song ids
+    albums: Album[]; // with only the added and updated tracks
+    unchanged: number;
+}
+
+// Queries on the native library database
+interface LibraryQuery {
+    offset?: number;
+    limit?: number; // 100 by default
+    sortBy?: string; // eg. "artist", "title", "year", "dateAdded", "playCount"
+    descending?: boolean;
+    search?: string;
+    artist?: string;
+    albumId?: string;
+}
+
+interface LibraryPage<T> {
+    items: T[];
+    total: number; // across all pages
+    offset: number;
+}
+
+interface LibraryArtist {
+    name: string;
+    songCount: number;
+    albumCount: number;
+}
+
+interface LibraryPlaylist {
+    id: number;
+    path: string;
+    title: string;
+    trackCount: number;
+}
+
+// Smart playlist queries run natively, eg. genre:jazz year:1955..1965 plays>3
+type SmartQuerySource = "library" | "beets";
+
+interface SmartQueryError {
+    message: string;
+    start: number; // character offsets in the query
+    end: number;
+}
+
+interface SmartQueryExplanation {
+    description: string;
+    sql?: string;
+    parameters: (string | number | null)[];
+    evaluated?: string; // checked song by song, eg. play counts for beets
+}
+
 interface ToImportAlbums {
     albums: Album[];
     progress: number;
@@ -404,7 +526,13 @@ interface SongChangeEvent {
     songId: string;
 }
 interface Waveform {
+    /** All peaks when complete, otherwise the ones new since the last event */
     data: number[];
+    /** Index of the first peak in data */
+    offset: number;
+    /** Expected number of peaks, 0 when unknown */
+    length: number;
+    complete: boolean;
 }
 
 interface WaveformPlayerState {
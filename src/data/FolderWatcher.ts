@@ -1,8 +1,10 @@
+import { invoke } from "@tauri-apps/api/core";
+import { getCurrentWebviewWindow } from "@tauri-apps/api/webviewWindow";
 import { exists, watchImmediate } from "@tauri-apps/plugin-fs";
 import md5 from "md5";
 import { get } from "svelte/store";
-import { isAudioFile, isFileOrDirectory } from "../utils/FileUtils";
-import { importPaths } from "./LibraryUtils";
+import { isFileOrDirectory } from "../utils/FileUtils";
+import { applyScanDelta } from "./LibraryUtils";
 import { db } from "./db";
 import {
     bottomBarNotification,
@@ -19,112 +21,47 @@ import {
 } from "./ArtistsToolkitData";
 import { scanPlaylists } from "./M3UUtils";
 
-// can also watch an array of paths
+const appWindow = getCurrentWebviewWindow();
+
+/**
+ * Watching happens natively, we apply the changes it finds
+ * @returns a function to stop watching
+ */
 export async function startWatchingLibraryFolders() {
     const settings = get(userSettings);
     const paths = settings.foldersToWatch;
 
-    const startWatching = await watchImmediate(
-        paths,
-        async (event) => {
-            console.log(event);
-            let toastText = "";
-            if (typeof event.type === "object") {
-                isFolderWatchUpdate.set(true);
-
-                // In this case we want to delete and re-add the track(s),
-                // since the ID is based on the filepath
-                for (const path of event.paths) {
-                    const result = isFileOrDirectory(path);
-                    console.log("result", result);
-                    if (paths.includes(path)) {
-                        continue;
-                    }
-                    if (result === "file") {
-                        if (isAudioFile(path)) {
-                            const file = path.split("/").pop();
-                            let fileExists = false;
-                            try {
-                                fileExists = await exists(path);
-                            } catch (err) {
-                                console.error("err in fileexists");
-                            }
-                            const filehash = md5(path);
-                            const song = await db.songs.get(filehash);
-
-                            console.log(
-                                "[Folder watcher] File exists",
-                                fileExists,
-                            );
-
-                            // New file
-                            if (!song && fileExists) {
-                                console.log("[Folder watcher] File added");
-                                bottomBarNotification.set({
-                                    text: "Folder watcher: File added - updating library...",
-                                    timeout: 2000,
-                                });
-                                await importPaths([path], true);
-                            }
-                            // Deletion
-                            else if (!fileExists && song) {
-                                console.log("[Folder watcher] File deleted");
-                                bottomBarNotification.set({
-                                    text: "Folder watcher: File deleted - updating library...",
-                                    timeout: 2000,
-                                });
-                                await db.songs.delete(filehash);
-                            }
-                        }
-                    } else if (result === "directory") {
-                        const pathExists = await exists(path);
-                        if (!pathExists) {
-                            console.log("[Folder watcher] Folder deleted");
-                            bottomBarNotification.set({
-                                text: "Folder watcher: Folder deleted - updating library...",
-                                timeout: 2000,
-                            });
-                            // Deleted folder - delete all songs from db
-                            const songsToDelete = await db.songs
-                                .where("path")
-                                .startsWith(path);
-                            // Check if album needs to be deleted too
-                            const songsToCheck = await songsToDelete.toArray();
-                            const keys = await songsToDelete.primaryKeys();
-                            await db.songs.bulkDelete(keys);
-                            if (
-                                songsToCheck.every(
-                                    (s) => s.album === songsToCheck[0].album,
-                                )
-                            ) {
-                                const song = songsToCheck[0];
-                                const albumPath = song.path.replace(
-                                    `/${song.file}`,
-                                    "",
-                                );
-
-                                let id = md5(
-                                    `${albumPath} - ${song.album}`.toLowerCase(),
-                                );
-                                await db.albums.delete(id);
-                            }
-                        } else {
-                            console.log("[Folder watcher] Folder added");
-                            bottomBarNotification.set({
-                                text: "Folder watcher: Folder added - updating library...",
-                                timeout: 2000,
-                            });
-                            // await importPaths([path], true);
-                        }
-                    }
-                }
-            }
+    const unlistenChanges = await appWindow.listen<ScanDelta>(
+        "library_changed",
+        async ({ payload: delta }) => {
+            console.log("[Folder watcher] Library changed", delta);
+            isFolderWatchUpdate.set(true);
+            bottomBarNotification.set({
+                text: "Folder watcher: Files changed - updating library...",
+                timeout: 2000,
+            });
+            await applyScanDelta(delta);
             isFolderWatchUpdate.set(false);
         },
-        { recursive: true },
     );
-
-    return startWatching;
+    const unlistenAvailability = await appWindow.listen<{
+        path: string;
+        available: boolean;
+    }>("library_folder_availability", ({ payload }) => {
+        bottomBarNotification.set({
+            text: payload.available
+                ? `Folder watcher: ${payload.path} is back - updating library...`
+                : `Folder watcher: ${payload.path} can't be reached`,
+            timeout: 3000,
+        });
+    });
+    await invoke("watch_library_folders", { paths });
+
+    return async () => {
+        unlistenChanges();
+        unlistenAvailability();
+        await invoke("unwatch_library_folders");
+    };
 }
 
 export async function startWatchingScrapbookFolder() {
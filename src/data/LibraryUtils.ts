@@ -88,6 +88,7 @@ export async function importPaths(
             process_m3u: false,
             is_async: true,
             is_cover_fullcheck: get(userSettings).isCoverFullCheckEnabled,
+            save_to_library: true,
         },
     });
 
@@ -312,13 +313,101 @@ export async function openTauriImportDialog() {
 export async function runScan() {
     const settings = get(userSettings);
 
-    for (const folder of settings.foldersToWatch) {
-        bottomBarNotification.set({
-            text: `Scanning ${folder} ...`,
-            timeout: 2000,
+    bottomBarNotification.set({
+        text: `Scanning ${settings.foldersToWatch.join(", ")} ...`,
+        timeout: 2000,
+    });
+    // All at once, so songs moved between folders are recognised
+    await rescanPaths(settings.foldersToWatch);
+}
+
+/**
+ * Rescans folders or files, only reading the new and changed files since the last scan
+ * @param paths folders or files
+ */
+export async function rescanPaths(paths: string[]) {
+    const delta = await invoke<ScanDelta>("rescan_paths", {
+        event: {
+            paths,
+            is_cover_fullcheck: get(userSettings).isCoverFullCheckEnabled,
+            // The index doesn't know the library was cleared, read it all
+            full: (await db.songs.count()) === 0,
+        },
+    });
+    await applyScanDelta(delta);
+    return delta;
+}
+
+/**
+ * Brings the library in line with a rescan
+ * @param delta from a rescan or the folder watcher
+ */
+export async function applyScanDelta(delta: ScanDelta) {
+    const songs = [
+        ...delta.added,
+        ...delta.updated,
+        ...delta.moved.map((m) => m.song),
+    ];
+    if (songs.length) {
+        // Keep the user-specific fields of songs we already had
+        const oldSongs = (
+            await db.songs.bulkGet(songs.map((s) => s.id))
+        ).filter(Boolean);
+        const songIdToOldAlbumId = {};
+        for (const song of oldSongs) {
+            songIdToOldAlbumId[song.id] = getAlbumId(song);
+        }
+        // Moved songs have a new id, carry them over from the old one
+        const movedFrom = await db.songs.bulkGet(
+            delta.moved.map((m) => m.from),
+        );
+        movedFrom.forEach((oldSong, idx) => {
+            if (oldSong) {
+                oldSongs.push({ ...oldSong, id: delta.moved[idx].song.id });
+            }
         });
-        await importPaths([folder], true);
+        await reImport(
+            { songs, albums: delta.albums, progress: 100, done: true },
+            oldSongs,
+            songIdToOldAlbumId,
+        );
     }
+    const removedIds = [...delta.removed, ...delta.moved.map((m) => m.from)];
+    if (removedIds.length) {
+        const removed = (await db.songs.bulkGet(removedIds)).filter(Boolean);
+        await deleteFromLibrary(removed);
+    }
+    if (delta.moved.length) {
+        await remapSongIds(delta.moved);
+        // Stems were moved to the new ids
+        await scanExistingStems();
+    }
+}
+
+/**
+ * Points playlists and song projects at the new ids of moved songs
+ * @param moved songs from a rescan, with their old ids
+ */
+async function remapSongIds(moved: ScanDelta["moved"]) {
+    const newIds: { [key: string]: string } = {};
+    for (const m of moved) {
+        newIds[m.from] = m.song.id;
+    }
+    await db.transaction(
+        "rw",
+        db.internalPlaylists,
+        db.songProjects,
+        async () => {
+            await db.internalPlaylists.toCollection().modify((playlist) => {
+                playlist.tracks = playlist.tracks.map((id) => newIds[id] ?? id);
+            });
+            await db.songProjects.toCollection().modify((project) => {
+                if (project.songId && newIds[project.songId]) {
+                    project.songId = newIds[project.songId];
+                }
+            });
+        },
+    );
 }
 
 export async function getArtistProfileImage(
@@ -388,6 +477,7 @@ export async function deleteFromLibrary(tracks: Song[]) {
             await db.albums.bulkDelete(albumsToDelete);
         }
     });
+    await invoke("delete_library_songs", { ids: tracks.map((t) => t.id) });
 }
 
 export function getAlbumId(track: Song): string {
@@ -138,6 +138,7 @@ export async function deleteDatabase() {
     await db.scrapbook.clear();
     await db.playlists.clear();
     await db.delete();
+    await invoke("clear_library");
 }
 
 // Helper functions for querying DB (using dexie or beets)
@@ -1,6 +1,6 @@
 import { invoke } from "@tauri-apps/api/core";
 import { get } from "svelte/store";
-import type { ArtworkSrc, Song, ToImport } from "../../App";
+import type { ArtworkSrc, AutoDjQueue, Song, ToImport } from "../../App";
 import { db, getAlbum, getAlbumTracks } from "../../data/db";
 import {
     current,
@@ -11,6 +11,7 @@ import {
     isSongReady,
     nextUpSong,
     os,
+    playbackSpeed,
     playerTime,
     queue,
     repeatMode,
@@ -242,6 +243,8 @@ class AudioPlayer {
                         path: this.currentSong.path,
                         seek: 0,
                         file_info: this.currentSong.fileInfo,
+                        cue_path: this.currentSong.cuePath,
+                        cue_track: this.currentSong.cueTrack,
                         volume: get(volume),
                         boot: true,
                     },
@@ -349,6 +352,38 @@ class AudioPlayer {
             console.log("play_previous");
             this.playPrevious();
         });
+
+        appWindow.listen("seek_to", async (event: any) => {
+            console.log("seek_to", event.payload);
+            this.setSeek(event.payload);
+        });
+
+        appWindow.listen("set_volume", async (event: any) => {
+            console.log("set_volume", event.payload);
+            volume.set(event.payload);
+        });
+
+        appWindow.listen("set_playback_speed", async (event: Event<number>) => {
+            console.log("set_playback_speed", event.payload);
+            playbackSpeed.set(event.payload);
+            invoke("playback_speed_control", {
+                event: {
+                    playback_speed: event.payload,
+                },
+            });
+        });
+
+        appWindow.listen(
+            "auto_dj_queue",
+            async (event: Event<AutoDjQueue>) => {
+                const ids = event.payload.tracks.map((track) => track.songId);
+                // Songs removed from the library since are left out
+                const songs = (await db.songs.bulkGet(ids)).filter(Boolean);
+                if (songs.length) {
+                    queue.update((queued) => [...queued, ...songs]);
+                }
+            },
+        );
     }
 
     async setupBuffers() {
@@ -547,6 +582,8 @@ class AudioPlayer {
                             path: nextSong.path,
                             seek: 0,
                             file_info: nextSong.fileInfo,
+                            cue_path: nextSong.cuePath,
+                            cue_track: nextSong.cueTrack,
                             volume: get(volume),
                         },
                     });
@@ -570,6 +607,8 @@ class AudioPlayer {
                         path: this.currentSong.path,
                         seek: 0,
                         file_info: this.currentSong.fileInfo,
+                        cue_path: this.currentSong.cuePath,
+                        cue_track: this.currentSong.cueTrack,
                         volume: get(volume),
                     },
                 });
@@ -582,6 +621,8 @@ class AudioPlayer {
                         path: this.queue[nextIndex].path,
                         seek: 0,
                         file_info: this.queue[nextIndex].fileInfo,
+                        cue_path: this.queue[nextIndex].cuePath,
+                        cue_track: this.queue[nextIndex].cueTrack,
                         volume: get(volume),
                     },
                 });
@@ -633,6 +674,8 @@ class AudioPlayer {
                         path: this.currentSong.path,
                         seek: position,
                         file_info: this.currentSong.fileInfo,
+                        cue_path: this.currentSong.cuePath,
+                        cue_track: this.currentSong.cueTrack,
                         volume: get(volume),
                     },
                 });
@@ -23,10 +23,15 @@ export interface IAnimation {
 
     isEnabled = true;
     analyzerType: AnalyzerType = "time";
+    stereo = false;
+    peakHold = false;
     shouldStopAnimation = false;
 
     timeDomain: Uint8Array;
-    freqDomain: Uint8Array;
+    /** Bars of each channel, left and right when stereo */
+    freqDomain: Uint8Array[];
+    /** Held peaks in the same layout, empty without peak hold */
+    peaks: Uint8Array[] = [];
 
     lastTick = performance.now();
     color: string;
@@ -38,7 +43,7 @@ export interface IAnimation {
         this._canvasContext = this.canvas.getContext("2d");
 
         this.timeDomain = new Uint8Array(256).fill(128);
-        this.freqDomain = new Uint8Array(256).fill(1);
+        this.freqDomain = [new Uint8Array(256).fill(1)];
         this.color = get(currentThemeObject)["accent-play"];
 
         // 1. Store the unsubscribe function for isPlaying
@@ -60,6 +65,8 @@ export interface IAnimation {
         const unsubPrefs = uiPreferences.subscribe((preferences) => {
             this.isEnabled = preferences.audioAnalyzer.isEnabled;
             this.analyzerType = preferences.audioAnalyzer.analyzerType;
+            this.stereo = preferences.audioAnalyzer.stereo ?? false;
+            this.peakHold = preferences.audioAnalyzer.peakHold ?? false;
 
             if (!get(isPlaying)) {
                 this.showEmptyView();
@@ -69,6 +76,8 @@ export interface IAnimation {
                 event: {
                     enabled: this.isEnabled,
                     analyzer_type: this.analyzerType,
+                    stereo: this.stereo,
+                    peak_hold: this.peakHold,
                 },
             });
         });
@@ -79,7 +88,11 @@ export interface IAnimation {
 
     showEmptyView() {
         this.timeDomain = new Uint8Array(256).fill(128);
-        this.freqDomain = new Uint8Array(256).fill(3);
+        this.freqDomain = Array.from(
+            { length: this.stereo ? 2 : 1 },
+            () => new Uint8Array(256).fill(3),
+        );
+        this.peaks = [];
 
         this.clearCanvas();
         if (this.analyzerType === "frequency") {
@@ -97,8 +110,11 @@ export interface IAnimation {
         }
         if (audioPlayer.webRTCReceiver) {
             audioPlayer.webRTCReceiver.onSampleData = (samples: Uint8Array) => {
-                this.timeDomain = samples;
-                this.freqDomain = samples;
+                if (this.analyzerType === "frequency") {
+                    this.parseFrequencyData(samples);
+                } else {
+                    this.timeDomain = samples;
+                }
             };
         }
         let tick = () => {
@@ -121,6 +137,21 @@ export interface IAnimation {
         this._animationFrameId = window.requestAnimationFrame(tick);
     }
 
+    /**
+     * Split the frequency analyzer data: the bars of each channel, followed
+     * by the peaks in the same layout when peak hold is on
+     */
+    parseFrequencyData(data: Uint8Array) {
+        const channels = this.stereo ? 2 : 1;
+        const parts = channels * (this.peakHold ? 2 : 1);
+        const bins = Math.floor(data.length / parts);
+        const slices = Array.from({ length: parts }, (_, i) =>
+            data.subarray(i * bins, (i + 1) * bins),
+        );
+        this.freqDomain = slices.slice(0, channels);
+        this.peaks = slices.slice(channels);
+    }
+
     /**
      * Draw Frequency Bars
      */
@@ -128,24 +159,62 @@ export interface IAnimation {
         if (!this.freqDomain || this.freqDomain.length === 0) return;
 
         this._canvasContext.shadowBlur = 0;
-        const barWidth = this.canvas.width / this.freqDomain.length;
+        this._canvasContext.fillStyle = this.color;
         const centerY = this.canvas.height / 2; // Find the vertical middle
-
-        for (let i = 0; i < this.freqDomain.length; i++) {
-            // Map the 0-255 value to the canvas height
-            const totalHeight = (this.freqDomain[i] / 255) * this.canvas.height;
-
-            // For symmetry, the bar "starts" half-way above the center
-            const x = i * barWidth;
-            const y = centerY - totalHeight / 2;
-
-            this._canvasContext.fillStyle = this.color;
-
-            // Draw the rectangle (x, y, width, height)
-            // By starting at y and drawing down 'totalHeight',
-            // it perfectly straddles the center line.
-            this._canvasContext.fillRect(x, y, barWidth - 1, totalHeight);
-        }
+        const stereo = this.freqDomain.length === 2;
+
+        this.freqDomain.forEach((bars, channel) => {
+            const barWidth = this.canvas.width / bars.length;
+            const peaks = this.peaks[channel];
+
+            for (let i = 0; i < bars.length; i++) {
+                const x = i * barWidth;
+
+                if (stereo) {
+                    // Left grows up from the center line, right grows down
+                    const height = (bars[i] / 255) * centerY;
+                    const y = channel === 0 ? centerY - height : centerY;
+                    this._canvasContext.fillRect(x, y, barWidth - 1, height);
+                    if (peaks) {
+                        const peak = (peaks[i] / 255) * centerY;
+                        const peakY =
+                            channel === 0 ? centerY - peak : centerY + peak - 2;
+                        this._canvasContext.fillRect(x, peakY, barWidth - 1, 2);
+                    }
+                } else {
+                    // Map the 0-255 value to the canvas height
+                    const totalHeight = (bars[i] / 255) * this.canvas.height;
+
+                    // For symmetry, the bar "starts" half-way above the center
+                    const y = centerY - totalHeight / 2;
+
+                    // Draw the rectangle (x, y, width, height)
+                    // By starting at y and drawing down 'totalHeight',
+                    // it perfectly straddles the center line.
+                    this._canvasContext.fillRect(
+                        x,
+                        y,
+                        barWidth - 1,
+                        totalHeight,
+                    );
+                    if (peaks) {
+                        const peak = (peaks[i] / 255) * this.canvas.height;
+                        this._canvasContext.fillRect(
+                            x,
+                            centerY - peak / 2,
+                            barWidth - 1,
+                            2,
+                        );
+                        this._canvasContext.fillRect(
+                            x,
+                            centerY + peak / 2 - 2,
+                            barWidth - 1,
+                            2,
+                        );
+                    }
+                }
+            }
+        });
     }
     drawOscilloscope() {
         if (!this.timeDomain || this.timeDomain.length === 0) return;
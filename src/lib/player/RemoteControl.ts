@@ -0,0 +1,122 @@
+import { invoke } from "@tauri-apps/api/core";
+import { listen } from "@tauri-apps/api/event";
+import { get } from "svelte/store";
+import type { Song, ToImport } from "../../App";
+import { db } from "../../data/db";
+import {
+    current,
+    isPlaying,
+    queue,
+    shuffledQueue,
+    userSettings,
+    volume,
+} from "../../data/store";
+import audioPlayer from "./AudioPlayer";
+
+/**
+ * Bridge for remote control (MPD server, HTTP API, command line).
+ * The queue and player state are mirrored to Rust, and control events from Rust are handled here.
+ */
+
+const SEARCH_LIMIT = 500;
+
+function toRemoteSong(song: Song) {
+    return {
+        id: song.id,
+        path: song.path,
+        title: song.title,
+        artist: song.artist,
+        album: song.album,
+        albumArtist: song.albumArtist,
+        genre: song.genre ?? [],
+        year: song.year,
+        trackNumber: song.trackNumber,
+        discNumber: song.discNumber,
+        duration: song.fileInfo?.duration,
+    };
+}
+
+function sync(event: {
+    queue?: ReturnType<typeof toRemoteSong>[];
+    currentIndex?: number;
+    isPlaying?: boolean;
+    volume?: number;
+}) {
+    invoke("remote_sync", { event }).catch((err) =>
+        console.error("remote_sync", err),
+    );
+}
+
+function syncQueue() {
+    // Let the player update its (possibly shuffled) queue first
+    setTimeout(() => {
+        sync({
+            queue: (audioPlayer.queue ?? []).filter(Boolean).map(toRemoteSong),
+            currentIndex: get(current).song ? get(current).index : -1,
+        });
+    });
+}
+
+export async function startRemoteControl() {
+    queue.subscribe(syncQueue);
+    shuffledQueue.subscribe(syncQueue);
+    current.subscribe(({ song, index }) => {
+        sync({ currentIndex: song ? index : -1 });
+    });
+    isPlaying.subscribe((playing) => sync({ isPlaying: playing }));
+    volume.subscribe((vol) => sync({ volume: vol }));
+
+    await listen<number>("remote_play_index", ({ payload }) => {
+        const song = audioPlayer.queue[payload];
+        if (song) {
+            audioPlayer.playSong(song, 0, true, payload);
+        }
+    });
+
+    await listen<{ paths: string[]; play: boolean }>(
+        "remote_enqueue",
+        async ({ payload }) => {
+            const response = await invoke<ToImport>("scan_paths", {
+                event: {
+                    paths: payload.paths,
+                    recursive: true,
+                    process_albums: false,
+                    process_m3u: true,
+                    is_async: false,
+                    is_cover_fullcheck:
+                        get(userSettings).isCoverFullCheckEnabled,
+                },
+            });
+            if (!response.songs?.length) {
+                return;
+            }
+            const start = get(queue).length;
+            queue.update((songs) => [...songs, ...response.songs]);
+            if (payload.play) {
+                audioPlayer.playSong(response.songs[0], 0, true, start);
+            }
+        },
+    );
+
+    await listen<{ id: number; query: string }>(
+        "remote_search",
+        async ({ payload }) => {
+            const query = payload.query.toLowerCase();
+            const songs = await db.songs
+                .filter(
+                    (song) =>
+                        !query ||
+                        song.title?.toLowerCase().includes(query) ||
+                        song.artist?.toLowerCase().includes(query) ||
+                        song.album?.toLowerCase().includes(query) ||
+                        song.albumArtist?.toLowerCase().includes(query),
+                )
+                .limit(SEARCH_LIMIT)
+                .toArray();
+            await invoke("remote_search_result", {
+                id: payload.id,
+                songs: songs.map(toRemoteSong),
+            });
+        },
+    );
+}
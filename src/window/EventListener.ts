@@ -5,7 +5,12 @@ import { get } from "svelte/store";
 import { path } from "@tauri-apps/api";
 import { appConfigDir, appDataDir } from "@tauri-apps/api/path";
 import { CACHE_DIR, deleteCacheDirectory } from "../data/Cacher";
-import { deleteDatabase, exportDatabase, importDatabase } from "../data/db";
+import {
+    db,
+    deleteDatabase,
+    exportDatabase,
+    importDatabase,
+} from "../data/db";
 import { openTauriImportDialog } from "../data/LibraryUtils";
 import {
     isCompactView,
@@ -19,6 +24,7 @@ import {
     updaterStatus,
 } from "../data/store";
 
+import type { SongAnalysis } from "../App";
 import { invoke } from "@tauri-apps/api/core";
 import toast from "svelte-french-toast";
 import { openPath } from "@tauri-apps/plugin-opener";
@@ -128,3 +134,13 @@ export async function startErrorListener() {
         toast.error(event.payload);
     });
 }
+
+/**
+ * Results of the backend's analyses (tempo, key...), for every song of the file
+ */
+export async function startSongAnalysisListener() {
+    appWindow.listen("song_analysis", async (event: Event<SongAnalysis>) => {
+        const { path, ...changes } = event.payload;
+        await db.songs.where("path").equals(path).modify(changes);
+    });
+}